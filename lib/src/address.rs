@@ -0,0 +1,52 @@
+use crate::crypto::PublicKey;
+use crate::error::{BtcError, Result};
+use crate::script::Script;
+use crate::sha256::Hash;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+// Base58check encoded pubkey hash, paid to with a PayToPubkeyHash output
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Address(Hash);
+
+impl Address {
+    pub const VERSION: u8 = 0x00;
+
+    pub fn from_public_key(pubkey: &PublicKey) -> Self {
+        Address(Script::pubkey_hash(pubkey))
+    }
+
+    pub fn pubkey_hash(&self) -> Hash {
+        self.0
+    }
+
+    pub fn script(&self) -> Script {
+        Script::PayToPubkeyHash(self.0)
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let encoded = bs58::encode(self.0.as_bytes())
+            .with_check_version(Self::VERSION)
+            .into_string();
+        write!(f, "{}", encoded)
+    }
+}
+
+impl FromStr for Address {
+    type Err = BtcError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let decoded = bs58::decode(s.trim())
+            .with_check(Some(Self::VERSION))
+            .into_vec()
+            .map_err(|_| BtcError::InvalidAddress)?;
+        // The version byte is kept in front of the payload
+        let bytes: [u8; 32] = decoded[1..]
+            .try_into()
+            .map_err(|_| BtcError::InvalidAddress)?;
+        Ok(Address(Hash::from_bytes(bytes)))
+    }
+}
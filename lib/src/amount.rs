@@ -0,0 +1,70 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+// A value in satoshis. Arithmetic is checked so overflows surface as None
+// instead of wrapping; serialized as a plain integer like the raw u64 before.
+#[derive(
+    Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+#[serde(transparent)]
+pub struct Amount(u64);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+    pub const SATS_PER_BTC: u64 = 100_000_000;
+
+    pub const fn from_sat(sats: u64) -> Self {
+        Amount(sats)
+    }
+
+    pub const fn to_sat(self) -> u64 {
+        self.0
+    }
+
+    pub fn from_btc(btc: f64) -> Option<Self> {
+        let sats = (btc * Self::SATS_PER_BTC as f64).round();
+        if !sats.is_finite() || sats < 0.0 || sats > u64::MAX as f64 {
+            return None;
+        }
+        Some(Amount(sats as u64))
+    }
+
+    pub fn to_btc(self) -> f64 {
+        self.0 as f64 / Self::SATS_PER_BTC as f64
+    }
+
+    pub fn checked_add(self, other: Amount) -> Option<Amount> {
+        self.0.checked_add(other.0).map(Amount)
+    }
+
+    pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+        self.0.checked_sub(other.0).map(Amount)
+    }
+
+    pub fn checked_mul(self, factor: u64) -> Option<Amount> {
+        self.0.checked_mul(factor).map(Amount)
+    }
+
+    pub fn checked_div(self, divisor: u64) -> Option<Amount> {
+        self.0.checked_div(divisor).map(Amount)
+    }
+
+    pub fn checked_sum(mut amounts: impl Iterator<Item = Amount>) -> Option<Amount> {
+        amounts.try_fold(Amount::ZERO, Amount::checked_add)
+    }
+
+    pub fn display_sat(self) -> String {
+        format!("{} sat", self.0)
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}.{:08} BTC",
+            self.0 / Self::SATS_PER_BTC,
+            self.0 % Self::SATS_PER_BTC
+        )
+    }
+}
@@ -1,32 +1,125 @@
-use btclib::crypto::PrivateKey;
+use btclib::amount::Amount;
+use btclib::crypto::{PrivateKey, SignatureScheme};
+use btclib::params::ChainParams;
+use btclib::script::{Script, Witness};
 use btclib::sha256::Hash;
-use btclib::types::{Block, BlockHeader, Transaction, TransactionOutput};
+use btclib::types::{
+    Block, BlockHeader, Blockchain, Transaction, TransactionInput, TransactionOutput,
+};
 use btclib::util::{MerkleRoot, Saveable};
 use chrono::Utc;
 use std::env;
 use std::process::exit;
-use uuid::Uuid;
+
+const USAGE: &str = "Usage: block_gen <block_file> [<chain_file> \
+    [valid|bad-merkle|bad-target|double-spend] [<private_key_file>]]";
 
 fn main() {
-    let path = if let Some(arg) = env::args().nth(1) {
-        arg
-    } else {
-        eprintln!("Usage: block_gen <block_file>");
+    let args = env::args().collect::<Vec<_>>();
+    let Some(path) = args.get(1) else {
+        eprintln!("{}", USAGE);
         exit(1);
     };
-    let private_key = PrivateKey::new_key();
-    let transactions = vec![Transaction::new(
-        vec![],
+    let private_key = PrivateKey::generate();
+    let block = match args.get(2) {
+        None => Block::genesis(&ChainParams::mainnet()),
+        Some(chain_file) => {
+            let mut blockchain =
+                Blockchain::load_from_file(chain_file).expect("Failed to load blockchain");
+            blockchain.rebuild_utxos();
+            let kind = args.get(3).map(String::as_str).unwrap_or("valid");
+            let spender = args
+                .get(4)
+                .map(|file| PrivateKey::load_from_file(file).expect("Failed to load private key"));
+            next_block(&blockchain, &private_key, kind, spender.as_ref())
+        }
+    };
+    block.save_to_file(path).expect("Failed to save block")
+}
+
+// Builds the block following the tip of the chain, broken in the requested way
+fn next_block(
+    blockchain: &Blockchain,
+    private_key: &PrivateKey,
+    kind: &str,
+    spender: Option<&PrivateKey>,
+) -> Block {
+    let height = blockchain.next_height().get();
+    let prev_block_hash = blockchain
+        .blocks()
+        .next_back()
+        .map(|block| block.hash())
+        .unwrap_or(Hash::zero());
+    let coinbase = Transaction::coinbase(
+        height,
         vec![TransactionOutput {
-            unique_id: Uuid::new_v4(),
-            value: btclib::INITIAL_REWARD * 10u64.pow(8),
-            pubkey: private_key.public_key(),
+            value: blockchain.block_subsidy(),
+            script: Script::PayToPubkey(private_key.public_key()),
         }],
-    )];
-    let merkle_root = MerkleRoot::calculate(&transactions);
-    let block = Block::new(
-        BlockHeader::new(Utc::now(), 0, Hash::zero(), merkle_root, btclib::MIN_TARGET),
-        transactions,
     );
-    block.save_to_file(path).expect("Failed to save block")
+    let mut transactions = vec![coinbase];
+    if kind == "double-spend" {
+        let Some(spender) = spender else {
+            eprintln!("double-spend needs the private key owning a mature output");
+            exit(1);
+        };
+        let (outpoint, entry) = blockchain
+            .utxos()
+            .iter()
+            .find(|(_, entry)| {
+                entry.output.script == Script::PayToPubkey(spender.public_key())
+                    && entry.is_mature(height, blockchain.params().coinbase_maturity)
+            })
+            .unwrap_or_else(|| {
+                eprintln!("no mature output owned by the given key");
+                exit(1);
+            });
+        // Two transactions spending the same output to different keys
+        for _ in 0..2 {
+            transactions.push(spend(*outpoint, entry.output.value, spender));
+        }
+    }
+    let mut header = BlockHeader::new(
+        Utc::now(),
+        0,
+        prev_block_hash,
+        MerkleRoot::calculate(&transactions),
+        blockchain.target(),
+    );
+    match kind {
+        "valid" | "double-spend" => {}
+        "bad-merkle" => {
+            header.merkle_root =
+                MerkleRoot::calculate(&[Transaction::coinbase(height + 1, vec![])]);
+        }
+        // Mined at half the expected target, so the proof of work holds up
+        // but the header commits to a target the chain does not expect
+        "bad-target" => {
+            header.bits = (blockchain.target() / 2).to_compact();
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            exit(1);
+        }
+    }
+    while !header.mine(1_000_000) {}
+    Block::new(header, transactions)
+}
+
+fn spend(outpoint: Hash, value: Amount, key: &PrivateKey) -> Transaction {
+    let mut transaction = Transaction::new(
+        vec![TransactionInput {
+            prev_transaction_output_hash: outpoint,
+            witness: Witness::default(),
+            sequence: 0,
+        }],
+        vec![TransactionOutput {
+            value,
+            script: Script::PayToPubkey(PrivateKey::generate().public_key()),
+        }],
+    );
+    transaction
+        .sign_input(0, key, SignatureScheme::Ecdsa)
+        .expect("Bug: Impossible");
+    transaction
 }
@@ -0,0 +1,139 @@
+use btclib::crypto::{PrivateKey, SignatureScheme};
+use btclib::params::ChainParams;
+use btclib::script::{Script, Witness};
+use btclib::sha256::Hash;
+use btclib::types::{
+    Block, BlockHeader, Transaction, TransactionInput, TransactionOutput, UtxoEntry,
+};
+use btclib::util::MerkleRoot;
+use chrono::Utc;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::env;
+use std::process::exit;
+use std::time::Instant;
+
+const USAGE: &str = "Usage: hash_bench [iterations] [block_transactions]";
+
+// Measures hashing where it dominates, header hashes while mining and txids
+// while validating, against encoding into a fresh buffer per call as
+// Hash::hash used to. Build with --release for meaningful numbers.
+fn main() {
+    let args = env::args().collect::<Vec<_>>();
+    let parse = |index: usize, default: usize| match args.get(index) {
+        Some(arg) => arg.parse::<usize>().unwrap_or_else(|_| {
+            eprintln!("{}", USAGE);
+            exit(1);
+        }),
+        None => default,
+    };
+    let iterations = parse(1, 1_000_000);
+    let block_transactions = parse(2, 1_000);
+
+    let params = ChainParams::mainnet();
+    let key = PrivateKey::from_bytes(&[1; 32]).unwrap();
+    let (block, utxos) = spending_block(&key, block_transactions, &params);
+
+    let mut header = block.header.clone();
+    let buffered = rate(iterations, || {
+        header.nonce += 1;
+        buffered_hash(&header)
+    });
+    let streamed = rate(iterations, || {
+        header.nonce += 1;
+        Hash::hash(&header)
+    });
+    report("header hashes", buffered, streamed);
+
+    let rounds = (iterations / block_transactions.max(1)).max(1);
+    // Hashes every transaction, which next_back would skip
+    let last = |hash: fn(&Transaction) -> Hash| {
+        block
+            .transactions
+            .iter()
+            .map(hash)
+            .fold(None, |_, hash| Some(hash))
+    };
+    let buffered = rate(rounds, || last(buffered_hash).unwrap());
+    let streamed = rate(rounds, || last(Hash::hash).unwrap());
+    report("blocks of transaction hashes", buffered, streamed);
+
+    let started = Instant::now();
+    for _ in 0..rounds {
+        block
+            .verify_transactions(1, &utxos, &params)
+            .expect("Bug: benchmark block is invalid");
+    }
+    let per_second = rounds as f64 / started.elapsed().as_secs_f64();
+    println!(
+        "validation: {:.1} blocks/s, {:.0} transactions/s",
+        per_second,
+        per_second * block_transactions as f64
+    );
+}
+
+fn buffered_hash<T: Serialize>(data: &T) -> Hash {
+    let mut serialized = Vec::new();
+    ciborium::into_writer(data, &mut serialized).expect("Bug: Impossible");
+    Hash::hash_bytes(&serialized)
+}
+
+fn rate(iterations: usize, mut f: impl FnMut() -> Hash) -> f64 {
+    let started = Instant::now();
+    let mut last = Hash::zero();
+    for _ in 0..iterations {
+        last = f();
+    }
+    // Keeps the loop from being optimized away
+    assert!(last != Hash::zero() || iterations == 0);
+    iterations as f64 / started.elapsed().as_secs_f64()
+}
+
+fn report(name: &str, buffered: f64, streamed: f64) {
+    println!(
+        "{}: {:.0}/s buffered, {:.0}/s streamed ({:+.1}%)",
+        name,
+        buffered,
+        streamed,
+        (streamed / buffered - 1.0) * 100.0
+    );
+}
+
+// A block at height 1 whose transactions each spend their own output
+fn spending_block(
+    key: &PrivateKey,
+    count: usize,
+    params: &ChainParams,
+) -> (Block, HashMap<Hash, UtxoEntry>) {
+    let mut utxos = HashMap::new();
+    let mut transactions = vec![Transaction::coinbase(
+        1,
+        vec![TransactionOutput {
+            value: params.block_subsidy(1),
+            script: Script::PayToPubkey(key.public_key()),
+        }],
+    )];
+    for index in 0..count {
+        let output = TransactionOutput {
+            value: params.block_subsidy(1),
+            script: Script::PayToPubkey(key.public_key()),
+        };
+        let outpoint = Hash::hash(&("hash_bench", index));
+        utxos.insert(outpoint, UtxoEntry::new(output.clone(), 0, false));
+        let mut transaction = Transaction::new(
+            vec![TransactionInput {
+                prev_transaction_output_hash: outpoint,
+                witness: Witness::default(),
+                sequence: 0,
+            }],
+            vec![output],
+        );
+        transaction
+            .sign_input(0, key, SignatureScheme::Ecdsa)
+            .expect("Bug: Impossible");
+        transactions.push(transaction);
+    }
+    let merkle_root = MerkleRoot::calculate(&transactions);
+    let header = BlockHeader::new(Utc::now(), 0, Hash::zero(), merkle_root, btclib::MIN_TARGET);
+    (Block::new(header, transactions), utxos)
+}
@@ -1,13 +1,15 @@
+use btclib::address::Address;
 use btclib::crypto::PrivateKey;
 use btclib::util::Saveable;
 use std::env;
 
 fn main() {
     let name = env::args().nth(1).expect("Please provide a name");
-    let private_key = PrivateKey::new_key();
+    let private_key = PrivateKey::generate();
     let public_key = private_key.public_key();
     let public_key_file = name.clone() + ".pub.pem";
-    let private_key_file = name + ".priv.cbor";
+    let private_key_file = name + ".priv.pem";
     private_key.save_to_file(&private_key_file).unwrap();
     public_key.save_to_file(&public_key_file).unwrap();
+    println!("address: {}", Address::from_public_key(&public_key));
 }
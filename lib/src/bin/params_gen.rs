@@ -0,0 +1,86 @@
+use btclib::amount::Amount;
+use btclib::params::ChainParams;
+use btclib::sha256::Hash;
+use btclib::util::Saveable;
+use btclib::U256;
+use std::env;
+use std::num::ParseIntError;
+use std::process::exit;
+
+const USAGE: &str = "Usage: params_gen <params_file> <mainnet|testnet|regtest> \
+    [<field>=<value>...]";
+
+// Writes chain parameters for a custom network, starting from a preset
+fn main() {
+    let args = env::args().collect::<Vec<_>>();
+    let (Some(path), Some(preset)) = (args.get(1), args.get(2)) else {
+        eprintln!("{}", USAGE);
+        exit(1);
+    };
+    let Some(mut params) = ChainParams::preset(preset) else {
+        eprintln!("{}", USAGE);
+        exit(1);
+    };
+    for arg in &args[3..] {
+        let Some((field, value)) = arg.split_once('=') else {
+            eprintln!("{}", USAGE);
+            exit(1);
+        };
+        if let Err(e) = set_field(&mut params, field, value) {
+            eprintln!("{}: {}", field, e);
+            exit(1);
+        }
+    }
+    if let Err(e) = params.check_assume_valid() {
+        eprintln!("{}", e);
+        exit(1);
+    }
+    params
+        .save_to_file(path)
+        .expect("Failed to save chain params");
+    println!("{:#?}", params);
+}
+
+fn set_field(params: &mut ChainParams, field: &str, value: &str) -> Result<(), String> {
+    let number = || value.parse::<u64>().map_err(|e| e.to_string());
+    match field {
+        "name" => params.name = value.to_string(),
+        "initial_reward" => params.initial_reward = number()?,
+        "halving_interval" => params.halving_interval = number()?,
+        "ideal_block_time" => params.ideal_block_time = number()?,
+        "difficulty_update_interval" => params.difficulty_update_interval = number()?,
+        "coinbase_maturity" => params.coinbase_maturity = number()?,
+        "assume_valid" => params.assume_valid = number()?,
+        // <height>:<block hash in hex>, may be given more than once
+        "checkpoint" => {
+            let (height, hash) = value.split_once(':').ok_or("expected <height>:<hash>")?;
+            let height = height.parse::<u64>().map_err(|e| e.to_string())?;
+            let hash = hash.parse::<Hash>().map_err(|e| e.to_string())?;
+            params.checkpoints.retain(|(known, _)| *known != height);
+            params.checkpoints.push((height, hash));
+            params.checkpoints.sort();
+        }
+        "genesis_timestamp" => {
+            params.genesis_timestamp = value.parse().map_err(|e: ParseIntError| e.to_string())?
+        }
+        // Given in hex
+        "min_target" => {
+            params.min_target = U256::from_str_radix(value, 16).map_err(|e| e.to_string())?
+        }
+        _ => return Err("unknown field".to_string()),
+    }
+    if params
+        .initial_reward
+        .checked_mul(Amount::SATS_PER_BTC)
+        .is_none()
+    {
+        return Err("initial_reward does not fit in satoshis".to_string());
+    }
+    if params.halving_interval == 0
+        || params.difficulty_update_interval == 0
+        || params.ideal_block_time == 0
+    {
+        return Err("intervals must not be zero".to_string());
+    }
+    Ok(())
+}
@@ -0,0 +1,78 @@
+use btclib::rules;
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::exit;
+
+const USAGE: &str = "Usage: rules_gen <output_file> <testdata_dir>";
+
+// Writes the annotated consensus rules as tab separated lines and reports
+// which of them are exercised by a test vector
+fn main() {
+    let args = env::args().collect::<Vec<_>>();
+    let (Some(output), Some(testdata)) = (args.get(1), args.get(2)) else {
+        eprintln!("{}", USAGE);
+        exit(1);
+    };
+
+    let rules = rules::rules();
+    let mut ids = BTreeSet::new();
+    for rule in &rules {
+        if !ids.insert(rule.id.as_str()) {
+            eprintln!("Rule {} is annotated twice ({})", rule.id, rule.location);
+            exit(1);
+        }
+    }
+
+    let manifest = fs::read_to_string(Path::new(testdata).join("vectors.txt"))
+        .expect("Failed to read vectors.txt");
+    let mut coverage: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for line in manifest.lines().filter(|line| !line.starts_with('#')) {
+        let fields = line.split('\t').collect::<Vec<_>>();
+        let (Some(name), Some(rule)) = (fields.first(), fields.get(5)) else {
+            eprintln!("Malformed vector: {}", line);
+            exit(1);
+        };
+        if *rule == "-" {
+            continue;
+        }
+        if !ids.contains(rule) {
+            eprintln!("Vector {} names unknown rule {}", name, rule);
+            exit(1);
+        }
+        coverage.entry(*rule).or_default().push(*name);
+    }
+
+    let mut list = String::from(
+        "# Generated by `cargo run --bin rules_gen -- <output_file> lib/testdata`\n\
+        # id\tlocation\tcovering vectors\tdescription\n",
+    );
+    for rule in &rules {
+        let vectors = coverage
+            .get(rule.id.as_str())
+            .map(|vectors| vectors.join(","))
+            .unwrap_or_else(|| "-".to_string());
+        list.push_str(&format!(
+            "{}\t{}\t{}\t{}\n",
+            rule.id, rule.location, vectors, rule.description
+        ));
+    }
+    fs::write(output, list).expect("Failed to write rule list");
+
+    let uncovered = rules
+        .iter()
+        .filter(|rule| !coverage.contains_key(rule.id.as_str()))
+        .collect::<Vec<_>>();
+    println!(
+        "{} consensus rules, {} covered by test vectors",
+        rules.len(),
+        rules.len() - uncovered.len()
+    );
+    for rule in uncovered {
+        println!(
+            "  uncovered: {} ({}) {}",
+            rule.id, rule.location, rule.description
+        );
+    }
+}
@@ -1,9 +1,10 @@
+use btclib::amount::Amount;
 use btclib::crypto::PrivateKey;
+use btclib::script::Script;
 use btclib::types::{Transaction, TransactionOutput};
 use btclib::util::Saveable;
 use std::env;
 use std::process::exit;
-use uuid::Uuid;
 
 fn main() {
     let path = if let Some(arg) = env::args().nth(1) {
@@ -12,13 +13,12 @@ fn main() {
         eprintln!("Usage: tx_gen <tx_file>");
         exit(1);
     };
-    let private_key = PrivateKey::new_key();
+    let private_key = PrivateKey::generate();
     let transaction = Transaction::new(
         vec![],
         vec![TransactionOutput {
-            unique_id: Uuid::new_v4(),
-            value: btclib::INITIAL_REWARD,
-            pubkey: private_key.public_key(),
+            value: Amount::from_sat(btclib::INITIAL_REWARD),
+            script: Script::PayToPubkey(private_key.public_key()),
         }],
     );
     transaction
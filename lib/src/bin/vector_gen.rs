@@ -0,0 +1,162 @@
+use btclib::crypto::{PrivateKey, SignatureScheme};
+use btclib::params::ChainParams;
+use btclib::script::{Script, Witness};
+use btclib::sha256::Hash;
+use btclib::types::{
+    Block, BlockHeader, Blockchain, Transaction, TransactionInput, TransactionOutput,
+};
+use btclib::util::{MerkleRoot, Saveable};
+use chrono::DateTime;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::exit;
+
+// Writes the consensus test vectors checked by lib/tests/vectors.rs.
+// Everything is derived from fixed keys and timestamps, so rerunning it
+// only produces a diff when hashing or validation semantics changed.
+fn main() {
+    let dir = if let Some(arg) = env::args().nth(1) {
+        arg
+    } else {
+        eprintln!("Usage: vector_gen <testdata_dir>");
+        exit(1);
+    };
+    let dir = Path::new(&dir);
+    fs::create_dir_all(dir).expect("Failed to create testdata directory");
+
+    let miner = PrivateKey::from_bytes(&[1; 32]).unwrap();
+    let recipient = PrivateKey::from_bytes(&[2; 32]).unwrap();
+
+    let genesis = Block::genesis(&ChainParams::mainnet());
+    let block_1 = block(1, genesis.hash(), vec![coinbase(1, &miner)]);
+    let bad_coinbase_height = block(2, block_1.hash(), vec![coinbase(5, &miner)]);
+
+    let block_1_coinbase = block_1.transactions[0].clone();
+    let spend_tx = spend(&block_1_coinbase, &miner, &recipient);
+    let immature_spend = block(
+        2,
+        block_1.hash(),
+        vec![coinbase(2, &miner), spend_tx.clone()],
+    );
+
+    let mut bad_merkle_root = block(2, block_1.hash(), vec![coinbase(2, &miner)]);
+    bad_merkle_root.header.merkle_root = MerkleRoot::calculate(std::slice::from_ref(&spend_tx));
+
+    // Signed by the recipient instead of the owner of the spent output
+    let bad_signature_tx = spend(&block_1_coinbase, &recipient, &recipient);
+
+    let mut manifest = String::from(
+        "# Generated by `cargo run --bin vector_gen -- lib/testdata`, do not edit by hand\n\
+        # name\tkind\thash or txid\twtxid\texpected result\tconsensus rule \
+        (blocks are replayed in order, transactions spend earlier transactions)\n",
+    );
+    let mut blockchain = Blockchain::new(ChainParams::mainnet());
+    for (name, rule, block) in [
+        ("genesis", "CHAIN-GENESIS", genesis),
+        ("block_1", "-", block_1),
+        ("bad_coinbase_height", "CB-HEIGHT", bad_coinbase_height),
+        ("immature_coinbase_spend", "TX-MATURITY", immature_spend),
+        ("bad_merkle_root", "HDR-MERKLE", bad_merkle_root),
+    ] {
+        block
+            .save_to_file(dir.join(format!("{}.cbor", name)))
+            .expect("Failed to save block");
+        let hash = block.hash();
+        let result = match blockchain.add_block(block) {
+            Ok(()) => "valid".to_string(),
+            Err(e) => e.to_string(),
+        };
+        manifest.push_str(&format!(
+            "{}\tblock\t{}\t-\t{}\t{}\n",
+            name, hash, result, rule
+        ));
+    }
+    let mut spendable = Vec::new();
+    for (name, rule, transaction) in [
+        ("coinbase_tx", "-", block_1_coinbase),
+        ("spend_tx", "TX-SIGNATURE", spend_tx),
+        ("bad_signature_tx", "TX-SIGNATURE", bad_signature_tx),
+    ] {
+        transaction
+            .save_to_file(dir.join(format!("{}.cbor", name)))
+            .expect("Failed to save transaction");
+        manifest.push_str(&format!(
+            "{}\ttransaction\t{}\t{}\t{}\t{}\n",
+            name,
+            transaction.txid(),
+            transaction.wtxid(),
+            check_witnesses(&transaction, &spendable),
+            rule
+        ));
+        spendable.push(transaction);
+    }
+    fs::write(dir.join("vectors.txt"), manifest).expect("Failed to write manifest");
+}
+
+// The same check lib/tests/vectors.rs runs, with the spent outputs looked
+// up among the transactions before
+fn check_witnesses(transaction: &Transaction, earlier: &[Transaction]) -> String {
+    for (index, input) in transaction.inputs.iter().enumerate() {
+        let spent = earlier
+            .iter()
+            .flat_map(|tx| (0..tx.outputs.len()).map(move |output| (tx, output)))
+            .find(|(tx, output)| tx.output_hash(*output) == input.prev_transaction_output_hash);
+        let valid = spent.is_some_and(|(tx, output)| {
+            transaction
+                .signature_digest(index, input.witness.sighash)
+                .is_some_and(|digest| {
+                    tx.outputs[output]
+                        .script
+                        .verify(&input.witness, &digest, u64::MAX)
+                })
+        });
+        if !valid {
+            return format!("invalid witness at input {}", index);
+        }
+    }
+    "valid".to_string()
+}
+
+fn coinbase(height: u64, key: &PrivateKey) -> Transaction {
+    Transaction::coinbase(
+        height,
+        vec![TransactionOutput {
+            value: ChainParams::mainnet().block_subsidy(height),
+            script: Script::PayToPubkey(key.public_key()),
+        }],
+    )
+}
+
+fn spend(prev: &Transaction, key: &PrivateKey, recipient: &PrivateKey) -> Transaction {
+    let mut transaction = Transaction::new(
+        vec![TransactionInput {
+            prev_transaction_output_hash: prev.output_hash(0),
+            witness: Witness::default(),
+            sequence: 0,
+        }],
+        vec![TransactionOutput {
+            value: prev.outputs[0].value,
+            script: Script::PayToPubkey(recipient.public_key()),
+        }],
+    );
+    transaction
+        .sign_input(0, key, SignatureScheme::Ecdsa)
+        .expect("Bug: Impossible");
+    transaction
+}
+
+fn block(height: i64, prev_block_hash: Hash, transactions: Vec<Transaction>) -> Block {
+    let timestamp = DateTime::from_timestamp(1_700_000_000 + height * 600, 0).unwrap();
+    let merkle_root = MerkleRoot::calculate(&transactions);
+    Block::new(
+        BlockHeader::new(
+            timestamp,
+            0,
+            prev_block_hash,
+            merkle_root,
+            btclib::MIN_TARGET,
+        ),
+        transactions,
+    )
+}
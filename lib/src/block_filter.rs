@@ -0,0 +1,233 @@
+use crate::encoding::Encode;
+use crate::sha256::Hash;
+use crate::types::Block;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+// Golomb-Rice parameters as in BIP158: remainders of P bits, and entries
+// hashed into a range M times the entry count
+const FILTER_P: u32 = 19;
+const FILTER_M: u64 = 784_931;
+
+// Compact summary of the pubkey hashes a block pays and the outpoints it
+// spends. Wallets test their own entries locally and only fetch the blocks
+// that match, so the node never learns which those are.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockFilter {
+    pub count: u64,
+    pub data: Vec<u8>,
+}
+
+impl BlockFilter {
+    pub fn build(block: &Block) -> BlockFilter {
+        let mut entries = BTreeSet::new();
+        for transaction in &block.transactions {
+            for output in &transaction.outputs {
+                entries.extend(output.script.pubkey_hashes());
+            }
+            for input in &transaction.inputs {
+                entries.insert(input.prev_transaction_output_hash);
+            }
+        }
+        let key = block.hash();
+        let count = entries.len() as u64;
+        let mut values = entries
+            .iter()
+            .map(|entry| hash_to_range(&key, entry, count * FILTER_M))
+            .collect::<Vec<_>>();
+        values.sort_unstable();
+        let mut writer = BitWriter::default();
+        let mut previous = 0;
+        for value in values {
+            writer.write_golomb(value - previous);
+            previous = value;
+        }
+        BlockFilter {
+            count,
+            data: writer.bytes,
+        }
+    }
+
+    // Whether the block may pay or spend any of the entries. False positives
+    // happen about once in 2^P tests, a filter that fails to decode matches.
+    pub fn matches_any(&self, block_hash: &Hash, entries: &[Hash]) -> bool {
+        if self.count == 0 || entries.is_empty() {
+            return false;
+        }
+        let mut targets = entries
+            .iter()
+            .map(|entry| hash_to_range(block_hash, entry, self.count * FILTER_M))
+            .collect::<Vec<_>>();
+        targets.sort_unstable();
+        let mut reader = BitReader::new(&self.data);
+        let mut value = 0u64;
+        let mut targets = targets.into_iter().peekable();
+        for _ in 0..self.count {
+            let Some(delta) = reader.read_golomb() else {
+                return true;
+            };
+            value = value.saturating_add(delta);
+            while targets.next_if(|target| *target < value).is_some() {}
+            match targets.peek() {
+                Some(target) if *target == value => return true,
+                Some(_) => {}
+                None => return false,
+            }
+        }
+        false
+    }
+}
+
+// Maps an entry uniformly onto 0..range, keyed by the block hash
+fn hash_to_range(key: &Hash, entry: &Hash, range: u64) -> u64 {
+    let digest = (key, entry).encoded_hash().as_bytes();
+    let value = u64::from_le_bytes(digest[..8].try_into().unwrap());
+    ((value as u128 * range as u128) >> 64) as u64
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    // Bits used of the last byte, 0 when it is full
+    used: u32,
+}
+
+impl BitWriter {
+    fn write_bit(&mut self, bit: bool) {
+        if self.used == 0 {
+            self.bytes.push(0);
+        }
+        if bit {
+            *self.bytes.last_mut().unwrap() |= 0x80 >> self.used;
+        }
+        self.used = (self.used + 1) % 8;
+    }
+
+    // The quotient in unary, then the remainder, most significant bit first
+    fn write_golomb(&mut self, value: u64) {
+        for _ in 0..value >> FILTER_P {
+            self.write_bit(true);
+        }
+        self.write_bit(false);
+        for bit in (0..FILTER_P).rev() {
+            self.write_bit((value >> bit) & 1 == 1);
+        }
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        BitReader { bytes, position: 0 }
+    }
+
+    fn read_bit(&mut self) -> Option<bool> {
+        let byte = self.bytes.get(self.position / 8)?;
+        let bit = byte & (0x80 >> (self.position % 8)) != 0;
+        self.position += 1;
+        Some(bit)
+    }
+
+    fn read_golomb(&mut self) -> Option<u64> {
+        let mut quotient = 0u64;
+        while self.read_bit()? {
+            quotient += 1;
+        }
+        let mut remainder = 0u64;
+        for _ in 0..FILTER_P {
+            remainder = (remainder << 1) | self.read_bit()? as u64;
+        }
+        Some((quotient << FILTER_P) | remainder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Amount;
+    use crate::crypto::PrivateKey;
+    use crate::script::{Script, Witness};
+    use crate::types::{BlockHeader, Transaction, TransactionInput, TransactionOutput};
+    use crate::util::MerkleRoot;
+    use crate::U256;
+    use chrono::DateTime;
+
+    fn block(outputs: Vec<Script>, spent: Vec<Hash>) -> Block {
+        let outputs = outputs
+            .into_iter()
+            .map(|script| TransactionOutput {
+                value: Amount::from_sat(1),
+                script,
+            })
+            .collect();
+        let inputs = spent
+            .into_iter()
+            .map(|outpoint| TransactionInput {
+                prev_transaction_output_hash: outpoint,
+                witness: Witness::default(),
+                sequence: 0,
+            })
+            .collect();
+        let transactions = vec![Transaction::new(inputs, outputs)];
+        let header = BlockHeader::new(
+            DateTime::from_timestamp(0, 0).unwrap(),
+            0,
+            Hash::zero(),
+            MerkleRoot::calculate(&transactions),
+            U256::MAX,
+        );
+        Block::new(header, transactions)
+    }
+
+    #[test]
+    fn golomb_coding_round_trips() {
+        let values = [
+            0,
+            1,
+            (1 << FILTER_P) - 1,
+            1 << FILTER_P,
+            5 << FILTER_P,
+            12_345,
+        ];
+        let mut writer = BitWriter::default();
+        for value in values {
+            writer.write_golomb(value);
+        }
+        let mut reader = BitReader::new(&writer.bytes);
+        for value in values {
+            assert_eq!(reader.read_golomb(), Some(value));
+        }
+        // Only the padding of the last byte is left
+        assert_eq!(reader.read_golomb(), None);
+    }
+
+    #[test]
+    fn filter_matches_paid_keys_and_spent_outpoints() {
+        let pubkey = PrivateKey::from_bytes(&[1; 32]).unwrap().public_key();
+        let outpoint = Hash::hash_bytes(b"spent");
+        let block = block(vec![Script::PayToPubkey(pubkey.clone())], vec![outpoint]);
+        let filter = BlockFilter::build(&block);
+        assert_eq!(filter.count, 2);
+
+        let hash = block.hash();
+        let paid = Script::pubkey_hash(&pubkey);
+        assert!(filter.matches_any(&hash, &[paid]));
+        assert!(filter.matches_any(&hash, &[outpoint]));
+        let unrelated: Vec<_> = (0..100u32)
+            .map(|n| Hash::hash_bytes(&n.to_le_bytes()))
+            .collect();
+        assert!(!filter.matches_any(&hash, &unrelated));
+        assert!(filter.matches_any(&hash, &[unrelated.clone(), vec![paid]].concat()));
+        assert!(!filter.matches_any(&hash, &[]));
+        // Entries are keyed by the block, so another block hash misses
+        assert!(!filter.matches_any(&Hash::zero(), &[paid, outpoint]));
+
+        let empty = BlockFilter::build(&self::block(vec![], vec![]));
+        assert_eq!(empty.count, 0);
+        assert!(!empty.matches_any(&hash, &[paid]));
+    }
+}
@@ -0,0 +1,85 @@
+use crate::encoding::Encode;
+use crate::sha256::Hash;
+use crate::types::Transaction;
+use serde::{Deserialize, Serialize};
+
+// Set of hashes a light client cares about: pubkey hashes of its keys,
+// txids and outpoints. False positives keep the node from telling which
+// matches are really the client's.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    hash_funcs: u32,
+    // Varies the positions between filters holding the same entries
+    tweak: u32,
+}
+
+impl BloomFilter {
+    // Sized for `elements` entries at the given false positive rate, within
+    // the limits nodes accept
+    pub fn new(elements: usize, false_positive_rate: f64, tweak: u32) -> Self {
+        let elements = elements.max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+        let bits = -elements * false_positive_rate.ln() / (ln2 * ln2);
+        let bytes = ((bits / 8.0).ceil() as usize).clamp(1, crate::MAX_BLOOM_FILTER_SIZE);
+        let hash_funcs = ((bytes * 8) as f64 / elements * ln2).round() as u32;
+        BloomFilter {
+            bits: vec![0; bytes],
+            hash_funcs: hash_funcs.clamp(1, crate::MAX_BLOOM_HASH_FUNCS),
+            tweak,
+        }
+    }
+
+    pub fn is_within_limits(&self) -> bool {
+        (1..=crate::MAX_BLOOM_FILTER_SIZE).contains(&self.bits.len())
+            && (1..=crate::MAX_BLOOM_HASH_FUNCS).contains(&self.hash_funcs)
+    }
+
+    // Bit positions by double hashing one digest of the entry
+    fn positions(&self, element: &Hash) -> Vec<usize> {
+        let digest = (self.tweak, element).encoded_hash().as_bytes();
+        let first = u64::from_le_bytes(digest[..8].try_into().unwrap());
+        let second = u64::from_le_bytes(digest[8..16].try_into().unwrap());
+        let size = self.bits.len().max(1) as u64 * 8;
+        (0..self.hash_funcs as u64)
+            .map(|i| (first.wrapping_add(i.wrapping_mul(second)) % size) as usize)
+            .collect()
+    }
+
+    pub fn insert(&mut self, element: &Hash) {
+        for position in self.positions(element) {
+            self.bits[position / 8] |= 1 << (position % 8);
+        }
+    }
+
+    pub fn contains(&self, element: &Hash) -> bool {
+        !self.bits.is_empty()
+            && self
+                .positions(element)
+                .into_iter()
+                .all(|position| self.bits[position / 8] & (1 << (position % 8)) != 0)
+    }
+
+    // Whether the transaction is an entry, pays an entry or spends one.
+    // Matching outputs are inserted so the transaction spending them
+    // matches as well.
+    pub fn matches(&mut self, transaction: &Transaction) -> bool {
+        let mut matched = self.contains(&transaction.txid());
+        for (index, output) in transaction.outputs.iter().enumerate() {
+            let pays_entry = output
+                .script
+                .pubkey_hashes()
+                .iter()
+                .any(|hash| self.contains(hash));
+            if pays_entry {
+                self.insert(&transaction.output_hash(index));
+                matched = true;
+            }
+        }
+        matched
+            || transaction
+                .inputs
+                .iter()
+                .any(|input| self.contains(&input.prev_transaction_output_hash))
+    }
+}
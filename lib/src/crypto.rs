@@ -1,37 +1,394 @@
+mod encrypted;
+mod hd;
+mod mnemonic;
+mod sealed;
+pub use encrypted::{EncryptedPrivateKey, KeyFile};
+pub use hd::{ChildNumber, DerivationPath, ExtendedPrivateKey, ExtendedPublicKey};
+pub use mnemonic::Mnemonic;
+pub use sealed::Sealed;
+
+use crate::encoding::Encode;
+use crate::error::{BtcError, Result};
+use crate::params::ChainParams;
 use crate::sha256::Hash;
+use crate::types::{Transaction, TransactionOutput};
 use crate::util::Saveable;
 use ecdsa::signature::Verifier;
 use ecdsa::{signature::Signer, Signature as ECDSASignature, SigningKey, VerifyingKey};
-use k256::Secp256k1;
+use k256::elliptic_curve::group::Group;
+use k256::elliptic_curve::ops::{LinearCombinationExt, Reduce};
+use k256::elliptic_curve::point::DecompactPoint;
+use k256::elliptic_curve::Field;
+use k256::pkcs8::{DecodePrivateKey, EncodePrivateKey, LineEnding};
+use k256::{schnorr, AffinePoint, ProjectivePoint, Scalar, Secp256k1, SecretKey};
+use rand::rngs::OsRng;
+#[cfg(feature = "parallel-verify")]
+use rayon::prelude::*;
 use serde;
 use serde::{Deserialize, Serialize};
-use spki::EncodePublicKey;
+use sha2::{Digest, Sha256};
+use spki::{DecodePublicKey, EncodePublicKey};
+use std::fmt;
 use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write};
+use std::str::FromStr;
+
+// Algorithm a signature is made with. Each output script accepts one of them.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum SignatureScheme {
+    #[default]
+    Ecdsa,
+    // BIP 340 over the x coordinate of the key, the kind that can be aggregated
+    Schnorr,
+}
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct Signature(ECDSASignature<Secp256k1>);
+impl Encode for SignatureScheme {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            SignatureScheme::Ecdsa => 0u8.encode(out),
+            SignatureScheme::Schnorr => 1u8.encode(out),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub enum Signature {
+    Ecdsa(ECDSASignature<Secp256k1>),
+    Schnorr(#[serde(with = "schnorr_serde")] schnorr::Signature),
+}
+
+mod schnorr_serde {
+    use serde::Deserialize;
+
+    pub fn serialize<S>(
+        signature: &k256::schnorr::Signature,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_bytes(&signature.to_bytes())
+    }
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<k256::schnorr::Signature, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let bytes: Vec<u8> = Vec::<u8>::deserialize(deserializer)?;
+        k256::schnorr::Signature::try_from(bytes.as_slice()).map_err(serde::de::Error::custom)
+    }
+}
 
 impl Signature {
-    pub fn sign_output(output_hash: &Hash, private_key: &PrivateKey) -> Self {
-        let signing_key = &private_key.0;
-        let signature = signing_key.sign(&output_hash.as_bytes());
-        Signature(signature)
+    // Schnorr signatures never verify against a key that has no x-only form
+    pub fn verify(&self, digest: &Hash, public_key: &PublicKey) -> bool {
+        match self {
+            Signature::Ecdsa(signature) => {
+                public_key.0.verify(&digest.as_bytes(), signature).is_ok()
+            }
+            Signature::Schnorr(signature) => public_key
+                .schnorr_key()
+                .is_some_and(|key| key.verify(&digest.as_bytes(), signature).is_ok()),
+        }
     }
 
-    pub fn verify(&self, output_hash: &Hash, public_key: &PublicKey) -> bool {
-        public_key
-            .0
-            .verify(&output_hash.as_bytes(), &self.0)
-            .is_ok()
+    pub fn scheme(&self) -> SignatureScheme {
+        match self {
+            Signature::Ecdsa(_) => SignatureScheme::Ecdsa,
+            Signature::Schnorr(_) => SignatureScheme::Schnorr,
+        }
+    }
+
+    // ECDSA r and s or BIP 340 r and s, 32 big endian bytes each
+    pub fn to_bytes(&self) -> [u8; 64] {
+        match self {
+            Signature::Ecdsa(signature) => signature.to_bytes().as_slice().try_into(),
+            Signature::Schnorr(signature) => signature.to_bytes().as_slice().try_into(),
+        }
+        .expect("Bug: Impossible")
+    }
+
+    pub fn from_bytes(scheme: SignatureScheme, bytes: &[u8]) -> Option<Self> {
+        match scheme {
+            SignatureScheme::Ecdsa => ECDSASignature::from_slice(bytes).ok().map(Signature::Ecdsa),
+            SignatureScheme::Schnorr => schnorr::Signature::try_from(bytes)
+                .ok()
+                .map(Signature::Schnorr),
+        }
+    }
+}
+
+impl std::hash::Hash for Signature {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        std::hash::Hash::hash(&(self.scheme(), self.to_bytes()), state);
+    }
+}
+
+// Hex of the bytes, Schnorr signatures prefixed with "schnorr:"
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.scheme() == SignatureScheme::Schnorr {
+            write!(f, "schnorr:")?;
+        }
+        write!(f, "{}", hex::encode(self.to_bytes()))
+    }
+}
+
+impl FromStr for Signature {
+    type Err = BtcError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (scheme, hex) = match s.trim().strip_prefix("schnorr:") {
+            Some(hex) => (SignatureScheme::Schnorr, hex),
+            None => (SignatureScheme::Ecdsa, s),
+        };
+        hex::decode(hex.trim())
+            .ok()
+            .and_then(|bytes| Signature::from_bytes(scheme, &bytes))
+            .ok_or(BtcError::InvalidSignature)
     }
 }
 
+// The scheme followed by the 64 signature bytes
+impl Encode for Signature {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.scheme().encode(out);
+        out.extend_from_slice(&self.to_bytes());
+    }
+}
+
+// True only if every signature verifies against its digest and key. Schnorr
+// signatures are checked together in one randomized linear combination per
+// chunk, ECDSA has no such algebra and is checked one by one. Both are split
+// across all cores with the parallel-verify feature.
+pub fn verify_batch(batch: &[(Hash, &Signature, &PublicKey)]) -> bool {
+    let (schnorr, ecdsa): (Vec<_>, Vec<_>) = batch
+        .iter()
+        .copied()
+        .partition(|(_, signature, _)| signature.scheme() == SignatureScheme::Schnorr);
+    let verify = |(digest, signature, pubkey): &(Hash, &Signature, &PublicKey)| {
+        signature.verify(digest, pubkey)
+    };
+    #[cfg(feature = "parallel-verify")]
+    let valid = ecdsa.par_iter().all(verify)
+        && schnorr
+            .par_chunks(SCHNORR_BATCH_SIZE)
+            .all(verify_schnorr_batch);
+    #[cfg(not(feature = "parallel-verify"))]
+    let valid =
+        ecdsa.iter().all(verify) && schnorr.chunks(SCHNORR_BATCH_SIZE).all(verify_schnorr_batch);
+    valid
+}
+
+// Signatures combined into one multiplication, large enough that the shared
+// doublings pay off and small enough to keep every core busy
+const SCHNORR_BATCH_SIZE: usize = 64;
+
+// BIP 340 batch verification: with random weights a, the sum of a * s * G
+// equals the sum of a * R + a * e * P only if every signature holds, except
+// with negligible probability
+fn verify_schnorr_batch(batch: &[(Hash, &Signature, &PublicKey)]) -> bool {
+    let mut terms = Vec::with_capacity(batch.len() * 2 + 1);
+    let mut s_sum = Scalar::ZERO;
+    for (index, (digest, signature, pubkey)) in batch.iter().enumerate() {
+        let Some(key) = pubkey.schnorr_key() else {
+            return false;
+        };
+        let bytes = signature.to_bytes();
+        let (r, s) = bytes.split_at(32);
+        let Some(r_point) = Option::<AffinePoint>::from(AffinePoint::decompact(r.into())) else {
+            return false;
+        };
+        let s = <Scalar as Reduce<k256::U256>>::reduce_bytes(s.into());
+        let message = Sha256::digest(digest.as_bytes());
+        let challenge = <Scalar as Reduce<k256::U256>>::reduce_bytes(
+            &tagged_hash(b"BIP0340/challenge")
+                .chain_update(r)
+                .chain_update(key.to_bytes())
+                .chain_update(message)
+                .finalize(),
+        );
+        // The first weight can be one, the rest only need to be unpredictable
+        let weight = match index {
+            0 => Scalar::ONE,
+            _ => Scalar::random(&mut OsRng),
+        };
+        s_sum += weight * s;
+        terms.push((ProjectivePoint::from(r_point), weight));
+        terms.push((ProjectivePoint::from(*key.as_affine()), weight * challenge));
+    }
+    terms.push((ProjectivePoint::GENERATOR, -s_sum));
+    ProjectivePoint::lincomb_ext(terms.as_slice())
+        .is_identity()
+        .into()
+}
+
+// SHA-256 prefixed with the hash of the tag twice, as BIP 340 defines it
+fn tagged_hash(tag: &[u8]) -> Sha256 {
+    let tag = Sha256::digest(tag);
+    Sha256::new().chain_update(tag).chain_update(tag)
+}
+
+// Separates signed messages from every other digest, so signing a message can
+// never authorize a transaction
+const MESSAGE_PREFIX: &[u8] = b"OtherBlockchainShennanigans Signed Message:\n";
+
+pub fn message_digest(message: &str) -> Hash {
+    let mut bytes = MESSAGE_PREFIX.to_vec();
+    message.len().encode(&mut bytes);
+    bytes.extend_from_slice(message.as_bytes());
+    Hash::hash_bytes(&bytes)
+}
+
+pub fn sign_message(key: &PrivateKey, message: &str) -> Signature {
+    key.sign(&message_digest(message))
+}
+
+pub fn verify_message(pubkey: &PublicKey, message: &str, signature: &Signature) -> bool {
+    signature.verify(&message_digest(message), pubkey)
+}
+
+// Selects the parts of the spending transaction a signature commits to. The
+// low bits pick the outputs, ANYONECANPAY restricts the inputs to the signed one.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct SigHash(pub u8);
+
+impl SigHash {
+    pub const ALL: SigHash = SigHash(0x01);
+    pub const NONE: SigHash = SigHash(0x02);
+    pub const SINGLE: SigHash = SigHash(0x03);
+    pub const ANYONECANPAY: u8 = 0x80;
+
+    pub fn with_anyone_can_pay(self) -> Self {
+        SigHash(self.0 | Self::ANYONECANPAY)
+    }
+
+    pub fn anyone_can_pay(&self) -> bool {
+        self.0 & Self::ANYONECANPAY != 0
+    }
+
+    fn base(&self) -> u8 {
+        self.0 & !Self::ANYONECANPAY
+    }
+}
+
+impl Encode for SigHash {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.0.encode(out);
+    }
+}
+
+impl Default for SigHash {
+    fn default() -> Self {
+        SigHash::ALL
+    }
+}
+
+// The digest signed for input `index` is the hash of the encoded tuple
+//   (spent output, inputs, outputs, coinbase height, lock time, sighash)
+// where
+// - inputs are the (spent output, sequence) pairs of all inputs, or an empty
+//   list with ANYONECANPAY; witnesses are never committed to
+// - outputs are all outputs for ALL, none for NONE and only the output at
+//   position `index` for SINGLE
+// Unknown flags, a missing input and SINGLE without a matching output have no digest.
+pub fn sighash(transaction: &Transaction, index: usize, sighash: SigHash) -> Option<Hash> {
+    let signed_input = transaction.inputs.get(index)?;
+    let inputs: Vec<(Hash, u64)> = if sighash.anyone_can_pay() {
+        vec![]
+    } else {
+        transaction
+            .inputs
+            .iter()
+            .map(|input| (input.prev_transaction_output_hash, input.sequence))
+            .collect()
+    };
+    let outputs: Vec<&TransactionOutput> = match sighash.base() {
+        0x01 => transaction.outputs.iter().collect(),
+        0x02 => vec![],
+        0x03 => vec![transaction.outputs.get(index)?],
+        _ => return None,
+    };
+    let digest = (
+        signed_input.prev_transaction_output_hash,
+        inputs,
+        outputs,
+        transaction.coinbase_height,
+        transaction.lock_time,
+        sighash,
+    );
+    Some(digest.encoded_hash())
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct PublicKey(VerifyingKey<Secp256k1>);
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+impl PublicKey {
+    // The compressed SEC1 point
+    pub fn to_bytes(&self) -> [u8; 33] {
+        let point = self.0.to_encoded_point(true);
+        point.as_bytes().try_into().expect("Bug: Impossible")
+    }
+
+    // Accepts compressed and uncompressed points
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        VerifyingKey::from_sec1_bytes(bytes).ok().map(PublicKey)
+    }
+
+    pub fn fingerprint(&self) -> Fingerprint {
+        let digest = Sha256::digest(self.to_bytes());
+        Fingerprint(digest[..4].try_into().expect("Bug: Impossible"))
+    }
+
+    // The x coordinate alone, as BIP 340 verifies against
+    fn schnorr_key(&self) -> Option<schnorr::VerifyingKey> {
+        schnorr::VerifyingKey::from_bytes(&self.to_bytes()[1..]).ok()
+    }
+}
+
+impl std::hash::Hash for PublicKey {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        std::hash::Hash::hash(&self.to_bytes(), state);
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.to_bytes()))
+    }
+}
+
+impl FromStr for PublicKey {
+    type Err = BtcError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        hex::decode(s.trim())
+            .ok()
+            .and_then(|bytes| PublicKey::from_bytes(&bytes))
+            .ok_or(BtcError::InvalidPublicKey)
+    }
+}
+
+impl Encode for PublicKey {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_bytes());
+    }
+}
+
+// First four bytes of the SHA-256 of the compressed key, short enough for
+// people to compare keys by eye
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Fingerprint([u8; 4]);
+
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+// Compared in constant time, never printed by Display
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct PrivateKey(#[serde(with = "signkey_serde")] pub SigningKey<Secp256k1>);
 mod signkey_serde {
+    use serde::de::Error;
     use serde::Deserialize;
 
     pub fn serialize<S>(
@@ -50,40 +407,128 @@ mod signkey_serde {
         D: serde::Deserializer<'de>,
     {
         let bytes: Vec<u8> = Vec::<u8>::deserialize(deserializer)?;
-        Ok(super::SigningKey::from_slice(&bytes).unwrap())
+        super::SigningKey::from_slice(&bytes)
+            .map_err(|_| D::Error::custom("invalid private key bytes"))
     }
 }
 
 impl PrivateKey {
-    pub fn new_key() -> Self {
-        PrivateKey(SigningKey::random(&mut rand::thread_rng()))
+    // Draws the key from the operating system's randomness
+    pub fn generate() -> Self {
+        PrivateKey(SigningKey::random(&mut OsRng))
+    }
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        SigningKey::from_slice(bytes).ok().map(PrivateKey)
+    }
+
+    // Signs a digest, usually Transaction::signature_digest of an input
+    pub fn sign(&self, digest: &Hash) -> Signature {
+        Signature::Ecdsa(self.0.sign(&digest.as_bytes()))
+    }
+
+    pub fn sign_schnorr(&self, digest: &Hash) -> Signature {
+        let key = schnorr::SigningKey::from_bytes(&self.to_bytes()).expect("Bug: Impossible");
+        Signature::Schnorr(key.sign(&digest.as_bytes()))
+    }
+
+    pub fn sign_with(&self, scheme: SignatureScheme, digest: &Hash) -> Signature {
+        match scheme {
+            SignatureScheme::Ecdsa => self.sign(digest),
+            SignatureScheme::Schnorr => self.sign_schnorr(digest),
+        }
+    }
+
+    // The secret scalar, big endian
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.0
+            .to_bytes()
+            .as_slice()
+            .try_into()
+            .expect("Bug: Impossible")
     }
     pub fn public_key(&self) -> PublicKey {
         PublicKey(self.0.verifying_key().clone())
     }
+
+    // Wallet import format: base58check of the network prefix, the key and
+    // 0x01 for a compressed public key, which is how it is always written
+    pub fn to_wif(&self, params: &ChainParams) -> String {
+        let mut payload = self.to_bytes().to_vec();
+        payload.push(0x01);
+        bs58::encode(payload)
+            .with_check_version(params.wif_prefix)
+            .into_string()
+    }
+
+    // Accepts keys with and without the compression flag
+    pub fn from_wif(s: &str, params: &ChainParams) -> Result<Self> {
+        let decoded = bs58::decode(s.trim())
+            .with_check(None)
+            .into_vec()
+            .map_err(|_| BtcError::InvalidPrivateKey)?;
+        let (&prefix, payload) = decoded.split_first().ok_or(BtcError::InvalidPrivateKey)?;
+        if prefix != params.wif_prefix {
+            return Err(BtcError::WrongNetwork);
+        }
+        let key = match payload {
+            [key @ .., 0x01] if key.len() == 32 => key,
+            key if key.len() == 32 => key,
+            _ => return Err(BtcError::InvalidPrivateKey),
+        };
+        Self::from_bytes(key).ok_or(BtcError::InvalidPrivateKey)
+    }
 }
 
+// Reads PKCS#8 or SEC1 keys as PEM or DER, raw 32 byte scalars and the CBOR
+// files written before keys were saved as PKCS#8 PEM
 impl Saveable for PrivateKey {
-    fn load<I: Read>(reader: I) -> IoResult<Self> {
-        ciborium::de::from_reader(reader)
-            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to deserialize PrivateKey"))
+    fn load<I: Read>(mut reader: I) -> IoResult<Self> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        let key = match std::str::from_utf8(&bytes) {
+            Ok(pem) if pem.trim_start().starts_with("-----BEGIN") => {
+                let pem = pem.trim();
+                SigningKey::from_pkcs8_pem(pem)
+                    .ok()
+                    .or_else(|| SecretKey::from_sec1_pem(pem).ok().map(SigningKey::from))
+            }
+            _ if bytes.len() == 32 => SigningKey::from_slice(&bytes).ok(),
+            _ => SigningKey::from_pkcs8_der(&bytes)
+                .ok()
+                .or_else(|| SecretKey::from_sec1_der(&bytes).ok().map(SigningKey::from))
+                .or_else(|| {
+                    ciborium::de::from_reader::<PrivateKey, _>(bytes.as_slice())
+                        .ok()
+                        .map(|key| key.0)
+                }),
+        };
+        key.map(PrivateKey)
+            .ok_or_else(|| IoError::new(IoErrorKind::InvalidData, "Failed to parse PrivateKey"))
     }
-    fn save<O: Write>(&self, writer: O) -> IoResult<()> {
-        ciborium::ser::into_writer(self, writer).map_err(|_| {
+
+    fn save<O: Write>(&self, mut writer: O) -> IoResult<()> {
+        let pem = self.0.to_pkcs8_pem(LineEnding::LF).map_err(|_| {
             IoError::new(IoErrorKind::InvalidData, "Failed to serialize PrivateKey")
         })?;
-        Ok(())
+        writer.write_all(pem.as_bytes())
     }
 }
 
+// Reads SubjectPublicKeyInfo PEM or DER and raw SEC1 points
 impl Saveable for PublicKey {
     fn load<I: Read>(mut reader: I) -> IoResult<Self> {
-        let mut buf = String::new();
-        reader.read_to_string(&mut buf)?;
-        let public_key = buf
-            .parse()
-            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to parse PublicKey"))?;
-        Ok(PublicKey(public_key))
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        let key = match std::str::from_utf8(&bytes) {
+            Ok(pem) if pem.trim_start().starts_with("-----BEGIN") => {
+                VerifyingKey::from_public_key_pem(pem.trim()).ok()
+            }
+            _ => VerifyingKey::from_public_key_der(&bytes)
+                .ok()
+                .or_else(|| VerifyingKey::from_sec1_bytes(&bytes).ok()),
+        };
+        key.map(PublicKey)
+            .ok_or_else(|| IoError::new(IoErrorKind::InvalidData, "Failed to parse PublicKey"))
     }
 
     fn save<O: Write>(&self, mut writer: O) -> IoResult<()> {
@@ -96,3 +541,91 @@ impl Saveable for PublicKey {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The example key from the Bitcoin wiki and its mainnet encodings
+    const KEY: &str = "0c28fca386c7a227600b2fe50b7cae11ec86d3bf1fbe471be89827e19d72aa1d";
+    const COMPRESSED_WIF: &str = "KwdMAjGmerYanjeui5SHS7JkmpZvVipYvB2LJGU1ZxJwYvP98617";
+    const UNCOMPRESSED_WIF: &str = "5HueCGU8rMjxEXxiPuD5BDku4MkFqeZyd4dZ1jvhTVqvbTLvyTJ";
+
+    fn key() -> PrivateKey {
+        PrivateKey::from_bytes(&hex::decode(KEY).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn wif_round_trips() {
+        let mainnet = ChainParams::mainnet();
+        assert_eq!(key().to_wif(&mainnet), COMPRESSED_WIF);
+        let parsed = PrivateKey::from_wif(COMPRESSED_WIF, &mainnet).unwrap();
+        assert_eq!(parsed.to_bytes(), key().to_bytes());
+        let parsed = PrivateKey::from_wif(UNCOMPRESSED_WIF, &mainnet).unwrap();
+        assert_eq!(parsed.to_bytes(), key().to_bytes());
+
+        let testnet = ChainParams::testnet();
+        let wif = key().to_wif(&testnet);
+        assert_ne!(wif, COMPRESSED_WIF);
+        let parsed = PrivateKey::from_wif(&wif, &testnet).unwrap();
+        assert_eq!(parsed.to_bytes(), key().to_bytes());
+    }
+
+    #[test]
+    fn wif_rejects_bad_checksums() {
+        // Last character changed, which lands in the checksum
+        let mut wif = COMPRESSED_WIF.to_string();
+        wif.pop();
+        wif.push('8');
+        assert!(matches!(
+            PrivateKey::from_wif(&wif, &ChainParams::mainnet()),
+            Err(BtcError::InvalidPrivateKey)
+        ));
+    }
+
+    #[test]
+    fn wif_rejects_other_version_bytes() {
+        let wif = key().to_wif(&ChainParams::testnet());
+        assert!(matches!(
+            PrivateKey::from_wif(&wif, &ChainParams::mainnet()),
+            Err(BtcError::WrongNetwork)
+        ));
+        assert!(matches!(
+            PrivateKey::from_wif(COMPRESSED_WIF, &ChainParams::testnet()),
+            Err(BtcError::WrongNetwork)
+        ));
+    }
+
+    #[test]
+    fn batch_rejects_one_bad_schnorr_signature() {
+        let keys: Vec<_> = (1..=5u8)
+            .map(|byte| PrivateKey::from_bytes(&[byte; 32]).unwrap())
+            .collect();
+        let digests: Vec<_> = (0..5u8).map(|byte| Hash::hash_bytes(&[byte])).collect();
+        let pubkeys: Vec<_> = keys.iter().map(PrivateKey::public_key).collect();
+        let mut signatures: Vec<_> = keys
+            .iter()
+            .zip(&digests)
+            .map(|(key, digest)| key.sign_schnorr(digest))
+            .collect();
+        // An ECDSA signature rides along and is checked on its own
+        signatures[4] = keys[4].sign(&digests[4]);
+        fn batch<'a>(
+            digests: &[Hash],
+            signatures: &'a [Signature],
+            pubkeys: &'a [PublicKey],
+        ) -> Vec<(Hash, &'a Signature, &'a PublicKey)> {
+            digests
+                .iter()
+                .zip(signatures)
+                .zip(pubkeys)
+                .map(|((digest, signature), pubkey)| (*digest, signature, pubkey))
+                .collect()
+        }
+        assert!(verify_batch(&batch(&digests, &signatures, &pubkeys)));
+
+        // Valid under its own digest, not under the one it is batched with
+        signatures[2] = keys[2].sign_schnorr(&digests[0]);
+        assert!(!verify_batch(&batch(&digests, &signatures, &pubkeys)));
+    }
+}
@@ -0,0 +1,66 @@
+use super::{PrivateKey, Sealed};
+use crate::error::{BtcError, Result};
+use crate::util::Saveable;
+use serde::{Deserialize, Serialize};
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write};
+
+const KEY_FILE_VERSION: u32 = 2;
+
+// A private key sealed under a passphrase
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct EncryptedPrivateKey {
+    version: u32,
+    sealed: Sealed,
+}
+
+impl EncryptedPrivateKey {
+    pub fn decrypt(&self, passphrase: &str) -> Result<PrivateKey> {
+        if self.version != KEY_FILE_VERSION {
+            return Err(BtcError::UnsupportedEncryption(self.version));
+        }
+        let plaintext = self.sealed.open(passphrase)?;
+        PrivateKey::from_bytes(&plaintext).ok_or(BtcError::InvalidPrivateKey)
+    }
+}
+
+impl PrivateKey {
+    pub fn encrypt(&self, passphrase: &str) -> EncryptedPrivateKey {
+        EncryptedPrivateKey {
+            version: KEY_FILE_VERSION,
+            sealed: Sealed::seal(passphrase, &self.to_bytes()),
+        }
+    }
+}
+
+// Either kind of private key file, so callers only ask for a passphrase
+// when the key is sealed
+#[derive(Debug, Clone)]
+pub enum KeyFile {
+    Plain(PrivateKey),
+    Encrypted(EncryptedPrivateKey),
+}
+
+impl Saveable for KeyFile {
+    fn load<I: Read>(mut reader: I) -> IoResult<Self> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        match ciborium::de::from_reader::<EncryptedPrivateKey, _>(bytes.as_slice()) {
+            Ok(sealed) => Ok(KeyFile::Encrypted(sealed)),
+            Err(_) => PrivateKey::load(bytes.as_slice()).map(KeyFile::Plain),
+        }
+    }
+
+    fn save<O: Write>(&self, writer: O) -> IoResult<()> {
+        match self {
+            KeyFile::Plain(key) => key.save(writer),
+            KeyFile::Encrypted(sealed) => {
+                ciborium::ser::into_writer(sealed, writer).map_err(|_| {
+                    IoError::new(
+                        IoErrorKind::InvalidData,
+                        "Failed to serialize EncryptedPrivateKey",
+                    )
+                })
+            }
+        }
+    }
+}
@@ -0,0 +1,377 @@
+use super::{PrivateKey, PublicKey};
+use crate::error::{BtcError, Result};
+use ecdsa::{SigningKey, VerifyingKey};
+use k256::elliptic_curve::PrimeField;
+use k256::{ProjectivePoint, Scalar};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha512};
+use std::fmt;
+use std::str::FromStr;
+
+// BIP 32 keys: a key and the chain code children are derived with. One seed
+// gives every key of a wallet, private children of a public key need the
+// private key, non hardened ones can be derived from the public key alone.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ExtendedPrivateKey {
+    key: PrivateKey,
+    chain_code: [u8; 32],
+    depth: u8,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ExtendedPublicKey {
+    key: PublicKey,
+    chain_code: [u8; 32],
+    depth: u8,
+}
+
+// Indices from 2^31 on are hardened
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ChildNumber(u32);
+
+impl ChildNumber {
+    const HARDENED: u32 = 1 << 31;
+
+    pub fn normal(index: u32) -> Option<Self> {
+        (index < Self::HARDENED).then_some(ChildNumber(index))
+    }
+
+    pub fn hardened(index: u32) -> Option<Self> {
+        (index < Self::HARDENED).then_some(ChildNumber(index | Self::HARDENED))
+    }
+
+    pub fn is_hardened(&self) -> bool {
+        self.0 & Self::HARDENED != 0
+    }
+
+    pub fn index(&self) -> u32 {
+        self.0 & !Self::HARDENED
+    }
+}
+
+impl fmt::Display for ChildNumber {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.index())?;
+        if self.is_hardened() {
+            write!(f, "'")?;
+        }
+        Ok(())
+    }
+}
+
+// Written like m/44'/0'/0'/0/5, with h accepted in place of '
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq, Hash)]
+pub struct DerivationPath(Vec<ChildNumber>);
+
+impl DerivationPath {
+    pub fn child(&self, number: ChildNumber) -> Self {
+        let mut path = self.clone();
+        path.0.push(number);
+        path
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ChildNumber> {
+        self.0.iter()
+    }
+}
+
+impl fmt::Display for DerivationPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "m")?;
+        for number in &self.0 {
+            write!(f, "/{}", number)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for DerivationPath {
+    type Err = BtcError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.trim().split('/');
+        if parts.next() != Some("m") {
+            return Err(BtcError::InvalidDerivationPath);
+        }
+        parts
+            .map(|part| {
+                let (index, hardened) = match part.strip_suffix(['\'', 'h']) {
+                    Some(index) => (index, true),
+                    None => (part, false),
+                };
+                let index = index.parse().map_err(|_| BtcError::InvalidDerivationPath)?;
+                let number = if hardened {
+                    ChildNumber::hardened(index)
+                } else {
+                    ChildNumber::normal(index)
+                };
+                number.ok_or(BtcError::InvalidDerivationPath)
+            })
+            .collect::<Result<Vec<_>>>()
+            .map(DerivationPath)
+    }
+}
+
+impl ExtendedPrivateKey {
+    // The master key of a seed, which should hold 16 to 64 random bytes
+    pub fn from_seed(seed: &[u8]) -> Result<Self> {
+        let (key, chain_code) = hmac_sha512(b"Bitcoin seed", seed);
+        let key = PrivateKey::from_bytes(&key).ok_or(BtcError::InvalidPrivateKey)?;
+        Ok(ExtendedPrivateKey {
+            key,
+            chain_code,
+            depth: 0,
+        })
+    }
+
+    // Fails with a probability below 2^-127, BIP 32 moves on to the next index then
+    pub fn derive_child(&self, number: ChildNumber) -> Result<Self> {
+        let mut data = Vec::with_capacity(37);
+        if number.is_hardened() {
+            data.push(0);
+            data.extend_from_slice(&self.key.to_bytes());
+        } else {
+            data.extend_from_slice(&self.key.public_key().to_bytes());
+        }
+        data.extend_from_slice(&number.0.to_be_bytes());
+        let (tweak, chain_code) = hmac_sha512(&self.chain_code, &data);
+        let tweak = parse_scalar(&tweak)?;
+        let parent = parse_scalar(&self.key.to_bytes())?;
+        let child = tweak + parent;
+        if child == Scalar::ZERO {
+            return Err(BtcError::InvalidPrivateKey);
+        }
+        let key =
+            SigningKey::from_bytes(&child.to_repr()).map_err(|_| BtcError::InvalidPrivateKey)?;
+        Ok(ExtendedPrivateKey {
+            key: PrivateKey(key),
+            chain_code,
+            depth: self.depth.saturating_add(1),
+        })
+    }
+
+    pub fn derive_path(&self, path: &DerivationPath) -> Result<Self> {
+        path.iter()
+            .try_fold(self.clone(), |key, number| key.derive_child(*number))
+    }
+
+    pub fn private_key(&self) -> &PrivateKey {
+        &self.key
+    }
+
+    pub fn public(&self) -> ExtendedPublicKey {
+        ExtendedPublicKey {
+            key: self.key.public_key(),
+            chain_code: self.chain_code,
+            depth: self.depth,
+        }
+    }
+
+    pub fn depth(&self) -> u8 {
+        self.depth
+    }
+}
+
+impl ExtendedPublicKey {
+    // Only non hardened children, those need the private key
+    pub fn derive_child(&self, number: ChildNumber) -> Result<Self> {
+        if number.is_hardened() {
+            return Err(BtcError::HardenedDerivation);
+        }
+        let mut data = Vec::with_capacity(37);
+        data.extend_from_slice(&self.key.to_bytes());
+        data.extend_from_slice(&number.0.to_be_bytes());
+        let (tweak, chain_code) = hmac_sha512(&self.chain_code, &data);
+        let tweak = parse_scalar(&tweak)?;
+        let parent = ProjectivePoint::from(*self.key.0.as_affine());
+        let point = ProjectivePoint::GENERATOR * tweak + parent;
+        let key =
+            VerifyingKey::from_affine(point.to_affine()).map_err(|_| BtcError::InvalidPublicKey)?;
+        Ok(ExtendedPublicKey {
+            key: PublicKey(key),
+            chain_code,
+            depth: self.depth.saturating_add(1),
+        })
+    }
+
+    pub fn derive_path(&self, path: &DerivationPath) -> Result<Self> {
+        path.iter()
+            .try_fold(self.clone(), |key, number| key.derive_child(*number))
+    }
+
+    pub fn public_key(&self) -> &PublicKey {
+        &self.key
+    }
+
+    pub fn depth(&self) -> u8 {
+        self.depth
+    }
+}
+
+// Below the group order, as BIP 32 requires of every tweak
+fn parse_scalar(bytes: &[u8; 32]) -> Result<Scalar> {
+    Option::from(Scalar::from_repr((*bytes).into())).ok_or(BtcError::InvalidPrivateKey)
+}
+
+// RFC 2104 over SHA-512, split into the key and chain code halves
+fn hmac_sha512(key: &[u8], data: &[u8]) -> ([u8; 32], [u8; 32]) {
+    const BLOCK_SIZE: usize = 128;
+    let mut padded = [0u8; BLOCK_SIZE];
+    if key.len() > BLOCK_SIZE {
+        padded[..64].copy_from_slice(&Sha512::digest(key));
+    } else {
+        padded[..key.len()].copy_from_slice(key);
+    }
+    let inner_pad = padded.map(|byte| byte ^ 0x36);
+    let outer_pad = padded.map(|byte| byte ^ 0x5c);
+    let inner = Sha512::new()
+        .chain_update(inner_pad)
+        .chain_update(data)
+        .finalize();
+    let outer = Sha512::new()
+        .chain_update(outer_pad)
+        .chain_update(inner)
+        .finalize();
+    let (left, right) = outer.split_at(32);
+    (
+        left.try_into().expect("Bug: Impossible"),
+        right.try_into().expect("Bug: Impossible"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Official BIP 32 test vectors: the path, then the xprv and xpub it gives
+    const VECTOR_1_SEED: &str = "000102030405060708090a0b0c0d0e0f";
+    const VECTOR_1: &[(&str, &str, &str)] = &[
+        (
+            "m",
+            "xprv9s21ZrQH143K3QTDL4LXw2F7HEK3wJUD2nW2nRk4stbPy6cq3jPPqjiChkVvvNKmPGJxWUtg6LnF5kejMRNNU3TGtRBeJgk33yuGBxrMPHi",
+            "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8",
+        ),
+        (
+            "m/0'",
+            "xprv9uHRZZhk6KAJC1avXpDAp4MDc3sQKNxDiPvvkX8Br5ngLNv1TxvUxt4cV1rGL5hj6KCesnDYUhd7oWgT11eZG7XnxHrnYeSvkzY7d2bhkJ7",
+            "xpub68Gmy5EdvgibQVfPdqkBBCHxA5htiqg55crXYuXoQRKfDBFA1WEjWgP6LHhwBZeNK1VTsfTFUHCdrfp1bgwQ9xv5ski8PX9rL2dZXvgGDnw",
+        ),
+        (
+            "m/0'/1",
+            "xprv9wTYmMFdV23N2TdNG573QoEsfRrWKQgWeibmLntzniatZvR9BmLnvSxqu53Kw1UmYPxLgboyZQaXwTCg8MSY3H2EU4pWcQDnRnrVA1xe8fs",
+            "xpub6ASuArnXKPbfEwhqN6e3mwBcDTgzisQN1wXN9BJcM47sSikHjJf3UFHKkNAWbWMiGj7Wf5uMash7SyYq527Hqck2AxYysAA7xmALppuCkwQ",
+        ),
+        (
+            "m/0'/1/2'",
+            "xprv9z4pot5VBttmtdRTWfWQmoH1taj2axGVzFqSb8C9xaxKymcFzXBDptWmT7FwuEzG3ryjH4ktypQSAewRiNMjANTtpgP4mLTj34bhnZX7UiM",
+            "xpub6D4BDPcP2GT577Vvch3R8wDkScZWzQzMMUm3PWbmWvVJrZwQY4VUNgqFJPMM3No2dFDFGTsxxpG5uJh7n7epu4trkrX7x7DogT5Uv6fcLW5",
+        ),
+        (
+            "m/0'/1/2'/2",
+            "xprvA2JDeKCSNNZky6uBCviVfJSKyQ1mDYahRjijr5idH2WwLsEd4Hsb2Tyh8RfQMuPh7f7RtyzTtdrbdqqsunu5Mm3wDvUAKRHSC34sJ7in334",
+            "xpub6FHa3pjLCk84BayeJxFW2SP4XRrFd1JYnxeLeU8EqN3vDfZmbqBqaGJAyiLjTAwm6ZLRQUMv1ZACTj37sR62cfN7fe5JnJ7dh8zL4fiyLHV",
+        ),
+        (
+            "m/0'/1/2'/2/1000000000",
+            "xprvA41z7zogVVwxVSgdKUHDy1SKmdb533PjDz7J6N6mV6uS3ze1ai8FHa8kmHScGpWmj4WggLyQjgPie1rFSruoUihUZREPSL39UNdE3BBDu76",
+            "xpub6H1LXWLaKsWFhvm6RVpEL9P4KfRZSW7abD2ttkWP3SSQvnyA8FSVqNTEcYFgJS2UaFcxupHiYkro49S8yGasTvXEYBVPamhGW6cFJodrTHy",
+        ),
+    ];
+
+    const VECTOR_2_SEED: &str = "fffcf9f6f3f0edeae7e4e1dedbd8d5d2cfccc9c6c3c0bdbab7b4b1aeaba8a5a29f9c999693908d8a8784817e7b7875726f6c696663605d5a5754514e4b484542";
+    const VECTOR_2: &[(&str, &str, &str)] = &[
+        (
+            "m",
+            "xprv9s21ZrQH143K31xYSDQpPDxsXRTUcvj2iNHm5NUtrGiGG5e2DtALGdso3pGz6ssrdK4PFmM8NSpSBHNqPqm55Qn3LqFtT2emdEXVYsCzC2U",
+            "xpub661MyMwAqRbcFW31YEwpkMuc5THy2PSt5bDMsktWQcFF8syAmRUapSCGu8ED9W6oDMSgv6Zz8idoc4a6mr8BDzTJY47LJhkJ8UB7WEGuduB",
+        ),
+        (
+            "m/0",
+            "xprv9vHkqa6EV4sPZHYqZznhT2NPtPCjKuDKGY38FBWLvgaDx45zo9WQRUT3dKYnjwih2yJD9mkrocEZXo1ex8G81dwSM1fwqWpWkeS3v86pgKt",
+            "xpub69H7F5d8KSRgmmdJg2KhpAK8SR3DjMwAdkxj3ZuxV27CprR9LgpeyGmXUbC6wb7ERfvrnKZjXoUmmDznezpbZb7ap6r1D3tgFxHmwMkQTPH",
+        ),
+        (
+            "m/0/2147483647'",
+            "xprv9wSp6B7kry3Vj9m1zSnLvN3xH8RdsPP1Mh7fAaR7aRLcQMKTR2vidYEeEg2mUCTAwCd6vnxVrcjfy2kRgVsFawNzmjuHc2YmYRmagcEPdU9",
+            "xpub6ASAVgeehLbnwdqV6UKMHVzgqAG8Gr6riv3Fxxpj8ksbH9ebxaEyBLZ85ySDhKiLDBrQSARLq1uNRts8RuJiHjaDMBU4Zn9h8LZNnBC5y4a",
+        ),
+        (
+            "m/0/2147483647'/1",
+            "xprv9zFnWC6h2cLgpmSA46vutJzBcfJ8yaJGg8cX1e5StJh45BBciYTRXSd25UEPVuesF9yog62tGAQtHjXajPPdbRCHuWS6T8XA2ECKADdw4Ef",
+            "xpub6DF8uhdarytz3FWdA8TvFSvvAh8dP3283MY7p2V4SeE2wyWmG5mg5EwVvmdMVCQcoNJxGoWaU9DCWh89LojfZ537wTfunKau47EL2dhHKon",
+        ),
+        (
+            "m/0/2147483647'/1/2147483646'",
+            "xprvA1RpRA33e1JQ7ifknakTFpgNXPmW2YvmhqLQYMmrj4xJXXWYpDPS3xz7iAxn8L39njGVyuoseXzU6rcxFLJ8HFsTjSyQbLYnMpCqE2VbFWc",
+            "xpub6ERApfZwUNrhLCkDtcHTcxd75RbzS1ed54G1LkBUHQVHQKqhMkhgbmJbZRkrgZw4koxb5JaHWkY4ALHY2grBGRjaDMzQLcgJvLJuZZvRcEL",
+        ),
+        (
+            "m/0/2147483647'/1/2147483646'/2",
+            "xprvA2nrNbFZABcdryreWet9Ea4LvTJcGsqrMzxHx98MMrotbir7yrKCEXw7nadnHM8Dq38EGfSh6dqA9QWTyefMLEcBYJUuekgW4BYPJcr9E7j",
+            "xpub6FnCn6nSzZAw5Tw7cgR9bi15UV96gLZhjDstkXXxvCLsUXBGXPdSnLFbdpq8p9HmGsApME5hQTZ3emM2rnY5agb9rXpVGyy3bdW6EEgAtqt",
+        ),
+    ];
+
+    // Serialized keys are version, depth, parent fingerprint, child number,
+    // chain code and key. Only what the extended keys hold is compared.
+    fn decode(extended: &str) -> Vec<u8> {
+        let bytes = bs58::decode(extended).with_check(None).into_vec().unwrap();
+        assert_eq!(bytes.len(), 78);
+        bytes
+    }
+
+    fn assert_private(key: &ExtendedPrivateKey, xprv: &str) {
+        let bytes = decode(xprv);
+        assert_eq!(key.depth, bytes[4]);
+        assert_eq!(key.chain_code, bytes[13..45]);
+        assert_eq!(bytes[45], 0);
+        assert_eq!(key.key.to_bytes(), bytes[46..78]);
+    }
+
+    fn assert_public(key: &ExtendedPublicKey, xpub: &str) {
+        let bytes = decode(xpub);
+        assert_eq!(key.depth, bytes[4]);
+        assert_eq!(key.chain_code, bytes[13..45]);
+        assert_eq!(key.key.to_bytes(), bytes[45..78]);
+    }
+
+    // Every step is derived from the private parent, and non hardened steps
+    // from the public parent alone as well
+    fn check_vector(seed: &str, vector: &[(&str, &str, &str)]) {
+        let master = ExtendedPrivateKey::from_seed(&hex::decode(seed).unwrap()).unwrap();
+        let mut parent: Option<ExtendedPrivateKey> = None;
+        for (path, xprv, xpub) in vector {
+            let path = path.parse::<DerivationPath>().unwrap();
+            let key = master.derive_path(&path).unwrap();
+            assert_private(&key, xprv);
+            assert_public(&key.public(), xpub);
+            if let (Some(parent), Some(number)) = (&parent, path.iter().last()) {
+                let derived = parent.public().derive_child(*number);
+                if number.is_hardened() {
+                    assert!(matches!(derived, Err(BtcError::HardenedDerivation)));
+                } else {
+                    assert_public(&derived.unwrap(), xpub);
+                }
+            }
+            parent = Some(key);
+        }
+    }
+
+    #[test]
+    fn bip32_test_vector_1() {
+        check_vector(VECTOR_1_SEED, VECTOR_1);
+    }
+
+    #[test]
+    fn bip32_test_vector_2() {
+        check_vector(VECTOR_2_SEED, VECTOR_2);
+    }
+
+    #[test]
+    fn derivation_paths_round_trip() {
+        let path = "m/44'/0'/0h/0/5".parse::<DerivationPath>().unwrap();
+        assert_eq!(path.to_string(), "m/44'/0'/0'/0/5");
+        assert!("44'/0".parse::<DerivationPath>().is_err());
+        assert!("m/2147483648".parse::<DerivationPath>().is_err());
+    }
+}
@@ -0,0 +1,134 @@
+use super::ExtendedPrivateKey;
+use crate::error::{BtcError, Result};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use std::fmt;
+use std::str::FromStr;
+
+// BIP 39 phrase from the English word list. 12 words hold 128 bits of
+// entropy, 24 words 256 bits, the last word carries a checksum.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mnemonic(bip39::Mnemonic);
+
+impl Mnemonic {
+    // 12, 15, 18, 21 or 24 words of fresh entropy from the operating system
+    pub fn generate(words: usize) -> Result<Self> {
+        if !(12..=24).contains(&words) || !words.is_multiple_of(3) {
+            return Err(BtcError::InvalidMnemonic);
+        }
+        let mut entropy = vec![0; words / 3 * 4];
+        OsRng.fill_bytes(&mut entropy);
+        Self::from_entropy(&entropy)
+    }
+
+    pub fn from_entropy(entropy: &[u8]) -> Result<Self> {
+        bip39::Mnemonic::from_entropy(entropy)
+            .map(Mnemonic)
+            .map_err(|_| BtcError::InvalidMnemonic)
+    }
+
+    pub fn word_count(&self) -> usize {
+        self.0.word_count()
+    }
+
+    // Different passphrases give unrelated seeds, none of them is wrong
+    pub fn to_seed(&self, passphrase: &str) -> [u8; 64] {
+        self.0.to_seed(passphrase)
+    }
+
+    pub fn master_key(&self, passphrase: &str) -> Result<ExtendedPrivateKey> {
+        ExtendedPrivateKey::from_seed(&self.to_seed(passphrase))
+    }
+}
+
+impl fmt::Display for Mnemonic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+// Rejects unknown words, wrong lengths and bad checksums. Case and extra
+// whitespace from copying the phrase by hand are ignored.
+impl FromStr for Mnemonic {
+    type Err = BtcError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let words = s
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+            .to_lowercase();
+        bip39::Mnemonic::parse_in_normalized(bip39::Language::English, &words)
+            .map(Mnemonic)
+            .map_err(|_| BtcError::InvalidMnemonic)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Official BIP 39 English vectors: entropy, phrase and the seed it gives
+    // with the passphrase "TREZOR"
+    const VECTORS: &[(&str, &str, &str)] = &[
+        (
+            "00000000000000000000000000000000",
+            "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about",
+            "c55257c360c07c72029aebc1b53c05ed0362ada38ead3e3e9efa3708e53495531f09a6987599d18264c1e1c92f2cf141630c7a3c4ab7c81b2f001698e7463b04",
+        ),
+        (
+            "7f7f7f7f7f7f7f7f7f7f7f7f7f7f7f7f",
+            "legal winner thank year wave sausage worth useful legal winner thank yellow",
+            "2e8905819b8723fe2c1d161860e5ee1830318dbf49a83bd451cfb8440c28bd6fa457fe1296106559a3c80937a1c1069be3a3a5bd381ee6260e8d9739fce1f607",
+        ),
+        (
+            "ffffffffffffffffffffffffffffffff",
+            "zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo wrong",
+            "ac27495480225222079d7be181583751e86f571027b0497b5b5d11218e0a8a13332572917f0f8e5a589620c6f15b11c61dee327651a14c34e18231052e48c069",
+        ),
+        (
+            "6610b25967cdcca9d59875f5cb50b0ea75433311869e930b",
+            "gravity machine north sort system female filter attitude volume fold club stay feature office ecology stable narrow fog",
+            "628c3827a8823298ee685db84f55caa34b5cc195a778e52d45f59bcf75aba68e4d7590e101dc414bc1bbd5737666fbbef35d1f1903953b66624f910feef245ac",
+        ),
+        (
+            "68a79eaca2324873eacc50cb9c6eca8cc68ea5d936f98787c60c7ebc74e6ce7c",
+            "hamster diagram private dutch cause delay private meat slide toddler razor book happy fancy gospel tennis maple dilemma loan word shrug inflict delay length",
+            "64c87cde7e12ecf6704ab95bb1408bef047c22db4cc7491c4271d170a1b213d20b385bc1588d9c7b38f1b39d415665b8a9030c9ec653d75e65f847d8fc1fc440",
+        ),
+    ];
+
+    #[test]
+    fn bip39_english_vectors() {
+        for (entropy, phrase, seed) in VECTORS {
+            let mnemonic = Mnemonic::from_entropy(&hex::decode(entropy).unwrap()).unwrap();
+            assert_eq!(mnemonic.to_string(), *phrase);
+            assert_eq!(hex::encode(mnemonic.to_seed("TREZOR")), *seed);
+            assert_eq!(phrase.parse::<Mnemonic>().unwrap(), mnemonic);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_checksums() {
+        // "about" is the only valid last word after eleven "abandon"
+        let phrase = ["abandon"; 12].join(" ");
+        assert!(matches!(
+            phrase.parse::<Mnemonic>(),
+            Err(BtcError::InvalidMnemonic)
+        ));
+        let phrase = "zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo";
+        assert!(matches!(
+            phrase.parse::<Mnemonic>(),
+            Err(BtcError::InvalidMnemonic)
+        ));
+    }
+
+    #[test]
+    fn ignores_case_and_spacing() {
+        let phrase =
+            "  Legal winner THANK year wave sausage\tworth useful legal winner thank yellow ";
+        let expected =
+            "legal winner thank year wave sausage worth useful legal winner thank yellow";
+        assert_eq!(phrase.parse::<Mnemonic>().unwrap().to_string(), expected);
+    }
+}
@@ -0,0 +1,109 @@
+use crate::error::{BtcError, Result};
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+const SEALED_VERSION: u32 = 1;
+const MEMORY_KIB: u32 = 64 * 1024;
+const ITERATIONS: u32 = 3;
+const MAX_MEMORY_KIB: u32 = 1024 * 1024;
+const MAX_ITERATIONS: u32 = 64;
+
+// Bytes encrypted with ChaCha20-Poly1305 under a key stretched from a
+// passphrase with Argon2id. The costs are stored so raising them later
+// keeps older data readable.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Sealed {
+    version: u32,
+    memory_kib: u32,
+    iterations: u32,
+    salt: [u8; 16],
+    nonce: [u8; 12],
+    ciphertext: Vec<u8>,
+}
+
+impl Sealed {
+    pub fn seal(passphrase: &str, plaintext: &[u8]) -> Self {
+        let mut salt = [0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = cipher(passphrase, &salt, MEMORY_KIB, ITERATIONS)
+            .expect("Bug: Impossible")
+            .encrypt(&nonce, plaintext)
+            .expect("Bug: Impossible");
+        Sealed {
+            version: SEALED_VERSION,
+            memory_kib: MEMORY_KIB,
+            iterations: ITERATIONS,
+            salt,
+            nonce: nonce.into(),
+            ciphertext,
+        }
+    }
+
+    pub fn open(&self, passphrase: &str) -> Result<Vec<u8>> {
+        if self.version != SEALED_VERSION {
+            return Err(BtcError::UnsupportedEncryption(self.version));
+        }
+        cipher(passphrase, &self.salt, self.memory_kib, self.iterations)?
+            .decrypt(Nonce::from_slice(&self.nonce), self.ciphertext.as_slice())
+            .map_err(|_| BtcError::WrongPassphrase)
+    }
+}
+
+// Costs read from a file are capped, so a crafted one cannot exhaust memory
+// or stall the unlock
+fn cipher(
+    passphrase: &str,
+    salt: &[u8],
+    memory_kib: u32,
+    iterations: u32,
+) -> Result<ChaCha20Poly1305> {
+    if memory_kib > MAX_MEMORY_KIB || iterations > MAX_ITERATIONS {
+        return Err(BtcError::EncryptionCostTooHigh {
+            memory_kib,
+            iterations,
+        });
+    }
+    let params =
+        Params::new(memory_kib, iterations, 1, Some(32)).map_err(|_| BtcError::WrongPassphrase)?;
+    let mut key = [0u8; 32];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|_| BtcError::WrongPassphrase)?;
+    Ok(ChaCha20Poly1305::new(Key::from_slice(&key)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn crafted(memory_kib: u32, iterations: u32) -> Sealed {
+        Sealed {
+            version: SEALED_VERSION,
+            memory_kib,
+            iterations,
+            salt: [0; 16],
+            nonce: [0; 12],
+            ciphertext: vec![0; 48],
+        }
+    }
+
+    #[test]
+    fn rejects_excessive_iterations() {
+        assert!(matches!(
+            crafted(MEMORY_KIB, u32::MAX).open("passphrase"),
+            Err(BtcError::EncryptionCostTooHigh { .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_excessive_memory() {
+        assert!(matches!(
+            crafted(u32::MAX, ITERATIONS).open("passphrase"),
+            Err(BtcError::EncryptionCostTooHigh { .. })
+        ));
+    }
+}
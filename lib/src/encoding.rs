@@ -0,0 +1,123 @@
+use chrono::{DateTime, Utc};
+
+use crate::amount::Amount;
+use crate::sha256::Hash;
+
+// The bytes hashes and signatures commit to, independent of how a value is
+// stored or sent. Integers are fixed width little endian, sequences are
+// prefixed by their length as u32, options by a 0 or 1 byte and enum
+// variants by a one byte tag. Changing this changes every txid and block hash.
+pub trait Encode {
+    fn encode(&self, out: &mut Vec<u8>);
+
+    fn encoded(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode(&mut out);
+        out
+    }
+
+    fn encoded_hash(&self) -> Hash {
+        Hash::hash_bytes(&self.encoded())
+    }
+}
+
+impl<T: Encode + ?Sized> Encode for &T {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (**self).encode(out);
+    }
+}
+
+impl Encode for u8 {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(*self);
+    }
+}
+
+impl Encode for u32 {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+impl Encode for u64 {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+impl Encode for i64 {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+// Lengths and counts above u32::MAX cannot occur in a valid block
+impl Encode for usize {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (*self as u32).encode(out);
+    }
+}
+
+impl Encode for Hash {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.as_bytes());
+    }
+}
+
+impl Encode for Amount {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.to_sat().encode(out);
+    }
+}
+
+// Whole seconds and the nanoseconds past them
+impl Encode for DateTime<Utc> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.timestamp().encode(out);
+        self.timestamp_subsec_nanos().encode(out);
+    }
+}
+
+impl<T: Encode> Encode for [T] {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.len().encode(out);
+        for item in self {
+            item.encode(out);
+        }
+    }
+}
+
+impl<T: Encode> Encode for Vec<T> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.as_slice().encode(out);
+    }
+}
+
+impl<T: Encode> Encode for Option<T> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            None => 0u8.encode(out),
+            Some(value) => {
+                1u8.encode(out);
+                value.encode(out);
+            }
+        }
+    }
+}
+
+// Tuples are their elements one after another
+macro_rules! encode_tuple {
+    ($($name:ident $index:tt),+) => {
+        impl<$($name: Encode),+> Encode for ($($name,)+) {
+            fn encode(&self, out: &mut Vec<u8>) {
+                $(self.$index.encode(out);)+
+            }
+        }
+    };
+}
+
+encode_tuple!(A 0, B 1);
+encode_tuple!(A 0, B 1, C 2);
+encode_tuple!(A 0, B 1, C 2, D 3);
+encode_tuple!(A 0, B 1, C 2, D 3, E 4);
+encode_tuple!(A 0, B 1, C 2, D 3, E 4, F 5);
@@ -1,3 +1,7 @@
+use crate::amount::Amount;
+use crate::sha256::Hash;
+use crate::types::TxIndex;
+use chrono::{DateTime, Utc};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -31,6 +35,120 @@ pub enum BtcError {
 
     #[error("Invalid Private Key")]
     InvalidPrivateKey,
+
+    #[error("Key Belongs To Another Network")]
+    WrongNetwork,
+
+    #[error("Immature Coinbase Spend")]
+    ImmatureCoinbase,
+
+    #[error("Transaction Not Final")]
+    NonFinalTransaction,
+
+    #[error("Operation Cancelled")]
+    Cancelled,
+
+    #[error("Invalid Script")]
+    InvalidScript,
+
+    #[error("Invalid Address")]
+    InvalidAddress,
+
+    #[error("Wrong Passphrase Or Corrupted Data")]
+    WrongPassphrase,
+
+    #[error("Unsupported Encryption Version {0}")]
+    UnsupportedEncryption(u32),
+
+    #[error("Encryption Cost Too High: {memory_kib} KiB, {iterations} Iterations")]
+    EncryptionCostTooHigh { memory_kib: u32, iterations: u32 },
+
+    #[error("Block Does Not Extend The Tip")]
+    StaleParent,
+
+    #[error("Insufficient Proof Of Work")]
+    InsufficientWork,
+
+    #[error("Block Bits {got:#010x} Do Not Match The Expected {expected:#010x}")]
+    UnexpectedTarget { expected: u32, got: u32 },
+
+    #[error("Block Timestamp {got} Is Not After The Median Time Past {min}")]
+    TimestampTooOld {
+        got: DateTime<Utc>,
+        min: DateTime<Utc>,
+    },
+
+    #[error("Block Timestamp {got} Is Later Than {max}")]
+    TimestampTooNew {
+        got: DateTime<Utc>,
+        max: DateTime<Utc>,
+    },
+
+    #[error("Output {outpoint} Is Not Unspent")]
+    MissingUtxo { outpoint: Hash },
+
+    #[error("Output {outpoint} Is Spent Twice")]
+    DoubleSpend { outpoint: Hash },
+
+    #[error("Bad Signature For Input {input} Of {txid}")]
+    BadSignature { txid: Hash, input: usize },
+
+    #[error("Outputs Worth {outputs} Exceed Inputs Worth {inputs}")]
+    InsufficientInputs { inputs: Amount, outputs: Amount },
+
+    #[error("Coinbase Commits To Height {got:?}, Expected {expected}")]
+    WrongCoinbaseHeight { expected: u64, got: Option<u64> },
+
+    #[error("Coinbase Pays {got}, Expected {expected}")]
+    WrongCoinbaseValue { expected: Amount, got: Amount },
+
+    #[error("Non Standard Transaction: {0}")]
+    NonStandard(&'static str),
+
+    #[error("Too Many Signature Operations")]
+    TooManySigops,
+
+    #[error("Replacement Rejected: {0}")]
+    ReplacementRejected(&'static str),
+
+    #[error("Mempool Full")]
+    MempoolFull,
+
+    #[error("Duplicate Transaction")]
+    DuplicateTransaction,
+
+    #[error("Block Conflicts With A Checkpoint")]
+    CheckpointMismatch,
+
+    #[error("Invalid Coinbase")]
+    InvalidCoinbase,
+
+    #[error("Value Overflow")]
+    ValueOverflow,
+
+    #[error("The Tip Cannot Be Disconnected")]
+    CannotDisconnect,
+
+    #[error("Blocks Below Height {below} Are Pruned")]
+    Pruned { below: u64 },
+
+    #[error("Supply Mismatch At Height {height}")]
+    SupplyMismatch { height: u64 },
+
+    #[error("Invalid Transaction At Index {index}: {reason}")]
+    InvalidTransactionAt {
+        index: TxIndex,
+        reason: Box<BtcError>,
+    },
+
+    #[error("Invalid Derivation Path")]
+    InvalidDerivationPath,
+
+    #[error("Hardened Keys Cannot Be Derived From A Public Key")]
+    HardenedDerivation,
+
+    #[error("Invalid Mnemonic")]
+    InvalidMnemonic,
 }
 
 pub type Result<T> = std::result::Result<T, BtcError>;
@@ -1,17 +1,158 @@
-use serde::{Deserialize, Serialize};
+use serde::de::{self, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
 use uint::construct_uint;
 
 construct_uint! {
-    #[derive(Serialize, Deserialize)]
     pub struct U256(4);
 }
+
+// Written as 32 big endian bytes
+impl Serialize for U256 {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.to_big_endian_bytes())
+    }
+}
+
+impl<'de> Deserialize<'de> for U256 {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let bytes = deserializer.deserialize_bytes(Bytes32Visitor)?;
+        Ok(U256::from_big_endian(&bytes))
+    }
+}
+
+// Exactly 32 bytes, as a byte string or, in formats without one, a sequence
+pub(crate) struct Bytes32Visitor;
+
+impl<'de> Visitor<'de> for Bytes32Visitor {
+    type Value = [u8; 32];
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "32 bytes")
+    }
+
+    fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<[u8; 32], E> {
+        bytes
+            .try_into()
+            .map_err(|_| E::invalid_length(bytes.len(), &self))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<[u8; 32], A::Error> {
+        let mut bytes = [0; 32];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = seq
+                .next_element()?
+                .ok_or_else(|| de::Error::invalid_length(i, &self))?;
+        }
+        if seq.next_element::<u8>()?.is_some() {
+            return Err(de::Error::invalid_length(33, &self));
+        }
+        Ok(bytes)
+    }
+}
+
+// Compact targets as in Bitcoin's nBits: the size in bytes followed by the
+// three most significant bytes. The 0x00800000 bit is a sign and never set.
+impl U256 {
+    pub fn to_compact(&self) -> u32 {
+        let mut size = self.bits().div_ceil(8);
+        let mut compact = if size <= 3 {
+            self.low_u32() << (8 * (3 - size))
+        } else {
+            (*self >> (8 * (size - 3))).low_u32()
+        };
+        if compact & 0x0080_0000 != 0 {
+            compact >>= 8;
+            size += 1;
+        }
+        compact | ((size as u32) << 24)
+    }
+
+    pub fn from_compact(bits: u32) -> Self {
+        let size = bits >> 24;
+        let mantissa = bits & 0x007f_ffff;
+        if bits & 0x0080_0000 != 0 {
+            return U256::zero();
+        }
+        if size <= 3 {
+            U256::from(mantissa >> (8 * (3 - size)))
+        } else {
+            U256::from(mantissa) << (8 * (size as usize - 3))
+        }
+    }
+
+    // Expected number of hashes to find one at or below this target
+    pub fn work(&self) -> U256 {
+        if *self == U256::MAX {
+            return U256::one();
+        }
+        (!*self / (*self + 1)) + 1
+    }
+
+    // The closest target a compact encoding can express
+    pub fn round_to_compact(&self) -> Self {
+        U256::from_compact(self.to_compact())
+    }
+
+    // Lossy, for display
+    pub fn to_f64(&self) -> f64 {
+        self.0
+            .iter()
+            .rev()
+            .fold(0.0, |value, limb| value * 2f64.powi(64) + *limb as f64)
+    }
+
+    pub fn to_big_endian_bytes(&self) -> [u8; 32] {
+        let mut bytes = [0; 32];
+        self.to_big_endian(&mut bytes);
+        bytes
+    }
+
+    // All 64 digits, so targets line up in logs. Display stays decimal.
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.to_big_endian_bytes())
+    }
+
+    // Accepts an optional 0x prefix and any number of leading zeros
+    pub fn from_hex(hex: &str) -> Result<Self, uint::FromStrRadixErr> {
+        let hex = hex.strip_prefix("0x").unwrap_or(hex);
+        U256::from_str_radix(hex, 16)
+    }
+
+    // How many times harder than `min_target` this target is to meet
+    pub fn difficulty(&self, min_target: U256) -> f64 {
+        min_target.to_f64() / (*self).max(U256::one()).to_f64()
+    }
+
+    // The target `difficulty` times harder than `min_target`, never easier
+    pub fn from_difficulty(min_target: U256, difficulty: f64) -> Self {
+        if difficulty <= 1.0 {
+            return min_target;
+        }
+        // Scaling by a power of two keeps the division exact enough in f64
+        let shift = min_target.bits().saturating_sub(53);
+        let scaled = (min_target >> shift).low_u64() as f64 / difficulty;
+        (U256::from(scaled as u64) << shift).max(U256::one())
+    }
+}
+pub mod address;
+pub mod amount;
+pub mod block_filter;
+pub mod bloom;
 pub mod crypto;
+pub mod encoding;
 pub mod error;
 pub mod network;
+pub mod params;
+pub mod policy;
+pub mod rules;
+pub mod script;
 pub mod sha256;
+pub mod store;
 pub mod types;
 pub mod util;
 
+// Mainnet consensus defaults, see params::ChainParams
 pub const INITIAL_REWARD: u64 = 50;
 pub const HALVING_INTERVAL: u64 = 210;
 pub const IDEAL_BLOCK_TIME: u64 = 10;
@@ -23,4 +164,84 @@ pub const MIN_TARGET: U256 = U256([
 ]);
 pub const DIFFICULTY_UPDATE_INTERVAL: u64 = 50;
 pub const MAX_MEMPOOL_TRANSACTION_AGE: u64 = 600;
-pub const BLOCK_TRANSACTION_CAP: usize = 20;
+// Serialized bytes the mempool may hold before evicting low fee rates
+pub const MAX_MEMPOOL_SIZE: usize = 300_000_000;
+pub const MAX_MEMPOOL_TRANSACTIONS: usize = 100_000;
+// Fee rate a replacement has to add on top of what it replaces, sats per 1000 bytes
+pub const MIN_RBF_FEE_RATE_INCREMENT: u64 = 1_000;
+// Unconfirmed transactions a pending transaction may depend on
+pub const MAX_MEMPOOL_ANCESTORS: usize = 25;
+pub const MAX_MULTISIG_KEYS: usize = 16;
+pub const MAX_BLOCK_SIZE: usize = 1_000_000;
+// Signature checks a block may require, see Script::sigops
+pub const MAX_BLOCK_SIGOPS: usize = 20_000;
+// Room kept free for the header and coinbase when assembling templates
+pub const BLOCK_TEMPLATE_RESERVED_SIZE: usize = 1_000;
+pub const COINBASE_MATURITY: u64 = 100;
+// Bumped whenever a consensus change invalidates previously saved chains
+pub const CHAIN_FORMAT_VERSION: u32 = 13;
+// Blocks per independently decodable segment of the blockchain file
+pub const CHAIN_FILE_SEGMENT_SIZE: usize = 1_000;
+pub const MEDIAN_TIME_SPAN: usize = 11;
+pub const MAX_FUTURE_BLOCK_TIME: i64 = 2 * 60 * 60;
+// Number of recent blocks the rolling chain statistics cover
+pub const CHAIN_STATS_WINDOW: usize = 100;
+// Number of most recent blocks the short term block interval covers
+pub const CHAIN_STATS_SHORT_WINDOW: usize = 10;
+// Recently confirmed transactions fee estimates are drawn from
+pub const FEE_ESTIMATE_SAMPLES: usize = 10_000;
+// Fewer samples paying at least a fee rate give no estimate for it
+pub const FEE_ESTIMATE_MIN_SAMPLES: u64 = 20;
+// Share of transactions that must have confirmed within the target
+pub const FEE_ESTIMATE_SUCCESS_PERCENT: u64 = 85;
+// Transactions per page of an address history
+pub const HISTORY_PAGE_SIZE: usize = 50;
+// Most headers and UTXO proofs answered in one message
+pub const MAX_HEADERS_PER_MESSAGE: u32 = 2_000;
+pub const MAX_UTXO_PROOFS_PER_MESSAGE: usize = 1_000;
+// Hashes a block locator may carry, ample for chains of 2^64 blocks
+pub const MAX_LOCATOR_HASHES: usize = 101;
+// Largest bloom filter a node accepts, in bytes, and most hash functions
+pub const MAX_BLOOM_FILTER_SIZE: usize = 36_000;
+pub const MAX_BLOOM_HASH_FUNCS: u32 = 50;
+// Compact block filters answered in one message
+pub const MAX_BLOCK_FILTERS_PER_MESSAGE: u32 = 1_000;
+// Defaults of the relay policy, see policy::RelayPolicy
+pub const DUST_LIMIT: u64 = 546;
+pub const MAX_STANDARD_TRANSACTION_SIZE: usize = 100_000;
+pub const MAX_STANDARD_INPUTS: usize = 500;
+pub const MAX_STANDARD_OUTPUTS: usize = 500;
+// Satoshis per 1000 serialized bytes
+pub const MIN_RELAY_FEE_RATE: u64 = 1_000;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compact_bits_round_trip() {
+        // Bitcoin's genesis target and the target of block 100_000
+        let genesis = U256::from(0xffff) << 208;
+        assert_eq!(U256::from_compact(0x1d00_ffff), genesis);
+        assert_eq!(genesis.to_compact(), 0x1d00_ffff);
+        assert_eq!(U256::from_compact(0x1b04_04cb), U256::from(0x0404cb) << 192);
+        for bits in [
+            0x1d00_ffff,
+            0x1b04_04cb,
+            0x0112_0000,
+            0x0200_8000,
+            0x2100_ffff,
+        ] {
+            assert_eq!(U256::from_compact(bits).to_compact(), bits);
+        }
+        // A mantissa with its top bit set moves a byte up instead
+        assert_eq!(U256::from(0x80).to_compact(), 0x0200_8000);
+        assert_eq!(U256::MAX.to_compact(), 0x2100_ffff);
+        // Rounding drops the bytes past the mantissa, never rounds up
+        let target = U256::from_compact(0x1d00_ffff) + 1;
+        assert_eq!(target.round_to_compact(), genesis);
+        assert_eq!(genesis.round_to_compact(), genesis);
+        // The sign bit encodes no valid target
+        assert_eq!(U256::from_compact(0x1d80_ffff), U256::zero());
+    }
+}
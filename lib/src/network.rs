@@ -1,13 +1,163 @@
+use crate::block_filter::BlockFilter;
+use crate::bloom::BloomFilter;
 use crate::crypto::PublicKey;
-use crate::types::{Block, Transaction, TransactionOutput};
+use crate::error::BtcError;
+use crate::params::ChainParams;
+use crate::sha256::Hash;
+use crate::types::{
+    Block, BlockHeader, BlockHeight, ChainStats, Transaction, TransactionOutput, TxIndex,
+    TxLocation,
+};
+use crate::util::MerkleProof;
+use crate::U256;
 use serde::{Deserialize, Serialize};
-use std::io::{Error as IoError, Read, Write};
+use std::fmt;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Write};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
+pub const PROTOCOL_VERSION: u32 = 3;
+// Oldest peer protocol this node can still talk to, version 3 encodes hashes
+// as 32 byte strings
+pub const MIN_PROTOCOL_VERSION: u32 = 3;
+// Largest single frame accepted from a peer
+pub const MAX_MESSAGE_SIZE: usize = 4 * crate::MAX_BLOCK_SIZE;
+
+// Optional protocol features as a bitfield, bits unknown to this
+// version are carried along but never acted upon
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Capabilities(pub u64);
+
+impl Capabilities {
+    pub const COMPRESSION: Capabilities = Capabilities(1 << 0);
+    pub const COMPACT_BLOCKS: Capabilities = Capabilities(1 << 1);
+    pub const SUBSCRIPTIONS: Capabilities = Capabilities(1 << 2);
+    pub const PACKAGE_RELAY: Capabilities = Capabilities(1 << 3);
+
+    // Features implemented by this version
+    pub const SUPPORTED: Capabilities = Capabilities::SUBSCRIPTIONS;
+
+    pub fn contains(&self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn with(self, other: Capabilities) -> Self {
+        Capabilities(self.0 | other.0)
+    }
+
+    pub fn intersection(self, other: Capabilities) -> Self {
+        Capabilities(self.0 & other.0)
+    }
+}
+
+pub fn is_compatible_version(version: u32) -> bool {
+    version >= MIN_PROTOCOL_VERSION
+}
+
+// The consensus rule a submitted block broke
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub enum RejectReason {
+    StaleParent,
+    InsufficientWork,
+    BadTarget,
+    BadTimestamp,
+    BadMerkleRoot,
+    BadCoinbase,
+    InvalidTransaction { index: TxIndex, reason: String },
+    Other(String),
+}
+
+impl From<&BtcError> for RejectReason {
+    fn from(error: &BtcError) -> Self {
+        match error {
+            BtcError::StaleParent => RejectReason::StaleParent,
+            BtcError::InsufficientWork => RejectReason::InsufficientWork,
+            BtcError::UnexpectedTarget { .. } => RejectReason::BadTarget,
+            BtcError::TimestampTooOld { .. } | BtcError::TimestampTooNew { .. } => {
+                RejectReason::BadTimestamp
+            }
+            BtcError::InvalidMerkleRoot => RejectReason::BadMerkleRoot,
+            BtcError::InvalidCoinbase
+            | BtcError::WrongCoinbaseHeight { .. }
+            | BtcError::WrongCoinbaseValue { .. } => RejectReason::BadCoinbase,
+            BtcError::InvalidTransactionAt { index, reason } => RejectReason::InvalidTransaction {
+                index: *index,
+                reason: reason.to_string(),
+            },
+            error => RejectReason::Other(error.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RejectReason::StaleParent => write!(f, "block does not extend the current tip"),
+            RejectReason::InsufficientWork => write!(f, "hash does not meet the target"),
+            RejectReason::BadTarget => write!(f, "target differs from the expected one"),
+            RejectReason::BadTimestamp => write!(f, "timestamp out of range"),
+            RejectReason::BadMerkleRoot => write!(f, "merkle root does not match"),
+            RejectReason::BadCoinbase => write!(f, "coinbase height or value is wrong"),
+            RejectReason::InvalidTransaction { index, reason } => {
+                write!(f, "transaction {} is invalid: {}", index, reason)
+            }
+            RejectReason::Other(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+// An unspent output with the transaction that created it and the proof that
+// transaction is in the block at `height`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct UtxoProof {
+    pub outpoint: Hash,
+    pub height: BlockHeight,
+    pub transaction: Transaction,
+    pub proof: MerkleProof,
+}
+
+impl UtxoProof {
+    pub fn output(&self) -> Option<&TransactionOutput> {
+        (0..self.transaction.outputs.len())
+            .find(|index| self.transaction.output_hash(*index) == self.outpoint)
+            .map(|index| &self.transaction.outputs[index])
+    }
+
+    // Against the header of the block at `height`, validated by the caller
+    pub fn verify(&self, header: &BlockHeader) -> bool {
+        self.output().is_some()
+            && MerkleProof::verify(&header.merkle_root, self.transaction.wtxid(), &self.proof)
+    }
+}
+
+// A block with only the transactions matching the filter of the client
+// that asked, each with its inclusion proof
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct FilteredBlock {
+    pub height: BlockHeight,
+    pub header: BlockHeader,
+    pub transactions: Vec<(Transaction, MerkleProof)>,
+}
+
+impl FilteredBlock {
+    pub fn verify(&self) -> bool {
+        self.transactions.iter().all(|(transaction, proof)| {
+            MerkleProof::verify(&self.header.merkle_root, transaction.wtxid(), proof)
+        })
+    }
+}
+
+// A confirmed transaction found by the txindex, without the transaction
+// if its block was pruned
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct IndexedTransaction {
+    pub location: TxLocation,
+    pub transaction: Option<Transaction>,
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub enum Message {
     FetchUTXOs(PublicKey),
-    UTXOs(Vec<(TransactionOutput, bool)>),
+    UTXOs(Vec<(Hash, TransactionOutput, bool)>),
     SubmitTransaction(Transaction),
     NewTransaction(Transaction),
     FetchTemplate(PublicKey),
@@ -19,8 +169,106 @@ pub enum Message {
     NodeList(Vec<String>),
     AskDifference(u32),
     Difference(i32),
-    FetchBlock(usize),
+    FetchBlock(BlockHeight),
     NewBlock(Block),
+    Authenticate(String),
+    Authenticated(bool),
+    Unauthorized,
+    NotReady {
+        progress: u8,
+    },
+    TransactionAccepted(bool),
+    Hello {
+        version: u32,
+        capabilities: Capabilities,
+    },
+    // Carries the capabilities both sides support
+    HelloAck {
+        version: u32,
+        capabilities: Capabilities,
+    },
+    Incompatible {
+        min_version: u32,
+    },
+    // Asks the node to push TipChanged whenever a block is connected
+    SubscribeTip,
+    TipChanged {
+        height: BlockHeight,
+        hash: Hash,
+    },
+    // Lets miners check they work on the network they were started for
+    FetchChainParams,
+    ChainParams(ChainParams),
+    // Reply to SubmitTemplate
+    BlockAccepted(Hash),
+    Reject(RejectReason),
+    // Fork choice compares cumulative work rather than height
+    AskWork,
+    // `height` is the number of blocks, one more than the tip height
+    Work {
+        work: U256,
+        height: u64,
+    },
+    FetchHistory {
+        pubkey: PublicKey,
+        page: u32,
+    },
+    // Txids with the height of their block, newest first
+    History(Vec<(Hash, BlockHeight)>),
+    // Frames of a streamed NewBlock, receive_async never returns them
+    BlockStream {
+        header: BlockHeader,
+        transactions: u64,
+    },
+    StreamedTransaction(Transaction),
+    // The node is busy validating blocks, retry the request later
+    Busy,
+    // Fee rate in sats per 1000 bytes likely to confirm within the target
+    // number of blocks, None while the node lacks the samples to tell
+    FetchFeeEstimate {
+        confirmation_target: u64,
+    },
+    FeeEstimate(Option<u64>),
+    // Tips of blocks with valid proof of work that do not extend the node's
+    // chain, highest first
+    FetchCompetingTips,
+    CompetingTips(Vec<(BlockHeight, Hash)>),
+    // Up to MAX_HEADERS_PER_MESSAGE headers from `start` on
+    FetchHeaders {
+        start: BlockHeight,
+        count: u32,
+    },
+    Headers(Vec<BlockHeader>),
+    // Asks for the common ancestor of the asker's chain, given by a block
+    // locator, and ours. Fetching headers or blocks after it stays on our
+    // chain even where theirs forked off.
+    FetchForkPoint(Vec<Hash>),
+    ForkPoint(Option<BlockHeight>),
+    // Proofs for up to MAX_UTXO_PROOFS_PER_MESSAGE outputs, those that are
+    // not unspent are left out
+    FetchUtxoProofs(Vec<Hash>),
+    UtxoProofs(Vec<UtxoProof>),
+    // Sets the filter blocks fetched on this connection are matched against.
+    // It grows by the outputs of matching transactions.
+    FilterLoad(BloomFilter),
+    FilterClear,
+    // The block at a height filtered down, None past the tip. Without a
+    // filter loaded no transaction matches.
+    FetchMerkleBlock(BlockHeight),
+    MerkleBlock(Option<FilteredBlock>),
+    // Up to MAX_BLOCK_FILTERS_PER_MESSAGE compact filters from `start` on,
+    // each with the hash of its block, which keys it
+    FetchBlockFilters {
+        start: BlockHeight,
+        count: u32,
+    },
+    BlockFilters(Vec<(Hash, BlockFilter)>),
+    // None for unconfirmed or unknown txids and from nodes without --txindex
+    FetchTransaction(Hash),
+    TransactionInfo(Option<IndexedTransaction>),
+    // Chain and mempool summary for status displays
+    FetchStats,
+    Stats(ChainStats),
 }
 
 impl Message {
@@ -35,39 +283,102 @@ impl Message {
     }
     pub fn send(&self, stream: &mut impl Write) -> Result<(), ciborium::ser::Error<IoError>> {
         let bytes = self.encode()?;
-        let len = bytes.len();
+        let len = bytes.len() as u64;
         stream.write_all(&len.to_be_bytes())?;
         stream.write_all(&bytes)?;
         Ok(())
     }
 
+    // Blocks go out as a BlockStream frame followed by one frame per
+    // transaction, so the encoded block is never buffered as a whole
     pub async fn send_async(
         &self,
         stream: &mut (impl AsyncWrite + Unpin),
     ) -> Result<(), ciborium::ser::Error<IoError>> {
-        let bytes = self.encode()?;
-        let len = bytes.len() as u64;
-        stream.write_all(&len.to_be_bytes()).await?;
-        stream.write_all(&bytes).await?;
+        let Message::NewBlock(block) = self else {
+            return write_frame_async(stream, &self.encode()?).await;
+        };
+        let start = Message::BlockStream {
+            header: block.header.clone(),
+            transactions: block.transactions.len() as u64,
+        };
+        write_frame_async(stream, &start.encode()?).await?;
+        for transaction in &block.transactions {
+            let frame = Message::StreamedTransaction(transaction.clone()).encode()?;
+            write_frame_async(stream, &frame).await?;
+        }
         Ok(())
     }
 
     pub fn receive(stream: &mut impl Read) -> Result<Self, ciborium::de::Error<IoError>> {
         let mut len_bytes = [0u8; 8];
         stream.read_exact(&mut len_bytes)?;
-        let len = u64::from_be_bytes(len_bytes) as usize;
+        let len = frame_len(len_bytes)?;
         let mut data = vec![0u8; len];
         stream.read_exact(&mut data)?;
         Self::decode(&data)
     }
+
+    // Reassembles streamed blocks frame by frame, bounded by MAX_BLOCK_SIZE
     pub async fn receive_async(
         stream: &mut (impl AsyncRead + Unpin),
     ) -> Result<Self, ciborium::de::Error<IoError>> {
-        let mut len_bytes = [0u8; 8];
-        stream.read_exact(&mut len_bytes).await?;
-        let len = u64::from_be_bytes(len_bytes) as usize;
-        let mut data = vec![0u8; len];
-        stream.read_exact(&mut data).await?;
-        Self::decode(&data)
+        let message = Self::decode(&read_frame_async(stream).await?)?;
+        let Message::BlockStream {
+            header,
+            transactions: count,
+        } = message
+        else {
+            return Ok(message);
+        };
+        let mut size = 0;
+        let mut transactions = Vec::new();
+        for _ in 0..count {
+            let frame = read_frame_async(stream).await?;
+            size += frame.len();
+            if size > crate::MAX_BLOCK_SIZE {
+                return Err(invalid_data(
+                    "streamed block exceeds the maximum block size",
+                ));
+            }
+            match Self::decode(&frame)? {
+                Message::StreamedTransaction(transaction) => transactions.push(transaction),
+                _ => return Err(invalid_data("unexpected message inside a streamed block")),
+            }
+        }
+        Ok(Message::NewBlock(Block::new(header, transactions)))
+    }
+}
+
+fn frame_len(len_bytes: [u8; 8]) -> Result<usize, ciborium::de::Error<IoError>> {
+    let len = u64::from_be_bytes(len_bytes) as usize;
+    if len > MAX_MESSAGE_SIZE {
+        return Err(invalid_data("message exceeds the maximum message size"));
     }
+    Ok(len)
+}
+
+async fn read_frame_async(
+    stream: &mut (impl AsyncRead + Unpin),
+) -> Result<Vec<u8>, ciborium::de::Error<IoError>> {
+    let mut len_bytes = [0u8; 8];
+    stream.read_exact(&mut len_bytes).await?;
+    let mut data = vec![0u8; frame_len(len_bytes)?];
+    stream.read_exact(&mut data).await?;
+    Ok(data)
+}
+
+async fn write_frame_async(
+    stream: &mut (impl AsyncWrite + Unpin),
+    bytes: &[u8],
+) -> Result<(), ciborium::ser::Error<IoError>> {
+    stream
+        .write_all(&(bytes.len() as u64).to_be_bytes())
+        .await?;
+    stream.write_all(bytes).await?;
+    Ok(())
+}
+
+fn invalid_data(message: &str) -> ciborium::de::Error<IoError> {
+    ciborium::de::Error::Io(IoError::new(IoErrorKind::InvalidData, message))
 }
@@ -0,0 +1,151 @@
+use crate::amount::Amount;
+use crate::sha256::Hash;
+use crate::util::Saveable;
+use crate::U256;
+use serde::{Deserialize, Serialize};
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write};
+
+// Consensus rules of a network. Nodes and miners only cooperate on chains
+// built with the same parameters.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ChainParams {
+    pub name: String,
+    // Whole coins paid by the first coinbase
+    pub initial_reward: u64,
+    pub halving_interval: u64,
+    // Seconds
+    pub ideal_block_time: u64,
+    pub min_target: U256,
+    pub difficulty_update_interval: u64,
+    pub coinbase_maturity: u64,
+    // Unix timestamp of the genesis block, which is otherwise fully derived
+    // from these parameters
+    pub genesis_timestamp: i64,
+    // Blocks every node must have at these heights
+    #[serde(default)]
+    pub checkpoints: Vec<(u64, Hash)>,
+    // Signatures of blocks below this height are not checked. Structure,
+    // proof of work and amounts still are. It may not exceed the last
+    // checkpoint, which pins the branch those blocks are on.
+    #[serde(default)]
+    pub assume_valid: u64,
+    // First byte of private keys in wallet import format, the values Bitcoin
+    // Core uses so keys move between the two
+    #[serde(default = "default_wif_prefix")]
+    pub wif_prefix: u8,
+}
+
+fn default_wif_prefix() -> u8 {
+    0x80
+}
+
+impl ChainParams {
+    pub fn mainnet() -> Self {
+        ChainParams {
+            name: "mainnet".to_string(),
+            initial_reward: crate::INITIAL_REWARD,
+            halving_interval: crate::HALVING_INTERVAL,
+            ideal_block_time: crate::IDEAL_BLOCK_TIME,
+            min_target: crate::MIN_TARGET,
+            difficulty_update_interval: crate::DIFFICULTY_UPDATE_INTERVAL,
+            coinbase_maturity: crate::COINBASE_MATURITY,
+            genesis_timestamp: 1_700_000_000,
+            checkpoints: Vec::new(),
+            assume_valid: 0,
+            wif_prefix: default_wif_prefix(),
+        }
+    }
+
+    pub fn testnet() -> Self {
+        ChainParams {
+            name: "testnet".to_string(),
+            coinbase_maturity: 10,
+            genesis_timestamp: 1_710_000_000,
+            wif_prefix: 0xef,
+            ..Self::mainnet()
+        }
+    }
+
+    // Easy blocks spendable right away, for local testing
+    pub fn regtest() -> Self {
+        ChainParams {
+            name: "regtest".to_string(),
+            halving_interval: 150,
+            ideal_block_time: 1,
+            difficulty_update_interval: 10,
+            coinbase_maturity: 1,
+            genesis_timestamp: 1_720_000_000,
+            wif_prefix: 0xef,
+            ..Self::mainnet()
+        }
+    }
+
+    pub fn preset(name: &str) -> Option<Self> {
+        match name {
+            "mainnet" => Some(Self::mainnet()),
+            "testnet" => Some(Self::testnet()),
+            "regtest" => Some(Self::regtest()),
+            _ => None,
+        }
+    }
+
+    pub fn checkpoint(&self, height: u64) -> Option<Hash> {
+        self.checkpoints
+            .iter()
+            .find(|(checkpoint_height, _)| *checkpoint_height == height)
+            .map(|(_, hash)| *hash)
+    }
+
+    pub fn verifies_signatures(&self, height: u64) -> bool {
+        height >= self.assume_valid
+    }
+
+    // Refuses parameters skipping signatures on a branch no checkpoint pins
+    pub fn check_assume_valid(&self) -> IoResult<()> {
+        let last_checkpoint = self.checkpoints.iter().map(|(height, _)| *height).max();
+        if self.assume_valid > last_checkpoint.unwrap_or(0) {
+            return Err(IoError::new(
+                IoErrorKind::InvalidData,
+                format!(
+                    "assume_valid {} lies beyond the last checkpoint",
+                    self.assume_valid
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    // Newly created coins in the coinbase at `height`. Shifting a u64 by 64
+    // or more overflows, by then every satoshi has been halved away anyway.
+    pub fn block_subsidy(&self, height: u64) -> Amount {
+        let halvings = height / self.halving_interval;
+        if halvings >= 64 {
+            return Amount::ZERO;
+        }
+        let initial = self.initial_reward.saturating_mul(Amount::SATS_PER_BTC);
+        Amount::from_sat(initial >> halvings)
+    }
+}
+
+impl Default for ChainParams {
+    fn default() -> Self {
+        Self::mainnet()
+    }
+}
+
+impl Saveable for ChainParams {
+    fn load<I: Read>(reader: I) -> IoResult<Self> {
+        let params: ChainParams = ciborium::de::from_reader(reader).map_err(|_| {
+            IoError::new(
+                IoErrorKind::InvalidData,
+                "Failed to deserialize ChainParams",
+            )
+        })?;
+        params.check_assume_valid()?;
+        Ok(params)
+    }
+    fn save<O: Write>(&self, writer: O) -> IoResult<()> {
+        ciborium::ser::into_writer(self, writer)
+            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to serialize ChainParams"))
+    }
+}
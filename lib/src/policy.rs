@@ -0,0 +1,120 @@
+use crate::amount::Amount;
+use crate::error::{BtcError, Result};
+use crate::types::Transaction;
+use serde::{Deserialize, Serialize};
+
+// Standardness rules nodes apply before accepting or relaying a loose
+// transaction. They are not consensus, blocks breaking them stay valid.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct RelayPolicy {
+    // Outputs below this are not worth the cost of spending them
+    pub dust_limit: Amount,
+    // Serialized bytes
+    pub max_transaction_size: usize,
+    pub max_inputs: usize,
+    pub max_outputs: usize,
+    // Satoshis per 1000 serialized bytes
+    pub min_fee_rate: u64,
+}
+
+impl RelayPolicy {
+    // `size` is the serialized size, which the mempool computes once
+    pub fn check(&self, transaction: &Transaction, size: usize) -> Result<()> {
+        if transaction.coinbase_height.is_some() {
+            return Err(BtcError::NonStandard("coinbase outside a block"));
+        }
+        if transaction.inputs.is_empty() {
+            return Err(BtcError::NonStandard("no inputs"));
+        }
+        if transaction.outputs.is_empty() {
+            return Err(BtcError::NonStandard("no outputs"));
+        }
+        if transaction.inputs.len() > self.max_inputs {
+            return Err(BtcError::NonStandard("too many inputs"));
+        }
+        if transaction.outputs.len() > self.max_outputs {
+            return Err(BtcError::NonStandard("too many outputs"));
+        }
+        if transaction
+            .outputs
+            .iter()
+            .any(|output| output.value < self.dust_limit)
+        {
+            return Err(BtcError::NonStandard("dust output"));
+        }
+        if size > self.max_transaction_size {
+            return Err(BtcError::NonStandard("transaction too large"));
+        }
+        Ok(())
+    }
+
+    // Checked once the fee is known, which takes the spent outputs
+    pub fn check_fee_rate(&self, fee_rate: u64) -> Result<()> {
+        if fee_rate < self.min_fee_rate {
+            return Err(BtcError::NonStandard("fee rate below the relay minimum"));
+        }
+        Ok(())
+    }
+}
+
+impl Default for RelayPolicy {
+    fn default() -> Self {
+        RelayPolicy {
+            dust_limit: Amount::from_sat(crate::DUST_LIMIT),
+            max_transaction_size: crate::MAX_STANDARD_TRANSACTION_SIZE,
+            max_inputs: crate::MAX_STANDARD_INPUTS,
+            max_outputs: crate::MAX_STANDARD_OUTPUTS,
+            min_fee_rate: crate::MIN_RELAY_FEE_RATE,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::PrivateKey;
+    use crate::script::{Script, Witness};
+    use crate::sha256::Hash;
+    use crate::types::{TransactionInput, TransactionOutput};
+
+    fn transaction(inputs: usize, outputs: usize) -> Transaction {
+        let input = TransactionInput {
+            prev_transaction_output_hash: Hash::zero(),
+            witness: Witness::default(),
+            sequence: 0,
+        };
+        let output = TransactionOutput {
+            value: Amount::from_sat(crate::DUST_LIMIT),
+            script: Script::PayToPubkey(PrivateKey::from_bytes(&[1; 32]).unwrap().public_key()),
+        };
+        Transaction::new(vec![input; inputs], vec![output; outputs])
+    }
+
+    #[test]
+    fn accepts_standard_transaction() {
+        let transaction = transaction(1, 1);
+        let size = transaction.serialized_size();
+        assert!(RelayPolicy::default().check(&transaction, size).is_ok());
+    }
+
+    #[test]
+    fn rejects_empty_inputs_or_outputs() {
+        let policy = RelayPolicy::default();
+        for transaction in [transaction(0, 1), transaction(1, 0)] {
+            let size = transaction.serialized_size();
+            assert!(matches!(
+                policy.check(&transaction, size),
+                Err(BtcError::NonStandard(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn fee_rate_boundary() {
+        let policy = RelayPolicy::default();
+        assert!(policy.check_fee_rate(crate::MIN_RELAY_FEE_RATE).is_ok());
+        assert!(policy
+            .check_fee_rate(crate::MIN_RELAY_FEE_RATE - 1)
+            .is_err());
+    }
+}
@@ -0,0 +1,54 @@
+// Marks the consensus check that follows it. Expands to nothing, rules_gen
+// collects the annotations into an auditable list of rules.
+#[macro_export]
+macro_rules! consensus_rule {
+    ($id:literal, $description:literal) => {};
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rule {
+    pub id: String,
+    pub description: String,
+    // Where the check lives, as file:line
+    pub location: String,
+}
+
+// Validation sources scanned for annotations, embedded at build time
+const SOURCES: &[(&str, &str)] = &[
+    ("lib/src/types/block.rs", include_str!("types/block.rs")),
+    (
+        "lib/src/types/blockchain.rs",
+        include_str!("types/blockchain.rs"),
+    ),
+];
+
+const ANNOTATION: &str = "consensus_rule!(";
+
+// Every annotated rule in source order
+pub fn rules() -> Vec<Rule> {
+    SOURCES
+        .iter()
+        .flat_map(|(file, source)| parse(file, source))
+        .collect()
+}
+
+fn parse(file: &str, source: &str) -> Vec<Rule> {
+    source
+        .match_indices(ANNOTATION)
+        .filter_map(|(start, _)| {
+            let args = &source[start + ANNOTATION.len()..];
+            let args = &args[..args.find(");")?];
+            // Arguments are two string literals without escaped quotes
+            let literals = args.split('"').skip(1).step_by(2).collect::<Vec<_>>();
+            let [id, description] = literals[..] else {
+                return None;
+            };
+            let line = source[..start].matches('\n').count() + 1;
+            Some(Rule {
+                id: id.to_string(),
+                description: description.to_string(),
+                location: format!("{}:{}", file, line),
+            })
+        })
+        .collect()
+}
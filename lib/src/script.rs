@@ -0,0 +1,297 @@
+use crate::crypto::{PublicKey, SigHash, Signature, SignatureScheme};
+use crate::encoding::Encode;
+use crate::error::{BtcError, Result};
+use crate::sha256::Hash;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum Script {
+    PayToPubkey(PublicKey),
+    PayToPubkeyHash(Hash),
+    Multisig {
+        threshold: usize,
+        pubkeys: Vec<PublicKey>,
+    },
+    // Requires the SHA-256 preimage of `hash` and a signature of `pubkey`
+    HashLock {
+        hash: Hash,
+        pubkey: PublicKey,
+    },
+    // Spendable by `pubkey` from block `height` on
+    TimeLock {
+        height: u64,
+        pubkey: PublicKey,
+    },
+    // Claimed by `recipient` with the SHA-256 preimage of `hash`,
+    // or by `refund` once block `timeout` is reached
+    Htlc {
+        hash: Hash,
+        recipient: PublicKey,
+        refund: PublicKey,
+        timeout: u64,
+    },
+    // Like PayToPubkey, signed with a Schnorr signature
+    PayToSchnorrPubkey(PublicKey),
+}
+
+impl Encode for Script {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Script::PayToPubkey(pubkey) => (0u8, pubkey).encode(out),
+            Script::PayToPubkeyHash(hash) => (1u8, hash).encode(out),
+            Script::Multisig { threshold, pubkeys } => (2u8, threshold, pubkeys).encode(out),
+            Script::HashLock { hash, pubkey } => (3u8, hash, pubkey).encode(out),
+            Script::TimeLock { height, pubkey } => (4u8, height, pubkey).encode(out),
+            Script::Htlc {
+                hash,
+                recipient,
+                refund,
+                timeout,
+            } => (5u8, hash, recipient, refund, timeout).encode(out),
+            Script::PayToSchnorrPubkey(pubkey) => (6u8, pubkey).encode(out),
+        }
+    }
+}
+
+// Data provided by an input to satisfy the script of the output it spends
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Witness {
+    pub signatures: Vec<Signature>,
+    pub pubkey: Option<PublicKey>,
+    pub preimage: Option<Vec<u8>>,
+    #[serde(default)]
+    pub sighash: SigHash,
+}
+
+impl Witness {
+    pub fn signature(signature: Signature) -> Self {
+        Witness {
+            signatures: vec![signature],
+            ..Default::default()
+        }
+    }
+
+    pub fn with_pubkey(mut self, pubkey: PublicKey) -> Self {
+        self.pubkey = Some(pubkey);
+        self
+    }
+
+    pub fn with_preimage(mut self, preimage: Vec<u8>) -> Self {
+        self.preimage = Some(preimage);
+        self
+    }
+
+    pub fn with_sighash(mut self, sighash: SigHash) -> Self {
+        self.sighash = sighash;
+        self
+    }
+}
+
+impl Encode for Witness {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (&self.signatures, &self.pubkey, &self.preimage, self.sighash).encode(out);
+    }
+}
+
+impl Script {
+    pub fn pubkey_hash(pubkey: &PublicKey) -> Hash {
+        pubkey.encoded_hash()
+    }
+
+    pub fn multisig(threshold: usize, pubkeys: Vec<PublicKey>) -> Result<Self> {
+        let script = Script::Multisig { threshold, pubkeys };
+        if !script.is_well_formed() {
+            return Err(BtcError::InvalidScript);
+        }
+        Ok(script)
+    }
+
+    // Structural rules every output script has to follow
+    pub fn is_well_formed(&self) -> bool {
+        match self {
+            Script::Multisig { threshold, pubkeys } => {
+                let distinct = pubkeys.iter().collect::<BTreeSet<_>>().len() == pubkeys.len();
+                *threshold >= 1
+                    && *threshold <= pubkeys.len()
+                    && pubkeys.len() <= crate::MAX_MULTISIG_KEYS
+                    && distinct
+            }
+            _ => true,
+        }
+    }
+
+    // Whether height based conditions allow spending at `spending_height`
+    pub fn is_unlocked(&self, spending_height: u64) -> bool {
+        match self {
+            Script::TimeLock { height, .. } => spending_height >= *height,
+            _ => true,
+        }
+    }
+
+    // Whether the key takes part in spending this output
+    pub fn references(&self, key: &PublicKey) -> bool {
+        match self {
+            Script::PayToPubkey(pubkey)
+            | Script::PayToSchnorrPubkey(pubkey)
+            | Script::HashLock { pubkey, .. }
+            | Script::TimeLock { pubkey, .. } => pubkey == key,
+            Script::PayToPubkeyHash(hash) => Self::pubkey_hash(key) == *hash,
+            Script::Multisig { pubkeys, .. } => pubkeys.contains(key),
+            Script::Htlc {
+                recipient, refund, ..
+            } => recipient == key || refund == key,
+        }
+    }
+
+    // Hashes of all keys the output references, the way addresses index it
+    pub fn pubkey_hashes(&self) -> Vec<Hash> {
+        match self {
+            Script::PayToPubkey(pubkey)
+            | Script::PayToSchnorrPubkey(pubkey)
+            | Script::HashLock { pubkey, .. }
+            | Script::TimeLock { pubkey, .. } => vec![Self::pubkey_hash(pubkey)],
+            Script::PayToPubkeyHash(hash) => vec![*hash],
+            Script::Multisig { pubkeys, .. } => pubkeys.iter().map(Self::pubkey_hash).collect(),
+            Script::Htlc {
+                recipient, refund, ..
+            } => vec![Self::pubkey_hash(recipient), Self::pubkey_hash(refund)],
+        }
+    }
+
+    // The only kind of signature spending the output may use
+    pub fn scheme(&self) -> SignatureScheme {
+        match self {
+            Script::PayToSchnorrPubkey(_) => SignatureScheme::Schnorr,
+            _ => SignatureScheme::Ecdsa,
+        }
+    }
+
+    // Upper bound on the signature checks verify makes for this witness
+    pub fn sigops(&self, witness: &Witness) -> usize {
+        match self {
+            Script::Multisig { pubkeys, .. } => {
+                witness.signatures.len().saturating_mul(pubkeys.len())
+            }
+            _ => 1,
+        }
+    }
+
+    pub fn verify(&self, witness: &Witness, message: &Hash, spending_height: u64) -> bool {
+        if witness
+            .signatures
+            .iter()
+            .any(|signature| signature.scheme() != self.scheme())
+        {
+            return false;
+        }
+        match self {
+            Script::Multisig { threshold, pubkeys } => {
+                if !self.is_well_formed() {
+                    return false;
+                }
+                // Each key can account for at most one signature
+                let mut used = vec![false; pubkeys.len()];
+                let mut valid = 0;
+                for signature in &witness.signatures {
+                    let signer = (0..pubkeys.len())
+                        .find(|&idx| !used[idx] && signature.verify(message, &pubkeys[idx]));
+                    if let Some(idx) = signer {
+                        used[idx] = true;
+                        valid += 1;
+                    }
+                }
+                valid >= *threshold
+            }
+            _ => match (
+                self.signer(witness, spending_height),
+                witness.signatures.as_slice(),
+            ) {
+                (Some(pubkey), [signature]) => signature.verify(message, pubkey),
+                _ => false,
+            },
+        }
+    }
+
+    // The key the single signature of a witness has to verify against once
+    // every other condition of the script holds, so the signature can be
+    // checked in a batch. None for multisig and when another condition fails.
+    pub fn signer<'a>(
+        &'a self,
+        witness: &'a Witness,
+        spending_height: u64,
+    ) -> Option<&'a PublicKey> {
+        match self {
+            Script::PayToPubkey(pubkey) | Script::PayToSchnorrPubkey(pubkey) => Some(pubkey),
+            Script::PayToPubkeyHash(hash) => witness
+                .pubkey
+                .as_ref()
+                .filter(|pubkey| Self::pubkey_hash(pubkey) == *hash),
+            Script::Multisig { .. } => None,
+            Script::HashLock { hash, pubkey } => witness
+                .preimage
+                .as_ref()
+                .is_some_and(|preimage| Hash::hash_bytes(preimage) == *hash)
+                .then_some(pubkey),
+            Script::TimeLock { height, pubkey } => (spending_height >= *height).then_some(pubkey),
+            Script::Htlc {
+                hash,
+                recipient,
+                refund,
+                timeout,
+            } => match &witness.preimage {
+                Some(preimage) => (Hash::hash_bytes(preimage) == *hash).then_some(recipient),
+                None => (spending_height >= *timeout).then_some(refund),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::PrivateKey;
+
+    // SHA-256 of "hello", as any other chain computes it
+    const HELLO_SHA256: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    fn hello_hash() -> Hash {
+        let hash = Hash::hash_bytes(b"hello");
+        assert_eq!(hash.to_string(), HELLO_SHA256);
+        hash
+    }
+
+    fn signed_witness(key: &PrivateKey, message: &Hash, preimage: &[u8]) -> Witness {
+        Witness::signature(key.sign(message)).with_preimage(preimage.to_vec())
+    }
+
+    #[test]
+    fn hash_lock_spends_with_sha256_preimage() {
+        let key = PrivateKey::generate();
+        let message = Hash::hash_bytes(b"spending transaction");
+        let script = Script::HashLock {
+            hash: hello_hash(),
+            pubkey: key.public_key(),
+        };
+        assert!(script.verify(&signed_witness(&key, &message, b"hello"), &message, 0));
+        assert!(!script.verify(&signed_witness(&key, &message, b"hellO"), &message, 0));
+    }
+
+    #[test]
+    fn htlc_claims_with_sha256_preimage_and_refunds_after_timeout() {
+        let recipient = PrivateKey::generate();
+        let refund = PrivateKey::generate();
+        let message = Hash::hash_bytes(b"spending transaction");
+        let script = Script::Htlc {
+            hash: hello_hash(),
+            recipient: recipient.public_key(),
+            refund: refund.public_key(),
+            timeout: 10,
+        };
+        assert!(script.verify(&signed_witness(&recipient, &message, b"hello"), &message, 0));
+        assert!(!script.verify(&signed_witness(&recipient, &message, b"bye"), &message, 0));
+        let refund_witness = Witness::signature(refund.sign(&message));
+        assert!(!script.verify(&refund_witness, &message, 9));
+        assert!(script.verify(&refund_witness, &message, 10));
+    }
+}
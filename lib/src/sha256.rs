@@ -1,27 +1,32 @@
 use core::panic;
 
 use crate::U256;
-use serde::{Deserialize, Serialize};
-use sha256::digest;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
 use std::fmt;
+use std::io;
+use std::str::FromStr;
 
-#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Hash)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Hash(U256);
 
 impl Hash {
+    // Hashes the CBOR encoding of `data` as it is written, without
+    // buffering it first. Same result as hash_bytes over the encoding.
     pub fn hash<T: serde::Serialize>(data: &T) -> Self {
-        let mut serialized: Vec<u8> = vec![];
-        if let Err(e) = ciborium::into_writer(data, &mut serialized) {
+        let mut writer = HashWriter(Sha256::new());
+        if let Err(e) = ciborium::into_writer(data, &mut writer) {
             panic!(
                 "Failed to serialize data: {:?}.\
                 This should not happen",
                 e
             );
         }
-        let hash = digest(&serialized);
-        let hash_bytes = hex::decode(hash).unwrap();
-        let hash_array: [u8; 32] = hash_bytes.as_slice().try_into().unwrap();
-        Hash(U256::from(hash_array))
+        Hash(U256::from_big_endian(&writer.0.finalize()))
+    }
+
+    pub fn hash_bytes(bytes: &[u8]) -> Self {
+        Hash(U256::from_big_endian(&Sha256::digest(bytes)))
     }
 
     pub fn matches_target(&self, target: U256) -> bool {
@@ -32,6 +37,10 @@ impl Hash {
         Hash(U256::zero())
     }
 
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        Hash(U256::from_little_endian(&bytes))
+    }
+
     pub fn as_bytes(&self) -> [u8; 32] {
         let mut bytes: Vec<u8> = vec![0; 32];
         self.0.to_little_endian(&mut bytes);
@@ -44,3 +53,41 @@ impl fmt::Display for Hash {
         write!(f, "{:x}", self.0)
     }
 }
+
+// Parses the hex form printed by Display
+impl FromStr for Hash {
+    type Err = uint::FromStrRadixErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        U256::from_str_radix(s, 16).map(Hash)
+    }
+}
+
+// Written as a single 32 byte string instead of the four words of the U256
+impl Serialize for Hash {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.as_bytes())
+    }
+}
+
+impl<'de> Deserialize<'de> for Hash {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer
+            .deserialize_bytes(crate::Bytes32Visitor)
+            .map(Hash::from_bytes)
+    }
+}
+
+// Feeds serialized bytes straight into the digest
+struct HashWriter(Sha256);
+
+impl io::Write for HashWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,782 @@
+use crate::params::ChainParams;
+use crate::sha256::Hash;
+use crate::types::{Block, BlockHeader, BlockHeight, UtxoEntry};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sled::transaction::{TransactionError, Transactional};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{
+    BufReader, BufWriter, Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult,
+    Seek, SeekFrom, Write,
+};
+use std::path::PathBuf;
+
+// Where a chain keeps its blocks, tip and UTXO set between runs. Blocks are
+// only appended, the tip is the last of them. Blockchain saves to and loads
+// from any store, so the node can keep its chain on disk block by block and
+// tests can keep it in memory. A pruned store keeps only the headers of its
+// oldest blocks, block and block_by_hash return None for them.
+pub trait ChainStore {
+    // None until the first save
+    fn params(&self) -> IoResult<Option<ChainParams>>;
+    fn set_params(&mut self, params: &ChainParams) -> IoResult<()>;
+    fn block_count(&self) -> u64;
+    fn block(&self, height: BlockHeight) -> IoResult<Option<Block>>;
+    fn block_by_hash(&self, hash: &Hash) -> IoResult<Option<Block>>;
+    fn append(&mut self, block: &Block) -> IoResult<()>;
+    fn header(&self, height: BlockHeight) -> IoResult<Option<BlockHeader>>;
+    // Number of leading blocks whose transactions were discarded
+    fn pruned(&self) -> u64;
+    // Discards the transactions of the blocks below `below`
+    fn prune(&mut self, below: u64) -> IoResult<()>;
+    // The UTXO set with the number of blocks it reflects
+    fn utxos(&self) -> IoResult<Option<(u64, HashMap<Hash, UtxoEntry>)>>;
+    fn set_utxos(&mut self, block_count: u64, utxos: &HashMap<Hash, UtxoEntry>) -> IoResult<()>;
+}
+
+#[derive(Default)]
+pub struct MemoryStore {
+    params: Option<ChainParams>,
+    blocks: Vec<Block>,
+    pruned: u64,
+    utxos: Option<(u64, HashMap<Hash, UtxoEntry>)>,
+}
+
+impl ChainStore for MemoryStore {
+    fn params(&self) -> IoResult<Option<ChainParams>> {
+        Ok(self.params.clone())
+    }
+
+    fn set_params(&mut self, params: &ChainParams) -> IoResult<()> {
+        self.params = Some(params.clone());
+        Ok(())
+    }
+
+    fn block_count(&self) -> u64 {
+        self.blocks.len() as u64
+    }
+
+    fn block(&self, height: BlockHeight) -> IoResult<Option<Block>> {
+        if height.get() < self.pruned {
+            return Ok(None);
+        }
+        Ok(self.blocks.get(height.index()).cloned())
+    }
+
+    fn block_by_hash(&self, hash: &Hash) -> IoResult<Option<Block>> {
+        let retained = &self.blocks[self.pruned as usize..];
+        Ok(retained.iter().find(|block| block.hash() == *hash).cloned())
+    }
+
+    fn append(&mut self, block: &Block) -> IoResult<()> {
+        self.blocks.push(block.clone());
+        Ok(())
+    }
+
+    fn header(&self, height: BlockHeight) -> IoResult<Option<BlockHeader>> {
+        Ok(self
+            .blocks
+            .get(height.index())
+            .map(|block| block.header.clone()))
+    }
+
+    fn pruned(&self) -> u64 {
+        self.pruned
+    }
+
+    fn prune(&mut self, below: u64) -> IoResult<()> {
+        let below = below.min(self.block_count());
+        for block in &mut self.blocks[..below as usize] {
+            block.transactions = Vec::new();
+        }
+        self.pruned = self.pruned.max(below);
+        Ok(())
+    }
+
+    fn utxos(&self) -> IoResult<Option<(u64, HashMap<Hash, UtxoEntry>)>> {
+        Ok(self.utxos.clone())
+    }
+
+    fn set_utxos(&mut self, block_count: u64, utxos: &HashMap<Hash, UtxoEntry>) -> IoResult<()> {
+        self.utxos = Some((block_count, utxos.clone()));
+        Ok(())
+    }
+}
+
+// A directory holding the parameters, an append-only file of length
+// prefixed blocks, an index of their hashes and offsets, and the latest
+// UTXO set. Only the index stays in memory, blocks are read on demand.
+pub struct DirStore {
+    dir: PathBuf,
+    // Offset of each block in the block file, by height
+    offsets: Vec<u64>,
+    heights: HashMap<Hash, u64>,
+    pruned: u64,
+}
+
+impl DirStore {
+    const PARAMS: &'static str = "params.cbor";
+    const BLOCKS: &'static str = "blocks.dat";
+    // Records of a block hash and its offset, in block order
+    const INDEX: &'static str = "blocks.idx";
+    const INDEX_RECORD_SIZE: u64 = 40;
+    const UTXOS: &'static str = "utxos.cbor";
+    const PRUNED: &'static str = "pruned.cbor";
+
+    // Blocks are written before their index records. Records pointing past
+    // the block file are dropped, blocks missing from the index are indexed
+    // again and a block cut short by a crash while appending is removed.
+    pub fn open(dir: impl Into<PathBuf>) -> IoResult<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        let mut store = DirStore {
+            dir,
+            offsets: Vec::new(),
+            heights: HashMap::new(),
+            pruned: 0,
+        };
+        let blocks_path = store.dir.join(Self::BLOCKS);
+        let index_path = store.dir.join(Self::INDEX);
+        // A prune interrupted before the block file was replaced is dropped,
+        // one interrupted right after is finished
+        let blocks_temporary = store.temporary(Self::BLOCKS);
+        let index_temporary = store.temporary(Self::INDEX);
+        if blocks_temporary.exists() {
+            fs::remove_file(&blocks_temporary)?;
+            if index_temporary.exists() {
+                fs::remove_file(&index_temporary)?;
+            }
+        } else if index_temporary.exists() {
+            fs::rename(&index_temporary, &index_path)?;
+        }
+        store.pruned = store.read(Self::PRUNED)?.unwrap_or(0);
+        let size = if blocks_path.exists() {
+            fs::metadata(&blocks_path)?.len()
+        } else {
+            0
+        };
+        if index_path.exists() {
+            let mut reader = BufReader::new(File::open(&index_path)?);
+            let mut record = [0u8; Self::INDEX_RECORD_SIZE as usize];
+            while reader.read_exact(&mut record).is_ok() {
+                let hash = Hash::from_bytes(record[..32].try_into().unwrap());
+                let offset = u64::from_be_bytes(record[32..].try_into().unwrap());
+                if offset >= size {
+                    break;
+                }
+                store.heights.insert(hash, store.offsets.len() as u64);
+                store.offsets.push(offset);
+            }
+            // Records of blocks the file holds only part of are dropped too
+            while let Some(&last) = store.offsets.last() {
+                let mut file = File::open(&blocks_path)?;
+                if last + 8 <= size && last + 8 + store.record_len(&mut file, last)? <= size {
+                    break;
+                }
+                store.offsets.pop();
+            }
+            let count = store.offsets.len() as u64;
+            store.heights.retain(|_, height| *height < count);
+            let indexed = count * Self::INDEX_RECORD_SIZE;
+            OpenOptions::new()
+                .write(true)
+                .open(&index_path)?
+                .set_len(indexed)?;
+        }
+        if size == 0 {
+            return Ok(store);
+        }
+        let mut file = File::open(&blocks_path)?;
+        let mut offset = match store.offsets.last() {
+            Some(&last) => last + 8 + store.record_len(&mut file, last)?,
+            None => 0,
+        };
+        while offset + 8 <= size {
+            let len = store.record_len(&mut file, offset)?;
+            if offset + 8 + len > size {
+                break;
+            }
+            let block = store.read_record(&mut file, offset)?;
+            store.index(block.hash(), offset)?;
+            offset += 8 + len;
+        }
+        if offset != size {
+            OpenOptions::new()
+                .write(true)
+                .open(&blocks_path)?
+                .set_len(offset)?;
+        }
+        Ok(store)
+    }
+
+    pub fn height_of(&self, hash: &Hash) -> Option<BlockHeight> {
+        self.heights.get(hash).copied().map(BlockHeight::new)
+    }
+
+    fn record_len(&self, file: &mut File, offset: u64) -> IoResult<u64> {
+        file.seek(SeekFrom::Start(offset))?;
+        let mut len_bytes = [0u8; 8];
+        file.read_exact(&mut len_bytes)?;
+        Ok(u64::from_be_bytes(len_bytes))
+    }
+
+    fn read_raw(&self, file: &mut File, offset: u64) -> IoResult<Vec<u8>> {
+        let mut data = vec![0u8; self.record_len(file, offset)? as usize];
+        file.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    fn read_record(&self, file: &mut File, offset: u64) -> IoResult<Block> {
+        let data = self.read_raw(file, offset)?;
+        ciborium::de::from_reader(&data[..])
+            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to deserialize Block"))
+    }
+
+    fn temporary(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.tmp", name))
+    }
+
+    fn index(&mut self, hash: Hash, offset: u64) -> IoResult<()> {
+        let path = self.dir.join(Self::INDEX);
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        file.write_all(&hash.as_bytes())?;
+        file.write_all(&offset.to_be_bytes())?;
+        self.heights.insert(hash, self.offsets.len() as u64);
+        self.offsets.push(offset);
+        Ok(())
+    }
+
+    fn write_atomic<T: Serialize>(&self, name: &str, value: &T) -> IoResult<()> {
+        let temporary = self.temporary(name);
+        let mut file = File::create(&temporary)?;
+        ciborium::ser::into_writer(value, &mut file).map_err(|_| {
+            IoError::new(
+                IoErrorKind::InvalidData,
+                format!("Failed to write {}", name),
+            )
+        })?;
+        file.sync_all()?;
+        fs::rename(temporary, self.dir.join(name))
+    }
+
+    fn read<T: DeserializeOwned>(&self, name: &str) -> IoResult<Option<T>> {
+        let path = self.dir.join(name);
+        if !path.exists() {
+            return Ok(None);
+        }
+        ciborium::de::from_reader(BufReader::new(File::open(path)?))
+            .map(Some)
+            .map_err(|_| IoError::new(IoErrorKind::InvalidData, format!("Failed to read {}", name)))
+    }
+}
+
+impl ChainStore for DirStore {
+    fn params(&self) -> IoResult<Option<ChainParams>> {
+        self.read(Self::PARAMS)
+    }
+
+    fn set_params(&mut self, params: &ChainParams) -> IoResult<()> {
+        self.write_atomic(Self::PARAMS, params)
+    }
+
+    fn block_count(&self) -> u64 {
+        self.offsets.len() as u64
+    }
+
+    fn block(&self, height: BlockHeight) -> IoResult<Option<Block>> {
+        if height.get() < self.pruned {
+            return Ok(None);
+        }
+        let Some(&offset) = self.offsets.get(height.index()) else {
+            return Ok(None);
+        };
+        let mut file = File::open(self.dir.join(Self::BLOCKS))?;
+        self.read_record(&mut file, offset).map(Some)
+    }
+
+    fn block_by_hash(&self, hash: &Hash) -> IoResult<Option<Block>> {
+        match self.height_of(hash) {
+            Some(height) => self.block(height),
+            None => Ok(None),
+        }
+    }
+
+    fn append(&mut self, block: &Block) -> IoResult<()> {
+        let mut data = Vec::new();
+        ciborium::ser::into_writer(block, &mut data)
+            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to serialize Block"))?;
+        let path = self.dir.join(Self::BLOCKS);
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        let offset = file.seek(SeekFrom::End(0))?;
+        file.write_all(&(data.len() as u64).to_be_bytes())?;
+        file.write_all(&data)?;
+        self.index(block.hash(), offset)
+    }
+
+    fn header(&self, height: BlockHeight) -> IoResult<Option<BlockHeader>> {
+        let Some(&offset) = self.offsets.get(height.index()) else {
+            return Ok(None);
+        };
+        let mut file = File::open(self.dir.join(Self::BLOCKS))?;
+        Ok(Some(self.read_record(&mut file, offset)?.header))
+    }
+
+    fn pruned(&self) -> u64 {
+        self.pruned
+    }
+
+    // Writes a new block file with the pruned blocks cut down to their
+    // headers and its index aside, then renames both into place. The
+    // pruned count is saved before, claiming too much only hides blocks.
+    fn prune(&mut self, below: u64) -> IoResult<()> {
+        let below = below.min(self.block_count());
+        if below <= self.pruned {
+            return Ok(());
+        }
+        let mut hashes = vec![Hash::zero(); self.offsets.len()];
+        for (hash, &height) in &self.heights {
+            hashes[height as usize] = *hash;
+        }
+        let blocks_temporary = self.temporary(Self::BLOCKS);
+        let index_temporary = self.temporary(Self::INDEX);
+        let mut source = File::open(self.dir.join(Self::BLOCKS))?;
+        let mut blocks = BufWriter::new(File::create(&blocks_temporary)?);
+        let mut index = BufWriter::new(File::create(&index_temporary)?);
+        let mut offsets = Vec::with_capacity(self.offsets.len());
+        let mut offset = 0u64;
+        for (height, &old_offset) in self.offsets.iter().enumerate() {
+            let data = if (height as u64) < below {
+                let header = self.read_record(&mut source, old_offset)?.header;
+                encode(&Block::new(header, Vec::new()))?
+            } else {
+                self.read_raw(&mut source, old_offset)?
+            };
+            blocks.write_all(&(data.len() as u64).to_be_bytes())?;
+            blocks.write_all(&data)?;
+            index.write_all(&hashes[height].as_bytes())?;
+            index.write_all(&offset.to_be_bytes())?;
+            offsets.push(offset);
+            offset += 8 + data.len() as u64;
+        }
+        blocks.flush()?;
+        blocks.get_ref().sync_all()?;
+        index.flush()?;
+        index.get_ref().sync_all()?;
+        self.write_atomic(Self::PRUNED, &below)?;
+        fs::rename(blocks_temporary, self.dir.join(Self::BLOCKS))?;
+        fs::rename(index_temporary, self.dir.join(Self::INDEX))?;
+        self.offsets = offsets;
+        self.pruned = below;
+        Ok(())
+    }
+
+    fn utxos(&self) -> IoResult<Option<(u64, HashMap<Hash, UtxoEntry>)>> {
+        self.read(Self::UTXOS)
+    }
+
+    fn set_utxos(&mut self, block_count: u64, utxos: &HashMap<Hash, UtxoEntry>) -> IoResult<()> {
+        self.write_atomic(Self::UTXOS, &(block_count, utxos))
+    }
+}
+
+// Blocks by hash, an index from heights to hashes and the UTXO set in
+// separate trees of a sled database. Every append and every UTXO update is
+// one atomic batch, so a crash leaves either the old or the new state.
+pub struct SledStore {
+    db: sled::Db,
+    blocks: sled::Tree,
+    heights: sled::Tree,
+    utxos: sled::Tree,
+    block_count: u64,
+    pruned: u64,
+}
+
+impl SledStore {
+    const PARAMS_KEY: &'static [u8] = b"params";
+    // Shorter than any outpoint, so it cannot collide with one
+    const UTXO_COUNT_KEY: &'static [u8] = b"count";
+    // Kept in the blocks tree, shorter than any block hash
+    const PRUNED_KEY: &'static [u8] = b"pruned";
+
+    pub fn open(path: impl AsRef<std::path::Path>) -> IoResult<Self> {
+        let db = sled::open(path)?;
+        let blocks = db.open_tree("blocks")?;
+        let heights = db.open_tree("heights")?;
+        let utxos = db.open_tree("utxos")?;
+        let block_count = match heights.last()? {
+            Some((key, _)) => height_from_key(&key)? + 1,
+            None => 0,
+        };
+        let pruned = match blocks.get(Self::PRUNED_KEY)? {
+            Some(bytes) => height_from_key(&bytes)?,
+            None => 0,
+        };
+        Ok(SledStore {
+            db,
+            blocks,
+            heights,
+            utxos,
+            block_count,
+            pruned,
+        })
+    }
+}
+
+fn encode<T: Serialize>(value: &T) -> IoResult<Vec<u8>> {
+    let mut bytes = Vec::new();
+    ciborium::ser::into_writer(value, &mut bytes)
+        .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to serialize store entry"))?;
+    Ok(bytes)
+}
+
+fn decode<T: DeserializeOwned>(bytes: &[u8]) -> IoResult<T> {
+    ciborium::de::from_reader(bytes).map_err(|_| {
+        IoError::new(
+            IoErrorKind::InvalidData,
+            "Failed to deserialize store entry",
+        )
+    })
+}
+
+// Big endian, so heights sort in order
+fn height_from_key(key: &[u8]) -> IoResult<u64> {
+    let bytes = key
+        .try_into()
+        .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Malformed height key"))?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+impl ChainStore for SledStore {
+    fn params(&self) -> IoResult<Option<ChainParams>> {
+        self.db
+            .get(Self::PARAMS_KEY)?
+            .map(|bytes| decode(&bytes))
+            .transpose()
+    }
+
+    fn set_params(&mut self, params: &ChainParams) -> IoResult<()> {
+        self.db.insert(Self::PARAMS_KEY, encode(params)?)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn block(&self, height: BlockHeight) -> IoResult<Option<Block>> {
+        if height.get() < self.pruned {
+            return Ok(None);
+        }
+        let Some(hash) = self.heights.get(height.get().to_be_bytes())? else {
+            return Ok(None);
+        };
+        self.blocks
+            .get(hash)?
+            .map(|bytes| decode(&bytes))
+            .transpose()
+    }
+
+    // Pruned blocks are the only ones stored without transactions
+    fn block_by_hash(&self, hash: &Hash) -> IoResult<Option<Block>> {
+        let block = self
+            .blocks
+            .get(hash.as_bytes())?
+            .map(|bytes| decode(&bytes))
+            .transpose()?;
+        Ok(block.filter(|block: &Block| !block.transactions.is_empty()))
+    }
+
+    fn append(&mut self, block: &Block) -> IoResult<()> {
+        let hash = block.hash().as_bytes();
+        let data = encode(block)?;
+        let height = self.block_count.to_be_bytes();
+        (&self.blocks, &self.heights)
+            .transaction(|(blocks, heights)| {
+                blocks.insert(&hash[..], data.as_slice())?;
+                heights.insert(&height[..], &hash[..])?;
+                Ok(())
+            })
+            .map_err(|e: TransactionError<()>| IoError::other(format!("{:?}", e)))?;
+        self.block_count += 1;
+        Ok(())
+    }
+
+    fn header(&self, height: BlockHeight) -> IoResult<Option<BlockHeader>> {
+        let Some(hash) = self.heights.get(height.get().to_be_bytes())? else {
+            return Ok(None);
+        };
+        let block = self
+            .blocks
+            .get(hash)?
+            .map(|bytes| decode::<Block>(&bytes))
+            .transpose()?;
+        Ok(block.map(|block| block.header))
+    }
+
+    fn pruned(&self) -> u64 {
+        self.pruned
+    }
+
+    // Bodies and the new count in one batch
+    fn prune(&mut self, below: u64) -> IoResult<()> {
+        let below = below.min(self.block_count);
+        if below <= self.pruned {
+            return Ok(());
+        }
+        let mut batch = sled::Batch::default();
+        for height in self.pruned..below {
+            let Some(hash) = self.heights.get(height.to_be_bytes())? else {
+                continue;
+            };
+            if let Some(bytes) = self.blocks.get(&hash)? {
+                let header = decode::<Block>(&bytes)?.header;
+                batch.insert(hash, encode(&Block::new(header, Vec::new()))?);
+            }
+        }
+        batch.insert(Self::PRUNED_KEY, &below.to_be_bytes()[..]);
+        self.blocks.apply_batch(batch)?;
+        self.db.flush()?;
+        self.pruned = below;
+        Ok(())
+    }
+
+    fn utxos(&self) -> IoResult<Option<(u64, HashMap<Hash, UtxoEntry>)>> {
+        let Some(count) = self.utxos.get(Self::UTXO_COUNT_KEY)? else {
+            return Ok(None);
+        };
+        let count = height_from_key(&count)?;
+        let mut utxos = HashMap::new();
+        for entry in self.utxos.iter() {
+            let (key, value) = entry?;
+            if let Ok(bytes) = <[u8; 32]>::try_from(&key[..]) {
+                utxos.insert(Hash::from_bytes(bytes), decode(&value)?);
+            }
+        }
+        Ok(Some((count, utxos)))
+    }
+
+    // Writes only what changed since the last update, in one batch
+    fn set_utxos(&mut self, block_count: u64, utxos: &HashMap<Hash, UtxoEntry>) -> IoResult<()> {
+        let mut batch = sled::Batch::default();
+        let mut stored = std::collections::HashSet::new();
+        for key in self.utxos.iter().keys() {
+            let key = key?;
+            let Ok(bytes) = <[u8; 32]>::try_from(&key[..]) else {
+                continue;
+            };
+            let outpoint = Hash::from_bytes(bytes);
+            if utxos.contains_key(&outpoint) {
+                stored.insert(outpoint);
+            } else {
+                batch.remove(key);
+            }
+        }
+        for (outpoint, entry) in utxos {
+            if !stored.contains(outpoint) {
+                batch.insert(&outpoint.as_bytes()[..], encode(entry)?);
+            }
+        }
+        batch.insert(Self::UTXO_COUNT_KEY, &block_count.to_be_bytes()[..]);
+        self.utxos.apply_batch(batch)?;
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Amount;
+    use crate::script::Script;
+    use crate::types::{BlockHeader, Transaction, TransactionOutput};
+    use crate::util::MerkleRoot;
+    use chrono::DateTime;
+
+    // Linked blocks, not valid ones, a store never checks them
+    fn blocks(count: u64) -> Vec<Block> {
+        let mut blocks: Vec<Block> = Vec::new();
+        for height in 0..count {
+            let output = TransactionOutput {
+                value: Amount::from_sat(height),
+                script: Script::PayToPubkeyHash(Hash::zero()),
+            };
+            let transactions = vec![Transaction::coinbase(height, vec![output])];
+            let header = BlockHeader::new(
+                DateTime::from_timestamp(height as i64, 0).unwrap(),
+                0,
+                blocks.last().map(Block::hash).unwrap_or(Hash::zero()),
+                MerkleRoot::calculate(&transactions),
+                crate::U256::MAX,
+            );
+            blocks.push(Block::new(header, transactions));
+        }
+        blocks
+    }
+
+    // A fresh directory under the system's temporary one
+    fn temporary_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("btclib-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn fill(store: &mut dyn ChainStore, blocks: &[Block]) {
+        assert!(store.params().unwrap().is_none());
+        store.set_params(&ChainParams::regtest()).unwrap();
+        for block in blocks {
+            store.append(block).unwrap();
+        }
+        let utxos = HashMap::from([(
+            Hash::hash_bytes(b"utxo"),
+            UtxoEntry::new(blocks[0].transactions[0].outputs[0].clone(), 0, true),
+        )]);
+        store.set_utxos(blocks.len() as u64, &utxos).unwrap();
+    }
+
+    fn check(store: &dyn ChainStore, blocks: &[Block]) {
+        let params = store.params().unwrap().unwrap();
+        assert_eq!(
+            params.genesis_timestamp,
+            ChainParams::regtest().genesis_timestamp
+        );
+        assert_eq!(store.block_count(), blocks.len() as u64);
+        for (height, block) in blocks.iter().enumerate() {
+            let height = BlockHeight::new(height as u64);
+            assert_eq!(store.block(height).unwrap().unwrap().hash(), block.hash());
+        }
+        let past_tip = BlockHeight::new(blocks.len() as u64);
+        assert!(store.block(past_tip).unwrap().is_none());
+        let (count, utxos) = store.utxos().unwrap().unwrap();
+        assert_eq!(count, blocks.len() as u64);
+        assert!(utxos.contains_key(&Hash::hash_bytes(b"utxo")));
+    }
+
+    #[test]
+    fn memory_store_round_trips() {
+        let blocks = blocks(5);
+        let mut store = MemoryStore::default();
+        fill(&mut store, &blocks);
+        check(&store, &blocks);
+    }
+
+    #[test]
+    fn dir_store_round_trips_across_reopening() {
+        let dir = temporary_dir("dir-store");
+        let blocks = blocks(5);
+        fill(&mut DirStore::open(&dir).unwrap(), &blocks);
+        check(&DirStore::open(&dir).unwrap(), &blocks);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // Sled's flusher thread may hold the lock of a dropped database for a
+    // moment, reopening retries until it lets go
+    fn reopen_sled(dir: &PathBuf) -> SledStore {
+        for _ in 0..50 {
+            if let Ok(store) = SledStore::open(dir) {
+                return store;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+        SledStore::open(dir).unwrap()
+    }
+
+    #[test]
+    fn sled_store_round_trips_across_reopening() {
+        let dir = temporary_dir("sled-store");
+        let blocks = blocks(5);
+        fill(&mut SledStore::open(&dir).unwrap(), &blocks);
+        let mut store = reopen_sled(&dir);
+        check(&store, &blocks);
+
+        // Only the difference is written, removed outpoints go away
+        store.set_utxos(6, &HashMap::new()).unwrap();
+        let (count, utxos) = store.utxos().unwrap().unwrap();
+        assert_eq!(count, 6);
+        assert!(utxos.is_empty());
+        drop(store);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn dir_store_recovers_its_index_after_a_crash() {
+        let dir = temporary_dir("dir-store-index");
+        let blocks = blocks(5);
+        fill(&mut DirStore::open(&dir).unwrap(), &blocks);
+        let resize = |name: &str, len: u64| {
+            let file = OpenOptions::new().write(true).open(dir.join(name)).unwrap();
+            file.set_len(len).unwrap();
+        };
+        let len = |name: &str| fs::metadata(dir.join(name)).unwrap().len();
+
+        // Blocks missing from the index are indexed again
+        resize(DirStore::INDEX, 2 * DirStore::INDEX_RECORD_SIZE);
+        let store = DirStore::open(&dir).unwrap();
+        check(&store, &blocks);
+        assert_eq!(
+            store.height_of(&blocks[4].hash()),
+            Some(BlockHeight::new(4))
+        );
+        drop(store);
+
+        // A block cut short is removed along with its index record
+        resize(DirStore::BLOCKS, len(DirStore::BLOCKS) - 1);
+        let store = DirStore::open(&dir).unwrap();
+        assert_eq!(store.block_count(), 4);
+        assert!(store.block_by_hash(&blocks[4].hash()).unwrap().is_none());
+        assert_eq!(len(DirStore::INDEX), 4 * DirStore::INDEX_RECORD_SIZE);
+        drop(store);
+
+        // and can be appended again
+        let mut store = DirStore::open(&dir).unwrap();
+        store.append(&blocks[4]).unwrap();
+        check(&DirStore::open(&dir).unwrap(), &blocks);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn prune(store: &mut dyn ChainStore, blocks: &[Block]) {
+        store.prune(3).unwrap();
+        // Pruning less is a no-op
+        store.prune(2).unwrap();
+        assert_eq!(store.pruned(), 3);
+        check_pruned(store, blocks, 3);
+    }
+
+    fn check_pruned(store: &dyn ChainStore, blocks: &[Block], below: usize) {
+        assert_eq!(store.pruned(), below as u64);
+        for (height, block) in blocks.iter().enumerate() {
+            let kept = height >= below;
+            let height = BlockHeight::new(height as u64);
+            assert_eq!(store.block(height).unwrap().is_some(), kept);
+            assert_eq!(store.block_by_hash(&block.hash()).unwrap().is_some(), kept);
+            assert_eq!(store.header(height).unwrap().unwrap().hash(), block.hash());
+        }
+    }
+
+    #[test]
+    fn stores_prune_to_headers() {
+        let blocks = blocks(5);
+        let mut memory = MemoryStore::default();
+        fill(&mut memory, &blocks);
+        prune(&mut memory, &blocks);
+        // Pruning more than there is stops at the tip
+        memory.prune(10).unwrap();
+        check_pruned(&memory, &blocks, 5);
+
+        let dir = temporary_dir("dir-store-prune");
+        let mut store = DirStore::open(&dir).unwrap();
+        fill(&mut store, &blocks);
+        prune(&mut store, &blocks);
+        check_pruned(&DirStore::open(&dir).unwrap(), &blocks, 3);
+        fs::remove_dir_all(dir).unwrap();
+
+        let dir = temporary_dir("sled-store-prune");
+        let mut store = SledStore::open(&dir).unwrap();
+        fill(&mut store, &blocks);
+        prune(&mut store, &blocks);
+        drop(store);
+        check_pruned(&reopen_sled(&dir), &blocks, 3);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,6 +1,14 @@
+mod address_index;
 mod block;
 mod blockchain;
+mod height;
+mod mempool;
 mod transaction;
-pub use block::{Block, BlockHeader};
-pub use blockchain::Blockchain;
-pub use transaction::{Transaction, TransactionInput, TransactionOutput};
+mod utxo_view;
+pub use address_index::AddressIndex;
+pub use block::{Block, BlockHeader, PrecheckedBlock};
+pub use blockchain::{Blockchain, ChainStats, TxLocation, UtxoEntry};
+pub use height::{BlockHeight, TxIndex};
+pub use mempool::{Mempool, MempoolEntry, MempoolSnapshot, MempoolStats};
+pub use transaction::{LockTime, Transaction, TransactionInput, TransactionOutput};
+pub use utxo_view::{UtxoOverlay, UtxoView};
@@ -0,0 +1,146 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use super::{Block, BlockHeight, TransactionOutput, UtxoView};
+use crate::sha256::Hash;
+
+// Maps pubkey hashes to the transactions that paid to or spent from them,
+// oldest first, and to the outpoints still paying to them. Every connected
+// block keeps what it changed so it can be disconnected again.
+#[derive(Clone, Debug, Default)]
+pub struct AddressIndex {
+    transactions: HashMap<Hash, Vec<(Hash, BlockHeight)>>,
+    unspent: HashMap<Hash, HashSet<Hash>>,
+    undo: Vec<BlockUndo>,
+}
+
+#[derive(Clone, Debug)]
+struct BlockUndo {
+    height: BlockHeight,
+    touched: Vec<Hash>,
+    // Address and outpoint, true if the block created it, false if it spent it
+    unspent: Vec<(Hash, Hash, bool)>,
+}
+
+impl AddressIndex {
+    // Unspent outputs of a UTXO set without any history, for chains that
+    // cannot be replayed from genesis
+    pub fn with_utxos(utxos: &dyn UtxoView) -> Self {
+        let mut index = AddressIndex::default();
+        for (outpoint, entry) in utxos.iter() {
+            for address in entry.output.script.pubkey_hashes() {
+                index.unspent.entry(address).or_default().insert(*outpoint);
+            }
+        }
+        index
+    }
+
+    // Must run before the block's inputs are removed from `utxos`
+    pub fn connect(&mut self, block: &Block, height: BlockHeight, utxos: &dyn UtxoView) {
+        let mut touched = BTreeSet::new();
+        let mut changes = Vec::new();
+        // Outputs of earlier transactions in the block
+        let mut created: HashMap<Hash, &TransactionOutput> = HashMap::new();
+        for transaction in &block.transactions {
+            let txid = transaction.hash();
+            let spent = transaction
+                .inputs
+                .iter()
+                .filter_map(|input| {
+                    let outpoint = input.prev_transaction_output_hash;
+                    let output = match utxos.get(&outpoint) {
+                        Some(entry) => &entry.output,
+                        None => *created.get(&outpoint)?,
+                    };
+                    Some((outpoint, output))
+                })
+                .collect::<Vec<_>>();
+            let mut addresses = BTreeSet::new();
+            for (outpoint, output) in spent {
+                for address in output.script.pubkey_hashes() {
+                    addresses.insert(address);
+                    if self.remove_unspent(address, outpoint) {
+                        changes.push((address, outpoint, false));
+                    }
+                }
+            }
+            for (index, output) in transaction.outputs.iter().enumerate() {
+                let outpoint = transaction.output_hash(index);
+                for address in output.script.pubkey_hashes() {
+                    addresses.insert(address);
+                    if self.unspent.entry(address).or_default().insert(outpoint) {
+                        changes.push((address, outpoint, true));
+                    }
+                }
+                created.insert(outpoint, output);
+            }
+            for address in addresses {
+                self.transactions
+                    .entry(address)
+                    .or_default()
+                    .push((txid, height));
+                touched.insert(address);
+            }
+        }
+        self.undo.push(BlockUndo {
+            height,
+            touched: touched.into_iter().collect(),
+            unspent: changes,
+        });
+    }
+
+    // Undoes the most recently connected block
+    pub fn disconnect(&mut self) {
+        let Some(undo) = self.undo.pop() else {
+            return;
+        };
+        for address in undo.touched {
+            let Some(transactions) = self.transactions.get_mut(&address) else {
+                continue;
+            };
+            while transactions.last().is_some_and(|(_, h)| *h == undo.height) {
+                transactions.pop();
+            }
+            if transactions.is_empty() {
+                self.transactions.remove(&address);
+            }
+        }
+        for (address, outpoint, created) in undo.unspent.into_iter().rev() {
+            if created {
+                self.remove_unspent(address, outpoint);
+            } else {
+                self.unspent.entry(address).or_default().insert(outpoint);
+            }
+        }
+    }
+
+    fn remove_unspent(&mut self, address: Hash, outpoint: Hash) -> bool {
+        let Some(outpoints) = self.unspent.get_mut(&address) else {
+            return false;
+        };
+        let removed = outpoints.remove(&outpoint);
+        if outpoints.is_empty() {
+            self.unspent.remove(&address);
+        }
+        removed
+    }
+
+    // Newest transactions first, HISTORY_PAGE_SIZE per page
+    pub fn history(&self, address: &Hash, page: usize) -> Vec<(Hash, BlockHeight)> {
+        self.transactions
+            .get(address)
+            .map(|transactions| {
+                transactions
+                    .iter()
+                    .rev()
+                    .skip(page.saturating_mul(crate::HISTORY_PAGE_SIZE))
+                    .take(crate::HISTORY_PAGE_SIZE)
+                    .copied()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn unspent(&self, address: &Hash) -> impl Iterator<Item = &Hash> {
+        self.unspent.get(address).into_iter().flatten()
+    }
+}
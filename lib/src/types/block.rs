@@ -1,13 +1,21 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::usize;
 
-use super::{Transaction, TransactionOutput};
+use super::{Transaction, TransactionOutput, TxIndex, UtxoEntry, UtxoOverlay, UtxoView};
+use crate::amount::Amount;
+use crate::consensus_rule;
+use crate::crypto::{self, PublicKey, Signature};
+use crate::encoding::Encode;
 use crate::error::{BtcError, Result};
+use crate::params::ChainParams;
+use crate::script::Script;
 use crate::sha256::Hash;
 use crate::util::MerkleRoot;
 use crate::util::Saveable;
 use crate::U256;
 use chrono::{DateTime, Utc};
+#[cfg(feature = "parallel-verify")]
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write};
 
@@ -17,10 +25,29 @@ pub struct BlockHeader {
     pub nonce: u64,
     pub prev_block_hash: Hash,
     pub merkle_root: MerkleRoot,
-    pub target: U256,
+    // Compact encoding of the target, see U256::to_compact
+    pub bits: u32,
+}
+
+impl Encode for BlockHeader {
+    fn encode(&self, out: &mut Vec<u8>) {
+        let BlockHeader {
+            timestamp,
+            nonce,
+            prev_block_hash,
+            merkle_root,
+            bits,
+        } = self;
+        (timestamp, nonce, prev_block_hash, merkle_root, bits).encode(out);
+    }
 }
 
 impl BlockHeader {
+    // Timestamp seconds and nanoseconds, nonce, previous block hash, merkle
+    // root and bits, each at a fixed offset
+    pub const SIZE: usize = 88;
+    const NONCE_OFFSET: usize = 12;
+
     pub fn new(
         timestamp: DateTime<Utc>,
         nonce: u64,
@@ -33,24 +60,61 @@ impl BlockHeader {
             nonce,
             prev_block_hash,
             merkle_root,
-            target,
+            bits: target.to_compact(),
         }
     }
     pub fn hash(&self) -> Hash {
-        Hash::hash(&self)
+        self.encoded_hash()
     }
+
+    // The canonical encoding, so hashing these bytes gives the header hash
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        self.encoded().try_into().expect("Bug: Impossible")
+    }
+
+    // None if the timestamp is out of range
+    pub fn from_bytes(bytes: &[u8; Self::SIZE]) -> Option<Self> {
+        let seconds = i64::from_le_bytes(bytes[0..8].try_into().unwrap());
+        let nanos = u32::from_le_bytes(bytes[8..12].try_into().unwrap());
+        Some(BlockHeader {
+            timestamp: DateTime::from_timestamp(seconds, nanos)?,
+            nonce: u64::from_le_bytes(bytes[Self::NONCE_OFFSET..20].try_into().unwrap()),
+            prev_block_hash: Hash::from_bytes(bytes[20..52].try_into().unwrap()),
+            merkle_root: MerkleRoot::from_hash(Hash::from_bytes(bytes[52..84].try_into().unwrap())),
+            bits: u32::from_le_bytes(bytes[84..Self::SIZE].try_into().unwrap()),
+        })
+    }
+
+    // Changes the nonce of header bytes in place, nothing else needs encoding again
+    pub fn write_nonce(bytes: &mut [u8; Self::SIZE], nonce: u64) {
+        bytes[Self::NONCE_OFFSET..Self::NONCE_OFFSET + 8].copy_from_slice(&nonce.to_le_bytes());
+    }
+
+    pub fn target(&self) -> U256 {
+        U256::from_compact(self.bits)
+    }
+
+    // Expected number of hashes to meet the target, 2^256 / (target + 1)
+    pub fn work(&self) -> U256 {
+        self.target().work()
+    }
+
     pub fn mine(&mut self, steps: usize) -> bool {
-        if self.hash().matches_target(self.target) {
+        let target = self.target();
+        let mut bytes = self.to_bytes();
+        if Hash::hash_bytes(&bytes).matches_target(target) {
             return true;
         }
         for _ in 0..steps {
             if let Some(new_nonce) = self.nonce.checked_add(1) {
                 self.nonce = new_nonce;
+                Self::write_nonce(&mut bytes, new_nonce);
             } else {
                 self.nonce = 0;
                 self.timestamp = Utc::now();
+                bytes = self.to_bytes();
             }
-            if self.hash().matches_target(self.target) {
+            if Hash::hash_bytes(&bytes).matches_target(target) {
                 return true;
             }
         }
@@ -72,121 +136,461 @@ impl Block {
         }
     }
 
+    // The header commits to the transactions through the merkle root, so
+    // header chains link without the transactions
     pub fn hash(&self) -> Hash {
-        Hash::hash(self)
+        self.header.hash()
+    }
+
+    // Block 0 of a network, identical on every node using the same parameters.
+    // Its coinbase pays to a key hash nobody can produce a key for.
+    pub fn genesis(params: &ChainParams) -> Self {
+        let transactions = vec![Transaction::coinbase(
+            0,
+            vec![TransactionOutput {
+                value: params.block_subsidy(0),
+                script: Script::PayToPubkeyHash(Hash::zero()),
+            }],
+        )];
+        let timestamp =
+            DateTime::from_timestamp(params.genesis_timestamp, 0).expect("Bug: Impossible");
+        let merkle_root = MerkleRoot::calculate(&transactions);
+        Block::new(
+            BlockHeader::new(timestamp, 0, Hash::zero(), merkle_root, params.min_target),
+            transactions,
+        )
+    }
+
+    pub fn check_proof_of_work(&self) -> Result<()> {
+        consensus_rule!("HDR-POW", "The header hash meets the target it commits to");
+        if !self.header.hash().matches_target(self.header.target()) {
+            return Err(BtcError::InsufficientWork);
+        }
+        Ok(())
+    }
+
+    pub fn check_merkle_root(&self) -> Result<()> {
+        consensus_rule!(
+            "HDR-MERKLE",
+            "The merkle root commits to the transactions and count"
+        );
+        if MerkleRoot::calculate(&self.transactions) != self.header.merkle_root {
+            return Err(BtcError::InvalidMerkleRoot);
+        }
+        Ok(())
+    }
+
+    // The checks that need no chain state, so blocks can go through them
+    // ahead of being connected and on other threads. The genesis block
+    // needs no proof of work.
+    pub fn precheck(self, params: &ChainParams) -> Result<PrecheckedBlock> {
+        let is_genesis = self.header.prev_block_hash == Hash::zero()
+            && self.hash() == Block::genesis(params).hash();
+        if !is_genesis {
+            self.check_proof_of_work()?;
+        }
+        self.check_merkle_root()?;
+        Ok(PrecheckedBlock(self))
+    }
+
+    pub fn serialized_size(&self) -> usize {
+        crate::util::cbor_size(self)
     }
 
     pub fn verify_coinbase_transaction(
         &self,
         predicted_block_height: u64,
-        utxos: &HashMap<Hash, (bool, TransactionOutput)>,
+        utxos: &dyn UtxoView,
+        params: &ChainParams,
     ) -> Result<()> {
         let coinbase_transaction = &self.transactions[0];
 
+        consensus_rule!(
+            "CB-SHAPE",
+            "The coinbase has no inputs and at least one output"
+        );
         if coinbase_transaction.inputs.len() != 0 {
-            return Err(BtcError::InvalidTransaction);
+            return Err(BtcError::InvalidCoinbase);
         }
 
         if coinbase_transaction.outputs.len() == 0 {
-            return Err(BtcError::InvalidTransaction);
+            return Err(BtcError::InvalidCoinbase);
         }
 
-        let miner_fees = self.calculate_miner_fees(utxos)?;
-        let block_reward = crate::INITIAL_REWARD * 10u64.pow(8)
-            / 2u64.pow((predicted_block_height / crate::HALVING_INTERVAL) as u32);
+        consensus_rule!(
+            "CB-HEIGHT",
+            "The coinbase commits to the height of its block"
+        );
+        if coinbase_transaction.coinbase_height != Some(predicted_block_height) {
+            return Err(BtcError::WrongCoinbaseHeight {
+                expected: predicted_block_height,
+                got: coinbase_transaction.coinbase_height,
+            });
+        }
 
-        let total_coinbase_outputs: u64 = coinbase_transaction
+        consensus_rule!("CB-SCRIPT", "Coinbase output scripts are well formed");
+        if coinbase_transaction
             .outputs
             .iter()
-            .map(|output| output.value)
-            .sum();
+            .any(|output| !output.script.is_well_formed())
+        {
+            return Err(BtcError::InvalidScript);
+        }
 
-        if total_coinbase_outputs != block_reward + miner_fees {
-            return Err(BtcError::InvalidTransaction);
+        let miner_fees = self.calculate_miner_fees(utxos)?;
+        let block_subsidy = params.block_subsidy(predicted_block_height);
+
+        let total_coinbase_outputs = Amount::checked_sum(
+            coinbase_transaction
+                .outputs
+                .iter()
+                .map(|output| output.value),
+        )
+        .ok_or(BtcError::ValueOverflow)?;
+        let reward = block_subsidy
+            .checked_add(miner_fees)
+            .ok_or(BtcError::ValueOverflow)?;
+
+        consensus_rule!(
+            "CB-VALUE",
+            "The coinbase pays exactly the block reward plus fees"
+        );
+        if total_coinbase_outputs != reward {
+            return Err(BtcError::WrongCoinbaseValue {
+                expected: reward,
+                got: total_coinbase_outputs,
+            });
         }
         Ok(())
     }
 
-    pub fn calculate_miner_fees(
-        &self,
-        utxos: &HashMap<Hash, (bool, TransactionOutput)>,
-    ) -> Result<u64> {
-        let inputs: HashMap<Hash, TransactionOutput> = HashMap::new();
+    pub fn calculate_miner_fees(&self, utxos: &dyn UtxoView) -> Result<Amount> {
+        let mut inputs: HashMap<Hash, TransactionOutput> = HashMap::new();
         let mut outputs: HashMap<Hash, TransactionOutput> = HashMap::new();
 
         for tx in self.transactions.iter().skip(1) {
             for input in &tx.inputs {
                 let prev_output = utxos
                     .get(&input.prev_transaction_output_hash)
-                    .map(|(_, output)| output);
-                if prev_output.is_none() {
-                    return Err(BtcError::InvalidTransaction);
-                }
-                let _prev_output = prev_output.unwrap();
+                    .map(|entry| &entry.output)
+                    .or_else(|| outputs.get(&input.prev_transaction_output_hash));
+                let Some(prev_output) = prev_output else {
+                    return Err(BtcError::MissingUtxo {
+                        outpoint: input.prev_transaction_output_hash,
+                    });
+                };
                 if inputs.contains_key(&input.prev_transaction_output_hash) {
-                    return Err(BtcError::InvalidTransaction);
+                    return Err(BtcError::DoubleSpend {
+                        outpoint: input.prev_transaction_output_hash,
+                    });
                 }
-                for output in &tx.outputs {
-                    if outputs.contains_key(&output.hash()) {
-                        return Err(BtcError::InvalidTransaction);
-                    }
-                    outputs.insert(output.hash(), output.clone());
+                inputs.insert(input.prev_transaction_output_hash, prev_output.clone());
+            }
+            for (index, output) in tx.outputs.iter().enumerate() {
+                let output_hash = tx.output_hash(index);
+                if outputs.contains_key(&output_hash) {
+                    return Err(BtcError::InvalidTransaction);
                 }
+                outputs.insert(output_hash, output.clone());
             }
         }
-        let input_value: u64 = inputs.values().map(|output| output.value).sum();
-        let output_value: u64 = outputs.values().map(|output| output.value).sum();
+        let input_value = Amount::checked_sum(inputs.values().map(|output| output.value))
+            .ok_or(BtcError::ValueOverflow)?;
+        let output_value = Amount::checked_sum(outputs.values().map(|output| output.value))
+            .ok_or(BtcError::ValueOverflow)?;
+        input_value
+            .checked_sub(output_value)
+            .ok_or(BtcError::InsufficientInputs {
+                inputs: input_value,
+                outputs: output_value,
+            })
+    }
 
-        Ok(input_value - output_value)
+    pub fn sigops(&self, utxos: &dyn UtxoView) -> usize {
+        let created = self
+            .transactions
+            .iter()
+            .flat_map(|transaction| {
+                (0..transaction.outputs.len())
+                    .map(move |index| (transaction.output_hash(index), &transaction.outputs[index]))
+            })
+            .collect::<HashMap<_, _>>();
+        self.transactions
+            .iter()
+            .map(|transaction| {
+                transaction.sigops(|hash| {
+                    utxos
+                        .get(hash)
+                        .map(|entry| &entry.output)
+                        .or_else(|| created.get(hash).copied())
+                })
+            })
+            .fold(0, usize::saturating_add)
     }
 
     pub fn verify_transactions(
         &self,
         predicted_block_height: u64,
-        utxos: &HashMap<Hash, (bool, TransactionOutput)>,
+        utxos: &dyn UtxoView,
+        params: &ChainParams,
     ) -> Result<()> {
         let mut inputs: HashMap<Hash, TransactionOutput> = HashMap::new();
+        consensus_rule!(
+            "BLK-NONEMPTY",
+            "A block holds at least its coinbase transaction"
+        );
         if self.transactions.is_empty() {
             return Err(BtcError::InvalidTransaction);
         }
+        consensus_rule!(
+            "BLK-SIZE",
+            "A serialized block is at most MAX_BLOCK_SIZE bytes"
+        );
+        if self.serialized_size() > crate::MAX_BLOCK_SIZE {
+            return Err(BtcError::InvalidBlock);
+        }
+        // Counted before any signature is checked
+        consensus_rule!(
+            "BLK-SIGOPS",
+            "A block needs at most MAX_BLOCK_SIGOPS signature checks"
+        );
+        if self.sigops(utxos) > crate::MAX_BLOCK_SIGOPS {
+            return Err(BtcError::TooManySigops);
+        }
+        consensus_rule!("BLK-UNIQUE-TXID", "No transaction appears twice in a block");
+        let mut txids = HashSet::new();
+        if !self
+            .transactions
+            .iter()
+            .all(|transaction| txids.insert(transaction.txid()))
+        {
+            return Err(BtcError::DuplicateTransaction);
+        }
 
-        self.verify_coinbase_transaction(predicted_block_height, utxos)?;
-        for transaction in self.transactions.iter().skip(1) {
-            let mut input_value = 0;
-            let mut output_value = 0;
-            for input in &transaction.inputs {
-                let prev_output = utxos
-                    .get(&input.prev_transaction_output_hash)
-                    .map(|(_, output)| output);
-                if prev_output.is_none() {
-                    return Err(BtcError::InvalidTransaction);
-                }
-                let prev_output = prev_output.unwrap();
-                if inputs.contains_key(&input.prev_transaction_output_hash) {
-                    return Err(BtcError::InvalidTransaction);
-                }
+        // Outputs of earlier transactions in the block, spendable by later ones
+        let mut created: HashMap<Hash, UtxoEntry> = HashMap::new();
+        let mut signatures = Vec::new();
+        for (index, transaction) in self.transactions.iter().enumerate().skip(1) {
+            let view = UtxoOverlay {
+                base: utxos,
+                created: &created,
+            };
+            let checks = self
+                .verify_transaction(
+                    transaction,
+                    &mut inputs,
+                    predicted_block_height,
+                    &view,
+                    params,
+                )
+                .map_err(|reason| BtcError::InvalidTransactionAt {
+                    index: TxIndex::new(index),
+                    reason: Box::new(reason),
+                })?;
+            signatures.extend(checks.into_iter().map(|(input, script)| SignatureCheck {
+                index: TxIndex::new(index),
+                transaction,
+                input,
+                script,
+            }));
+            for (output_index, output) in transaction.outputs.iter().enumerate() {
+                created.insert(
+                    transaction.output_hash(output_index),
+                    UtxoEntry::new(output.clone(), predicted_block_height, false),
+                );
+            }
+        }
+        verify_signatures(&signatures, predicted_block_height)?;
+        // Fees are only known once every other transaction checked out
+        self.verify_coinbase_transaction(predicted_block_height, utxos, params)
+    }
 
-                if !input
-                    .signature
-                    .verify(&input.prev_transaction_output_hash, &prev_output.pubkey)
-                {
-                    return Err(BtcError::InvalidSignature);
-                }
-                input_value += prev_output.value;
-                inputs.insert(input.prev_transaction_output_hash, prev_output.clone());
+    // Returns the inputs whose signatures are left to check, with the
+    // scripts they spend
+    fn verify_transaction(
+        &self,
+        transaction: &Transaction,
+        inputs: &mut HashMap<Hash, TransactionOutput>,
+        predicted_block_height: u64,
+        utxos: &dyn UtxoView,
+        params: &ChainParams,
+    ) -> Result<Vec<(usize, Script)>> {
+        consensus_rule!(
+            "TX-COINBASE",
+            "Only the first transaction of a block is a coinbase"
+        );
+        if transaction.coinbase_height.is_some() {
+            return Err(BtcError::InvalidTransaction);
+        }
+        consensus_rule!(
+            "TX-NONEMPTY",
+            "Transactions other than the coinbase spend outputs"
+        );
+        if transaction.inputs.is_empty() {
+            return Err(BtcError::InvalidTransaction);
+        }
+        consensus_rule!(
+            "TX-FINAL",
+            "Transactions are final at the height and time of their block"
+        );
+        if !transaction.is_final(predicted_block_height, self.header.timestamp) {
+            return Err(BtcError::NonFinalTransaction);
+        }
+        let mut input_value = Amount::ZERO;
+        let mut output_value = Amount::ZERO;
+        let mut signatures = Vec::new();
+        for (index, input) in transaction.inputs.iter().enumerate() {
+            let prev_entry = utxos.get(&input.prev_transaction_output_hash);
+            consensus_rule!(
+                "TX-INPUT",
+                "Inputs spend unspent outputs of the chain or this block"
+            );
+            let Some(prev_entry) = prev_entry else {
+                return Err(BtcError::MissingUtxo {
+                    outpoint: input.prev_transaction_output_hash,
+                });
+            };
+            consensus_rule!(
+                "TX-MATURITY",
+                "Coinbase outputs need coinbase_maturity confirmations"
+            );
+            if !prev_entry.is_mature(predicted_block_height, params.coinbase_maturity) {
+                return Err(BtcError::ImmatureCoinbase);
+            }
+            consensus_rule!(
+                "TX-SEQUENCE",
+                "Relative lock times in input sequences have passed"
+            );
+            if !prev_entry.relative_lock_satisfied(input.sequence, predicted_block_height) {
+                return Err(BtcError::NonFinalTransaction);
             }
-            for output in &transaction.outputs {
-                output_value += output.value;
+            let prev_output = &prev_entry.output;
+            consensus_rule!("TX-DOUBLE-SPEND", "No output is spent twice within a block");
+            if inputs.contains_key(&input.prev_transaction_output_hash) {
+                return Err(BtcError::DoubleSpend {
+                    outpoint: input.prev_transaction_output_hash,
+                });
             }
 
-            if input_value < output_value {
-                return Err(BtcError::InvalidTransaction);
+            if params.verifies_signatures(predicted_block_height) {
+                signatures.push((index, prev_output.script.clone()));
             }
-            if input_value < output_value {
-                return Err(BtcError::InvalidTransaction);
+            input_value = input_value
+                .checked_add(prev_output.value)
+                .ok_or(BtcError::ValueOverflow)?;
+            inputs.insert(input.prev_transaction_output_hash, prev_output.clone());
+        }
+        consensus_rule!("TX-SCRIPT", "Output scripts are well formed");
+        for output in &transaction.outputs {
+            if !output.script.is_well_formed() {
+                return Err(BtcError::InvalidScript);
             }
+            output_value = output_value
+                .checked_add(output.value)
+                .ok_or(BtcError::ValueOverflow)?;
         }
-        Ok(())
+
+        consensus_rule!(
+            "TX-VALUE",
+            "Outputs do not exceed inputs and value sums do not overflow"
+        );
+        if input_value < output_value {
+            return Err(BtcError::InsufficientInputs {
+                inputs: input_value,
+                outputs: output_value,
+            });
+        }
+        Ok(signatures)
+    }
+}
+
+// A signature check deferred until every other rule of the block passed,
+// so that all of a block's checks can run at once
+struct SignatureCheck<'a> {
+    index: TxIndex,
+    transaction: &'a Transaction,
+    input: usize,
+    script: Script,
+}
+
+impl SignatureCheck<'_> {
+    fn verify(&self, spending_height: u64) -> bool {
+        let witness = &self.transaction.inputs[self.input].witness;
+        self.transaction
+            .signature_digest(self.input, witness.sighash)
+            .is_some_and(|digest| self.script.verify(witness, &digest, spending_height))
+    }
+
+    // The single signature left to check once every other condition of the
+    // script holds, None if the script has to run in full
+    fn batched(&self, spending_height: u64) -> Option<(Hash, &Signature, &PublicKey)> {
+        let witness = &self.transaction.inputs[self.input].witness;
+        let [signature] = witness.signatures.as_slice() else {
+            return None;
+        };
+        if signature.scheme() != self.script.scheme() {
+            return None;
+        }
+        let pubkey = self.script.signer(witness, spending_height)?;
+        let digest = self
+            .transaction
+            .signature_digest(self.input, witness.sighash)?;
+        Some((digest, signature, pubkey))
+    }
+}
+
+// The failure reported is the first in block order, however the checks ran
+fn verify_signatures(checks: &[SignatureCheck], spending_height: u64) -> Result<()> {
+    consensus_rule!(
+        "TX-SIGNATURE",
+        "Witnesses satisfy spent scripts from assume_valid on"
+    );
+    #[cfg(feature = "parallel-verify")]
+    let batched: Vec<_> = checks
+        .par_iter()
+        .map(|check| check.batched(spending_height))
+        .collect();
+    #[cfg(not(feature = "parallel-verify"))]
+    let batched: Vec<_> = checks
+        .iter()
+        .map(|check| check.batched(spending_height))
+        .collect();
+    let batch = batched.iter().flatten().copied().collect::<Vec<_>>();
+    let valid = crypto::verify_batch(&batch)
+        && checks
+            .iter()
+            .zip(&batched)
+            .filter(|(_, batched)| batched.is_none())
+            .all(|(check, _)| check.verify(spending_height));
+    if valid {
+        return Ok(());
+    }
+    // Only invalid blocks get here. A failed batch is never accepted, even
+    // if the checks one by one cannot find the culprit.
+    match checks.iter().find(|check| !check.verify(spending_height)) {
+        Some(check) => Err(BtcError::InvalidTransactionAt {
+            index: check.index,
+            reason: Box::new(BtcError::BadSignature {
+                txid: check.transaction.hash(),
+                input: check.input,
+            }),
+        }),
+        None => Err(BtcError::InvalidSignature),
+    }
+}
+
+// A block whose proof of work and merkle root were checked, only
+// Block::precheck creates one
+#[derive(Clone, Debug)]
+pub struct PrecheckedBlock(Block);
+
+impl PrecheckedBlock {
+    pub fn block(&self) -> &Block {
+        &self.0
+    }
+
+    pub fn into_block(self) -> Block {
+        self.0
     }
 }
 
@@ -200,3 +604,222 @@ impl Saveable for Block {
             .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to serialize Block"))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{PrivateKey, PublicKey, SignatureScheme};
+    use crate::script::{Script, Witness};
+    use crate::types::{LockTime, TransactionInput};
+    use chrono::Duration;
+
+    const HEIGHT: u64 = 10;
+
+    fn output(pubkey: &PublicKey, value: u64) -> TransactionOutput {
+        TransactionOutput {
+            value: Amount::from_sat(value),
+            script: Script::PayToPubkey(pubkey.clone()),
+        }
+    }
+
+    // A block at HEIGHT whose coinbase pays the subsidy to the first of
+    // `outputs` outputs and nothing to the rest
+    fn block(pubkey: &PublicKey, outputs: usize, transactions: Vec<Transaction>) -> Block {
+        let outputs = (0..outputs)
+            .map(|i| match i {
+                0 => output(pubkey, crate::INITIAL_REWARD * 10u64.pow(8)),
+                _ => output(pubkey, 0),
+            })
+            .collect();
+        let mut all = vec![Transaction::coinbase(HEIGHT, outputs)];
+        all.extend(transactions);
+        let merkle_root = MerkleRoot::calculate(&all);
+        let timestamp = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let header = BlockHeader::new(timestamp, 0, Hash::zero(), merkle_root, crate::MIN_TARGET);
+        Block::new(header, all)
+    }
+
+    #[test]
+    fn block_size_boundary() {
+        let params = ChainParams::mainnet();
+        let key = PrivateKey::generate();
+        let outpoint = Hash::hash_bytes(b"utxo");
+        let utxos = HashMap::from([(
+            outpoint,
+            UtxoEntry::new(output(&key.public_key(), 100_000), 0, false),
+        )]);
+        // Zero bytes of a witness preimage the script ignores, one byte each
+        let padded = |bytes: usize| {
+            let mut transaction = spend(&key, outpoint);
+            transaction.inputs[0].witness.preimage = Some(vec![0; bytes]);
+            block(&key.public_key(), 1, vec![transaction])
+        };
+        let base = padded(100_000).serialized_size();
+        let limit = 100_000 + crate::MAX_BLOCK_SIZE - base;
+        let block = padded(limit);
+        assert_eq!(block.serialized_size(), crate::MAX_BLOCK_SIZE);
+        assert!(block.verify_transactions(HEIGHT, &utxos, &params).is_ok());
+        assert!(matches!(
+            padded(limit + 1).verify_transactions(HEIGHT, &utxos, &params),
+            Err(BtcError::InvalidBlock)
+        ));
+    }
+
+    #[test]
+    fn rejects_transaction_without_inputs() {
+        let pubkey = PrivateKey::generate().public_key();
+        for outputs in [vec![], vec![output(&pubkey, 0)]] {
+            let block = block(&pubkey, 1, vec![Transaction::new(vec![], outputs)]);
+            assert!(matches!(
+                block.verify_transactions(HEIGHT, &HashMap::new(), &ChainParams::mainnet()),
+                Err(BtcError::InvalidTransactionAt { index, reason })
+                    if index == TxIndex::new(1) && matches!(*reason, BtcError::InvalidTransaction)
+            ));
+        }
+    }
+
+    #[test]
+    fn lock_time_boundary() {
+        let block_time = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let cases = [
+            (LockTime::Height(HEIGHT), true),
+            (LockTime::Height(HEIGHT + 1), false),
+            (LockTime::Timestamp(block_time), true),
+            (
+                LockTime::Timestamp(block_time + Duration::seconds(1)),
+                false,
+            ),
+        ];
+        for (lock_time, is_final) in cases {
+            let transaction = Transaction::new(vec![], vec![]).with_lock_time(lock_time);
+            assert_eq!(transaction.is_final(HEIGHT, block_time), is_final);
+        }
+    }
+
+    // Spends `outpoint` to `key` without a fee
+    fn spend(key: &PrivateKey, outpoint: Hash) -> Transaction {
+        let input = TransactionInput {
+            prev_transaction_output_hash: outpoint,
+            witness: Witness::default(),
+            sequence: 0,
+        };
+        let mut transaction =
+            Transaction::new(vec![input], vec![output(&key.public_key(), 100_000)]);
+        transaction
+            .sign_input(0, key, SignatureScheme::Ecdsa)
+            .unwrap();
+        transaction
+    }
+
+    #[test]
+    fn rejects_duplicate_txids() {
+        let key = PrivateKey::generate();
+        let pubkey = key.public_key();
+        let params = ChainParams::mainnet();
+        let (first, second) = (Hash::hash_bytes(b"first"), Hash::hash_bytes(b"second"));
+        let utxos = HashMap::from([
+            (first, UtxoEntry::new(output(&pubkey, 100_000), 0, false)),
+            (second, UtxoEntry::new(output(&pubkey, 100_000), 0, false)),
+        ]);
+        let distinct = vec![spend(&key, first), spend(&key, second)];
+        let block = block(&pubkey, 1, distinct);
+        assert!(block.verify_transactions(HEIGHT, &utxos, &params).is_ok());
+
+        // Caught before the second copy could count as a double spend
+        let duplicate = vec![spend(&key, first), spend(&key, first)];
+        let block = self::block(&pubkey, 1, duplicate);
+        assert!(matches!(
+            block.verify_transactions(HEIGHT, &utxos, &params),
+            Err(BtcError::DuplicateTransaction)
+        ));
+    }
+
+    #[test]
+    fn sigop_limit_boundary() {
+        // Signatures are only counted here, so they need not verify
+        let mut params = ChainParams::regtest();
+        params.assume_valid = HEIGHT + 1;
+        let pubkeys: Vec<_> = (1..=crate::MAX_MULTISIG_KEYS as u8)
+            .map(|byte| PrivateKey::from_bytes(&[byte; 32]).unwrap().public_key())
+            .collect();
+        let multisig = TransactionOutput {
+            value: Amount::from_sat(100_000),
+            script: Script::Multisig {
+                threshold: 1,
+                pubkeys,
+            },
+        };
+        let outpoint = Hash::hash_bytes(b"multisig");
+        let utxos = HashMap::from([(outpoint, UtxoEntry::new(multisig, 0, false))]);
+        // Each signature counts once per key of the script
+        let limit = crate::MAX_BLOCK_SIGOPS / crate::MAX_MULTISIG_KEYS;
+        let key = PrivateKey::generate();
+        let signature = key.sign(&Hash::zero());
+        let with_signatures = |count: usize| {
+            let mut transaction = spend(&key, outpoint);
+            transaction.inputs[0].witness.signatures = vec![signature.clone(); count];
+            block(&key.public_key(), 1, vec![transaction])
+        };
+        let block = with_signatures(limit);
+        assert_eq!(block.sigops(&utxos), crate::MAX_BLOCK_SIGOPS);
+        assert!(block.verify_transactions(HEIGHT, &utxos, &params).is_ok());
+        assert!(matches!(
+            with_signatures(limit + 1).verify_transactions(HEIGHT, &utxos, &params),
+            Err(BtcError::TooManySigops)
+        ));
+    }
+
+    #[test]
+    fn rejects_one_bad_signature_in_a_batch() {
+        let params = ChainParams::regtest();
+        let key = PrivateKey::from_bytes(&[1; 32]).unwrap();
+        let schnorr = TransactionOutput {
+            value: Amount::from_sat(100_000),
+            script: Script::PayToSchnorrPubkey(key.public_key()),
+        };
+        let outpoints: Vec<_> = (0..4u8).map(|byte| Hash::hash_bytes(&[byte])).collect();
+        let utxos: HashMap<_, _> = outpoints
+            .iter()
+            .map(|outpoint| (*outpoint, UtxoEntry::new(schnorr.clone(), 0, false)))
+            .collect();
+        let spends = |bad: Option<usize>| {
+            outpoints
+                .iter()
+                .enumerate()
+                .map(|(index, outpoint)| {
+                    let input = TransactionInput {
+                        prev_transaction_output_hash: *outpoint,
+                        witness: Witness::default(),
+                        sequence: 0,
+                    };
+                    let mut transaction =
+                        Transaction::new(vec![input], vec![output(&key.public_key(), 100_000)]);
+                    let signer = match bad == Some(index) {
+                        true => PrivateKey::from_bytes(&[2; 32]).unwrap(),
+                        false => key.clone(),
+                    };
+                    transaction
+                        .sign_input(0, &signer, SignatureScheme::Schnorr)
+                        .unwrap();
+                    transaction
+                })
+                .collect::<Vec<_>>()
+        };
+        let block = block(&key.public_key(), 1, spends(None));
+        assert!(block.verify_transactions(HEIGHT, &utxos, &params).is_ok());
+
+        let bad = spends(Some(2));
+        let txid = bad[2].hash();
+        let block = self::block(&key.public_key(), 1, bad);
+        match block.verify_transactions(HEIGHT, &utxos, &params) {
+            Err(BtcError::InvalidTransactionAt { index, reason }) => {
+                assert_eq!(index, TxIndex::new(3));
+                assert!(matches!(
+                    *reason,
+                    BtcError::BadSignature { txid: got, input: 0 } if got == txid
+                ));
+            }
+            other => panic!("expected a bad signature, got {other:?}"),
+        }
+    }
+}
@@ -1,30 +1,157 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::usize;
 
+use super::AddressIndex;
 use super::Block;
-use super::{Transaction, TransactionOutput};
+use super::BlockHeader;
+use super::BlockHeight;
+use super::Mempool;
+use super::MempoolEntry;
+use super::MempoolStats;
+use super::PrecheckedBlock;
+use super::Transaction;
+use super::TransactionOutput;
+use super::TxIndex;
+use crate::amount::Amount;
+use crate::consensus_rule;
+use crate::crypto::PublicKey;
 use crate::error::{BtcError, Result};
+use crate::params::ChainParams;
+use crate::script::Script;
 use crate::sha256::Hash;
-use crate::util::MerkleRoot;
+use crate::store::ChainStore;
 use crate::util::Saveable;
 use crate::U256;
-use bigdecimal::BigDecimal;
 use chrono::{DateTime, Utc};
+#[cfg(feature = "parallel-verify")]
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write};
 
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UtxoEntry {
+    pub output: TransactionOutput,
+    pub height: u64,
+    pub is_coinbase: bool,
+}
+
+impl UtxoEntry {
+    pub fn new(output: TransactionOutput, height: u64, is_coinbase: bool) -> Self {
+        UtxoEntry {
+            output,
+            height,
+            is_coinbase,
+        }
+    }
+
+    // Only coinbase outputs have to wait `maturity` blocks
+    pub fn is_mature(&self, spending_height: u64, maturity: u64) -> bool {
+        !self.is_coinbase || spending_height.saturating_sub(self.height) >= maturity
+    }
+
+    pub fn relative_lock_satisfied(&self, sequence: u64, spending_height: u64) -> bool {
+        spending_height.saturating_sub(self.height) >= sequence
+    }
+}
+
+// Where the txindex found a confirmed transaction
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TxLocation {
+    pub block_hash: Hash,
+    pub height: BlockHeight,
+    pub index: TxIndex,
+}
+
+// Per block samples over the last CHAIN_STATS_WINDOW blocks, updated when
+// a block is connected
+#[derive(Clone, Debug, Default)]
+struct StatsWindow {
+    intervals: VecDeque<i64>,
+    utxo_counts: VecDeque<usize>,
+    fees: VecDeque<Amount>,
+}
+
+impl StatsWindow {
+    fn record(&mut self, interval: Option<i64>, utxo_count: usize, fees: Amount) {
+        if let Some(interval) = interval {
+            push_bounded(&mut self.intervals, interval);
+        }
+        push_bounded(&mut self.utxo_counts, utxo_count);
+        push_bounded(&mut self.fees, fees);
+    }
+}
+
+fn push_bounded<T>(window: &mut VecDeque<T>, value: T) {
+    if window.len() == crate::CHAIN_STATS_WINDOW {
+        window.pop_front();
+    }
+    window.push_back(value);
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ChainStats {
+    pub tip: Option<BlockHeight>,
+    pub total_work: U256,
+    pub target: U256,
+    // How many times harder than the network minimum the target is
+    pub difficulty: f64,
+    // Value of all unspent outputs
+    pub supply: Amount,
+    // Blocks the statistics window holds
+    pub blocks: usize,
+    // Seconds between consecutive blocks over the window and over the last
+    // CHAIN_STATS_SHORT_WINDOW blocks
+    pub average_block_interval: f64,
+    pub recent_block_interval: f64,
+    // Change in the number of UTXOs per block
+    pub utxo_growth_rate: f64,
+    pub mean_fees: Amount,
+    pub utxo_count: usize,
+    pub mempool: MempoolStats,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Blockchain {
-    utxos: HashMap<Hash, (bool, TransactionOutput)>,
+    #[serde(default)]
+    version: u32,
+    utxos: HashMap<Hash, UtxoEntry>,
     target: U256,
     blocks: Vec<Block>,
-
-    #[serde(default, skip_serializing)]
-    mempool: Vec<(DateTime<Utc>, Transaction)>,
+    #[serde(skip)]
+    stats: StatsWindow,
+    #[serde(skip)]
+    params: ChainParams,
+    #[serde(skip)]
+    address_index: AddressIndex,
+    // Cumulative work up to and including each block
+    #[serde(skip)]
+    chainwork: Vec<U256>,
+    // Every confirmed txid, so a transaction cannot be included again
+    #[serde(skip)]
+    txids: HashSet<Hash>,
+    // Fee rates of recently confirmed transactions with the number of blocks
+    // it took to confirm them, oldest first
+    #[serde(skip)]
+    fee_samples: VecDeque<(u64, u64)>,
+    // Hashes of the leading blocks whose transactions were pruned, the
+    // blocks themselves only keep their headers
+    #[serde(skip)]
+    pruned: Vec<Hash>,
+    // Locations of confirmed transactions by txid, None unless enabled
+    #[serde(skip)]
+    tx_index: Option<HashMap<Hash, TxLocation>>,
+    #[serde(skip)]
+    heights: HashMap<Hash, BlockHeight>,
+    // Outputs each block spent, restored when it is disconnected. Empty for
+    // chains rebuilt from a pruned UTXO set.
+    #[serde(skip)]
+    undo: Vec<Vec<(Hash, UtxoEntry)>>,
 }
 
 impl Blockchain {
-    pub fn utxos(&self) -> &HashMap<Hash, (bool, TransactionOutput)> {
+    pub fn utxos(&self) -> &HashMap<Hash, UtxoEntry> {
         &self.utxos
     }
 
@@ -32,244 +159,1280 @@ impl Blockchain {
         self.target
     }
 
-    pub fn blocks(&self) -> impl Iterator<Item = &Block> {
+    // Pruned blocks come without their transactions
+    pub fn blocks(&self) -> impl DoubleEndedIterator<Item = &Block> + ExactSizeIterator {
         self.blocks.iter()
     }
 
-    pub fn block_height(&self) -> u64 {
+    // Headers of the blocks in the range that exist, pruned ones included
+    pub fn headers(
+        &self,
+        range: Range<BlockHeight>,
+    ) -> impl DoubleEndedIterator<Item = &BlockHeader> + ExactSizeIterator {
+        let end = range.end.index().min(self.blocks.len());
+        let start = range.start.index().min(end);
+        self.blocks[start..end].iter().map(|block| &block.header)
+    }
+
+    // Number of blocks, one more than the tip height
+    pub fn block_count(&self) -> u64 {
         self.blocks.len() as u64
     }
 
-    pub fn new() -> Self {
+    pub fn tip_height(&self) -> Option<BlockHeight> {
+        BlockHeight::tip_of(self.block_count())
+    }
+
+    // Height the next connected block gets
+    pub fn next_height(&self) -> BlockHeight {
+        BlockHeight::after(self.block_count())
+    }
+
+    pub fn block_by_height(&self, height: BlockHeight) -> Option<&Block> {
+        if self.is_pruned(height) {
+            return None;
+        }
+        self.blocks.get(height.index())
+    }
+
+    pub fn block_by_hash(&self, hash: &Hash) -> Option<&Block> {
+        self.block_by_height(self.height_of(hash)?)
+    }
+
+    pub fn height_of(&self, hash: &Hash) -> Option<BlockHeight> {
+        self.heights.get(hash).copied()
+    }
+
+    // Hashes from the tip back to genesis, dense near the tip and
+    // exponentially spaced further down, for a peer to find where our
+    // chains part
+    pub fn locator(&self) -> Vec<Hash> {
+        let Some(tip) = self.tip_height() else {
+            return Vec::new();
+        };
+        tip.locator_heights()
+            .into_iter()
+            .filter_map(|height| self.hash_at(height))
+            .collect()
+    }
+
+    // The highest block of a peer's locator that is on this chain, their
+    // common ancestor. None when not even genesis matches.
+    pub fn find_fork_point(&self, locator: &[Hash]) -> Option<BlockHeight> {
+        locator
+            .iter()
+            .take(crate::MAX_LOCATOR_HASHES)
+            .find_map(|hash| self.height_of(hash))
+    }
+
+    pub fn header_at(&self, height: BlockHeight) -> Option<&BlockHeader> {
+        self.blocks.get(height.index()).map(|block| &block.header)
+    }
+
+    pub fn hash_at(&self, height: BlockHeight) -> Option<Hash> {
+        match self.pruned.get(height.index()) {
+            Some(hash) => Some(*hash),
+            None => self.blocks.get(height.index()).map(Block::hash),
+        }
+    }
+
+    // Number of leading blocks whose transactions were pruned
+    pub fn pruned_count(&self) -> u64 {
+        self.pruned.len() as u64
+    }
+
+    pub fn is_pruned(&self, height: BlockHeight) -> bool {
+        height.get() < self.pruned_count()
+    }
+
+    pub fn params(&self) -> &ChainParams {
+        &self.params
+    }
+
+    pub fn new(params: ChainParams) -> Self {
         Blockchain {
+            version: crate::CHAIN_FORMAT_VERSION,
             blocks: vec![],
             utxos: HashMap::new(),
-            target: crate::MIN_TARGET,
-            mempool: vec![],
+            target: params.min_target.round_to_compact(),
+            stats: StatsWindow::default(),
+            params,
+            address_index: AddressIndex::default(),
+            chainwork: vec![],
+            txids: HashSet::new(),
+            fee_samples: VecDeque::new(),
+            pruned: vec![],
+            tx_index: None,
+            heights: HashMap::new(),
+            undo: vec![],
+        }
+    }
+
+    // Starts the chain at the network's genesis block
+    pub fn with_genesis(params: ChainParams) -> Self {
+        let genesis = Block::genesis(&params);
+        let mut blockchain = Blockchain::new(params);
+        blockchain.add_block(genesis).expect("Bug: Impossible");
+        blockchain
+    }
+
+    pub fn total_work(&self) -> U256 {
+        self.chainwork.last().copied().unwrap_or_default()
+    }
+
+    pub fn chainwork_at(&self, height: BlockHeight) -> Option<U256> {
+        self.chainwork.get(height.index()).copied()
+    }
+
+    // Transactions paying to or spending from the key, newest first
+    pub fn address_history(&self, pubkey: &PublicKey, page: usize) -> Vec<(Hash, BlockHeight)> {
+        self.address_index
+            .history(&Script::pubkey_hash(pubkey), page)
+    }
+
+    // Builds the txindex from the blocks so far, connected blocks extend it.
+    // Pruned blocks have no transactions left to index.
+    pub fn enable_tx_index(&mut self) {
+        let mut tx_index = HashMap::new();
+        for (height, block) in self.blocks.iter().enumerate() {
+            index_transactions(&mut tx_index, block, BlockHeight::new(height as u64));
+        }
+        self.tx_index = Some(tx_index);
+    }
+
+    pub fn tx_location(&self, txid: &Hash) -> Option<&TxLocation> {
+        self.tx_index.as_ref()?.get(txid)
+    }
+
+    // None if the txindex is disabled, the transaction unknown or its block pruned
+    pub fn transaction(&self, txid: &Hash) -> Option<&Transaction> {
+        let location = self.tx_location(txid)?;
+        self.block_by_height(location.height)?
+            .transactions
+            .get(location.index.get())
+    }
+
+    // Unspent outputs referencing the key, looked up in the address index
+    pub fn utxos_of(&self, pubkey: &PublicKey) -> impl Iterator<Item = (&Hash, &UtxoEntry)> {
+        self.address_index
+            .unspent(&Script::pubkey_hash(pubkey))
+            .filter_map(|outpoint| self.utxos.get_key_value(outpoint))
+    }
+
+    pub fn stats(&self, mempool: &Mempool) -> ChainStats {
+        let stats = &self.stats;
+        let average = |intervals: &[i64]| {
+            if intervals.is_empty() {
+                0.0
+            } else {
+                intervals.iter().sum::<i64>() as f64 / intervals.len() as f64
+            }
+        };
+        let intervals = stats.intervals.iter().copied().collect::<Vec<_>>();
+        let recent = intervals
+            .len()
+            .saturating_sub(crate::CHAIN_STATS_SHORT_WINDOW);
+        let utxo_growth_rate = match (stats.utxo_counts.front(), stats.utxo_counts.back()) {
+            (Some(first), Some(last)) if stats.utxo_counts.len() > 1 => {
+                (*last as f64 - *first as f64) / (stats.utxo_counts.len() - 1) as f64
+            }
+            _ => 0.0,
+        };
+        let mean_fees = Amount::checked_sum(stats.fees.iter().copied())
+            .and_then(|total| total.checked_div(stats.fees.len() as u64))
+            .unwrap_or(Amount::ZERO);
+        let supply = Amount::checked_sum(self.utxos.values().map(|entry| entry.output.value));
+        ChainStats {
+            tip: self.tip_height(),
+            total_work: self.total_work(),
+            target: self.target,
+            difficulty: self.target.difficulty(self.params.min_target),
+            supply: supply.unwrap_or(Amount::ZERO),
+            blocks: stats.utxo_counts.len(),
+            average_block_interval: average(&intervals),
+            recent_block_interval: average(&intervals[recent..]),
+            utxo_growth_rate,
+            mean_fees,
+            utxo_count: self.utxos.len(),
+            mempool: mempool.stats(),
         }
     }
 
     pub fn add_block(&mut self, block: Block) -> Result<()> {
+        self.connect(block, false)
+    }
+
+    // Skips the checks Block::precheck already did
+    pub fn add_prechecked_block(&mut self, block: PrecheckedBlock) -> Result<()> {
+        self.connect(block.into_block(), true)
+    }
+
+    fn connect(&mut self, block: Block, prechecked: bool) -> Result<()> {
+        consensus_rule!(
+            "CHAIN-CHECKPOINT",
+            "Blocks at checkpoint heights match the checkpoint"
+        );
+        if let Some(checkpoint) = self.params.checkpoint(self.next_height().get()) {
+            if block.hash() != checkpoint {
+                return Err(BtcError::CheckpointMismatch);
+            }
+        }
         if self.blocks.is_empty() {
-            if block.header.prev_block_hash != Hash::zero() {
-                println!("zero hash!");
+            consensus_rule!(
+                "CHAIN-GENESIS",
+                "The first block is the genesis block of the network"
+            );
+            if block.hash() != Block::genesis(&self.params).hash() {
                 return Err(BtcError::InvalidBlock);
             }
         } else {
             let last_block = self.blocks.last().unwrap();
-            if !block.header.hash().matches_target(block.header.target) {
-                println!("does not match target");
-                return Err(BtcError::InvalidBlock);
+            consensus_rule!("HDR-BITS", "Headers commit to the target the chain expects");
+            let expected = self.target.to_compact();
+            if block.header.bits != expected {
+                return Err(BtcError::UnexpectedTarget {
+                    expected,
+                    got: block.header.bits,
+                });
             }
+            if !prechecked {
+                block.check_proof_of_work()?;
+            }
+            consensus_rule!("HDR-PREV", "A block extends the current tip");
             if block.header.prev_block_hash != last_block.hash() {
-                println!("prev hash is wrong");
-                return Err(BtcError::InvalidBlock);
+                return Err(BtcError::StaleParent);
             }
-            let calculated_merkle_root = MerkleRoot::calculate(&block.transactions);
-            if calculated_merkle_root != block.header.merkle_root {
-                println!("Invalid Merkle root");
-                return Err(BtcError::InvalidMerkleRoot);
+            if !prechecked {
+                block.check_merkle_root()?;
             }
 
-            if block.header.timestamp <= last_block.header.timestamp {
-                return Err(BtcError::InvalidBlock);
+            let median_time_past = self.median_time_past().expect("Bug: Impossible");
+            consensus_rule!("HDR-MTP", "The timestamp is after the median time past");
+            if block.header.timestamp <= median_time_past {
+                return Err(BtcError::TimestampTooOld {
+                    got: block.header.timestamp,
+                    min: median_time_past,
+                });
+            }
+            consensus_rule!(
+                "HDR-FUTURE",
+                "Timestamps are at most MAX_FUTURE_BLOCK_TIME ahead"
+            );
+            let max_timestamp =
+                Utc::now() + chrono::Duration::seconds(crate::MAX_FUTURE_BLOCK_TIME);
+            if block.header.timestamp > max_timestamp {
+                return Err(BtcError::TimestampTooNew {
+                    got: block.header.timestamp,
+                    max: max_timestamp,
+                });
             }
 
-            block.verify_transactions(self.block_height(), &self.utxos)?;
+            block.verify_transactions(self.next_height().get(), &self.utxos, &self.params)?;
+
+            consensus_rule!(
+                "CHAIN-UNIQUE-TXID",
+                "Confirmed transactions are not included again"
+            );
+            if block
+                .transactions
+                .iter()
+                .any(|tx| self.txids.contains(&tx.txid()))
+            {
+                return Err(BtcError::DuplicateTransaction);
+            }
         }
 
-        let block_transaction: HashSet<_> = block.transactions.iter().map(|tx| tx.hash()).collect();
-        self.mempool
-            .retain(|(_, tx)| !block_transaction.contains(&tx.hash()));
+        let height = self.next_height();
+        self.address_index.connect(&block, height, &self.utxos);
+        let spent = apply_block_to_utxos(&mut self.utxos, &block, height);
+        self.undo.push(spent);
+        let interval = self
+            .blocks
+            .last()
+            .map(|last_block| (block.header.timestamp - last_block.header.timestamp).num_seconds());
+        let fees = block_fees(&block, height, &self.params);
+        self.stats.record(interval, self.utxos.len(), fees);
+        self.chainwork
+            .push(self.total_work().saturating_add(block.header.work()));
+        self.txids
+            .extend(block.transactions.iter().map(|tx| tx.txid()));
+        if let Some(tx_index) = &mut self.tx_index {
+            index_transactions(tx_index, &block, height);
+        }
+        self.heights.insert(block.hash(), height);
         self.blocks.push(block);
         self.try_adjust_target();
         Ok(())
     }
 
-    pub fn mempool(&self) -> &[(DateTime<Utc>, Transaction)] {
-        &self.mempool
+    // Undoes the tip block and hands it back. The genesis block and blocks
+    // connected before a pruned rebuild cannot be disconnected. Statistics
+    // keep what the block added.
+    pub fn disconnect_tip(&mut self) -> Result<Block> {
+        if self.blocks.len() <= 1 || self.undo.len() != self.blocks.len() {
+            return Err(BtcError::CannotDisconnect);
+        }
+        let block = self.blocks.pop().expect("Bug: Impossible");
+        let spent = self.undo.pop().expect("Bug: Impossible");
+        // Outputs created and spent within the block come back only to go again
+        self.utxos.extend(spent);
+        for transaction in &block.transactions {
+            for index in 0..transaction.outputs.len() {
+                self.utxos.remove(&transaction.output_hash(index));
+            }
+        }
+        self.address_index.disconnect();
+        self.chainwork.pop();
+        for transaction in &block.transactions {
+            let txid = transaction.txid();
+            self.txids.remove(&txid);
+            if let Some(tx_index) = &mut self.tx_index {
+                tx_index.remove(&txid);
+            }
+        }
+        self.heights.remove(&block.hash());
+        // The block committed to the target that was expected before it
+        self.target = block.header.target();
+        Ok(block)
     }
 
-    pub fn add_to_mempool(&mut self, transaction: Transaction) -> Result<()> {
-        let mut known_inputs = HashSet::new();
-        for input in &transaction.inputs {
-            if !self.utxos.contains_key(&input.prev_transaction_output_hash) {
-                return Err(BtcError::InvalidTransaction);
+    // Switches to `branch`, whose first block extends a block of this chain,
+    // if it ends up with more work. Returns the replaced blocks oldest first,
+    // for Mempool::resurrect. A branch with an invalid block or less work
+    // leaves the chain as it was.
+    pub fn reorganize(&mut self, branch: Vec<Block>) -> Result<Vec<Block>> {
+        let Some(first) = branch.first() else {
+            return Ok(Vec::new());
+        };
+        let fork_point = self
+            .height_of(&first.header.prev_block_hash)
+            .ok_or(BtcError::StaleParent)?;
+        if self.pruned_count() > fork_point.get() {
+            return Err(BtcError::Pruned {
+                below: self.pruned_count(),
+            });
+        }
+        let work = self.total_work();
+        let mut replaced = Vec::new();
+        while self.block_count() > fork_point.get() + 1 {
+            replaced.push(self.disconnect_tip()?);
+        }
+        replaced.reverse();
+        let mut connected = 0;
+        let mut result = Ok(());
+        for block in branch {
+            result = self.add_block(block);
+            if result.is_err() {
+                break;
             }
-            if known_inputs.contains(&input.prev_transaction_output_hash) {
-                return Err(BtcError::InvalidTransaction);
+            connected += 1;
+        }
+        if result.is_ok() && self.total_work() <= work {
+            result = Err(BtcError::InsufficientWork);
+        }
+        if let Err(e) = result {
+            for _ in 0..connected {
+                self.disconnect_tip()?;
             }
-            known_inputs.insert(input.prev_transaction_output_hash);
+            for block in replaced {
+                self.add_block(block)?;
+            }
+            return Err(e);
         }
+        Ok(replaced)
+    }
 
-        for input in &transaction.inputs {
-            if let Some((true, _)) = self.utxos.get(&input.prev_transaction_output_hash) {
-                let referencing_transaction =
-                    self.mempool
-                        .iter()
-                        .enumerate()
-                        .find(|(_, (_, transaction))| {
-                            transaction
-                                .outputs
-                                .iter()
-                                .any(|output| output.hash() == input.prev_transaction_output_hash)
-                        });
-                if let Some((idx, (_, referencing_transaction))) = referencing_transaction {
-                    for input in &referencing_transaction.inputs {
-                        self.utxos
-                            .entry(input.prev_transaction_output_hash)
-                            .and_modify(|(marked, _)| {
-                                *marked = false;
-                            });
-                    }
-                    self.mempool.remove(idx);
-                } else {
-                    self.utxos
-                        .entry(input.prev_transaction_output_hash)
-                        .and_modify(|(marked, _)| {
-                            *marked = false;
-                        });
-                }
+    // Samples the mempool entries the tip block confirmed. Only the node
+    // knows since when they were waiting, blocks alone do not tell.
+    pub fn record_confirmed(&mut self, confirmed: &[MempoolEntry]) {
+        let Some(tip) = self.tip_height() else {
+            return;
+        };
+        for entry in confirmed {
+            let blocks = tip.get().saturating_sub(entry.height) + 1;
+            if self.fee_samples.len() == crate::FEE_ESTIMATE_SAMPLES {
+                self.fee_samples.pop_front();
             }
+            self.fee_samples.push_back((entry.fee_rate(), blocks));
         }
+    }
 
-        let all_inputs = transaction
-            .inputs
+    // Lowest fee rate, in sats per 1000 bytes, at which recent transactions
+    // paying at least as much confirmed within `confirmation_target` blocks
+    // FEE_ESTIMATE_SUCCESS_PERCENT of the time. None without enough samples.
+    pub fn estimate_fee_rate(&self, confirmation_target: u64) -> Option<u64> {
+        let mut samples = self.fee_samples.iter().copied().collect::<Vec<_>>();
+        samples.sort_unstable_by_key(|&(fee_rate, _)| std::cmp::Reverse(fee_rate));
+        let mut estimate = None;
+        let (mut total, mut in_time) = (0u64, 0u64);
+        for (fee_rate, blocks) in samples {
+            total += 1;
+            if blocks <= confirmation_target {
+                in_time += 1;
+            }
+            if total < crate::FEE_ESTIMATE_MIN_SAMPLES {
+                continue;
+            }
+            // Cheaper transactions only do worse, stop at the first miss
+            if in_time * 100 < total * crate::FEE_ESTIMATE_SUCCESS_PERCENT {
+                break;
+            }
+            estimate = Some(fee_rate);
+        }
+        estimate
+    }
+
+    pub fn median_time_past(&self) -> Option<DateTime<Utc>> {
+        let mut timestamps = self
+            .blocks
             .iter()
-            .map(|input| {
-                self.utxos
-                    .get(&input.prev_transaction_output_hash)
-                    .expect("Bug Impossible")
-                    .1
-                    .value
-            })
-            .sum::<u64>();
-        let all_outputs: u64 = transaction.outputs.iter().map(|output| output.value).sum();
-        if all_inputs < all_outputs {
-            return Err(BtcError::InvalidTransaction);
+            .rev()
+            .take(crate::MEDIAN_TIME_SPAN)
+            .map(|block| block.header.timestamp)
+            .collect::<Vec<_>>();
+        if timestamps.is_empty() {
+            return None;
         }
-        for input in &transaction.inputs {
-            self.utxos
-                .entry(input.prev_transaction_output_hash)
-                .and_modify(|(marked, _)| {
-                    *marked = true;
-                });
+        timestamps.sort();
+        Some(timestamps[timestamps.len() / 2])
+    }
+
+    pub fn try_adjust_target(&mut self) {
+        self.target = next_target(&self.params, &self.blocks, self.target);
+    }
+
+    // Drops the transactions of all but the last `keep` blocks, never fewer
+    // than target adjustment and median time past look back on. Returns the
+    // number of pruned blocks.
+    pub fn prune(&mut self, keep: u64) -> u64 {
+        let keep = keep
+            .max(self.params.difficulty_update_interval)
+            .max(crate::MEDIAN_TIME_SPAN as u64);
+        let below = self.block_count().saturating_sub(keep);
+        for block in self
+            .blocks
+            .iter_mut()
+            .take(below as usize)
+            .skip(self.pruned.len())
+        {
+            self.pruned.push(block.hash());
+            block.transactions = Vec::new();
         }
+        self.pruned_count()
+    }
 
-        self.mempool.push((Utc::now(), transaction));
-        self.mempool.sort_by_key(|(_, tx)| {
-            let all_inputs = tx
-                .inputs
-                .iter()
-                .map(|input| {
-                    self.utxos
-                        .get(&input.prev_transaction_output_hash)
-                        .expect("Bug Impossible")
-                        .1
-                        .value
-                })
-                .sum::<u64>();
-            let all_outputs: u64 = tx.outputs.iter().map(|output| output.value).sum();
-            let miner_fee = all_inputs - all_outputs;
-            miner_fee
-        });
-        Ok(())
+    pub fn rebuild_utxos(&mut self) {
+        self.rebuild_utxos_with_progress(|_, _| {}, &AtomicBool::new(false))
+            .expect("Bug: Impossible");
     }
 
-    pub fn try_adjust_target(&mut self) {
-        if self.blocks.is_empty() {
-            return;
+    // The UTXO set is only replaced once the rebuild ran to completion
+    pub fn rebuild_utxos_with_progress(
+        &mut self,
+        mut progress: impl FnMut(u64, u64),
+        cancel: &AtomicBool,
+    ) -> Result<()> {
+        if !self.pruned.is_empty() {
+            return Err(BtcError::Pruned {
+                below: self.pruned_count(),
+            });
         }
-        if self.blocks.len() % crate::DIFFICULTY_UPDATE_INTERVAL as usize != 0 {
-            return;
+        let total = self.block_count();
+        let mut utxos = HashMap::new();
+        let mut undo = Vec::new();
+        let mut address_index = AddressIndex::default();
+        let chainwork = expected_chainwork(&self.params, &self.blocks);
+        let mut txids = HashSet::new();
+        let mut stats = StatsWindow::default();
+        let mut prev_timestamp: Option<DateTime<Utc>> = None;
+        for (height, block) in self.blocks.iter().enumerate() {
+            if cancel.load(Ordering::Relaxed) {
+                return Err(BtcError::Cancelled);
+            }
+            let height = BlockHeight::new(height as u64);
+            address_index.connect(block, height, &utxos);
+            txids.extend(block.transactions.iter().map(|tx| tx.txid()));
+            undo.push(apply_block_to_utxos(&mut utxos, block, height));
+            let interval = prev_timestamp.map(|prev| (block.header.timestamp - prev).num_seconds());
+            stats.record(
+                interval,
+                utxos.len(),
+                block_fees(block, height, &self.params),
+            );
+            prev_timestamp = Some(block.header.timestamp);
+            progress(height.next().get(), total);
         }
+        self.utxos = utxos;
+        self.undo = undo;
+        self.address_index = address_index;
+        self.chainwork = chainwork;
+        self.txids = txids;
+        self.stats = stats;
+        Ok(())
+    }
 
-        let start_time = self.blocks
-            [self.blocks.len() - crate::DIFFICULTY_UPDATE_INTERVAL as usize]
-            .header
-            .timestamp;
-        let end_time = self.blocks.last().unwrap().header.timestamp;
-        let time_diff = end_time - start_time;
-        let time_diff_seconds = time_diff.num_seconds();
-        let target_seconds = crate::IDEAL_BLOCK_TIME * crate::DIFFICULTY_UPDATE_INTERVAL;
-
-        let new_target = BigDecimal::parse_bytes(&self.target.to_string().as_bytes(), 10)
-            .expect("Bug: Impossible")
-            * BigDecimal::from(time_diff_seconds)
-            / BigDecimal::from(target_seconds);
-        let new_taret_str = new_target
-            .to_string()
-            .split('.')
-            .next()
-            .expect("Bug: Expected decimal separator")
-            .to_owned();
-        let new_target: U256 = U256::from_str_radix(&new_taret_str, 10).expect("Bug: Impossible");
-
-        // Clamp new_target range
-        let new_target = if new_target < self.target / 4 {
-            self.target / 4
-        } else if new_target > self.target * 4 {
-            self.target * 4
-        } else {
-            new_target
-        };
+    // What rebuild_utxos does for a pruned chain, which keeps the UTXO set it
+    // was loaded with. Chainwork comes from every header, txids and
+    // statistics from the retained blocks, and address history only covers
+    // blocks connected from now on while unspent outputs come from the set.
+    pub fn rebuild_pruned_indexes(&mut self) {
+        let chainwork = expected_chainwork(&self.params, &self.blocks);
+        let mut txids = HashSet::new();
+        let mut stats = StatsWindow::default();
+        let mut prev_timestamp: Option<DateTime<Utc>> = None;
+        for (height, block) in self.blocks.iter().enumerate().skip(self.pruned.len()) {
+            let height = BlockHeight::new(height as u64);
+            txids.extend(block.transactions.iter().map(|tx| tx.txid()));
+            let interval = prev_timestamp.map(|prev| (block.header.timestamp - prev).num_seconds());
+            stats.record(
+                interval,
+                self.utxos.len(),
+                block_fees(block, height, &self.params),
+            );
+            prev_timestamp = Some(block.header.timestamp);
+        }
+        self.address_index = AddressIndex::with_utxos(&self.utxos);
+        self.chainwork = chainwork;
+        self.txids = txids;
+        self.stats = stats;
+    }
 
-        self.target = new_target.min(crate::MIN_TARGET);
+    // Appends the blocks the store lacks, replaces its UTXO set and prunes it
+    // as far as this chain. A store of another network or holding more
+    // blocks than this chain is refused.
+    pub fn save_to_store(&self, store: &mut dyn ChainStore) -> IoResult<()> {
+        match store.params()? {
+            Some(params) if params != self.params => {
+                return Err(IoError::new(
+                    IoErrorKind::InvalidData,
+                    format!("store belongs to {}, not {}", params.name, self.params.name),
+                ));
+            }
+            Some(_) => {}
+            None => store.set_params(&self.params)?,
+        }
+        let stored = store.block_count();
+        if stored > self.block_count() {
+            return Err(IoError::new(
+                IoErrorKind::InvalidData,
+                "store holds more blocks than the chain being saved",
+            ));
+        }
+        if stored < self.pruned_count() {
+            return Err(IoError::new(
+                IoErrorKind::InvalidData,
+                "chain was pruned past the blocks saved to the store",
+            ));
+        }
+        for block in &self.blocks[stored as usize..] {
+            store.append(block)?;
+        }
+        store.set_utxos(self.block_count(), &self.utxos)?;
+        if store.pruned() < self.pruned_count() {
+            store.prune(self.pruned_count())?;
+        }
+        Ok(())
     }
 
-    pub fn cleanup_mempool(&mut self) {
-        let now = Utc::now();
-        let mut utxo_hashes_to_unmark: Vec<Hash> = vec![];
-        self.mempool.retain(|(timestamp, transaction)| {
-            if now - *timestamp
-                > chrono::Duration::seconds(crate::MAX_MEMPOOL_TRANSACTION_AGE as i64)
-            {
-                utxo_hashes_to_unmark.extend(
-                    transaction
-                        .inputs
-                        .iter()
-                        .map(|input| input.prev_transaction_output_hash),
-                );
-                false
+    // Blocks, target and, when it matches the tip, the UTXO set of a store.
+    // Indexes and statistics still need rebuild_utxos or reindex, or
+    // rebuild_pruned_indexes if the store was pruned. A pruned store
+    // without the UTXO set of its tip cannot be loaded.
+    pub fn load_from_store(store: &dyn ChainStore) -> IoResult<Self> {
+        let params = store
+            .params()?
+            .ok_or_else(|| IoError::new(IoErrorKind::NotFound, "store holds no chain"))?;
+        params.check_assume_valid()?;
+        let pruned = store.pruned();
+        if pruned > 0 && pruned >= store.block_count() {
+            return Err(IoError::new(
+                IoErrorKind::InvalidData,
+                "store pruned its tip",
+            ));
+        }
+        let mut blockchain = Blockchain::new(params);
+        for height in (0..store.block_count()).map(BlockHeight::new) {
+            let block = if height.get() < pruned {
+                store
+                    .header(height)?
+                    .map(|header| Block::new(header, Vec::new()))
             } else {
-                true
+                store.block(height)?
+            };
+            let block = block.ok_or_else(|| {
+                IoError::new(IoErrorKind::UnexpectedEof, "store is missing a block")
+            })?;
+            blockchain.blocks.push(block);
+        }
+        // Each pruned hash is the parent hash of the next header
+        blockchain.pruned = blockchain
+            .blocks
+            .iter()
+            .skip(1)
+            .take(pruned as usize)
+            .map(|block| block.header.prev_block_hash)
+            .collect();
+        blockchain.index_hashes();
+        // The tip committed to the target before it, retarget as connecting it did
+        if let Some(tip) = blockchain.blocks.last() {
+            blockchain.target = tip.header.target();
+            blockchain.try_adjust_target();
+        }
+        match store.utxos()? {
+            Some((count, utxos)) if count == blockchain.block_count() => {
+                blockchain.utxos = utxos;
             }
-        });
-        for hash in utxo_hashes_to_unmark {
-            self.utxos.entry(hash).and_modify(|(marked, _)| {
-                *marked = false;
-            });
+            _ if pruned > 0 => {
+                return Err(IoError::new(
+                    IoErrorKind::InvalidData,
+                    "pruned store lacks the UTXO set of its tip",
+                ));
+            }
+            _ => {}
         }
+        Ok(blockchain)
     }
 
-    pub fn rebuild_utxos(&mut self) {
+    // Replays every block through full validation on a fresh chain
+    pub fn reindex(
+        &mut self,
+        mut progress: impl FnMut(u64, u64),
+        cancel: &AtomicBool,
+    ) -> Result<()> {
+        if !self.pruned.is_empty() {
+            return Err(BtcError::Pruned {
+                below: self.pruned_count(),
+            });
+        }
+        let total = self.block_count();
+        let mut reindexed = Blockchain::new(self.params.clone());
+        if self.tx_index.is_some() {
+            reindexed.tx_index = Some(HashMap::new());
+        }
         for block in &self.blocks {
-            for transaction in &block.transactions {
-                for input in &transaction.inputs {
-                    self.utxos.remove(&input.prev_transaction_output_hash);
-                }
-                for output in transaction.outputs.iter() {
-                    self.utxos
-                        .insert(transaction.hash(), (false, output.clone()));
-                }
+            if cancel.load(Ordering::Relaxed) {
+                return Err(BtcError::Cancelled);
+            }
+            reindexed.add_block(block.clone())?;
+            progress(reindexed.block_count(), total);
+        }
+        *self = reindexed;
+        Ok(())
+    }
+
+    fn index_hashes(&mut self) {
+        self.heights = (0..self.block_count())
+            .map(BlockHeight::new)
+            .filter_map(|height| Some((self.hash_at(height)?, height)))
+            .collect();
+    }
+
+    pub fn block_subsidy(&self) -> Amount {
+        self.params.block_subsidy(self.next_height().get())
+    }
+
+    // Replays the chain checking that every coinbase created exactly its
+    // subsidy plus fees, and that the UTXO set holds exactly the issued coins
+    pub fn verify_supply(&self) -> Result<Amount> {
+        if !self.pruned.is_empty() {
+            return Err(BtcError::Pruned {
+                below: self.pruned_count(),
+            });
+        }
+        let mut utxos = HashMap::new();
+        let mut issued = Amount::ZERO;
+        for (height, block) in self.blocks.iter().enumerate() {
+            let height = BlockHeight::new(height as u64);
+            let mismatch = BtcError::SupplyMismatch {
+                height: height.get(),
+            };
+            let fees = block.calculate_miner_fees(&utxos)?;
+            let subsidy = self.params.block_subsidy(height.get());
+            let claimed = block.transactions.first().and_then(|coinbase| {
+                Amount::checked_sum(coinbase.outputs.iter().map(|output| output.value))
+            });
+            if claimed.is_none() || claimed != subsidy.checked_add(fees) {
+                return Err(mismatch);
+            }
+            issued = issued.checked_add(subsidy).ok_or(mismatch)?;
+            apply_block_to_utxos(&mut utxos, block, height);
+        }
+        let total = |utxos: &HashMap<Hash, UtxoEntry>| {
+            Amount::checked_sum(utxos.values().map(|entry| entry.output.value))
+        };
+        if total(&utxos) != Some(issued) || total(&self.utxos) != Some(issued) {
+            return Err(BtcError::SupplyMismatch {
+                height: self.block_count(),
+            });
+        }
+        Ok(issued)
+    }
+}
+
+// Keyed by txid like the txids set. The block hash does not depend on the
+// transactions, so it is taken once.
+fn index_transactions(
+    tx_index: &mut HashMap<Hash, TxLocation>,
+    block: &Block,
+    height: BlockHeight,
+) {
+    let block_hash = block.hash();
+    for (index, transaction) in block.transactions.iter().enumerate() {
+        let location = TxLocation {
+            block_hash,
+            height,
+            index: TxIndex::new(index),
+        };
+        tx_index.insert(transaction.txid(), location);
+    }
+}
+
+// Whatever the coinbase claims beyond the subsidy
+fn block_fees(block: &Block, height: BlockHeight, params: &ChainParams) -> Amount {
+    block
+        .transactions
+        .first()
+        .and_then(|coinbase| {
+            Amount::checked_sum(coinbase.outputs.iter().map(|output| output.value))
+        })
+        .and_then(|coinbase_value| coinbase_value.checked_sub(params.block_subsidy(height.get())))
+        .unwrap_or(Amount::ZERO)
+}
+
+// Returns the spent outputs, to undo the block with
+fn apply_block_to_utxos(
+    utxos: &mut HashMap<Hash, UtxoEntry>,
+    block: &Block,
+    height: BlockHeight,
+) -> Vec<(Hash, UtxoEntry)> {
+    let mut spent = Vec::new();
+    for (tx_index, transaction) in block.transactions.iter().enumerate() {
+        let is_coinbase = TxIndex::new(tx_index).is_coinbase();
+        for input in &transaction.inputs {
+            let outpoint = input.prev_transaction_output_hash;
+            if let Some(entry) = utxos.remove(&outpoint) {
+                spent.push((outpoint, entry));
             }
         }
+        for (index, output) in transaction.outputs.iter().enumerate() {
+            utxos.insert(
+                transaction.output_hash(index),
+                UtxoEntry::new(output.clone(), height.get(), is_coinbase),
+            );
+        }
     }
+    spent
+}
 
-    pub fn calculate_block_reward(&self) -> u64 {
-        let block_height = self.block_height();
-        let halvings = block_height / crate::HALVING_INTERVAL;
-        (crate::INITIAL_REWARD * 10u64.pow(8)) >> halvings
+// The blockchain file is a header followed by length prefixed segments of
+// CHAIN_FILE_SEGMENT_SIZE blocks each, so segments can be decoded in parallel.
+// UTXOs are not stored, call rebuild_utxos after loading.
+#[derive(Serialize, Deserialize)]
+struct ChainFileHeader {
+    version: u32,
+    params: ChainParams,
+    bits: u32,
+    segments: u64,
+}
+
+fn read_segment<I: Read>(reader: &mut I) -> IoResult<Vec<u8>> {
+    let mut len_bytes = [0u8; 8];
+    reader.read_exact(&mut len_bytes)?;
+    let len = u64::from_be_bytes(len_bytes) as usize;
+    // Also catches files written in an older format before allocating
+    if len > crate::MAX_BLOCK_SIZE * crate::CHAIN_FILE_SEGMENT_SIZE {
+        return Err(IoError::new(
+            IoErrorKind::InvalidData,
+            "Blockchain file segment is too large, the file may use an older format",
+        ));
     }
+    let mut data = vec![0u8; len];
+    reader.read_exact(&mut data)?;
+    Ok(data)
+}
+
+fn encode<T: Serialize>(value: &T) -> IoResult<Vec<u8>> {
+    let mut bytes = Vec::new();
+    ciborium::ser::into_writer(value, &mut bytes)
+        .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to serialize Blockchain"))?;
+    Ok(bytes)
+}
+
+fn write_segment<O: Write>(writer: &mut O, data: &[u8]) -> IoResult<()> {
+    writer.write_all(&(data.len() as u64).to_be_bytes())?;
+    writer.write_all(data)
+}
+
+// The target following `blocks`, the last of which was mined under `target`
+fn next_target(params: &ChainParams, blocks: &[Block], target: U256) -> U256 {
+    let interval = params.difficulty_update_interval;
+    if blocks.is_empty() || !blocks.len().is_multiple_of(interval as usize) {
+        return target;
+    }
+
+    let start_time = blocks[blocks.len() - interval as usize].header.timestamp;
+    let end_time = blocks.last().unwrap().header.timestamp;
+    let time_diff_seconds = U256::from((end_time - start_time).num_seconds().max(0) as u64);
+    let target_seconds = U256::from(params.ideal_block_time * interval);
+
+    // target * time_diff / target_seconds, split so the product cannot overflow
+    let new_target = (target / target_seconds)
+        .saturating_mul(time_diff_seconds)
+        .saturating_add((target % target_seconds) * time_diff_seconds / target_seconds);
+
+    // Clamp new_target range
+    let new_target = new_target.clamp(target / 4, target.saturating_mul(U256::from(4)));
+
+    new_target.min(params.min_target).round_to_compact()
+}
+
+// Cumulative work of each block, counted at the target the chain expected of
+// it rather than the one its header claims
+fn expected_chainwork(params: &ChainParams, blocks: &[Block]) -> Vec<U256> {
+    let mut target = params.min_target.round_to_compact();
+    let mut total = U256::zero();
+    (1..=blocks.len())
+        .map(|count| {
+            total = total.saturating_add(target.work());
+            target = next_target(params, &blocks[..count], target);
+            total
+        })
+        .collect()
 }
 
 impl Saveable for Blockchain {
-    fn load<I: Read>(reader: I) -> IoResult<Self> {
-        ciborium::de::from_reader(reader)
-            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to deserialize Blockchain"))
+    fn load<I: Read>(mut reader: I) -> IoResult<Self> {
+        let header = read_segment(&mut reader)?;
+        let header: ChainFileHeader = ciborium::de::from_reader(&header[..]).map_err(|_| {
+            IoError::new(IoErrorKind::InvalidData, "Failed to deserialize Blockchain")
+        })?;
+        if header.version != crate::CHAIN_FORMAT_VERSION {
+            return Err(IoError::new(
+                IoErrorKind::InvalidData,
+                format!(
+                    "Blockchain format version {} is not supported (expected {}), \
+                    remove the file and resync from peers",
+                    header.version,
+                    crate::CHAIN_FORMAT_VERSION
+                ),
+            ));
+        }
+        header.params.check_assume_valid()?;
+        let segments = (0..header.segments)
+            .map(|_| read_segment(&mut reader))
+            .collect::<IoResult<Vec<_>>>()?;
+        let decode = |segment: &Vec<u8>| ciborium::de::from_reader::<Vec<Block>, _>(&segment[..]);
+        #[cfg(feature = "parallel-verify")]
+        let segments = segments
+            .par_iter()
+            .map(decode)
+            .collect::<std::result::Result<Vec<_>, _>>();
+        #[cfg(not(feature = "parallel-verify"))]
+        let segments = segments
+            .iter()
+            .map(decode)
+            .collect::<std::result::Result<Vec<_>, _>>();
+        let segments = segments
+            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to deserialize Block"))?;
+        let mut blockchain = Blockchain {
+            version: header.version,
+            utxos: HashMap::new(),
+            target: U256::from_compact(header.bits),
+            blocks: segments.into_iter().flatten().collect(),
+            stats: StatsWindow::default(),
+            params: header.params,
+            address_index: AddressIndex::default(),
+            chainwork: vec![],
+            txids: HashSet::new(),
+            fee_samples: VecDeque::new(),
+            pruned: vec![],
+            tx_index: None,
+            heights: HashMap::new(),
+            undo: vec![],
+        };
+        blockchain.index_hashes();
+        Ok(blockchain)
+    }
+    fn save<O: Write>(&self, mut writer: O) -> IoResult<()> {
+        #[cfg(feature = "parallel-verify")]
+        let chunks = self.blocks.par_chunks(crate::CHAIN_FILE_SEGMENT_SIZE);
+        #[cfg(not(feature = "parallel-verify"))]
+        let chunks = self.blocks.chunks(crate::CHAIN_FILE_SEGMENT_SIZE);
+        let segments = chunks
+            .map(|blocks| encode(&blocks))
+            .collect::<IoResult<Vec<_>>>()?;
+        let header = ChainFileHeader {
+            version: self.version,
+            params: self.params.clone(),
+            bits: self.target.to_compact(),
+            segments: segments.len() as u64,
+        };
+        write_segment(&mut writer, &encode(&header)?)?;
+        for segment in segments {
+            write_segment(&mut writer, &segment)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{PrivateKey, SignatureScheme};
+    use crate::script::{Script, Witness};
+    use crate::types::{BlockHeader, Mempool, Transaction, TransactionInput};
+    use crate::util::MerkleRoot;
+    use chrono::Duration;
+
+    #[test]
+    fn coinbase_maturity_boundary() {
+        let output = TransactionOutput {
+            value: Amount::from_sat(100_000),
+            script: Script::PayToPubkey(PrivateKey::generate().public_key()),
+        };
+        let maturity = ChainParams::mainnet().coinbase_maturity;
+        let coinbase = UtxoEntry::new(output.clone(), 10, true);
+        assert!(coinbase.is_mature(10 + maturity, maturity));
+        assert!(!coinbase.is_mature(10 + maturity - 1, maturity));
+        assert!(UtxoEntry::new(output, 10, false).is_mature(10, maturity));
+    }
+
+    #[test]
+    fn sequence_lock_boundary() {
+        let output = TransactionOutput {
+            value: Amount::from_sat(100_000),
+            script: Script::PayToPubkey(PrivateKey::generate().public_key()),
+        };
+        let entry = UtxoEntry::new(output, 7, false);
+        assert!(entry.relative_lock_satisfied(3, 10));
+        assert!(!entry.relative_lock_satisfied(4, 10));
+    }
+
+    fn key() -> PrivateKey {
+        PrivateKey::from_bytes(&[1; 32]).unwrap()
+    }
+
+    fn coinbase(blockchain: &Blockchain) -> Transaction {
+        let output = TransactionOutput {
+            value: blockchain.block_subsidy(),
+            script: Script::PayToPubkey(key().public_key()),
+        };
+        Transaction::coinbase(blockchain.next_height().get(), vec![output])
+    }
+
+    #[test]
+    fn timestamp_must_pass_median_time_past() {
+        let mut blockchain = Blockchain::with_genesis(ChainParams::mainnet());
+        extend(&mut blockchain, crate::MEDIAN_TIME_SPAN as u64, 1);
+        let median_time_past = blockchain.median_time_past().unwrap();
+        let tip_time = blockchain.blocks.last().unwrap().header.timestamp;
+        let behind_tip = (median_time_past - tip_time).num_seconds();
+        assert!(behind_tip < 0);
+
+        let block = mine_after(
+            &blockchain,
+            vec![coinbase(&blockchain)],
+            blockchain.target,
+            behind_tip,
+        );
+        assert!(matches!(
+            blockchain.add_block(block),
+            Err(BtcError::TimestampTooOld { got, min }) if got == min
+        ));
+        let block = mine_after(
+            &blockchain,
+            vec![coinbase(&blockchain)],
+            blockchain.target,
+            behind_tip + 1,
+        );
+        assert!(blockchain.add_block(block).is_ok());
+    }
+
+    // Mines the transactions on top of the tip under the given target, a
+    // second after it
+    fn mine(blockchain: &Blockchain, transactions: Vec<Transaction>, target: U256) -> Block {
+        mine_after(blockchain, transactions, target, 1)
+    }
+
+    fn mine_after(
+        blockchain: &Blockchain,
+        transactions: Vec<Transaction>,
+        target: U256,
+        seconds: i64,
+    ) -> Block {
+        let tip = blockchain.blocks.last().unwrap();
+        let mut header = BlockHeader::new(
+            tip.header.timestamp + Duration::seconds(seconds),
+            0,
+            tip.hash(),
+            MerkleRoot::calculate(&transactions),
+            target,
+        );
+        while !header.mine(1_000_000) {}
+        Block::new(header, transactions)
     }
-    fn save<O: Write>(&self, writer: O) -> IoResult<()> {
-        ciborium::ser::into_writer(self, writer)
-            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to serialize Blockchain"))
+
+    #[test]
+    fn accepts_block_at_expected_target() {
+        let mut blockchain = Blockchain::with_genesis(ChainParams::regtest());
+        let block = mine(&blockchain, vec![coinbase(&blockchain)], blockchain.target);
+        blockchain.add_block(block).unwrap();
+        assert_eq!(blockchain.block_count(), 2);
+    }
+
+    #[test]
+    fn rejects_valid_work_under_unexpected_target() {
+        let mut blockchain = Blockchain::with_genesis(ChainParams::regtest());
+        let block = mine(
+            &blockchain,
+            vec![coinbase(&blockchain)],
+            blockchain.target / 2,
+        );
+        assert!(block.header.hash().matches_target(block.header.target()));
+        assert!(matches!(
+            blockchain.add_block(block),
+            Err(BtcError::UnexpectedTarget { .. })
+        ));
+        assert_eq!(blockchain.block_count(), 1);
+    }
+
+    // Extends the chain by `count` blocks spaced `seconds` apart
+    fn extend(blockchain: &mut Blockchain, count: u64, seconds: i64) {
+        for _ in 0..count {
+            let block = mine_after(
+                blockchain,
+                vec![coinbase(blockchain)],
+                blockchain.target,
+                seconds,
+            );
+            blockchain.add_block(block).unwrap();
+        }
+    }
+
+    #[test]
+    fn fork_choice_prefers_real_work_over_length() {
+        let params = ChainParams {
+            ideal_block_time: 100,
+            ..ChainParams::regtest()
+        };
+        // Fast blocks quadruple the difficulty at the first retarget
+        let mut harder = Blockchain::with_genesis(params.clone());
+        extend(&mut harder, 19, 1);
+        // Blocks on schedule keep the easiest target
+        let mut longer = Blockchain::with_genesis(params);
+        extend(&mut longer, 29, 100);
+        assert!(longer.block_count() > harder.block_count());
+        assert!(harder.total_work() > longer.total_work());
+        // Claiming the harder target without earning it adds no work
+        let work = longer.total_work();
+        let block = mine_after(&longer, vec![coinbase(&longer)], harder.target, 100);
+        assert!(longer.add_block(block).is_err());
+        assert_eq!(longer.total_work(), work);
+    }
+
+    #[test]
+    fn rebuilt_chainwork_counts_expected_targets() {
+        let mut blockchain = Blockchain::with_genesis(ChainParams::regtest());
+        extend(&mut blockchain, 14, 1);
+        let work = blockchain.total_work();
+        // A stored header overstating its target leaves the work unchanged
+        blockchain.blocks[12].header.bits = (blockchain.target / 16).to_compact();
+        blockchain.rebuild_utxos();
+        assert_eq!(blockchain.total_work(), work);
+    }
+
+    #[test]
+    fn assume_valid_stops_at_the_last_checkpoint() {
+        let mut params = ChainParams::regtest();
+        params.assume_valid = 3;
+        assert!(params.check_assume_valid().is_err());
+        params.checkpoints.push((3, Hash::zero()));
+        assert!(params.check_assume_valid().is_ok());
+    }
+
+    #[test]
+    fn unchecked_signatures_cannot_leave_the_checkpointed_branch() {
+        let mut honest = Blockchain::with_genesis(ChainParams::regtest());
+        extend(&mut honest, 3, 1);
+        let params = ChainParams {
+            checkpoints: vec![(3, honest.blocks[3].hash())],
+            assume_valid: 3,
+            ..ChainParams::regtest()
+        };
+        let mut fork = Blockchain::with_genesis(params);
+        extend(&mut fork, 1, 2);
+        // Spends the fork's coinbase with a signature by the wrong key
+        let reward = &fork.blocks[1].transactions[0];
+        let mut theft = Transaction::new(
+            vec![TransactionInput {
+                prev_transaction_output_hash: reward.output_hash(0),
+                witness: Witness::default(),
+                sequence: 0,
+            }],
+            vec![TransactionOutput {
+                value: reward.outputs[0].value,
+                script: Script::PayToPubkey(PrivateKey::generate().public_key()),
+            }],
+        );
+        let thief = PrivateKey::generate();
+        theft.sign_input(0, &thief, SignatureScheme::Ecdsa).unwrap();
+        let block = mine(&fork, vec![coinbase(&fork), theft], fork.target);
+        // Below assume_valid the signature goes unchecked...
+        fork.add_block(block).unwrap();
+        // ...but the branch cannot reach the checkpoint
+        let block = mine(&fork, vec![coinbase(&fork)], fork.target);
+        assert!(matches!(
+            fork.add_block(block),
+            Err(BtcError::CheckpointMismatch)
+        ));
+    }
+
+    #[test]
+    fn reorganization_resurrects_replaced_transactions() {
+        let params = ChainParams::regtest();
+        let mut blockchain = Blockchain::with_genesis(params.clone());
+        extend(&mut blockchain, 1, 1);
+        let reward = &blockchain.blocks[1].transactions[0];
+        let outpoint = reward.output_hash(0);
+        let mut payment = Transaction::new(
+            vec![TransactionInput {
+                prev_transaction_output_hash: outpoint,
+                witness: Witness::default(),
+                sequence: 0,
+            }],
+            vec![TransactionOutput {
+                value: reward.outputs[0]
+                    .value
+                    .checked_sub(Amount::from_sat(10_000))
+                    .unwrap(),
+                script: Script::PayToPubkey(key().public_key()),
+            }],
+        );
+        payment
+            .sign_input(0, &key(), SignatureScheme::Ecdsa)
+            .unwrap();
+        let mut mempool = Mempool::new();
+        mempool
+            .add(payment.clone(), &blockchain.utxos, 2, &params)
+            .unwrap();
+        // Two blocks extending the first one outweigh the one confirming the payment
+        let mut branch = blockchain.clone();
+        extend(&mut branch, 2, 2);
+        let branch = branch.blocks[2..].to_vec();
+        let mut with_fees = coinbase(&blockchain);
+        with_fees.outputs[0].value = with_fees.outputs[0]
+            .value
+            .checked_add(Amount::from_sat(10_000))
+            .unwrap();
+        let block = mine(
+            &blockchain,
+            vec![with_fees, payment.clone()],
+            blockchain.target,
+        );
+        blockchain.add_block(block.clone()).unwrap();
+        mempool.remove_confirmed(&block);
+        assert!(!mempool.contains(&payment.hash()));
+
+        // A branch without more work is refused and the chain stays
+        assert!(matches!(
+            blockchain.reorganize(branch[..1].to_vec()),
+            Err(BtcError::InsufficientWork)
+        ));
+        assert_eq!(blockchain.blocks[2].hash(), block.hash());
+        assert!(!blockchain.utxos.contains_key(&outpoint));
+
+        let replaced = blockchain.reorganize(branch.clone()).unwrap();
+        assert_eq!(replaced.len(), 1);
+        assert_eq!(blockchain.block_count(), 4);
+        assert_eq!(blockchain.blocks.last().unwrap().hash(), branch[1].hash());
+        assert!(blockchain.utxos.contains_key(&outpoint));
+        let resurrected = mempool.resurrect(
+            &replaced,
+            &blockchain.utxos,
+            blockchain.next_height().get(),
+            &params,
+        );
+        assert_eq!(resurrected, vec![payment.hash()]);
+    }
+
+    #[test]
+    fn store_reload_keeps_retargeted_target() {
+        let mut blockchain = Blockchain::with_genesis(ChainParams::regtest());
+        let blocks = blockchain.params.difficulty_update_interval - blockchain.block_count();
+        extend(&mut blockchain, blocks, 1);
+        assert!(blockchain.target < blockchain.params.min_target);
+        let mut store = crate::store::MemoryStore::default();
+        blockchain.save_to_store(&mut store).unwrap();
+        let loaded = Blockchain::load_from_store(&store).unwrap();
+        assert_eq!(loaded.target, blockchain.target);
+    }
+
+    #[test]
+    fn pruning_keeps_headers_and_the_lookback() {
+        let mut blockchain = Blockchain::with_genesis(ChainParams::regtest());
+        extend(&mut blockchain, 30, 1);
+        let hashes: Vec<_> = (0..31)
+            .map(|height| blockchain.hash_at(BlockHeight::new(height)).unwrap())
+            .collect();
+        let utxos = blockchain.utxos.len();
+        assert_eq!(blockchain.prune(31), 0);
+
+        // Never fewer than the median time past looks back on
+        let lookback = crate::MEDIAN_TIME_SPAN as u64;
+        assert_eq!(blockchain.prune(0), 31 - lookback);
+        assert!(blockchain.is_pruned(BlockHeight::new(30 - lookback)));
+        assert!(blockchain
+            .block_by_height(BlockHeight::new(30 - lookback))
+            .is_none());
+        assert!(!blockchain.is_pruned(BlockHeight::new(31 - lookback)));
+        assert!(blockchain
+            .block_by_height(BlockHeight::new(31 - lookback))
+            .is_some());
+        for (height, hash) in hashes.iter().enumerate() {
+            let height = BlockHeight::new(height as u64);
+            assert_eq!(blockchain.hash_at(height), Some(*hash));
+            assert_eq!(blockchain.header_at(height).unwrap().hash(), *hash);
+        }
+        assert_eq!(blockchain.utxos.len(), utxos);
+        assert!(matches!(
+            blockchain.rebuild_utxos_with_progress(|_, _| {}, &AtomicBool::new(false)),
+            Err(BtcError::Pruned { .. })
+        ));
+
+        // Pruned blocks stay pruned and the chain keeps growing
+        assert_eq!(blockchain.prune(31), 31 - lookback);
+        extend(&mut blockchain, 1, 1);
+        assert_eq!(blockchain.block_count(), 32);
+    }
+
+    #[test]
+    fn tx_index_locates_confirmed_transactions() {
+        let mut blockchain = Blockchain::with_genesis(ChainParams::regtest());
+        extend(&mut blockchain, 2, 1);
+        let early = blockchain.blocks[1].transactions[0].txid();
+        assert!(blockchain.tx_location(&early).is_none());
+
+        blockchain.enable_tx_index();
+        let location = *blockchain.tx_location(&early).unwrap();
+        assert_eq!(location.height, BlockHeight::new(1));
+        assert_eq!(location.index, TxIndex::new(0));
+        assert_eq!(location.block_hash, blockchain.blocks[1].hash());
+        assert_eq!(blockchain.transaction(&early).unwrap().txid(), early);
+
+        // Connected blocks extend the index
+        extend(&mut blockchain, 1, 1);
+        let late = blockchain.blocks[3].transactions[0].txid();
+        assert_eq!(
+            blockchain.tx_location(&late).unwrap().height,
+            BlockHeight::new(3)
+        );
+        assert!(blockchain.tx_location(&Hash::zero()).is_none());
+
+        // A pruned block still tells where, no longer what
+        extend(&mut blockchain, crate::MEDIAN_TIME_SPAN as u64, 1);
+        blockchain.prune(0);
+        assert!(blockchain.is_pruned(BlockHeight::new(1)));
+        assert_eq!(blockchain.tx_location(&early), Some(&location));
+        assert!(blockchain.transaction(&early).is_none());
     }
 }
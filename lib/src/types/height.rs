@@ -0,0 +1,108 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+// Position of a block in the chain, the genesis block at 0. A chain of n
+// blocks has its tip at n - 1 and the next block at n, keeping heights apart
+// from counts avoids mixing the two up.
+#[derive(
+    Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+#[serde(transparent)]
+pub struct BlockHeight(u64);
+
+impl BlockHeight {
+    pub const GENESIS: BlockHeight = BlockHeight(0);
+
+    pub const fn new(height: u64) -> Self {
+        BlockHeight(height)
+    }
+
+    pub const fn get(self) -> u64 {
+        self.0
+    }
+
+    // Position in a vector holding the chain from genesis on
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+
+    // Height of the last block of a chain holding `count` blocks
+    pub fn tip_of(count: u64) -> Option<Self> {
+        count.checked_sub(1).map(BlockHeight)
+    }
+
+    // The height the next block after a chain of `count` blocks gets
+    pub fn after(count: u64) -> Self {
+        BlockHeight(count)
+    }
+
+    pub fn next(self) -> Self {
+        BlockHeight(self.0.checked_add(1).expect("Bug: block height overflow"))
+    }
+
+    pub fn prev(self) -> Option<Self> {
+        self.0.checked_sub(1).map(BlockHeight)
+    }
+
+    // Number of blocks from `self` up to but not including `until`
+    pub fn blocks_until(self, until: BlockHeight) -> u64 {
+        until.0.saturating_sub(self.0)
+    }
+
+    // Heights a block locator from this tip samples: the last ten blocks,
+    // then doubling steps back, always ending at genesis
+    pub fn locator_heights(self) -> Vec<BlockHeight> {
+        let mut heights = Vec::new();
+        let mut height = self.0;
+        let mut step = 1;
+        loop {
+            heights.push(BlockHeight(height));
+            if height == 0 || heights.len() == crate::MAX_LOCATOR_HASHES - 1 {
+                break;
+            }
+            if heights.len() >= 10 {
+                step *= 2;
+            }
+            height = height.saturating_sub(step);
+        }
+        if height != 0 {
+            heights.push(BlockHeight::GENESIS);
+        }
+        heights
+    }
+}
+
+impl fmt::Display for BlockHeight {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+// Position of a transaction within its block, the coinbase at 0
+#[derive(
+    Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+#[serde(transparent)]
+pub struct TxIndex(usize);
+
+impl TxIndex {
+    pub const COINBASE: TxIndex = TxIndex(0);
+
+    pub const fn new(index: usize) -> Self {
+        TxIndex(index)
+    }
+
+    pub const fn get(self) -> usize {
+        self.0
+    }
+
+    pub fn is_coinbase(self) -> bool {
+        self == Self::COINBASE
+    }
+}
+
+impl fmt::Display for TxIndex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
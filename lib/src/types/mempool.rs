@@ -0,0 +1,776 @@
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write};
+
+use super::{Block, Transaction, TransactionOutput, UtxoEntry, UtxoView};
+use crate::amount::Amount;
+use crate::error::{BtcError, Result};
+use crate::params::ChainParams;
+use crate::policy::RelayPolicy;
+use crate::sha256::Hash;
+use crate::util::Saveable;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug)]
+pub struct MempoolEntry {
+    pub timestamp: DateTime<Utc>,
+    pub transaction: Transaction,
+    pub fee: Amount,
+    // Serialized size, computed once on admission
+    pub size: usize,
+    pub sigops: usize,
+    // Height of the block that could first have confirmed it
+    pub height: u64,
+}
+
+impl MempoolEntry {
+    pub fn fee_rate(&self) -> u64 {
+        fee_rate(self.fee, self.size)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct MempoolStats {
+    pub transactions: usize,
+    // Serialized bytes
+    pub size: usize,
+    pub total_fees: Amount,
+    // Fee rate needed to get in, sats per 1000 bytes
+    pub min_fee_rate: u64,
+}
+
+// Pending transactions as kept across restarts, parents before children.
+// Nothing in it is trusted, see Mempool::restore.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct MempoolSnapshot {
+    // Admission time and height with each transaction
+    pub entries: Vec<(DateTime<Utc>, u64, Transaction)>,
+}
+
+impl Saveable for MempoolSnapshot {
+    fn load<I: Read>(reader: I) -> IoResult<Self> {
+        ciborium::de::from_reader(reader).map_err(|_| {
+            IoError::new(
+                IoErrorKind::InvalidData,
+                "Failed to deserialize MempoolSnapshot",
+            )
+        })
+    }
+    fn save<O: Write>(&self, writer: O) -> IoResult<()> {
+        ciborium::ser::into_writer(self, writer).map_err(|_| {
+            IoError::new(
+                IoErrorKind::InvalidData,
+                "Failed to serialize MempoolSnapshot",
+            )
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Mempool {
+    transactions: HashMap<Hash, MempoolEntry>,
+    by_fee_rate: BTreeSet<(u64, Hash)>,
+    // Admission times, so expiry only visits expired entries
+    by_timestamp: BTreeSet<(DateTime<Utc>, Hash)>,
+    by_outpoint: HashMap<Hash, Hash>,
+    // Outputs of pending transactions, which later ones may spend, with the
+    // txid and index that created them
+    by_output: HashMap<Hash, (Hash, usize)>,
+    policy: RelayPolicy,
+    // Serialized bytes of all entries. Above max_size or max_count entries
+    // the cheapest packages are evicted.
+    size: usize,
+    max_size: usize,
+    max_count: usize,
+    // Fee rate new transactions need while the pool is full, raised above
+    // each evicted package and dropped once the pool is half empty
+    min_fee_rate: u64,
+    // Bumped on every insertion and removal
+    revision: u64,
+}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Mempool {
+            transactions: HashMap::new(),
+            by_fee_rate: BTreeSet::new(),
+            by_timestamp: BTreeSet::new(),
+            by_outpoint: HashMap::new(),
+            by_output: HashMap::new(),
+            policy: RelayPolicy::default(),
+            size: 0,
+            max_size: crate::MAX_MEMPOOL_SIZE,
+            max_count: crate::MAX_MEMPOOL_TRANSACTIONS,
+            min_fee_rate: 0,
+            revision: 0,
+        }
+    }
+}
+
+impl Mempool {
+    pub fn new() -> Self {
+        Mempool::default()
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn set_max_size(&mut self, max_size: usize) {
+        self.max_size = max_size;
+        self.trim();
+    }
+
+    pub fn set_max_count(&mut self, max_count: usize) {
+        self.max_count = max_count;
+        self.trim();
+    }
+
+    pub fn min_fee_rate(&self) -> u64 {
+        self.min_fee_rate
+    }
+
+    // Equal revisions mean the same pending transactions
+    pub fn stats(&self) -> MempoolStats {
+        let fees = self.transactions.values().map(|entry| entry.fee);
+        MempoolStats {
+            transactions: self.len(),
+            size: self.size,
+            total_fees: Amount::checked_sum(fees).unwrap_or(Amount::ZERO),
+            min_fee_rate: self.min_fee_rate,
+        }
+    }
+
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    pub fn policy(&self) -> &RelayPolicy {
+        &self.policy
+    }
+
+    pub fn set_policy(&mut self, policy: RelayPolicy) {
+        self.policy = policy;
+    }
+
+    pub fn len(&self) -> usize {
+        self.transactions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transactions.is_empty()
+    }
+
+    pub fn get(&self, txid: &Hash) -> Option<&MempoolEntry> {
+        self.transactions.get(txid)
+    }
+
+    pub fn contains(&self, txid: &Hash) -> bool {
+        self.transactions.contains_key(txid)
+    }
+
+    pub fn spender(&self, outpoint: &Hash) -> Option<Hash> {
+        self.by_outpoint.get(outpoint).copied()
+    }
+
+    pub fn is_spent(&self, outpoint: &Hash) -> bool {
+        self.by_outpoint.contains_key(outpoint)
+    }
+
+    pub fn pending_output(&self, outpoint: &Hash) -> Option<&TransactionOutput> {
+        let (txid, index) = self.by_output.get(outpoint)?;
+        self.transactions.get(txid)?.transaction.outputs.get(*index)
+    }
+
+    // Unspent output of the chain or of a pending transaction, the latter as
+    // if confirmed in the next block
+    fn prev_entry<'a>(
+        &'a self,
+        outpoint: &Hash,
+        utxos: &'a dyn UtxoView,
+        next_height: u64,
+    ) -> Option<Cow<'a, UtxoEntry>> {
+        if let Some(entry) = utxos.get(outpoint) {
+            return Some(Cow::Borrowed(entry));
+        }
+        let output = self.pending_output(outpoint)?.clone();
+        Some(Cow::Owned(UtxoEntry::new(output, next_height, false)))
+    }
+
+    // Pending transactions whose outputs the transaction spends
+    fn parents<'a>(&'a self, transaction: &'a Transaction) -> impl Iterator<Item = Hash> + 'a {
+        transaction
+            .inputs
+            .iter()
+            .filter_map(|input| self.by_output.get(&input.prev_transaction_output_hash))
+            .map(|(txid, _)| *txid)
+    }
+
+    // Every pending transaction that has to confirm before this one can
+    pub fn ancestors(&self, transaction: &Transaction) -> HashSet<Hash> {
+        let mut found = HashSet::new();
+        let mut pending = self.parents(transaction).collect::<Vec<_>>();
+        while let Some(txid) = pending.pop() {
+            if !found.insert(txid) {
+                continue;
+            }
+            if let Some(entry) = self.get(&txid) {
+                pending.extend(self.parents(&entry.transaction));
+            }
+        }
+        found
+    }
+
+    // The transaction and its ancestors not yet included, parents first
+    fn package(&self, txid: Hash, included: &HashSet<Hash>) -> Vec<&MempoolEntry> {
+        let Some(entry) = self.get(&txid) else {
+            return Vec::new();
+        };
+        let mut package = self
+            .ancestors(&entry.transaction)
+            .into_iter()
+            .filter(|ancestor| !included.contains(ancestor))
+            .filter_map(|ancestor| self.get(&ancestor))
+            .collect::<Vec<_>>();
+        // A parent always has fewer ancestors than its children
+        package.sort_by_cached_key(|entry| self.ancestors(&entry.transaction).len());
+        package.push(entry);
+        package
+    }
+
+    // Fills a block template within the given budgets. Packages of a
+    // transaction and its missing ancestors go in by their combined fee
+    // rate, so a child paying well pulls in a parent paying little. Parents
+    // always come before their children.
+    pub fn select(&self, mut size_budget: usize, mut sigop_budget: usize) -> Vec<&MempoolEntry> {
+        let mut included = HashSet::new();
+        let mut selected = Vec::new();
+        let mut scores = HashMap::new();
+        let mut queue = BTreeSet::new();
+        for txid in self.transactions.keys() {
+            let score = package_fee_rate(&self.package(*txid, &included));
+            scores.insert(*txid, score);
+            queue.insert((score, *txid));
+        }
+        while let Some((_, txid)) = queue.pop_last() {
+            scores.remove(&txid);
+            let package = self.package(txid, &included);
+            let size = package.iter().map(|entry| entry.size).sum::<usize>();
+            let sigops = package.iter().map(|entry| entry.sigops).sum::<usize>();
+            if size > size_budget || sigops > sigop_budget {
+                continue;
+            }
+            size_budget -= size;
+            sigop_budget -= sigops;
+            let mut added = BTreeSet::new();
+            for entry in package {
+                let txid = entry.transaction.hash();
+                if let Some(score) = scores.remove(&txid) {
+                    queue.remove(&(score, txid));
+                }
+                included.insert(txid);
+                added.insert(txid);
+                selected.push(entry);
+            }
+            // Descendants no longer pay for the ancestors just included
+            for descendant in self.with_descendants(added) {
+                let Some(score) = scores.get_mut(&descendant) else {
+                    continue;
+                };
+                queue.remove(&(*score, descendant));
+                *score = package_fee_rate(&self.package(descendant, &included));
+                queue.insert((*score, descendant));
+            }
+        }
+        selected
+    }
+
+    pub fn check_transaction(
+        &self,
+        transaction: &Transaction,
+        utxos: &dyn UtxoView,
+        next_height: u64,
+        params: &ChainParams,
+    ) -> Result<Amount> {
+        if !transaction.is_final(next_height, Utc::now()) {
+            return Err(BtcError::NonFinalTransaction);
+        }
+        let mut known_inputs = HashSet::new();
+        let mut input_value = Amount::ZERO;
+        for (index, input) in transaction.inputs.iter().enumerate() {
+            let Some(entry) =
+                self.prev_entry(&input.prev_transaction_output_hash, utxos, next_height)
+            else {
+                return Err(BtcError::MissingUtxo {
+                    outpoint: input.prev_transaction_output_hash,
+                });
+            };
+            if !entry.is_mature(next_height, params.coinbase_maturity) {
+                return Err(BtcError::ImmatureCoinbase);
+            }
+            if !entry.relative_lock_satisfied(input.sequence, next_height) {
+                return Err(BtcError::NonFinalTransaction);
+            }
+            if !known_inputs.insert(input.prev_transaction_output_hash) {
+                return Err(BtcError::DoubleSpend {
+                    outpoint: input.prev_transaction_output_hash,
+                });
+            }
+            let verified = transaction
+                .signature_digest(index, input.witness.sighash)
+                .is_some_and(|digest| {
+                    entry
+                        .output
+                        .script
+                        .verify(&input.witness, &digest, next_height)
+                });
+            if !verified {
+                return Err(BtcError::BadSignature {
+                    txid: transaction.hash(),
+                    input: index,
+                });
+            }
+            input_value = input_value
+                .checked_add(entry.output.value)
+                .ok_or(BtcError::ValueOverflow)?;
+        }
+        if transaction
+            .outputs
+            .iter()
+            .any(|output| !output.script.is_well_formed())
+        {
+            return Err(BtcError::InvalidScript);
+        }
+        let output_value =
+            Amount::checked_sum(transaction.outputs.iter().map(|output| output.value))
+                .ok_or(BtcError::ValueOverflow)?;
+        input_value
+            .checked_sub(output_value)
+            .ok_or(BtcError::InsufficientInputs {
+                inputs: input_value,
+                outputs: output_value,
+            })
+    }
+
+    pub fn add(
+        &mut self,
+        transaction: Transaction,
+        utxos: &dyn UtxoView,
+        next_height: u64,
+        params: &ChainParams,
+    ) -> Result<()> {
+        // Encoding is not free, every later size check uses this one
+        let size = transaction.serialized_size();
+        self.policy.check(&transaction, size)?;
+        // Could never be mined, and is refused before checking any signature
+        let sigops = transaction.sigops(|outpoint| {
+            utxos
+                .get(outpoint)
+                .map(|entry| &entry.output)
+                .or_else(|| self.pending_output(outpoint))
+        });
+        if sigops > crate::MAX_BLOCK_SIGOPS {
+            return Err(BtcError::TooManySigops);
+        }
+        if self.ancestors(&transaction).len() >= crate::MAX_MEMPOOL_ANCESTORS {
+            return Err(BtcError::NonStandard("too many unconfirmed ancestors"));
+        }
+        let fee = self.check_transaction(&transaction, utxos, next_height, params)?;
+        self.policy.check_fee_rate(fee_rate(fee, size))?;
+        let txid = transaction.hash();
+        if self.contains(&txid) {
+            return Ok(());
+        }
+        if fee_rate(fee, size) < self.min_fee_rate {
+            return Err(BtcError::MempoolFull);
+        }
+        let replaced = self
+            .check_replacement(&transaction, fee, size)?
+            .iter()
+            .filter_map(|txid| self.remove(txid))
+            .collect::<Vec<_>>();
+        let min_fee_rate = self.min_fee_rate;
+        self.insert(MempoolEntry {
+            timestamp: Utc::now(),
+            transaction,
+            fee,
+            size,
+            sigops,
+            height: next_height,
+        });
+        let evicted = self.trim();
+        if evicted.iter().any(|entry| entry.transaction.hash() == txid) {
+            // Too cheap to stay, so put back whatever it replaced or pushed out
+            for entry in evicted.into_iter().chain(replaced) {
+                if entry.transaction.hash() != txid {
+                    self.insert(entry);
+                }
+            }
+            self.min_fee_rate = min_fee_rate;
+            return Err(BtcError::MempoolFull);
+        }
+        Ok(())
+    }
+
+    // Pending transactions spending the same outputs, and everything spending
+    // theirs, are replaced only by a higher fee rate that also pays for all
+    // of them. Returns the txids to evict.
+    fn check_replacement(
+        &self,
+        transaction: &Transaction,
+        fee: Amount,
+        size: usize,
+    ) -> Result<Vec<Hash>> {
+        let conflicts = transaction
+            .inputs
+            .iter()
+            .filter_map(|input| self.spender(&input.prev_transaction_output_hash))
+            .collect::<BTreeSet<_>>();
+        let Some(conflict_rate) = conflicts
+            .iter()
+            .filter_map(|txid| self.get(txid))
+            .map(MempoolEntry::fee_rate)
+            .max()
+        else {
+            return Ok(Vec::new());
+        };
+        if fee_rate(fee, size) < conflict_rate.saturating_add(crate::MIN_RBF_FEE_RATE_INCREMENT) {
+            return Err(BtcError::ReplacementRejected("fee rate is not high enough"));
+        }
+        let replaced = self.with_descendants(conflicts);
+        let ancestors = self.ancestors(transaction);
+        if replaced.iter().any(|txid| ancestors.contains(txid)) {
+            return Err(BtcError::ReplacementRejected(
+                "spends a replaced transaction",
+            ));
+        }
+        let replaced_fees = Amount::checked_sum(
+            replaced
+                .iter()
+                .filter_map(|txid| self.get(txid))
+                .map(|entry| entry.fee),
+        );
+        match replaced_fees {
+            Some(replaced_fees) if replaced_fees <= fee => Ok(replaced),
+            _ => Err(BtcError::ReplacementRejected(
+                "fee does not cover the replaced fees",
+            )),
+        }
+    }
+
+    fn with_descendants(&self, txids: BTreeSet<Hash>) -> Vec<Hash> {
+        let mut found = txids.clone();
+        let mut pending = txids.into_iter().collect::<Vec<_>>();
+        while let Some(txid) = pending.pop() {
+            let Some(entry) = self.get(&txid) else {
+                continue;
+            };
+            for index in 0..entry.transaction.outputs.len() {
+                let output = entry.transaction.output_hash(index);
+                if let Some(child) = self.spender(&output) {
+                    if found.insert(child) {
+                        pending.push(child);
+                    }
+                }
+            }
+        }
+        found.into_iter().collect()
+    }
+
+    // Evicts the cheapest packages until the pool fits its limits, returning
+    // the evicted entries
+    fn trim(&mut self) -> Vec<MempoolEntry> {
+        let mut evicted = Vec::new();
+        while self.size > self.max_size || self.len() > self.max_count {
+            let Some((txid, score)) = self.eviction_candidate() else {
+                break;
+            };
+            let min_fee_rate = score.saturating_add(crate::MIN_RBF_FEE_RATE_INCREMENT);
+            self.min_fee_rate = self.min_fee_rate.max(min_fee_rate);
+            for txid in self.with_descendants(BTreeSet::from([txid])) {
+                evicted.extend(self.remove(&txid));
+            }
+        }
+        evicted
+    }
+
+    // The transaction that, evicted with its descendants, loses the least
+    // fees per byte. A parent ranks by its own fee rate or that of the whole
+    // package, whichever is higher, so children paying for it protect it.
+    fn eviction_candidate(&self) -> Option<(Hash, u64)> {
+        let mut best: Option<(Hash, u64)> = None;
+        for &(rate, txid) in &self.by_fee_rate {
+            // Scores are never below the own fee rate, nothing later can win
+            if best.is_some_and(|(_, score)| rate >= score) {
+                break;
+            }
+            let package = self
+                .with_descendants(BTreeSet::from([txid]))
+                .iter()
+                .filter_map(|txid| self.get(txid))
+                .collect::<Vec<_>>();
+            let score = rate.max(package_fee_rate(&package));
+            best = match best {
+                Some((_, best_score)) if best_score <= score => best,
+                _ => Some((txid, score)),
+            };
+        }
+        best
+    }
+
+    // Puts transactions of blocks dropped by a reorg back into the pool, given
+    // oldest block first and checked against the UTXOs of the new chain. Those
+    // the new chain confirmed or conflicts with no longer validate and are
+    // left out. Returns the txids that made it back. See
+    // Blockchain::reorganize.
+    pub fn resurrect(
+        &mut self,
+        disconnected: &[Block],
+        utxos: &dyn UtxoView,
+        next_height: u64,
+        params: &ChainParams,
+    ) -> Vec<Hash> {
+        let mut resurrected = Vec::new();
+        for transaction in disconnected
+            .iter()
+            .flat_map(|block| block.transactions.iter().skip(1))
+        {
+            let txid = transaction.hash();
+            // A pending replacement was made later and wins over the old transaction
+            let replaced = transaction
+                .inputs
+                .iter()
+                .any(|input| self.is_spent(&input.prev_transaction_output_hash));
+            if !replaced
+                && self
+                    .add(transaction.clone(), utxos, next_height, params)
+                    .is_ok()
+            {
+                resurrected.push(txid);
+            }
+        }
+        resurrected
+    }
+
+    pub fn snapshot(&self) -> MempoolSnapshot {
+        let mut entries = self.transactions.values().collect::<Vec<_>>();
+        // A parent always has fewer ancestors than its children
+        entries.sort_by_cached_key(|entry| self.ancestors(&entry.transaction).len());
+        MempoolSnapshot {
+            entries: entries
+                .into_iter()
+                .map(|entry| (entry.timestamp, entry.height, entry.transaction.clone()))
+                .collect(),
+        }
+    }
+
+    // Admits the transactions of a snapshot again under the current chain and
+    // policy, keeping their original admission time and height. Expired ones
+    // and those no longer valid are dropped. Returns how many made it back.
+    pub fn restore(
+        &mut self,
+        snapshot: MempoolSnapshot,
+        utxos: &dyn UtxoView,
+        next_height: u64,
+        params: &ChainParams,
+    ) -> usize {
+        let now = Utc::now();
+        let max_age = chrono::Duration::seconds(crate::MAX_MEMPOOL_TRANSACTION_AGE as i64);
+        let mut restored = 0;
+        for (timestamp, height, transaction) in snapshot.entries {
+            if now - timestamp > max_age {
+                continue;
+            }
+            let txid = transaction.hash();
+            if self.add(transaction, utxos, next_height, params).is_err() {
+                continue;
+            }
+            if let Some(mut entry) = self.remove(&txid) {
+                entry.timestamp = timestamp;
+                entry.height = height.min(next_height);
+                self.insert(entry);
+                restored += 1;
+            }
+        }
+        restored
+    }
+
+    fn insert(&mut self, entry: MempoolEntry) {
+        let txid = entry.transaction.hash();
+        for input in &entry.transaction.inputs {
+            self.by_outpoint
+                .insert(input.prev_transaction_output_hash, txid);
+        }
+        for index in 0..entry.transaction.outputs.len() {
+            self.by_output
+                .insert(entry.transaction.output_hash(index), (txid, index));
+        }
+        self.by_fee_rate.insert((entry.fee_rate(), txid));
+        self.by_timestamp.insert((entry.timestamp, txid));
+        self.size += entry.size;
+        self.revision += 1;
+        self.transactions.insert(txid, entry);
+    }
+
+    pub fn remove(&mut self, txid: &Hash) -> Option<MempoolEntry> {
+        let entry = self.transactions.remove(txid)?;
+        self.by_fee_rate.remove(&(entry.fee_rate(), *txid));
+        self.by_timestamp.remove(&(entry.timestamp, *txid));
+        self.size -= entry.size;
+        self.revision += 1;
+        for input in &entry.transaction.inputs {
+            if self.spender(&input.prev_transaction_output_hash) == Some(*txid) {
+                self.by_outpoint.remove(&input.prev_transaction_output_hash);
+            }
+        }
+        for index in 0..entry.transaction.outputs.len() {
+            self.by_output.remove(&entry.transaction.output_hash(index));
+        }
+        if self.size <= self.max_size / 2 && self.len() <= self.max_count / 2 {
+            self.min_fee_rate = 0;
+        }
+        Some(entry)
+    }
+
+    // Removes the transaction and everything spending its outputs, which
+    // could no longer be mined without it
+    pub fn remove_with_descendants(&mut self, txid: Hash) -> Vec<Hash> {
+        let removed = self.with_descendants(BTreeSet::from([txid]));
+        for txid in &removed {
+            self.remove(txid);
+        }
+        removed
+    }
+
+    // Drops transactions included in the block and those double spending it.
+    // Children of confirmed transactions stay, now spending chain outputs.
+    // Returns the entries the block confirmed.
+    pub fn remove_confirmed(&mut self, block: &Block) -> Vec<MempoolEntry> {
+        let mut confirmed = Vec::new();
+        for transaction in &block.transactions {
+            confirmed.extend(self.remove(&transaction.hash()));
+            for input in &transaction.inputs {
+                if let Some(txid) = self.spender(&input.prev_transaction_output_hash) {
+                    self.remove_with_descendants(txid);
+                }
+            }
+        }
+        confirmed
+    }
+
+    pub fn cleanup(&mut self) {
+        let now = Utc::now();
+        let max_age = chrono::Duration::seconds(crate::MAX_MEMPOOL_TRANSACTION_AGE as i64);
+        while let Some(&(timestamp, txid)) = self.by_timestamp.first() {
+            if now - timestamp <= max_age {
+                break;
+            }
+            self.remove_with_descendants(txid);
+        }
+    }
+}
+
+// Satoshis per 1000 serialized bytes
+fn fee_rate(fee: Amount, size: usize) -> u64 {
+    fee.to_sat().saturating_mul(1000) / size.max(1) as u64
+}
+
+fn package_fee_rate(package: &[&MempoolEntry]) -> u64 {
+    let fee = package
+        .iter()
+        .map(|entry| entry.fee.to_sat())
+        .fold(0, u64::saturating_add);
+    let size = package.iter().map(|entry| entry.size).sum();
+    fee_rate(Amount::from_sat(fee), size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{PrivateKey, SignatureScheme};
+    use crate::script::{Script, Witness};
+    use crate::types::{TransactionInput, TransactionOutput};
+
+    fn key() -> PrivateKey {
+        PrivateKey::from_bytes(&[1; 32]).unwrap()
+    }
+
+    fn output(value: u64) -> TransactionOutput {
+        TransactionOutput {
+            value: Amount::from_sat(value),
+            script: Script::PayToPubkey(key().public_key()),
+        }
+    }
+
+    fn spend(outpoints: &[Hash], values: &[u64]) -> Transaction {
+        let inputs = outpoints
+            .iter()
+            .map(|outpoint| TransactionInput {
+                prev_transaction_output_hash: *outpoint,
+                witness: Witness::default(),
+                sequence: 0,
+            })
+            .collect();
+        let outputs = values.iter().map(|value| output(*value)).collect();
+        let mut transaction = Transaction::new(inputs, outputs);
+        for index in 0..outpoints.len() {
+            transaction
+                .sign_input(index, &key(), SignatureScheme::Ecdsa)
+                .unwrap();
+        }
+        transaction
+    }
+
+    // Two mature chain outputs of 100_000 sats each
+    fn utxos() -> (HashMap<Hash, UtxoEntry>, Hash, Hash) {
+        let u1 = Hash::hash_bytes(b"u1");
+        let u2 = Hash::hash_bytes(b"u2");
+        let utxos = HashMap::from([
+            (u1, UtxoEntry::new(output(100_000), 0, false)),
+            (u2, UtxoEntry::new(output(100_000), 0, false)),
+        ]);
+        (utxos, u1, u2)
+    }
+
+    #[test]
+    fn replacement_cannot_spend_what_it_replaces() {
+        let params = ChainParams::regtest();
+        let (utxos, u1, _) = utxos();
+        let mut mempool = Mempool::new();
+        let a = spend(&[u1], &[90_000]);
+        mempool.add(a.clone(), &utxos, 1, &params).unwrap();
+        let r = spend(&[u1, a.output_hash(0)], &[10_000]);
+        assert!(matches!(
+            mempool.add(r, &utxos, 1, &params),
+            Err(BtcError::ReplacementRejected(
+                "spends a replaced transaction"
+            ))
+        ));
+        assert!(mempool.contains(&a.hash()));
+        assert_eq!(mempool.len(), 1);
+    }
+
+    #[test]
+    fn evicted_replacement_restores_the_originals() {
+        let params = ChainParams::regtest();
+        let (utxos, u1, u2) = utxos();
+        let mut mempool = Mempool::new();
+        let a = spend(&[u1], &[90_000]);
+        let b = spend(&[u2], &[50_000]);
+        mempool.add(a.clone(), &utxos, 1, &params).unwrap();
+        mempool.add(b.clone(), &utxos, 1, &params).unwrap();
+        mempool.set_max_size(mempool.size());
+        // Pays more than the one it replaces, but is larger and cheaper than b
+        let r = spend(&[u1], &[40_000, 40_000]);
+        assert!(matches!(
+            mempool.add(r.clone(), &utxos, 1, &params),
+            Err(BtcError::MempoolFull)
+        ));
+        assert!(!mempool.contains(&r.hash()));
+        assert!(mempool.contains(&a.hash()));
+        assert!(mempool.contains(&b.hash()));
+        assert_eq!(mempool.spender(&u1), Some(a.hash()));
+        assert_eq!(mempool.min_fee_rate(), 0);
+    }
+}
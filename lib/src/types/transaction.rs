@@ -1,41 +1,182 @@
-use crate::crypto::{PublicKey, Signature};
+use crate::amount::Amount;
+use crate::crypto::{self, PrivateKey, SigHash, SignatureScheme};
+use crate::encoding::Encode;
+use crate::error::{BtcError, Result};
+use crate::script::{Script, Witness};
 use crate::sha256::Hash;
 use crate::util::Saveable;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write};
-use uuid::Uuid;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct TransactionInput {
     pub prev_transaction_output_hash: Hash,
-    pub signature: Signature,
+    pub witness: Witness,
+    // Relative lock: blocks that must pass after the spent output confirmed
+    #[serde(default)]
+    pub sequence: u64,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct TransactionOutput {
-    pub value: u64,
-    pub unique_id: Uuid,
-    pub pubkey: PublicKey,
-}
-impl TransactionOutput {
-    pub fn hash(&self) -> Hash {
-        Hash::hash(self)
-    }
+    pub value: Amount,
+    pub script: Script,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Transaction {
     pub inputs: Vec<TransactionInput>,
     pub outputs: Vec<TransactionOutput>,
+    // Only set on coinbase transactions, makes every coinbase txid unique
+    #[serde(default)]
+    pub coinbase_height: Option<u64>,
+    #[serde(default)]
+    pub lock_time: Option<LockTime>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LockTime {
+    Height(u64),
+    Timestamp(DateTime<Utc>),
+}
+
+impl Encode for TransactionOutput {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (self.value, &self.script).encode(out);
+    }
+}
+
+impl Encode for LockTime {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            LockTime::Height(height) => (0u8, height).encode(out),
+            LockTime::Timestamp(timestamp) => (1u8, timestamp).encode(out),
+        }
+    }
 }
 
 impl Transaction {
     pub fn new(inputs: Vec<TransactionInput>, outputs: Vec<TransactionOutput>) -> Self {
-        Transaction { inputs, outputs }
+        Transaction {
+            inputs,
+            outputs,
+            coinbase_height: None,
+            lock_time: None,
+        }
     }
 
+    pub fn with_lock_time(mut self, lock_time: LockTime) -> Self {
+        self.lock_time = Some(lock_time);
+        self
+    }
+
+    pub fn coinbase(height: u64, outputs: Vec<TransactionOutput>) -> Self {
+        Transaction {
+            inputs: vec![],
+            outputs,
+            coinbase_height: Some(height),
+            lock_time: None,
+        }
+    }
+
+    // Transactions are identified by their txid
     pub fn hash(&self) -> Hash {
-        Hash::hash(self)
+        self.txid()
+    }
+
+    // Covers everything but the witnesses, so changing a signature cannot change it
+    pub fn txid(&self) -> Hash {
+        Hash::hash_bytes(&self.canonical_bytes(false))
+    }
+
+    // Covers the full transaction data including witnesses
+    pub fn wtxid(&self) -> Hash {
+        Hash::hash_bytes(&self.canonical_bytes(true))
+    }
+
+    // Encoded (inputs, outputs, coinbase height, lock time) with inputs as
+    // (spent output, sequence, witness), the witness left out when stripped
+    pub fn canonical_bytes(&self, with_witness: bool) -> Vec<u8> {
+        let inputs = self
+            .inputs
+            .iter()
+            .map(|input| {
+                (
+                    input.prev_transaction_output_hash,
+                    input.sequence,
+                    with_witness.then_some(&input.witness),
+                )
+            })
+            .collect::<Vec<_>>();
+        (inputs, &self.outputs, self.coinbase_height, self.lock_time).encoded()
+    }
+
+    // Signature checks needed to verify the inputs, unknown inputs count nothing
+    pub fn sigops<'a>(
+        &self,
+        prev_output: impl Fn(&Hash) -> Option<&'a TransactionOutput>,
+    ) -> usize {
+        self.inputs
+            .iter()
+            .filter_map(|input| {
+                let output = prev_output(&input.prev_transaction_output_hash)?;
+                Some(output.script.sigops(&input.witness))
+            })
+            .fold(0, usize::saturating_add)
+    }
+
+    // Bytes on the wire and in blocks, what fee rates and size limits refer to.
+    // Encodes the transaction on every call, mempool entries keep the result.
+    pub fn serialized_size(&self) -> usize {
+        crate::util::cbor_size(self)
+    }
+
+    // Digest an input signs to commit to every input, output and the lock time,
+    // so a signature cannot be reused in another transaction spending the same output
+    pub fn signing_digest(&self, index: usize) -> Option<Hash> {
+        self.signature_digest(index, SigHash::ALL)
+    }
+
+    // Digest input `index` signs under `sighash`, see crypto::sighash
+    pub fn signature_digest(&self, index: usize, sighash: SigHash) -> Option<Hash> {
+        crypto::sighash(self, index, sighash)
+    }
+
+    // Signs input `index` under the sighash its witness names with the scheme
+    // the spent script asks for, replacing its signatures. Multisig inputs
+    // collect theirs with signature_digest instead.
+    pub fn sign_input(
+        &mut self,
+        index: usize,
+        key: &PrivateKey,
+        scheme: SignatureScheme,
+    ) -> Result<()> {
+        let sighash = self
+            .inputs
+            .get(index)
+            .ok_or(BtcError::TransactionInput)?
+            .witness
+            .sighash;
+        let digest = self
+            .signature_digest(index, sighash)
+            .ok_or(BtcError::TransactionInput)?;
+        self.inputs[index].witness.signatures = vec![key.sign_with(scheme, &digest)];
+        Ok(())
+    }
+
+    // Outputs are identified by the transaction that created them and their position
+    pub fn output_hash(&self, index: usize) -> Hash {
+        (self.hash(), index as u32).encoded_hash()
+    }
+
+    // Whether the transaction may be included in a block at this height and time
+    pub fn is_final(&self, block_height: u64, block_time: DateTime<Utc>) -> bool {
+        match self.lock_time {
+            None => true,
+            Some(LockTime::Height(height)) => block_height >= height,
+            Some(LockTime::Timestamp(timestamp)) => block_time >= timestamp,
+        }
     }
 }
 
@@ -0,0 +1,51 @@
+use std::collections::HashMap;
+
+use super::UtxoEntry;
+use crate::sha256::Hash;
+
+// Unspent outputs by outpoint as validation sees them. The chain's map is
+// one, a layer of outputs over another view or a store on disk could be
+// others, all without copying the set.
+pub trait UtxoView {
+    fn get(&self, outpoint: &Hash) -> Option<&UtxoEntry>;
+
+    fn contains(&self, outpoint: &Hash) -> bool {
+        self.get(outpoint).is_some()
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (&Hash, &UtxoEntry)> + '_>;
+}
+
+impl UtxoView for HashMap<Hash, UtxoEntry> {
+    fn get(&self, outpoint: &Hash) -> Option<&UtxoEntry> {
+        HashMap::get(self, outpoint)
+    }
+
+    fn contains(&self, outpoint: &Hash) -> bool {
+        self.contains_key(outpoint)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (&Hash, &UtxoEntry)> + '_> {
+        Box::new(HashMap::iter(self))
+    }
+}
+
+// Outputs created on top of a base view, such as those of earlier
+// transactions in a block. Spending them does not hide them, callers
+// track spends themselves.
+pub struct UtxoOverlay<'a> {
+    pub base: &'a dyn UtxoView,
+    pub created: &'a HashMap<Hash, UtxoEntry>,
+}
+
+impl UtxoView for UtxoOverlay<'_> {
+    fn get(&self, outpoint: &Hash) -> Option<&UtxoEntry> {
+        self.base
+            .get(outpoint)
+            .or_else(|| self.created.get(outpoint))
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (&Hash, &UtxoEntry)> + '_> {
+        Box::new(self.base.iter().chain(self.created.iter()))
+    }
+}
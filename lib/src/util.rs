@@ -3,28 +3,174 @@ use std::fs::File;
 use std::io::{Read, Result as IoResult, Write};
 use std::path::Path;
 
+use crate::encoding::Encode;
 use crate::sha256::Hash;
 use crate::types::Transaction;
 
+// Encoded exactly like the Hash it wraps
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(transparent)]
 pub struct MerkleRoot(Hash);
 
 impl MerkleRoot {
     pub fn calculate(transactions: &[Transaction]) -> MerkleRoot {
-        let mut layer: Vec<Hash> = vec![];
-        for tx in transactions {
-            layer.push(Hash::hash(tx));
-        }
-        while layer.len() > 1 {
-            let mut new_layer = vec![];
-            for pair in layer.chunks(2) {
-                let left = pair[0];
-                let right = pair.get(1).unwrap_or(&pair[0]);
-                new_layer.push(Hash::hash(&[left, *right]));
+        MerkleTree::new(transactions).root()
+    }
+
+    // For roots read back from raw header bytes
+    pub(crate) fn from_hash(hash: Hash) -> MerkleRoot {
+        MerkleRoot(hash)
+    }
+
+    // Odd layers pair their last hash with itself, so the tree alone gives a
+    // list ending in a repeated run the root of the shorter list. Committing
+    // to the count as well tells them apart.
+    fn commit(count: usize, tree: Hash) -> MerkleRoot {
+        MerkleRoot((count as u64, tree).encoded_hash())
+    }
+}
+
+impl Encode for MerkleRoot {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.0.encode(out);
+    }
+}
+
+// Every layer of the tree, kept so replacing or appending a transaction
+// only rehashes its path to the root. Templates swap their coinbase this way.
+#[derive(Debug, Clone)]
+pub struct MerkleTree {
+    // Leaves first, each layer pairing up the hashes of the one below
+    layers: Vec<Vec<Hash>>,
+}
+
+impl MerkleTree {
+    pub fn new(transactions: &[Transaction]) -> MerkleTree {
+        // Leaves commit to the witnesses as well
+        let mut layers = vec![transactions
+            .iter()
+            .map(Transaction::wtxid)
+            .collect::<Vec<_>>()];
+        while layers[layers.len() - 1].len() > 1 {
+            let layer = layers[layers.len() - 1]
+                .chunks(2)
+                .map(|pair| (pair[0], *pair.get(1).unwrap_or(&pair[0])).encoded_hash())
+                .collect();
+            layers.push(layer);
+        }
+        MerkleTree { layers }
+    }
+
+    pub fn len(&self) -> usize {
+        self.layers[0].len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.layers[0].is_empty()
+    }
+
+    pub fn root(&self) -> MerkleRoot {
+        let top = self.layers[self.layers.len() - 1].first().copied();
+        MerkleRoot::commit(self.len(), top.unwrap_or(Hash::zero()))
+    }
+
+    // Returns false, changing nothing, when there is no transaction at `index`
+    pub fn replace(&mut self, index: usize, transaction: &Transaction) -> bool {
+        if index >= self.len() {
+            return false;
+        }
+        self.layers[0][index] = transaction.wtxid();
+        self.rehash(index);
+        true
+    }
+
+    pub fn push(&mut self, transaction: &Transaction) {
+        self.layers[0].push(transaction.wtxid());
+        self.rehash(self.len() - 1);
+    }
+
+    // Recomputes the parents of the leaf at `index`, growing layers that
+    // gained a node on the way
+    fn rehash(&mut self, mut index: usize) {
+        let mut level = 0;
+        while self.layers[level].len() > 1 {
+            let layer = &self.layers[level];
+            let left = layer[index & !1];
+            let right = *layer.get(index | 1).unwrap_or(&left);
+            let parent = (left, right).encoded_hash();
+            index /= 2;
+            if level + 1 == self.layers.len() {
+                self.layers.push(Vec::new());
             }
-            layer = new_layer;
+            let next = &mut self.layers[level + 1];
+            if index == next.len() {
+                next.push(parent);
+            } else {
+                next[index] = parent;
+            }
+            level += 1;
+        }
+    }
+
+    // Proves the transaction at `index` is part of the tree
+    pub fn proof(&self, index: usize) -> Option<MerkleProof> {
+        if index >= self.len() {
+            return None;
+        }
+        let mut position = index;
+        let mut siblings = vec![];
+        for layer in &self.layers[..self.layers.len() - 1] {
+            siblings.push(*layer.get(position ^ 1).unwrap_or(&layer[position]));
+            position /= 2;
         }
-        MerkleRoot(layer[0])
+        Some(MerkleProof {
+            index,
+            count: self.len(),
+            siblings,
+        })
+    }
+}
+
+// Path from a transaction up to the merkle root of its block
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct MerkleProof {
+    // Position of the transaction in the block
+    pub index: usize,
+    // Transactions in the block
+    pub count: usize,
+    // The other hash paired with at each layer, leaves first
+    pub siblings: Vec<Hash>,
+}
+
+impl MerkleProof {
+    // Proves the transaction at `index` is part of the block
+    pub fn prove(transactions: &[Transaction], index: usize) -> Option<MerkleProof> {
+        MerkleTree::new(transactions).proof(index)
+    }
+
+    // Leaves commit to witnesses, so `tx_hash` is the wtxid of the transaction
+    pub fn verify(root: &MerkleRoot, tx_hash: Hash, proof: &MerkleProof) -> bool {
+        // The count fixes the depth of the tree
+        let mut depth = 0;
+        let mut width = proof.count;
+        while width > 1 {
+            width = width.div_ceil(2);
+            depth += 1;
+        }
+        if proof.index >= proof.count || proof.siblings.len() != depth {
+            return false;
+        }
+        let mut hash = tx_hash;
+        let mut position = proof.index;
+        for sibling in &proof.siblings {
+            hash = if position.is_multiple_of(2) {
+                (hash, *sibling).encoded_hash()
+            } else {
+                (*sibling, hash).encoded_hash()
+            };
+            position /= 2;
+        }
+        MerkleRoot::commit(proof.count, hash) == *root
     }
 }
 
@@ -43,3 +189,87 @@ where
         Self::load(file)
     }
 }
+
+// Length of the CBOR encoding of `value`, counted without buffering it
+pub(crate) fn cbor_size<T: Serialize>(value: &T) -> usize {
+    struct Counter(usize);
+
+    impl Write for Counter {
+        fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+            self.0 += buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> IoResult<()> {
+            Ok(())
+        }
+    }
+
+    let mut counter = Counter(0);
+    ciborium::into_writer(value, &mut counter).expect("Bug: Impossible");
+    counter.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Amount;
+    use crate::script::Script;
+    use crate::types::TransactionOutput;
+
+    // Distinct transactions, told apart by the height they commit to
+    fn transactions(count: u64) -> Vec<Transaction> {
+        (0..count)
+            .map(|height| {
+                let output = TransactionOutput {
+                    value: Amount::from_sat(1),
+                    script: Script::PayToPubkeyHash(Hash::zero()),
+                };
+                Transaction::coinbase(height, vec![output])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn merkle_root_commits_to_the_count() {
+        let transactions = transactions(3);
+        let mut repeated = transactions.clone();
+        repeated.push(transactions[2].clone());
+        let root = MerkleRoot::calculate(&transactions);
+        // Same tree hash, the last pair of both lists is the third twice
+        assert_ne!(root, MerkleRoot::calculate(&repeated));
+
+        for index in 0..3 {
+            let proof = MerkleProof::prove(&transactions, index).unwrap();
+            assert!(MerkleProof::verify(
+                &root,
+                transactions[index].wtxid(),
+                &proof
+            ));
+        }
+        let proof = MerkleProof::prove(&repeated, 3).unwrap();
+        assert!(!MerkleProof::verify(&root, repeated[3].wtxid(), &proof));
+        let mut proof = MerkleProof::prove(&transactions, 2).unwrap();
+        proof.count = 4;
+        assert!(!MerkleProof::verify(&root, transactions[2].wtxid(), &proof));
+    }
+
+    #[test]
+    fn merkle_tree_updates_match_a_rebuild() {
+        let mut transactions = self::transactions(20);
+        let other = transactions.split_off(10);
+        let mut tree = MerkleTree::new(&[]);
+        for count in 1..=transactions.len() {
+            tree.push(&transactions[count - 1]);
+            assert_eq!(tree.root(), MerkleRoot::calculate(&transactions[..count]));
+        }
+        let mut replaced = transactions.clone();
+        for index in 0..replaced.len() {
+            assert!(tree.replace(index, &other[index]));
+            replaced[index] = other[index].clone();
+            assert_eq!(tree.root(), MerkleRoot::calculate(&replaced));
+        }
+        assert!(!tree.replace(replaced.len(), &other[0]));
+        assert_eq!(tree.root(), MerkleRoot::calculate(&replaced));
+    }
+}
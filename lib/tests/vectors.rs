@@ -0,0 +1,78 @@
+use btclib::params::ChainParams;
+use btclib::types::{Block, Blockchain, Transaction};
+use btclib::util::Saveable;
+use std::fs;
+use std::path::PathBuf;
+
+// Any change to these results alters consensus, regenerate the vectors
+// with vector_gen only when that is intended.
+#[test]
+fn consensus_vectors() {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("testdata");
+    let manifest = fs::read_to_string(dir.join("vectors.txt")).expect("missing vectors.txt");
+    let mut blockchain = Blockchain::new(ChainParams::mainnet());
+    let mut transactions = Vec::new();
+    let (mut blocks, mut checked_transactions) = (0, 0);
+    for line in manifest.lines().filter(|line| !line.starts_with('#')) {
+        let fields = line.split('\t').collect::<Vec<_>>();
+        // The last field names the consensus rule a vector exercises, see rules_gen
+        let [name, kind, hash, wtxid, expected, _rule] = fields[..] else {
+            panic!("malformed vector: {}", line);
+        };
+        let path = dir.join(format!("{}.cbor", name));
+        match kind {
+            "block" => {
+                let block = Block::load_from_file(&path).expect("failed to load block");
+                assert_eq!(block.hash().to_string(), hash, "hash of {}", name);
+                let result = match blockchain.add_block(block) {
+                    Ok(()) => "valid".to_string(),
+                    Err(e) => e.to_string(),
+                };
+                assert_eq!(result, expected, "validation of {}", name);
+                blocks += 1;
+            }
+            "transaction" => {
+                let transaction =
+                    Transaction::load_from_file(&path).expect("failed to load transaction");
+                assert_eq!(transaction.txid().to_string(), hash, "txid of {}", name);
+                assert_eq!(transaction.wtxid().to_string(), wtxid, "wtxid of {}", name);
+                let result = check_witnesses(&transaction, &transactions);
+                assert_eq!(result, expected, "validation of {}", name);
+                transactions.push(transaction);
+                checked_transactions += 1;
+            }
+            _ => panic!("unknown vector kind {}", kind),
+        }
+    }
+    assert!(blocks > 0, "no block vectors, run vector_gen");
+    assert!(
+        checked_transactions > 0,
+        "no transaction vectors, run vector_gen"
+    );
+    blockchain
+        .verify_supply()
+        .expect("supply does not match the subsidy schedule");
+}
+
+// Each input has to satisfy the script of an output of an earlier vector
+fn check_witnesses(transaction: &Transaction, earlier: &[Transaction]) -> String {
+    for (index, input) in transaction.inputs.iter().enumerate() {
+        let spent = earlier
+            .iter()
+            .flat_map(|tx| (0..tx.outputs.len()).map(move |output| (tx, output)))
+            .find(|(tx, output)| tx.output_hash(*output) == input.prev_transaction_output_hash);
+        let valid = spent.is_some_and(|(tx, output)| {
+            transaction
+                .signature_digest(index, input.witness.sighash)
+                .is_some_and(|digest| {
+                    tx.outputs[output]
+                        .script
+                        .verify(&input.witness, &digest, u64::MAX)
+                })
+        });
+        if !valid {
+            return format!("invalid witness at input {}", index);
+        }
+    }
+    "valid".to_string()
+}
@@ -1,7 +1,8 @@
 use anyhow::{anyhow, Result};
 use btclib::crypto::PublicKey;
-use btclib::network::Message;
-use btclib::types::Block;
+use btclib::network::{self, Capabilities, Message};
+use btclib::params::ChainParams;
+use btclib::types::{Block, BlockHeight};
 use btclib::util::Saveable;
 use clap::Parser;
 use std::sync::{
@@ -11,7 +12,9 @@ use std::sync::{
 use std::thread;
 use tokio::net::TcpStream;
 use tokio::sync::Mutex;
-use tokio::time::{interval, Duration};
+use tokio::time::{interval, sleep, Duration};
+
+const SUBMIT_ATTEMPTS: usize = 5;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = "None")]
@@ -20,6 +23,13 @@ struct Cli {
     address: String,
     #[arg(short, long)]
     public_key_file: String,
+    #[arg(short, long)]
+    token: Option<String>,
+    #[arg(short, long, default_value = "mainnet")]
+    network: String,
+    /// Custom chain parameters file, overrides --network
+    #[arg(short, long)]
+    chain_params: Option<String>,
 }
 
 struct Miner {
@@ -29,11 +39,78 @@ struct Miner {
     mining: Arc<AtomicBool>,
     mined_block_sender: flume::Sender<Block>,
     mined_block_receiver: flume::Receiver<Block>,
+    tip_receiver: flume::Receiver<BlockHeight>,
+}
+
+async fn connect(address: &str, token: Option<&str>) -> Result<TcpStream> {
+    let mut stream = TcpStream::connect(address).await?;
+    if let Some(token) = token {
+        Message::Authenticate(token.to_string())
+            .send_async(&mut stream)
+            .await?;
+        match Message::receive_async(&mut stream).await? {
+            Message::Authenticated(true) => println!("Authenticated with node"),
+            _ => return Err(anyhow!("Node rejected the access token")),
+        }
+    }
+    Ok(stream)
+}
+
+// Refuses to mine for a node that follows different consensus rules
+async fn check_chain_params(stream: &mut TcpStream, params: &ChainParams) -> Result<()> {
+    Message::FetchChainParams.send_async(stream).await?;
+    match Message::receive_async(stream).await? {
+        Message::ChainParams(node_params) if node_params == *params => Ok(()),
+        Message::ChainParams(node_params) => Err(anyhow!(
+            "Node runs on {}, miner was started for {}",
+            node_params.name,
+            params.name
+        )),
+        _ => Err(anyhow!(
+            "Unexpected message received when fetching chain params"
+        )),
+    }
+}
+
+// Tip changes arrive on their own connection so they never interleave with replies
+async fn follow_tip(mut stream: TcpStream, sender: flume::Sender<BlockHeight>) -> Result<()> {
+    Message::Hello {
+        version: network::PROTOCOL_VERSION,
+        capabilities: Capabilities::SUBSCRIPTIONS,
+    }
+    .send_async(&mut stream)
+    .await?;
+    match Message::receive_async(&mut stream).await? {
+        Message::HelloAck { capabilities, .. }
+            if capabilities.contains(Capabilities::SUBSCRIPTIONS) => {}
+        _ => return Err(anyhow!("Node does not offer tip subscriptions")),
+    }
+    Message::SubscribeTip.send_async(&mut stream).await?;
+    loop {
+        match Message::receive_async(&mut stream).await? {
+            Message::TipChanged { height, .. } => sender.send_async(height).await?,
+            _ => return Err(anyhow!("Unexpected message on tip subscription")),
+        }
+    }
 }
 
 impl Miner {
-    async fn new(address: String, public_key: PublicKey) -> Result<Self> {
-        let stream = TcpStream::connect(&address).await?;
+    async fn new(
+        address: String,
+        public_key: PublicKey,
+        token: Option<String>,
+        params: ChainParams,
+    ) -> Result<Self> {
+        let mut stream = connect(&address, token.as_deref()).await?;
+        check_chain_params(&mut stream, &params).await?;
+        println!("Mining on {}", params.name);
+        let tip_stream = connect(&address, token.as_deref()).await?;
+        let (tip_sender, tip_receiver) = flume::unbounded();
+        tokio::spawn(async move {
+            if let Err(e) = follow_tip(tip_stream, tip_sender).await {
+                println!("Tip subscription ended, relying on polling: {}", e);
+            }
+        });
         let (mined_block_sender, mined_block_receiver) = flume::unbounded();
         Ok(Self {
             public_key,
@@ -42,6 +119,7 @@ impl Miner {
             mining: Arc::new(AtomicBool::new(false)),
             mined_block_sender,
             mined_block_receiver,
+            tip_receiver,
         })
     }
     async fn run(&self) -> Result<()> {
@@ -49,6 +127,7 @@ impl Miner {
         let mut template_interval = interval(Duration::from_secs(5));
         loop {
             let receiver_clone = self.mined_block_receiver.clone();
+            let tip_receiver = self.tip_receiver.clone();
             tokio::select! {
                 _ = template_interval.tick() => {
                     self.fetch_and_validate_template().await?;
@@ -56,6 +135,11 @@ impl Miner {
                 Ok(mined_block) = receiver_clone.recv_async() => {
                 self.submit_block(mined_block).await?;
                 }
+                Ok(height) = tip_receiver.recv_async() => {
+                    println!("New tip at height {}, abandoning current work", height);
+                    self.mining.store(false, Ordering::Relaxed);
+                    self.fetch_template().await?;
+                }
             }
         }
     }
@@ -67,7 +151,10 @@ impl Miner {
         thread::spawn(move || loop {
             if mining.load(Ordering::Relaxed) {
                 if let Some(mut block) = template.lock().unwrap().clone() {
-                    println!("Mining block with target: {}", block.header.target);
+                    println!(
+                        "Mining block with target: {}",
+                        block.header.target().to_hex()
+                    );
                     if block.header.mine(2_000_000) {
                         println!("Block mined: {}", block.hash());
                         sender.send(block).expect("Failed to send mined block");
@@ -99,19 +186,25 @@ impl Miner {
                 drop(stream_lock);
                 println!(
                     "Received new template with target {}",
-                    template.header.target
+                    template.header.target()
                 );
                 *self.current_template.lock().unwrap() = Some(template);
                 self.mining.store(true, Ordering::Relaxed);
                 Ok(())
             }
+            Message::NotReady { progress } => {
+                println!("Node is still syncing ({}%), waiting", progress);
+                Ok(())
+            }
             _ => Err(anyhow!(
                 "Unexpected message received when fetching template"
             )),
         }
     }
     async fn validate_template(&self) -> Result<()> {
-        if let Some(template) = self.current_template.lock().unwrap().clone() {
+        // Cloned first, the guard must not be held across the awaits below
+        let template = self.current_template.lock().unwrap().clone();
+        if let Some(template) = template {
             let message = Message::ValidateTemplate(template);
             let mut stream_lock = self.stream.lock().await;
             message.send_async(&mut *stream_lock).await?;
@@ -128,6 +221,10 @@ impl Miner {
                     }
                     Ok(())
                 }
+                Message::NotReady { progress } => {
+                    println!("Node is syncing ({}%), keeping current template", progress);
+                    Ok(())
+                }
                 _ => Err(anyhow!(
                     "Unexpected message received when validating template"
                 )),
@@ -138,11 +235,33 @@ impl Miner {
     }
 
     async fn submit_block(&self, block: Block) -> Result<()> {
-        println!("Submitting mined block");
-        let message = Message::SubmitTemplate(block);
-        let mut stream_lock = self.stream.lock().await;
-        message.send_async(&mut *stream_lock).await?;
         self.mining.store(false, Ordering::Relaxed);
+        for _ in 0..SUBMIT_ATTEMPTS {
+            println!("Submitting mined block");
+            let message = Message::SubmitTemplate(block.clone());
+            let mut stream_lock = self.stream.lock().await;
+            message.send_async(&mut *stream_lock).await?;
+            match Message::receive_async(&mut *stream_lock).await? {
+                Message::BlockAccepted(hash) => {
+                    println!("Block {} accepted", hash);
+                    return Ok(());
+                }
+                // Whatever was wrong, a fresh template reflects the node's current view
+                Message::Reject(reason) => {
+                    drop(stream_lock);
+                    println!("Block rejected: {}, fetching a new template", reason);
+                    return self.fetch_template().await;
+                }
+                // The block is still good once the node caught up
+                Message::NotReady { progress } => {
+                    drop(stream_lock);
+                    println!("Node is syncing ({}%), resubmitting shortly", progress);
+                    sleep(Duration::from_secs(1)).await;
+                }
+                _ => return Err(anyhow!("Unexpected message received when submitting block")),
+            }
+        }
+        println!("Giving up on mined block");
         Ok(())
     }
 }
@@ -152,6 +271,12 @@ async fn main() -> Result<()> {
     let cli = Cli::parse();
     let public_key = PublicKey::load_from_file(&cli.public_key_file)
         .map_err(|e| anyhow!("Error loading public key: {}", e))?;
-    let miner = Miner::new(cli.address, public_key).await?;
+    let params = match &cli.chain_params {
+        Some(file) => ChainParams::load_from_file(file)
+            .map_err(|e| anyhow!("Error loading chain params: {}", e))?,
+        None => ChainParams::preset(&cli.network)
+            .ok_or_else(|| anyhow!("Unknown network: {}", cli.network))?,
+    };
+    let miner = Miner::new(cli.address, public_key, cli.token, params).await?;
     miner.run().await
 }
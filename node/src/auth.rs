@@ -0,0 +1,121 @@
+use anyhow::{anyhow, Result};
+use btclib::network::Message;
+use std::str::FromStr;
+use tokio::net::TcpStream;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    ReadOnly,
+    Wallet,
+    Admin,
+}
+
+impl FromStr for Role {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "read-only" | "readonly" => Ok(Role::ReadOnly),
+            "wallet" => Ok(Role::Wallet),
+            "admin" => Ok(Role::Admin),
+            _ => Err(anyhow!("unknown role: {}", s)),
+        }
+    }
+}
+
+// Tokens are given on the command line as `<role>:<token>`
+pub fn parse_token(s: &str) -> Result<(String, Role)> {
+    let (role, token) = s
+        .split_once(':')
+        .ok_or_else(|| anyhow!("expected <role>:<token>, got {}", s))?;
+    if token.is_empty() {
+        return Err(anyhow!("empty token for role {}", role));
+    }
+    Ok((token.to_string(), role.parse()?))
+}
+
+// Without configured tokens the node keeps its open, localhost-only behaviour
+pub fn default_role() -> Role {
+    if crate::TOKENS.is_empty() {
+        Role::Admin
+    } else {
+        Role::ReadOnly
+    }
+}
+
+pub fn authenticate(token: &str) -> Option<Role> {
+    crate::TOKENS.get(token).map(|role| *role)
+}
+
+// Only wallet and mining RPCs and operator queries are gated, blocks and
+// transactions relayed by peers are open to every role
+pub fn required_role(message: &Message) -> Role {
+    use btclib::network::Message::*;
+    match message {
+        SubmitTransaction(_) => Role::Wallet,
+        FetchTemplate(_) | ValidateTemplate(_) | SubmitTemplate(_) | FetchCompetingTips => {
+            Role::Admin
+        }
+        _ => Role::ReadOnly,
+    }
+}
+
+pub async fn authenticate_stream(stream: &mut TcpStream, token: &str) -> Result<()> {
+    Message::Authenticate(token.to_string())
+        .send_async(stream)
+        .await?;
+    match Message::receive_async(stream).await? {
+        Message::Authenticated(true) => Ok(()),
+        Message::Authenticated(false) => Err(anyhow!("token rejected by peer")),
+        e => Err(anyhow!("unexpected message during authentication: {:?}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use btclib::crypto::PrivateKey;
+    use btclib::sha256::Hash;
+    use btclib::types::{Block, BlockHeader, Transaction};
+    use btclib::util::MerkleRoot;
+    use chrono::Utc;
+
+    #[test]
+    fn role_matrix() {
+        let transaction = Transaction::coinbase(0, vec![]);
+        let merkle_root = MerkleRoot::calculate(std::slice::from_ref(&transaction));
+        let header = BlockHeader::new(Utc::now(), 0, Hash::zero(), merkle_root, btclib::MIN_TARGET);
+        let block = Block::new(header, vec![transaction.clone()]);
+        let pubkey = PrivateKey::generate().public_key();
+        let cases = [
+            (Message::NewBlock(block.clone()), Role::ReadOnly),
+            (Message::NewTransaction(transaction.clone()), Role::ReadOnly),
+            (Message::SubmitTransaction(transaction), Role::Wallet),
+            (Message::FetchTemplate(pubkey), Role::Admin),
+            (Message::ValidateTemplate(block.clone()), Role::Admin),
+            (Message::SubmitTemplate(block), Role::Admin),
+            (Message::FetchCompetingTips, Role::Admin),
+        ];
+        for (message, role) in cases {
+            assert_eq!(required_role(&message), role, "{:?}", message);
+        }
+    }
+
+    #[test]
+    fn default_role_without_and_with_tokens() {
+        assert_eq!(default_role(), Role::Admin);
+        let (token, role) = parse_token("wallet:secret").unwrap();
+        crate::TOKENS.insert(token, role);
+        assert_eq!(default_role(), Role::ReadOnly);
+        assert_eq!(authenticate("secret"), Some(Role::Wallet));
+        assert_eq!(authenticate("guess"), None);
+        crate::TOKENS.clear();
+    }
+
+    #[test]
+    fn parse_token_rejects_malformed() {
+        assert!(parse_token("admin").is_err());
+        assert!(parse_token("admin:").is_err());
+        assert!(parse_token("root:secret").is_err());
+    }
+}
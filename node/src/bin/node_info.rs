@@ -0,0 +1,51 @@
+use anyhow::{anyhow, Result};
+use btclib::network::Message;
+use std::env;
+use std::process::exit;
+use tokio::net::TcpStream;
+
+const USAGE: &str = "Usage: node_info <address> [token]";
+
+// Prints the chain, peers and competing tips a running node knows about
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = env::args().collect::<Vec<_>>();
+    let Some(address) = args.get(1) else {
+        eprintln!("{}", USAGE);
+        exit(1);
+    };
+    let mut stream = TcpStream::connect(address).await?;
+    if let Some(token) = args.get(2) {
+        Message::Authenticate(token.clone())
+            .send_async(&mut stream)
+            .await?;
+        match Message::receive_async(&mut stream).await? {
+            Message::Authenticated(true) => {}
+            _ => return Err(anyhow!("node rejected the token")),
+        }
+    }
+
+    Message::AskWork.send_async(&mut stream).await?;
+    match Message::receive_async(&mut stream).await? {
+        Message::Work { work, height } => println!("blocks: {}\nchain work: {}", height, work),
+        message => return Err(anyhow!("unexpected response: {:?}", message)),
+    }
+
+    Message::DiscoverNodes.send_async(&mut stream).await?;
+    match Message::receive_async(&mut stream).await? {
+        Message::NodeList(nodes) => println!("peers: {}", nodes.len()),
+        message => return Err(anyhow!("unexpected response: {:?}", message)),
+    }
+
+    Message::FetchCompetingTips.send_async(&mut stream).await?;
+    match Message::receive_async(&mut stream).await? {
+        Message::CompetingTips(tips) => {
+            println!("competing tips: {}", tips.len());
+            for (height, hash) in tips {
+                println!("  {} {}", height, hash);
+            }
+        }
+        message => return Err(anyhow!("unexpected response: {:?}", message)),
+    }
+    Ok(())
+}
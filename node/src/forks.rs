@@ -0,0 +1,125 @@
+use btclib::sha256::Hash;
+use btclib::types::{Block, BlockHeight, Blockchain};
+use dashmap::DashMap;
+use static_init::dynamic;
+use std::time::{Duration, Instant};
+use tokio::time;
+
+// Competing tips further behind our tip than this are forgotten
+const MAX_FORK_DEPTH: u64 = 100;
+// Only tips this close to ours count as a live fork worth alerting about
+const LIVE_FORK_DEPTH: u64 = 6;
+// Tips watched at once, the lowest make room for higher ones
+const MAX_COMPETING_TIPS: usize = 64;
+
+// A block with valid proof of work under the target our chain expects at
+// its height that does not extend our chain. Nothing reorganizes to it yet,
+// it is only watched.
+pub struct CompetingTip {
+    pub height: BlockHeight,
+    // When the fork this tip is on was first seen
+    pub first_seen: Instant,
+    alerted: bool,
+}
+
+#[dynamic]
+pub static COMPETING_TIPS: DashMap<Hash, CompetingTip> = DashMap::new();
+
+// Called with blocks peers announced that did not extend our tip
+pub fn observe(block: &Block, blockchain: &Blockchain) {
+    // The coinbase commits to the height even when the parent is unknown
+    let Some(height) = block
+        .transactions
+        .first()
+        .and_then(|coinbase| coinbase.coinbase_height)
+        .map(BlockHeight::new)
+    else {
+        return;
+    };
+    let hash = block.hash();
+    if blockchain.hash_at(height) == Some(hash) {
+        return;
+    }
+    if COMPETING_TIPS.contains_key(&hash) {
+        return;
+    }
+    // Headers past our tip are held to the target we would expect next
+    let expected = blockchain
+        .header_at(height)
+        .map(|header| header.bits)
+        .unwrap_or_else(|| blockchain.target().to_compact());
+    if block.header.bits != expected || !hash.matches_target(block.header.target()) {
+        return;
+    }
+    // A tip building on a known competing tip replaces it, the fork stays as old
+    let (first_seen, alerted) = match COMPETING_TIPS.remove(&block.header.prev_block_hash) {
+        Some((_, parent)) => (parent.first_seen, parent.alerted),
+        None => (Instant::now(), false),
+    };
+    if COMPETING_TIPS.len() >= MAX_COMPETING_TIPS {
+        let lowest = COMPETING_TIPS
+            .iter()
+            .map(|tip| (tip.height, *tip.key()))
+            .min();
+        match lowest {
+            Some((lowest, lowest_hash)) if lowest < height => {
+                COMPETING_TIPS.remove(&lowest_hash);
+            }
+            _ => return,
+        }
+    }
+    println!("competing tip {} at height {}", hash, height);
+    COMPETING_TIPS.insert(
+        hash,
+        CompetingTip {
+            height,
+            first_seen,
+            alerted,
+        },
+    );
+}
+
+// Heights and hashes, highest first
+pub fn competing_tips() -> Vec<(BlockHeight, Hash)> {
+    let mut tips = COMPETING_TIPS
+        .iter()
+        .map(|tip| (tip.height, *tip.key()))
+        .collect::<Vec<_>>();
+    tips.sort_by(|a, b| b.cmp(a));
+    tips
+}
+
+// Forgets tips left far behind or that our chain connected after all, for
+// blocks that merely arrived ahead of their parent, and warns once about
+// each fork that stays close to our tip for longer than `alert_after`
+pub async fn monitor(alert_after: Duration) {
+    let mut interval = time::interval(Duration::from_secs(30));
+    loop {
+        interval.tick().await;
+        let tip = {
+            let blockchain = crate::BLOCKCHAIN.read().await;
+            let Some(tip) = blockchain.tip_height() else {
+                continue;
+            };
+            COMPETING_TIPS.retain(|hash, fork| {
+                let connected = blockchain.hash_at(fork.height) == Some(*hash);
+                !connected && fork.height.get().saturating_add(MAX_FORK_DEPTH) >= tip.get()
+            });
+            tip
+        };
+        for mut fork in COMPETING_TIPS.iter_mut() {
+            let live = fork.height.get().saturating_add(LIVE_FORK_DEPTH) >= tip.get();
+            if live && !fork.alerted && fork.first_seen.elapsed() >= alert_after {
+                println!(
+                    "WARNING: fork persists for {}s, competing tip {} at height {} (ours {}), \
+                    peers may disagree on consensus",
+                    fork.first_seen.elapsed().as_secs(),
+                    fork.key(),
+                    fork.height,
+                    tip
+                );
+                fork.alerted = true;
+            }
+        }
+    }
+}
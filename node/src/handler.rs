@@ -1,14 +1,26 @@
-use btclib::network::Message;
+use crate::auth;
+use crate::load;
+use btclib::error::BtcError;
+use btclib::network::{self, Capabilities, IndexedTransaction, Message, RejectReason};
 use btclib::sha256::Hash;
-use btclib::types::{Block, BlockHeader, Transaction, TransactionOutput};
-use btclib::util::MerkleRoot;
-use chrono::Utc;
+use btclib::types::BlockHeight;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Instant;
 use tokio::net::TcpStream;
-use uuid::Uuid;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Mutex;
 
-pub async fn handle_connection(mut socket: TcpStream) {
+pub async fn handle_connection(socket: TcpStream) {
+    // Replies and pushed events share the write half
+    let (mut reader, writer) = socket.into_split();
+    let writer = Arc::new(Mutex::new(writer));
+    let mut role = auth::default_role();
+    // Peers that never say hello are older versions without optional features
+    let mut capabilities = Capabilities::default();
+    let mut filter = None;
     loop {
-        let message = match Message::receive_async(&mut socket).await {
+        let message = match Message::receive_async(&mut reader).await {
             Ok(message) => message,
             Err(e) => {
                 println!("invalid message from peer: {e}, closing that connection");
@@ -16,18 +28,204 @@ pub async fn handle_connection(mut socket: TcpStream) {
             }
         };
         use btclib::network::Message::*;
+        if auth::required_role(&message) > role {
+            println!("unauthorized request from peer with role {:?}", role);
+            if Unauthorized
+                .send_async(&mut *writer.lock().await)
+                .await
+                .is_err()
+            {
+                return;
+            }
+            continue;
+        }
+        if crate::WATCH_ONLY.load(Ordering::Relaxed) {
+            match &message {
+                SubmitTransaction(_) | FetchTemplate(_) | ValidateTemplate(_)
+                | SubmitTemplate(_) => {
+                    println!("watch-only node, refusing request");
+                    if Unauthorized
+                        .send_async(&mut *writer.lock().await)
+                        .await
+                        .is_err()
+                    {
+                        return;
+                    }
+                    continue;
+                }
+                NewTransaction(_) => continue,
+                _ => {}
+            }
+        }
+        if load::is_expensive(&message) && load::is_overloaded() {
+            if Busy.send_async(&mut *writer.lock().await).await.is_err() {
+                return;
+            }
+            continue;
+        }
+        if !crate::READY.load(Ordering::Relaxed) {
+            match &message {
+                FetchUTXOs(_)
+                | FetchTemplate(_)
+                | ValidateTemplate(_)
+                | SubmitTransaction(_)
+                | SubmitTemplate(_)
+                | FetchHistory { .. }
+                | FetchUtxoProofs(_)
+                | FetchMerkleBlock(_)
+                | FetchBlockFilters { .. }
+                | FetchTransaction(_) => {
+                    let progress = crate::SYNC_PROGRESS.load(Ordering::Relaxed);
+                    let message = NotReady { progress };
+                    if message.send_async(&mut *writer.lock().await).await.is_err() {
+                        return;
+                    }
+                    continue;
+                }
+                NewTransaction(_) | NewBlock(_) => {
+                    println!("still syncing, ignoring submission");
+                    continue;
+                }
+                _ => {}
+            }
+        }
         match message {
-            UTXOs(_) | Template(_) | Difference(_) | TemplateValidity(_) | NodeList(_) => {
+            UTXOs(_)
+            | Template(_)
+            | Difference(_)
+            | TemplateValidity(_)
+            | NodeList(_)
+            | Authenticated(_)
+            | Unauthorized
+            | NotReady { .. }
+            | TransactionAccepted(_)
+            | HelloAck { .. }
+            | Incompatible { .. }
+            | TipChanged { .. }
+            | ChainParams(_)
+            | BlockAccepted(_)
+            | Reject(_)
+            | BlockStream { .. }
+            | StreamedTransaction(_)
+            | History(_)
+            | Work { .. }
+            | Busy
+            | FeeEstimate(_)
+            | CompetingTips(_)
+            | Headers(_)
+            | UtxoProofs(_)
+            | MerkleBlock(_)
+            | BlockFilters(_)
+            | TransactionInfo(_)
+            | ForkPoint(_)
+            | Stats(_) => {
                 println!("I am neither a miner nor a wallet! Goodbye");
                 return;
             }
+            Authenticate(token) => {
+                let accepted = match auth::authenticate(&token) {
+                    Some(new_role) => {
+                        println!("peer authenticated with role {:?}", new_role);
+                        role = new_role;
+                        true
+                    }
+                    None => {
+                        println!("peer presented an unknown token");
+                        false
+                    }
+                };
+                let message = Authenticated(accepted);
+                message.send_async(&mut *writer.lock().await).await.unwrap();
+            }
+            Hello {
+                version,
+                capabilities: theirs,
+            } => {
+                if !network::is_compatible_version(version) {
+                    println!("peer speaks incompatible protocol version {}", version);
+                    let message = Incompatible {
+                        min_version: network::MIN_PROTOCOL_VERSION,
+                    };
+                    let _ = message.send_async(&mut *writer.lock().await).await;
+                    return;
+                }
+                capabilities = Capabilities::SUPPORTED.intersection(theirs);
+                println!(
+                    "peer speaks version {} with capabilities {:?}",
+                    version, capabilities
+                );
+                let message = HelloAck {
+                    version: network::PROTOCOL_VERSION,
+                    capabilities,
+                };
+                message.send_async(&mut *writer.lock().await).await.unwrap();
+            }
+            SubscribeTip => {
+                if !capabilities.contains(Capabilities::SUBSCRIPTIONS) {
+                    println!("peer subscribed without negotiating subscriptions");
+                    let reason = RejectReason::Other("subscriptions were not negotiated".into());
+                    let _ = Reject(reason).send_async(&mut *writer.lock().await).await;
+                    continue;
+                }
+                println!("peer subscribed to tip changes");
+                let mut tip_changes = crate::TIP_CHANGES.subscribe();
+                let current_tip = {
+                    let blockchain = crate::BLOCKCHAIN.read().await;
+                    crate::util::current_tip(&blockchain)
+                };
+                let writer = writer.clone();
+                tokio::spawn(async move {
+                    if let Some((height, hash)) = current_tip {
+                        let message = TipChanged { height, hash };
+                        if message.send_async(&mut *writer.lock().await).await.is_err() {
+                            return;
+                        }
+                    }
+                    loop {
+                        let (height, hash) = match tip_changes.recv().await {
+                            Ok(tip) => tip,
+                            // Only the newest tip matters to subscribers
+                            Err(RecvError::Lagged(_)) => continue,
+                            Err(RecvError::Closed) => return,
+                        };
+                        let message = TipChanged { height, hash };
+                        if message.send_async(&mut *writer.lock().await).await.is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+            FetchChainParams => {
+                let params = crate::BLOCKCHAIN.read().await.params().clone();
+                let message = ChainParams(params);
+                message.send_async(&mut *writer.lock().await).await.unwrap();
+            }
             FetchBlock(height) => {
-                let blockchain = crate::BLOCKCHAIN.read().await;
-                let Some(block) = blockchain.blocks().nth(height).cloned() else {
+                let pruned = crate::BLOCKCHAIN.read().await.pruned_count();
+                if height.get() < pruned {
+                    let reason = RejectReason::from(&BtcError::Pruned { below: pruned });
+                    let message = Reject(reason);
+                    message.send_async(&mut *writer.lock().await).await.unwrap();
+                    continue;
+                }
+                // Saved blocks come from the store, only newer ones from memory
+                let stored = match crate::STORE.read().await.as_ref() {
+                    Some(store) => store.block(height).ok().flatten(),
+                    None => None,
+                };
+                let block = match stored {
+                    Some(block) => Some(block),
+                    None => crate::BLOCKCHAIN
+                        .read()
+                        .await
+                        .block_by_height(height)
+                        .cloned(),
+                };
+                let Some(block) = block else {
                     return;
                 };
                 let message = NewBlock(block);
-                message.send_async(&mut socket).await.unwrap();
+                message.send_async(&mut *writer.lock().await).await.unwrap();
             }
             DiscoverNodes => {
                 let nodes = crate::NODES
@@ -35,39 +233,167 @@ pub async fn handle_connection(mut socket: TcpStream) {
                     .map(|x| x.key().clone())
                     .collect::<Vec<_>>();
                 let message = NodeList(nodes);
-                message.send_async(&mut socket).await.unwrap();
+                message.send_async(&mut *writer.lock().await).await.unwrap();
+            }
+            FetchHistory { pubkey, page } => {
+                let blockchain = crate::BLOCKCHAIN.read().await;
+                let message = History(blockchain.address_history(&pubkey, page as usize));
+                message.send_async(&mut *writer.lock().await).await.unwrap();
+            }
+            FetchFeeEstimate {
+                confirmation_target,
+            } => {
+                let blockchain = crate::BLOCKCHAIN.read().await;
+                let message = FeeEstimate(blockchain.estimate_fee_rate(confirmation_target));
+                message.send_async(&mut *writer.lock().await).await.unwrap();
+            }
+            FetchHeaders { start, count } => {
+                let blockchain = crate::BLOCKCHAIN.read().await;
+                let count = count.min(btclib::MAX_HEADERS_PER_MESSAGE) as u64;
+                let end = BlockHeight::new(start.get().saturating_add(count));
+                let headers = blockchain.headers(start..end).cloned().collect();
+                let message = Headers(headers);
+                message.send_async(&mut *writer.lock().await).await.unwrap();
+            }
+            FetchTransaction(txid) => {
+                let blockchain = crate::BLOCKCHAIN.read().await;
+                let info = blockchain
+                    .tx_location(&txid)
+                    .map(|location| IndexedTransaction {
+                        location: *location,
+                        transaction: blockchain.transaction(&txid).cloned(),
+                    });
+                let message = TransactionInfo(info);
+                message.send_async(&mut *writer.lock().await).await.unwrap();
+            }
+            FetchStats => {
+                let blockchain = crate::BLOCKCHAIN.read().await;
+                let mempool = crate::MEMPOOL.read().await;
+                let message = Stats(blockchain.stats(&mempool));
+                message.send_async(&mut *writer.lock().await).await.unwrap();
+            }
+            FetchForkPoint(locator) => {
+                let blockchain = crate::BLOCKCHAIN.read().await;
+                let message = ForkPoint(blockchain.find_fork_point(&locator));
+                message.send_async(&mut *writer.lock().await).await.unwrap();
+            }
+            FetchUtxoProofs(outpoints) => {
+                let blockchain = crate::BLOCKCHAIN.read().await;
+                let message = UtxoProofs(crate::util::utxo_proofs(&blockchain, &outpoints));
+                message.send_async(&mut *writer.lock().await).await.unwrap();
+            }
+            FilterLoad(new_filter) => {
+                if !new_filter.is_within_limits() {
+                    println!("peer loaded an oversized bloom filter, closing that connection");
+                    return;
+                }
+                filter = Some(new_filter);
+            }
+            FilterClear => filter = None,
+            FetchMerkleBlock(height) => {
+                let blockchain = crate::BLOCKCHAIN.read().await;
+                let block = blockchain
+                    .block_by_height(height)
+                    .map(|block| crate::util::filter_block(block, height, filter.as_mut()));
+                let message = MerkleBlock(block);
+                message.send_async(&mut *writer.lock().await).await.unwrap();
+            }
+            FetchBlockFilters { start, count } => {
+                let blockchain = crate::BLOCKCHAIN.read().await;
+                // Nothing from a pruned start, pruned blocks have no transactions to filter
+                let filters = (start.get()..)
+                    .take(count.min(btclib::MAX_BLOCK_FILTERS_PER_MESSAGE) as usize)
+                    .map_while(|height| blockchain.block_by_height(BlockHeight::new(height)))
+                    .map(|block| (block.hash(), crate::util::block_filter(block)))
+                    .collect();
+                let message = BlockFilters(filters);
+                message.send_async(&mut *writer.lock().await).await.unwrap();
+            }
+            FetchCompetingTips => {
+                let message = CompetingTips(crate::forks::competing_tips());
+                message.send_async(&mut *writer.lock().await).await.unwrap();
+            }
+            AskWork => {
+                let blockchain = crate::BLOCKCHAIN.read().await;
+                let message = Work {
+                    work: blockchain.total_work(),
+                    height: blockchain.block_count(),
+                };
+                message.send_async(&mut *writer.lock().await).await.unwrap();
             }
             AskDifference(height) => {
                 let blockchain = crate::BLOCKCHAIN.read().await;
-                let count = blockchain.block_height() as i32 - height as i32;
+                let count = blockchain.block_count() as i32 - height as i32;
                 let message = Difference(count);
-                message.send_async(&mut socket).await.unwrap();
+                message.send_async(&mut *writer.lock().await).await.unwrap();
             }
             FetchUTXOs(key) => {
                 println!("received request to fetch UTXOs");
                 let blockchain = crate::BLOCKCHAIN.read().await;
+                let mempool = crate::MEMPOOL.read().await;
+                let next_height = blockchain.next_height().get();
+                let maturity = blockchain.params().coinbase_maturity;
+                // Outputs spent by pending transactions, immature coinbase and
+                // still time locked outputs are reported as marked so wallets
+                // count them in the balance but do not try to spend them yet
                 let utxos = blockchain
-                    .utxos()
-                    .iter()
-                    .filter(|(_, (_, txout))| txout.pubkey == key)
-                    .map(|(_, (marked, txout))| (txout.clone(), *marked))
+                    .utxos_of(&key)
+                    .map(|(hash, entry)| {
+                        (
+                            *hash,
+                            entry.output.clone(),
+                            mempool.is_spent(hash)
+                                || !entry.is_mature(next_height, maturity)
+                                || !entry.output.script.is_unlocked(next_height),
+                        )
+                    })
                     .collect::<Vec<_>>();
                 let message = UTXOs(utxos);
-                message.send_async(&mut socket).await.unwrap();
+                message.send_async(&mut *writer.lock().await).await.unwrap();
             }
             NewBlock(block) => {
+                let _validation = load::Validation::start();
                 let mut blockchain = crate::BLOCKCHAIN.write().await;
                 println!("received new blcok");
-                if blockchain.add_block(block).is_err() {
-                    println!("block rejected");
+                let started = Instant::now();
+                let result = blockchain.add_block(block.clone());
+                crate::metrics::record_validation(started);
+                if let Err(e) = result {
+                    println!("block rejected: {e}");
+                    // A fork block commits to the target of its own height
+                    if let BtcError::StaleParent | BtcError::UnexpectedTarget { .. } = e {
+                        crate::forks::observe(&block, &blockchain);
+                    }
+                } else {
+                    let confirmed = crate::MEMPOOL.write().await.remove_confirmed(&block);
+                    blockchain.record_confirmed(&confirmed);
+                    crate::util::publish_tip(&blockchain);
                 }
             }
             NewTransaction(tx) => {
-                let mut blockchain = crate::BLOCKCHAIN.write().await;
+                let blockchain = crate::BLOCKCHAIN.read().await;
+                let mut mempool = crate::MEMPOOL.write().await;
                 println!("received transaction");
-                if blockchain.add_to_mempool(tx).is_err() {
-                    println!("Transaction rejected. Closing connection");
-                    return;
+                let result = mempool.add(
+                    tx,
+                    blockchain.utxos(),
+                    blockchain.next_height().get(),
+                    blockchain.params(),
+                );
+                match result {
+                    Ok(()) => {}
+                    // Peers may run a looser relay policy, that is no reason to drop them
+                    Err(
+                        e @ (BtcError::NonStandard(_)
+                        | BtcError::MempoolFull
+                        | BtcError::ReplacementRejected(_)),
+                    ) => {
+                        println!("ignoring transaction: {}", e);
+                    }
+                    Err(_) => {
+                        println!("Transaction rejected. Closing connection");
+                        return;
+                    }
                 }
             }
             ValidateTemplate(block_template) => {
@@ -75,20 +401,30 @@ pub async fn handle_connection(mut socket: TcpStream) {
                 let status = block_template.header.prev_block_hash
                     == blockchain
                         .blocks()
-                        .last()
+                        .next_back()
                         .map(|last_block| last_block.hash())
                         .unwrap_or(Hash::zero());
                 let message = TemplateValidity(status);
-                message.send_async(&mut socket).await.unwrap();
+                message.send_async(&mut *writer.lock().await).await.unwrap();
             }
             SubmitTemplate(block) => {
                 println!("received allegedly mined tempate");
+                let _validation = load::Validation::start();
                 let mut blockchain = crate::BLOCKCHAIN.write().await;
-                if let Err(e) = blockchain.add_block(block.clone()) {
-                    println!("block rejected: {e}, closing conncection");
-                    return;
+                let started = Instant::now();
+                let result = blockchain.add_block(block.clone());
+                crate::metrics::record_validation(started);
+                if let Err(e) = result {
+                    println!("block rejected: {e}");
+                    let message = Reject(RejectReason::from(&e));
+                    message.send_async(&mut *writer.lock().await).await.unwrap();
+                    continue;
                 }
-                blockchain.rebuild_utxos();
+                let confirmed = crate::MEMPOOL.write().await.remove_confirmed(&block);
+                blockchain.record_confirmed(&confirmed);
+                crate::util::publish_tip(&blockchain);
+                let message = BlockAccepted(block.hash());
+                message.send_async(&mut *writer.lock().await).await.unwrap();
                 println!("block looks good, broadcasting");
                 let nodes = crate::NODES
                     .iter()
@@ -105,9 +441,21 @@ pub async fn handle_connection(mut socket: TcpStream) {
             }
             SubmitTransaction(tx) => {
                 println!("Submitting tx");
-                let mut blockchain = crate::BLOCKCHAIN.write().await;
-                if let Err(e) = blockchain.add_to_mempool(tx.clone()) {
-                    println!("transaction rejected, closing connection: {e}");
+                let blockchain = crate::BLOCKCHAIN.read().await;
+                let mut mempool = crate::MEMPOOL.write().await;
+                let result = mempool.add(
+                    tx.clone(),
+                    blockchain.utxos(),
+                    blockchain.next_height().get(),
+                    blockchain.params(),
+                );
+                drop(mempool);
+                drop(blockchain);
+                let message = TransactionAccepted(result.is_ok());
+                message.send_async(&mut *writer.lock().await).await.unwrap();
+                if let Err(e) = result {
+                    println!("transaction rejected: {e}");
+                    continue;
                 }
                 println!("added transaction to mempool");
                 let nodes = crate::NODES
@@ -126,56 +474,9 @@ pub async fn handle_connection(mut socket: TcpStream) {
                 println!("transaction sent to friendlies");
             }
             FetchTemplate(pubkey) => {
-                let blockchain = crate::BLOCKCHAIN.read().await;
-                let mut transactions = vec![];
-                transactions.extend(
-                    blockchain
-                        .mempool()
-                        .iter()
-                        .take(btclib::BLOCK_TRANSACTION_CAP)
-                        .map(|(_, tx)| tx)
-                        .cloned()
-                        .collect::<Vec<_>>(),
-                );
-                transactions.insert(
-                    0,
-                    Transaction {
-                        inputs: vec![],
-                        outputs: vec![TransactionOutput {
-                            pubkey,
-                            unique_id: Uuid::new_v4(),
-                            value: 0,
-                        }],
-                    },
-                );
-                let merkle_root = MerkleRoot::calculate(&transactions);
-                let mut block = Block::new(
-                    BlockHeader {
-                        timestamp: Utc::now(),
-                        prev_block_hash: blockchain
-                            .blocks()
-                            .last()
-                            .map(|last_block| last_block.hash())
-                            .unwrap_or(Hash::zero()),
-                        nonce: 0,
-                        target: blockchain.target(),
-                        merkle_root,
-                    },
-                    transactions,
-                );
-                let miner_fees = match block.calculate_miner_fees(blockchain.utxos()) {
-                    Ok(fees) => fees,
-                    Err(e) => {
-                        eprintln!("{e}");
-                        return;
-                    }
-                };
-                let reward = blockchain.calculate_block_reward();
-                block.transactions[0].outputs[0].value = reward + miner_fees;
-                // TODO: Calculating merkle root twice. Is there a better way
-                block.header.merkle_root = MerkleRoot::calculate(&block.transactions);
+                let block = crate::template::build(pubkey).await;
                 let message = Template(block);
-                message.send_async(&mut socket).await.unwrap();
+                message.send_async(&mut *writer.lock().await).await.unwrap();
             }
         }
     }
@@ -0,0 +1,45 @@
+use btclib::network::Message;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Blocks waiting for or undergoing validation
+static VALIDATION_BACKLOG: AtomicUsize = AtomicUsize::new(0);
+
+// A few blocks in flight are normal while syncing, more than this means
+// validation has fallen behind
+pub const DEFAULT_BUSY_BACKLOG: usize = 8;
+
+// Backlog at which expensive requests are answered with Busy
+pub static BUSY_BACKLOG: AtomicUsize = AtomicUsize::new(DEFAULT_BUSY_BACKLOG);
+
+// Counts a block as backlogged until dropped
+pub struct Validation;
+
+impl Validation {
+    pub fn start() -> Self {
+        VALIDATION_BACKLOG.fetch_add(1, Ordering::Relaxed);
+        Validation
+    }
+}
+
+impl Drop for Validation {
+    fn drop(&mut self) {
+        VALIDATION_BACKLOG.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Requests that scan large parts of the chain state and can wait
+pub fn is_expensive(message: &Message) -> bool {
+    matches!(
+        message,
+        Message::FetchHistory { .. }
+            | Message::FetchUTXOs(_)
+            | Message::FetchUtxoProofs(_)
+            | Message::FetchMerkleBlock(_)
+            | Message::FetchBlockFilters { .. }
+            | Message::FetchStats
+    )
+}
+
+pub fn is_overloaded() -> bool {
+    VALIDATION_BACKLOG.load(Ordering::Relaxed) >= BUSY_BACKLOG.load(Ordering::Relaxed)
+}
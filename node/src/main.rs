@@ -1,21 +1,66 @@
+mod auth;
+mod forks;
 mod handler;
+mod load;
+mod metrics;
+mod rewards;
+mod template;
 mod util;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use argh::FromArgs;
-use btclib::types::Blockchain;
+use auth::Role;
+use btclib::amount::Amount;
+use btclib::block_filter::BlockFilter;
+use btclib::crypto::PublicKey;
+use btclib::params::ChainParams;
+use btclib::policy::RelayPolicy;
+use btclib::sha256::Hash;
+use btclib::store::{ChainStore, DirStore, SledStore};
+use btclib::types::{BlockHeight, Blockchain, Mempool};
+use btclib::util::Saveable;
 use dashmap::DashMap;
 use static_init::dynamic;
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::time::Duration;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::RwLock;
+use tokio::sync::{broadcast, RwLock};
 
 #[dynamic]
-pub static BLOCKCHAIN: RwLock<Blockchain> = RwLock::new(Blockchain::new());
+pub static BLOCKCHAIN: RwLock<Blockchain> = RwLock::new(Blockchain::new(ChainParams::mainnet()));
+
+#[dynamic]
+pub static MEMPOOL: RwLock<Mempool> = RwLock::new(Mempool::new());
 
 #[dynamic]
 pub static NODES: DashMap<String, TcpStream> = DashMap::new();
 
+pub static READY: AtomicBool = AtomicBool::new(false);
+
+pub static SYNC_PROGRESS: AtomicU8 = AtomicU8::new(0);
+
+pub static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+// Only syncs and serves queries, never takes transactions or mined blocks
+pub static WATCH_ONLY: AtomicBool = AtomicBool::new(false);
+
+// Height and hash of every newly connected tip
+#[dynamic]
+pub static TIP_CHANGES: broadcast::Sender<(BlockHeight, Hash)> = broadcast::channel(16).0;
+
+// Set with --store-dir, holds the blocks saved so far
+#[dynamic]
+pub static STORE: RwLock<Option<Box<dyn ChainStore + Send + Sync>>> = RwLock::new(None);
+
+// Compact filters already built, by block hash, at most MAX_BLOCK_FILTERS
+pub const MAX_BLOCK_FILTERS: usize = 4096;
+#[dynamic]
+pub static BLOCK_FILTERS: DashMap<Hash, BlockFilter> = DashMap::new();
+
+#[dynamic]
+pub static TOKENS: DashMap<String, Role> = DashMap::new();
+
 #[derive(FromArgs)]
 /// Blockchain node
 struct Args {
@@ -27,6 +72,114 @@ struct Args {
     /// blockchain file location
     blockchain_file: String,
 
+    #[argh(option)]
+    /// keep the chain in this directory block by block instead of the blockchain file
+    store_dir: Option<String>,
+
+    #[argh(option, default = "String::from(\"dir\")")]
+    /// backend of --store-dir: dir for block files, sled for a key-value database
+    store_backend: String,
+
+    #[argh(option)]
+    /// keep the transactions of only this many recent blocks, requires --store-dir
+    prune: Option<u64>,
+
+    #[argh(option, default = "String::from(\"./mempool.cbor\")")]
+    /// pending transactions are saved here on shutdown and reloaded on startup
+    mempool_file: String,
+
+    #[argh(option)]
+    /// access token as <role>:<token>, role is read-only, wallet or admin
+    token: Vec<String>,
+
+    #[argh(option)]
+    /// token presented to the initial nodes
+    peer_token: Option<String>,
+
+    #[argh(switch)]
+    /// revalidate every block of the blockchain file on startup
+    reindex: bool,
+
+    #[argh(switch)]
+    /// index confirmed transactions by txid to answer transaction queries
+    txindex: bool,
+
+    #[argh(switch)]
+    /// sync, validate and answer queries only, refusing transactions and mining
+    watch_only: bool,
+
+    #[argh(option, default = "String::from(\"mainnet\")")]
+    /// network to join: mainnet, testnet or regtest
+    network: String,
+
+    #[argh(option)]
+    /// custom chain parameters file, overrides --network
+    chain_params: Option<String>,
+
+    #[argh(option, default = "btclib::DUST_LIMIT")]
+    /// smallest output value relayed, in satoshis
+    dust_limit: u64,
+
+    #[argh(option, default = "btclib::MAX_STANDARD_TRANSACTION_SIZE")]
+    /// largest transaction relayed, in serialized bytes
+    max_tx_size: usize,
+
+    #[argh(option, default = "btclib::MAX_STANDARD_INPUTS")]
+    /// most inputs a relayed transaction may have
+    max_tx_inputs: usize,
+
+    #[argh(option, default = "btclib::MAX_STANDARD_OUTPUTS")]
+    /// most outputs a relayed transaction may have
+    max_tx_outputs: usize,
+
+    #[argh(option, default = "btclib::MIN_RELAY_FEE_RATE")]
+    /// lowest fee rate relayed, in satoshis per 1000 bytes
+    min_relay_fee_rate: u64,
+
+    #[argh(option, default = "btclib::MAX_MEMPOOL_SIZE")]
+    /// mempool size in bytes above which the lowest fee rates are evicted
+    max_mempool_size: usize,
+
+    #[argh(option, default = "btclib::MAX_MEMPOOL_TRANSACTIONS")]
+    /// mempool transactions above which the lowest fee rates are evicted
+    max_mempool_transactions: usize,
+
+    #[argh(option)]
+    /// public key file paid by templates in turn instead of the requesting miner
+    reward_key: Vec<String>,
+
+    #[argh(option)]
+    /// public key file paid the operator fee of every template
+    operator_key: Option<String>,
+
+    #[argh(option, default = "0")]
+    /// operator fee in basis points of the block reward
+    operator_fee_bps: u64,
+
+    #[argh(option, default = "load::DEFAULT_BUSY_BACKLOG")]
+    /// blocks awaiting validation at which history and UTXO queries get Busy
+    busy_backlog: usize,
+
+    #[argh(option)]
+    /// append metrics to this file, as JSON lines if it ends in .jsonl, CSV otherwise
+    metrics_file: Option<String>,
+
+    #[argh(option, default = "60")]
+    /// seconds between metrics samples
+    metrics_interval: u64,
+
+    #[argh(option, default = "10_000_000")]
+    /// rotate the metrics file once it is larger than this many bytes
+    metrics_max_bytes: u64,
+
+    #[argh(option, default = "86400")]
+    /// rotate the metrics file once it is older than this many seconds
+    metrics_max_age: u64,
+
+    #[argh(option, default = "600")]
+    /// warn when a competing tip stays close to ours for this many seconds
+    fork_alert_secs: u64,
+
     #[argh(positional)]
     nodes: Vec<String>,
 }
@@ -37,35 +190,170 @@ async fn main() -> Result<()> {
     let port = args.port;
     let blockchain_file = args.blockchain_file;
     let nodes = args.nodes;
-    if Path::new(&blockchain_file).exists() {
-        util::load_blockchain(&blockchain_file).await?;
+    for token in &args.token {
+        let (token, role) = auth::parse_token(token)?;
+        TOKENS.insert(token, role);
+    }
+    if !TOKENS.is_empty() {
+        println!("access tokens configured: {}", TOKENS.len());
+    }
+    let params = match &args.chain_params {
+        Some(file) => ChainParams::load_from_file(file)?,
+        None => ChainParams::preset(&args.network)
+            .ok_or_else(|| anyhow!("unknown network: {}", args.network))?,
+    };
+    println!("running on {}", params.name);
+    if args.prune.is_some() && args.store_dir.is_none() {
+        return Err(anyhow!("--prune requires --store-dir"));
+    }
+    if args.watch_only {
+        println!("watch-only mode, transactions and mining requests are refused");
+        WATCH_ONLY.store(true, Ordering::Relaxed);
+    }
+    *BLOCKCHAIN.write().await = Blockchain::new(params.clone());
+    MEMPOOL.write().await.set_policy(RelayPolicy {
+        dust_limit: Amount::from_sat(args.dust_limit),
+        max_transaction_size: args.max_tx_size,
+        max_inputs: args.max_tx_inputs,
+        max_outputs: args.max_tx_outputs,
+        min_fee_rate: args.min_relay_fee_rate,
+    });
+    MEMPOOL.write().await.set_max_size(args.max_mempool_size);
+    MEMPOOL
+        .write()
+        .await
+        .set_max_count(args.max_mempool_transactions);
+    configure_rewards(
+        &args.reward_key,
+        args.operator_key.as_deref(),
+        args.operator_fee_bps,
+    )?;
+    load::BUSY_BACKLOG.store(args.busy_backlog.max(1), Ordering::Relaxed);
+    let addr = format!("0.0.0.0:{}", port);
+    let listener = TcpListener::bind(&addr).await?;
+    println!("Listening on {}", addr);
+    let acceptor = tokio::spawn(util::accept_connections(listener));
+    tokio::spawn(util::handle_shutdown(args.mempool_file.clone()));
+    let store = match args.store_dir.as_deref() {
+        Some(dir) => Some(open_store(dir, &args.store_backend)?),
+        None => None,
+    };
+    let stored = match &store {
+        Some(store) if store.block_count() > 0 => {
+            println!("loading blockchain from the store...");
+            Some(Blockchain::load_from_store(store.as_ref())?)
+        }
+        Some(_) => None,
+        None if Path::new(&blockchain_file).exists() => {
+            println!("Blockchain file exists, loading...");
+            Some(Blockchain::load_from_file(&blockchain_file)?)
+        }
+        None => None,
+    };
+    if let Some(stored) = stored {
+        util::load_blockchain(stored, args.reindex, &params).await?;
     } else {
-        println!("blockchain file does not exist!");
-        util::populate_connections(&nodes).await?;
+        println!("no stored blockchain!");
+        util::populate_connections(&nodes, args.peer_token.as_deref()).await?;
         println!("total amount of known nodes: {}", NODES.len());
         if nodes.is_empty() {
-            println!("no initial nodes provided, starting as a seed")
+            println!("no initial nodes provided, starting as a seed");
+            *BLOCKCHAIN.write().await = Blockchain::with_genesis(params);
         } else {
-            let (longest_name, longest_count) = util::find_longest_chain_node().await?;
+            let (longest_name, longest_count, claimed_work) = util::find_most_work_node().await?;
             util::download_blockchain(&longest_name, longest_count).await?;
             println!("blockchain downloaded from {}", longest_name);
             {
                 let mut blockchain = BLOCKCHAIN.write().await;
+                // Connecting checked every target, so this is the work actually done
+                if blockchain.total_work() < claimed_work {
+                    return Err(anyhow!(
+                        "{} claimed chain work {} but its chain carries {}",
+                        longest_name,
+                        claimed_work,
+                        blockchain.total_work()
+                    ));
+                }
                 blockchain.rebuild_utxos();
-            }
-            {
-                let mut blockchain = BLOCKCHAIN.write().await;
-                blockchain.try_adjust_target();
+                let supply = blockchain.verify_supply()?;
+                println!("supply of the downloaded chain checks out: {}", supply);
             }
         }
     }
-    let addr = format!("0.0.0.0:{}", port);
-    let listener = TcpListener::bind(&addr).await?;
-    println!("Listening on {}", addr);
+    if args.txindex {
+        println!("building the transaction index...");
+        BLOCKCHAIN.write().await.enable_tx_index();
+    }
+    if Path::new(&args.mempool_file).exists() {
+        util::load_mempool(&args.mempool_file).await?;
+    }
+    READY.store(true, Ordering::Relaxed);
+    println!("node is ready to serve clients");
     tokio::spawn(util::cleanup());
-    tokio::spawn(util::save(blockchain_file.clone()));
-    loop {
-        let (socket, _) = listener.accept().await?;
-        tokio::spawn(handler::handle_connection(socket));
+    match store {
+        Some(store) => {
+            *STORE.write().await = Some(store);
+            tokio::spawn(util::save_to_store(args.prune))
+        }
+        None => tokio::spawn(util::save(blockchain_file.clone())),
+    };
+    tokio::spawn(forks::monitor(Duration::from_secs(args.fork_alert_secs)));
+    if let Some(path) = args.metrics_file {
+        println!("exporting metrics to {}", path);
+        let exporter = metrics::Exporter {
+            path: path.into(),
+            interval: Duration::from_secs(args.metrics_interval.max(1)),
+            max_bytes: args.metrics_max_bytes,
+            max_age: Duration::from_secs(args.metrics_max_age),
+        };
+        tokio::spawn(exporter.run());
+    }
+    acceptor.await?
+}
+
+fn configure_rewards(keys: &[String], operator: Option<&str>, fee_bps: u64) -> Result<()> {
+    if fee_bps > rewards::FULL_REWARD_BPS {
+        return Err(anyhow!(
+            "operator fee above {} basis points",
+            rewards::FULL_REWARD_BPS
+        ));
+    }
+    let keys = keys
+        .iter()
+        .map(PublicKey::load_from_file)
+        .collect::<std::io::Result<Vec<_>>>()?;
+    if !keys.is_empty() {
+        let fingerprints = keys
+            .iter()
+            .map(|key| key.fingerprint().to_string())
+            .collect::<Vec<_>>();
+        println!(
+            "rotating template rewards over keys {}",
+            fingerprints.join(", ")
+        );
+    }
+    let operator = match operator {
+        Some(file) if fee_bps > 0 => Some((PublicKey::load_from_file(file)?, fee_bps)),
+        Some(_) => None,
+        None if fee_bps > 0 => return Err(anyhow!("operator fee set without --operator-key")),
+        None => None,
+    };
+    if let Some((key, fee_bps)) = &operator {
+        println!(
+            "paying {} basis points to operator key {}",
+            fee_bps,
+            key.fingerprint()
+        );
+    }
+    *rewards::REWARDS.write().unwrap() = rewards::RewardConfig { keys, operator };
+    Ok(())
+}
+
+fn open_store(dir: &str, backend: &str) -> Result<Box<dyn ChainStore + Send + Sync>> {
+    println!("using the {} store at {}", backend, dir);
+    match backend {
+        "dir" => Ok(Box::new(DirStore::open(dir)?)),
+        "sled" => Ok(Box::new(SledStore::open(dir)?)),
+        _ => Err(anyhow!("unknown store backend: {}", backend)),
     }
 }
@@ -0,0 +1,132 @@
+use anyhow::Result;
+use chrono::Utc;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::time;
+
+// How long connecting the most recent block took, in microseconds
+pub static VALIDATION_MICROS: AtomicU64 = AtomicU64::new(0);
+
+pub fn record_validation(started: Instant) {
+    VALIDATION_MICROS.store(started.elapsed().as_micros() as u64, Ordering::Relaxed);
+}
+
+const CSV_HEADER: &str = "timestamp,blocks,peers,mempool_size,validation_micros,competing_tips\n";
+
+pub struct Snapshot {
+    pub timestamp: i64,
+    pub blocks: u64,
+    pub peers: usize,
+    pub mempool_size: usize,
+    pub validation_micros: u64,
+    pub competing_tips: usize,
+}
+
+impl Snapshot {
+    pub async fn take() -> Self {
+        Snapshot {
+            timestamp: Utc::now().timestamp(),
+            blocks: crate::BLOCKCHAIN.read().await.block_count(),
+            peers: crate::NODES.len(),
+            mempool_size: crate::MEMPOOL.read().await.len(),
+            validation_micros: VALIDATION_MICROS.load(Ordering::Relaxed),
+            competing_tips: crate::forks::COMPETING_TIPS.len(),
+        }
+    }
+
+    fn csv(&self) -> String {
+        format!(
+            "{},{},{},{},{},{}\n",
+            self.timestamp,
+            self.blocks,
+            self.peers,
+            self.mempool_size,
+            self.validation_micros,
+            self.competing_tips
+        )
+    }
+
+    fn json(&self) -> String {
+        format!(
+            "{{\"timestamp\":{},\"blocks\":{},\"peers\":{},\"mempool_size\":{},\
+            \"validation_micros\":{},\"competing_tips\":{}}}\n",
+            self.timestamp,
+            self.blocks,
+            self.peers,
+            self.mempool_size,
+            self.validation_micros,
+            self.competing_tips
+        )
+    }
+}
+
+// Appends a snapshot every interval, as JSON lines when the file ends in
+// .jsonl and as CSV otherwise. A file that grew past max_bytes or is older
+// than max_age is renamed with a timestamp suffix and started over.
+pub struct Exporter {
+    pub path: PathBuf,
+    pub interval: Duration,
+    pub max_bytes: u64,
+    pub max_age: Duration,
+}
+
+impl Exporter {
+    pub async fn run(self) {
+        let mut interval = time::interval(self.interval);
+        loop {
+            interval.tick().await;
+            let snapshot = Snapshot::take().await;
+            if let Err(e) = self.append(&snapshot) {
+                println!("failed to export metrics to {}: {}", self.path.display(), e);
+            }
+        }
+    }
+
+    fn append(&self, snapshot: &Snapshot) -> Result<()> {
+        self.rotate_if_needed()?;
+        let jsonl = self
+            .path
+            .extension()
+            .is_some_and(|extension| extension == "jsonl");
+        let is_new = !self.path.exists();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        if jsonl {
+            file.write_all(snapshot.json().as_bytes())?;
+        } else {
+            if is_new {
+                file.write_all(CSV_HEADER.as_bytes())?;
+            }
+            file.write_all(snapshot.csv().as_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn rotate_if_needed(&self) -> Result<()> {
+        let Ok(metadata) = fs::metadata(&self.path) else {
+            return Ok(());
+        };
+        let age = metadata
+            .created()
+            .or_else(|_| metadata.modified())
+            .ok()
+            .and_then(|created| SystemTime::now().duration_since(created).ok())
+            .unwrap_or_default();
+        if metadata.len() < self.max_bytes && age < self.max_age {
+            return Ok(());
+        }
+        fs::rename(&self.path, rotated_path(&self.path))?;
+        Ok(())
+    }
+}
+
+fn rotated_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", Utc::now().timestamp()));
+    PathBuf::from(name)
+}
@@ -0,0 +1,53 @@
+use btclib::amount::Amount;
+use btclib::crypto::PublicKey;
+use btclib::script::Script;
+use btclib::types::TransactionOutput;
+use static_init::dynamic;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::RwLock;
+
+// A whole block reward, in basis points
+pub const FULL_REWARD_BPS: u64 = 10_000;
+
+// Who the coinbase of a template pays. Without reward keys it is the miner
+// that asked for the template.
+#[derive(Default)]
+pub struct RewardConfig {
+    // Paid in turn, one per template, instead of the requesting miner
+    pub keys: Vec<PublicKey>,
+    // Operator key and its cut of every reward in basis points
+    pub operator: Option<(PublicKey, u64)>,
+}
+
+#[dynamic]
+pub static REWARDS: RwLock<RewardConfig> = RwLock::new(RewardConfig::default());
+
+static NEXT_KEY: AtomicUsize = AtomicUsize::new(0);
+
+// Splits the subsidy and fees of a template between the recipient, which
+// always gets the first output, and the operator
+pub fn coinbase_outputs(requested: PublicKey, reward: Amount) -> Vec<TransactionOutput> {
+    let config = REWARDS.read().unwrap();
+    let recipient = if config.keys.is_empty() {
+        requested
+    } else {
+        let next = NEXT_KEY.fetch_add(1, Ordering::Relaxed);
+        config.keys[next % config.keys.len()].clone()
+    };
+    let mut outputs = vec![TransactionOutput {
+        value: reward,
+        script: Script::PayToPubkey(recipient),
+    }];
+    if let Some((operator, bps)) = &config.operator {
+        let cut = reward.to_sat() as u128 * *bps as u128 / FULL_REWARD_BPS as u128;
+        let cut = Amount::from_sat(cut as u64);
+        if cut.to_sat() > 0 {
+            outputs[0].value = reward.checked_sub(cut).expect("Bug: Impossible");
+            outputs.push(TransactionOutput {
+                value: cut,
+                script: Script::PayToPubkey(operator.clone()),
+            });
+        }
+    }
+    outputs
+}
@@ -0,0 +1,75 @@
+use btclib::amount::Amount;
+use btclib::crypto::PublicKey;
+use btclib::sha256::Hash;
+use btclib::types::{Block, BlockHeader, Transaction};
+use btclib::util::MerkleTree;
+use chrono::Utc;
+use static_init::dynamic;
+use std::sync::Mutex;
+
+// Transactions of the last template and their merkle tree. Until the tip
+// or the mempool changes, templates reuse them and only swap the coinbase.
+struct CachedTemplate {
+    tip: Hash,
+    revision: u64,
+    transactions: Vec<Transaction>,
+    fees: Amount,
+    tree: MerkleTree,
+}
+
+#[dynamic]
+static CACHE: Mutex<Option<CachedTemplate>> = Mutex::new(None);
+
+pub async fn build(pubkey: PublicKey) -> Block {
+    let blockchain = crate::BLOCKCHAIN.read().await;
+    let mempool = crate::MEMPOOL.read().await;
+    let tip = blockchain
+        .blocks()
+        .next_back()
+        .map(|last_block| last_block.hash())
+        .unwrap_or(Hash::zero());
+    let height = blockchain.next_height().get();
+    let mut cache = CACHE.lock().unwrap();
+    let stale = match cache.as_ref() {
+        Some(cached) => cached.tip != tip || cached.revision != mempool.revision(),
+        None => true,
+    };
+    if stale {
+        let selected = mempool.select(
+            btclib::MAX_BLOCK_SIZE - btclib::BLOCK_TEMPLATE_RESERVED_SIZE,
+            btclib::MAX_BLOCK_SIGOPS,
+        );
+        let fees =
+            Amount::checked_sum(selected.iter().map(|entry| entry.fee)).expect("Bug: Impossible");
+        // The coinbase goes in front, this one only holds its place
+        let mut transactions = vec![Transaction::coinbase(height, Vec::new())];
+        transactions.extend(selected.into_iter().map(|entry| entry.transaction.clone()));
+        let tree = MerkleTree::new(&transactions);
+        *cache = Some(CachedTemplate {
+            tip,
+            revision: mempool.revision(),
+            transactions,
+            fees,
+            tree,
+        });
+    }
+    let cached = cache.as_mut().expect("Bug: Impossible");
+    let reward = blockchain
+        .block_subsidy()
+        .checked_add(cached.fees)
+        .expect("Bug: Impossible");
+    let coinbase = Transaction::coinbase(height, crate::rewards::coinbase_outputs(pubkey, reward));
+    cached.tree.replace(0, &coinbase);
+    let mut transactions = cached.transactions.clone();
+    transactions[0] = coinbase;
+    Block::new(
+        BlockHeader {
+            timestamp: Utc::now(),
+            prev_block_hash: tip,
+            nonce: 0,
+            bits: blockchain.target().to_compact(),
+            merkle_root: cached.tree.root(),
+        },
+        transactions,
+    )
+}
@@ -1,54 +1,198 @@
 use anyhow::{Context, Result};
-use btclib::network::Message;
-use btclib::types::Blockchain;
-use btclib::util::Saveable;
-use tokio::net::TcpStream;
+use btclib::block_filter::BlockFilter;
+use btclib::bloom::BloomFilter;
+use btclib::encoding::Encode;
+use btclib::network::{self, Capabilities, FilteredBlock, Message, UtxoProof};
+use btclib::params::ChainParams;
+use btclib::sha256::Hash;
+use btclib::types::{Block, BlockHeight, Blockchain, MempoolSnapshot, PrecheckedBlock};
+use btclib::util::{MerkleProof, MerkleTree, Saveable};
+use btclib::U256;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::Ordering;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::task::{self, JoinHandle};
 use tokio::time;
 
-pub async fn load_blockchain(blockchain_file: &str) -> Result<()> {
-    println!("Blockchain file exists, loading...");
-    let new_blockchain = Blockchain::load_from_file(blockchain_file)?;
+// Downloaded blocks waiting to be connected at most
+const DOWNLOAD_PIPELINE_DEPTH: usize = 64;
+
+pub async fn load_blockchain(
+    new_blockchain: Blockchain,
+    reindex: bool,
+    params: &ChainParams,
+) -> Result<()> {
+    if new_blockchain.params() != params {
+        return Err(anyhow::anyhow!(
+            "stored blockchain belongs to {}, not {}",
+            new_blockchain.params().name,
+            params.name
+        ));
+    }
     println!("blockchain loaded");
 
     let mut blockchain = crate::BLOCKCHAIN.write().await;
     *blockchain = new_blockchain;
-    println!("rebuilding utxos...");
-    blockchain.rebuild_utxos();
-    println!("utxos rebuilt");
-    println!("checking if target needs to be adjusted");
-    println!("Current target {}", blockchain.target());
-    blockchain.try_adjust_target();
-    println!("new target: {}", blockchain.target());
+    if blockchain.pruned_count() > 0 {
+        if reindex {
+            return Err(anyhow::anyhow!("a pruned blockchain cannot be reindexed"));
+        }
+        let pruned = blockchain.pruned_count();
+        println!(
+            "blocks below height {} are pruned, keeping the stored utxos",
+            pruned
+        );
+        blockchain.rebuild_pruned_indexes();
+    } else if reindex {
+        println!("reindexing blockchain with full validation...");
+        blockchain.reindex(report_progress("reindex"), &crate::SHUTDOWN)?;
+        println!("reindex complete");
+    } else {
+        println!("rebuilding utxos...");
+        blockchain
+            .rebuild_utxos_with_progress(report_progress("utxo rebuild"), &crate::SHUTDOWN)?;
+        println!("utxos rebuilt");
+    }
+    println!("current target: {}", blockchain.target().to_hex());
     println!("Initialization complete");
     Ok(())
 }
 
-pub async fn find_longest_chain_node() -> Result<(String, u32)> {
-    println!("finding nodes with the highest blockchain length");
-    let mut longest_name = String::new();
-    let mut longest_count = 0;
+fn report_progress(operation: &'static str) -> impl FnMut(u64, u64) {
+    move |done, total| {
+        let progress = (done * 100).checked_div(total).unwrap_or(100);
+        crate::SYNC_PROGRESS.store(progress as u8, Ordering::Relaxed);
+        if done % 1000 == 0 || done == total {
+            println!("{}: {}/{} blocks ({}%)", operation, done, total, progress);
+        }
+    }
+}
+
+pub fn current_tip(blockchain: &Blockchain) -> Option<(BlockHeight, Hash)> {
+    let height = blockchain.tip_height()?;
+    Some((height, blockchain.block_by_height(height)?.hash()))
+}
+
+pub fn publish_tip(blockchain: &Blockchain) {
+    if let Some(tip) = current_tip(blockchain) {
+        // Failing only means nobody is subscribed
+        let _ = crate::TIP_CHANGES.send(tip);
+    }
+}
+
+// The transactions of the block matching the filter, with proofs from one
+// tree. The filter learns the outputs of every match on the way.
+pub fn filter_block(
+    block: &Block,
+    height: BlockHeight,
+    filter: Option<&mut BloomFilter>,
+) -> FilteredBlock {
+    let mut transactions = Vec::new();
+    if let Some(filter) = filter {
+        let tree = MerkleTree::new(&block.transactions);
+        for (index, transaction) in block.transactions.iter().enumerate() {
+            if !filter.matches(transaction) {
+                continue;
+            }
+            if let Some(proof) = tree.proof(index) {
+                transactions.push((transaction.clone(), proof));
+            }
+        }
+    }
+    FilteredBlock {
+        height,
+        header: block.header.clone(),
+        transactions,
+    }
+}
+
+// The cached filter of the block, built on first use. A full cache drops
+// an arbitrary entry, rebuilding a filter only costs a pass over the block.
+pub fn block_filter(block: &Block) -> BlockFilter {
+    let hash = block.hash();
+    if let Some(filter) = crate::BLOCK_FILTERS.get(&hash) {
+        return filter.clone();
+    }
+    let filter = BlockFilter::build(block);
+    if crate::BLOCK_FILTERS.len() >= crate::MAX_BLOCK_FILTERS {
+        let evicted = crate::BLOCK_FILTERS.iter().next().map(|entry| *entry.key());
+        if let Some(evicted) = evicted {
+            crate::BLOCK_FILTERS.remove(&evicted);
+        }
+    }
+    crate::BLOCK_FILTERS.insert(hash, filter.clone());
+    filter
+}
+
+// Proofs for the outpoints that are unspent, at most MAX_UTXO_PROOFS_PER_MESSAGE
+pub fn utxo_proofs(blockchain: &Blockchain, outpoints: &[Hash]) -> Vec<UtxoProof> {
+    let mut by_height: BTreeMap<BlockHeight, Vec<Hash>> = BTreeMap::new();
+    for outpoint in outpoints.iter().take(btclib::MAX_UTXO_PROOFS_PER_MESSAGE) {
+        if let Some(entry) = blockchain.utxos().get(outpoint) {
+            by_height
+                .entry(BlockHeight::new(entry.height))
+                .or_default()
+                .push(*outpoint);
+        }
+    }
+    let mut proofs = Vec::new();
+    for (height, outpoints) in by_height {
+        let Some(block) = blockchain.block_by_height(height) else {
+            continue;
+        };
+        // Each output of the block hashed once for all outpoints in it
+        let positions = block
+            .transactions
+            .iter()
+            .enumerate()
+            .flat_map(|(index, transaction)| {
+                let txid = transaction.hash();
+                (0..transaction.outputs.len() as u32)
+                    .map(move |output| ((txid, output).encoded_hash(), index))
+            })
+            .collect::<HashMap<_, _>>();
+        for outpoint in outpoints {
+            let Some(&index) = positions.get(&outpoint) else {
+                continue;
+            };
+            if let Some(proof) = MerkleProof::prove(&block.transactions, index) {
+                proofs.push(UtxoProof {
+                    outpoint,
+                    height,
+                    transaction: block.transactions[index].clone(),
+                    proof,
+                });
+            }
+        }
+    }
+    proofs
+}
+
+// The peer claiming the most cumulative work, with its block count and the
+// claimed work, which only the downloaded chain can confirm
+pub async fn find_most_work_node() -> Result<(String, u64, U256)> {
+    println!("finding nodes with the most chain work");
+    let mut best_name = String::new();
+    let mut best_work = U256::zero();
+    let mut best_count = 0;
     let all_nodes = crate::NODES
         .iter()
         .map(|x| x.key().clone())
         .collect::<Vec<_>>();
     for node in all_nodes {
-        println!("asking {} for blockchain length", node);
+        println!("asking {} for chain work", node);
         let mut stream = crate::NODES.get_mut(&node).context("no node")?;
-        let message = Message::AskDifference(0);
+        let message = Message::AskWork;
         message.send_async(&mut *stream).await.unwrap();
-        println!("sent AskDifference to {}", node);
         let message = Message::receive_async(&mut *stream).await?;
         match message {
-            Message::Difference(count) => {
-                println!("received Difference from {}", node);
-                if count > longest_count {
-                    println!(
-                        "new longest blockchain: \
-                        {} from {node}",
-                        count
-                    );
-                    longest_count = count;
-                    longest_name = node;
+            Message::Work { work, height } => {
+                println!("{} has {} blocks with work {}", node, height, work);
+                if work > best_work {
+                    best_work = work;
+                    best_count = height;
+                    best_name = node;
                 }
             }
             e => {
@@ -56,33 +200,74 @@ pub async fn find_longest_chain_node() -> Result<(String, u32)> {
             }
         }
     }
-    Ok((longest_name, longest_count as u32))
+    Ok((best_name, best_count, best_work))
 }
 
-pub async fn download_blockchain(node: &str, count: u32) -> Result<()> {
+// Downloads in three overlapping stages: this task fetches blocks, the
+// blocking pool prechecks proof of work and merkle roots of up to
+// DOWNLOAD_PIPELINE_DEPTH of them at once, and a connecting task adds them
+// in order, checking each block's signatures in parallel
+pub async fn download_blockchain(node: &str, count: u64) -> Result<()> {
+    let params = crate::BLOCKCHAIN.read().await.params().clone();
+    let (sender, receiver) = mpsc::channel(DOWNLOAD_PIPELINE_DEPTH);
+    let connector = tokio::spawn(connect_downloaded(receiver, count));
     let mut stream = crate::NODES.get_mut(node).unwrap();
-    for i in 0..count as usize {
-        let message = Message::FetchBlock(i);
+    for height in (0..count).map(BlockHeight::new) {
+        if crate::SHUTDOWN.load(Ordering::Relaxed) {
+            return Err(anyhow::anyhow!("download cancelled"));
+        }
+        let message = Message::FetchBlock(height);
         message.send_async(&mut *stream).await?;
         let message = Message::receive_async(&mut *stream).await?;
         match message {
             Message::NewBlock(block) => {
-                let mut blockchain = crate::BLOCKCHAIN.write().await;
-                blockchain.add_block(block)?;
+                let params = params.clone();
+                let precheck = task::spawn_blocking(move || block.precheck(&params));
+                // The connector stopped at an invalid block, its error tells why
+                if sender.send(precheck).await.is_err() {
+                    break;
+                }
+            }
+            Message::Reject(reason) => {
+                return Err(anyhow::anyhow!(
+                    "{} cannot serve block {}: {}",
+                    node,
+                    height,
+                    reason
+                ));
             }
             _ => {
                 println!("unexpected message form {}", node);
             }
         }
     }
+    drop(sender);
+    connector.await?
+}
+
+async fn connect_downloaded(
+    mut receiver: mpsc::Receiver<JoinHandle<btclib::error::Result<PrecheckedBlock>>>,
+    count: u64,
+) -> Result<()> {
+    while let Some(precheck) = receiver.recv().await {
+        let block = precheck.await??;
+        let mut blockchain = crate::BLOCKCHAIN.write().await;
+        blockchain.add_prechecked_block(block)?;
+        let progress = blockchain.block_count() * 100 / count;
+        crate::SYNC_PROGRESS.store(progress as u8, Ordering::Relaxed);
+    }
     Ok(())
 }
 
-pub async fn populate_connections(nodes: &[String]) -> Result<()> {
+pub async fn populate_connections(nodes: &[String], peer_token: Option<&str>) -> Result<()> {
     println!("trying to connect to other nodes...");
     for node in nodes {
         println!("connecting to {}", node);
         let mut stream = TcpStream::connect(&node).await?;
+        handshake(&mut stream, node).await?;
+        if let Some(token) = peer_token {
+            crate::auth::authenticate_stream(&mut stream, token).await?;
+        }
         let message = Message::DiscoverNodes;
         message.send_async(&mut stream).await?;
         println!("sent DiscoverNodes to {}", node);
@@ -92,7 +277,11 @@ pub async fn populate_connections(nodes: &[String]) -> Result<()> {
                 println!("receive NodeList from {}", node);
                 for child_node in child_nodes {
                     println!("adding node {}", child_node);
-                    let new_stream = TcpStream::connect(&child_node).await?;
+                    let mut new_stream = TcpStream::connect(&child_node).await?;
+                    handshake(&mut new_stream, &child_node).await?;
+                    if let Some(token) = peer_token {
+                        crate::auth::authenticate_stream(&mut new_stream, token).await?;
+                    }
                     crate::NODES.insert(child_node, new_stream);
                 }
             }
@@ -103,13 +292,82 @@ pub async fn populate_connections(nodes: &[String]) -> Result<()> {
     Ok(())
 }
 
+async fn handshake(stream: &mut TcpStream, node: &str) -> Result<()> {
+    let message = Message::Hello {
+        version: network::PROTOCOL_VERSION,
+        capabilities: Capabilities::SUPPORTED,
+    };
+    message.send_async(stream).await?;
+    match Message::receive_async(stream).await? {
+        Message::HelloAck {
+            version,
+            capabilities,
+        } => {
+            println!(
+                "{} speaks version {} with capabilities {:?}",
+                node, version, capabilities
+            );
+            Ok(())
+        }
+        Message::Incompatible { min_version } => Err(anyhow::anyhow!(
+            "{} requires protocol version {} or newer",
+            node,
+            min_version
+        )),
+        e => Err(anyhow::anyhow!(
+            "unexpected message during handshake: {:?}",
+            e
+        )),
+    }
+}
+
+pub async fn accept_connections(listener: TcpListener) -> Result<()> {
+    loop {
+        let (socket, _) = listener.accept().await?;
+        tokio::spawn(crate::handler::handle_connection(socket));
+    }
+}
+
+// Long running startup work checks SHUTDOWN and stops cleanly,
+// once the node is ready ctrl-c exits right away as before
+pub async fn load_mempool(mempool_file: &str) -> Result<()> {
+    let snapshot = MempoolSnapshot::load_from_file(mempool_file)?;
+    let total = snapshot.entries.len();
+    let blockchain = crate::BLOCKCHAIN.read().await;
+    let restored = crate::MEMPOOL.write().await.restore(
+        snapshot,
+        blockchain.utxos(),
+        blockchain.next_height().get(),
+        blockchain.params(),
+    );
+    println!("restored {}/{} pending transactions", restored, total);
+    Ok(())
+}
+
+pub async fn handle_shutdown(mempool_file: String) {
+    while tokio::signal::ctrl_c().await.is_ok() {
+        if crate::READY.load(Ordering::Relaxed) {
+            let snapshot = crate::MEMPOOL.read().await.snapshot();
+            match snapshot.save_to_file(&mempool_file) {
+                Ok(()) => println!("saved {} pending transactions", snapshot.entries.len()),
+                Err(e) => println!("failed to save the mempool: {}", e),
+            }
+            std::process::exit(0);
+        }
+        if crate::SHUTDOWN.swap(true, Ordering::Relaxed) {
+            std::process::exit(0);
+        }
+        println!("shutdown requested, stopping startup work");
+    }
+}
+
 pub async fn cleanup() {
     let mut interval = time::interval(time::Duration::from_secs(30));
     loop {
         interval.tick().await;
         println!("cleaning the mempool from old transactions");
-        let mut blockchain = crate::BLOCKCHAIN.write().await;
-        blockchain.cleanup_mempool();
+        let mut mempool = crate::MEMPOOL.write().await;
+        mempool.cleanup();
     }
 }
 
@@ -122,3 +380,25 @@ pub async fn save(name: String) {
         blockchain.save_to_file(name.clone()).unwrap();
     }
 }
+
+// Only blocks connected since the last save are written. Pruning follows
+// the save under the same lock, so no block loses its transactions before
+// the store has them, and reaches the store with the next save.
+pub async fn save_to_store(prune: Option<u64>) {
+    let mut interval = time::interval(time::Duration::from_secs(15));
+    loop {
+        interval.tick().await;
+        println!("Saving blockchain to the store...");
+        let mut blockchain = crate::BLOCKCHAIN.write().await;
+        if let Some(store) = crate::STORE.write().await.as_mut() {
+            blockchain.save_to_store(store.as_mut()).unwrap();
+        }
+        if let Some(keep) = prune {
+            let before = blockchain.pruned_count();
+            let pruned = blockchain.prune(keep);
+            if pruned > before {
+                println!("pruned the transactions of blocks below height {}", pruned);
+            }
+        }
+    }
+}
@@ -0,0 +1,187 @@
+use crate::core::Config;
+use crate::profile::PrimaryProfile;
+use anyhow::{anyhow, Result};
+use btclib::crypto::Sealed;
+use btclib::sha256::Hash;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const ARCHIVE_VERSION: u32 = 2;
+
+// Contents sealed under the backup passphrase
+#[derive(Serialize, Deserialize)]
+struct Archive {
+    version: u32,
+    sealed: Sealed,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Contents {
+    created: u64,
+    manifest: Vec<ManifestEntry>,
+    files: Vec<Vec<u8>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ManifestEntry {
+    path: PathBuf,
+    size: u64,
+    hash: Hash,
+}
+
+// When the last backup was made and how old it may get before the UI
+// reminds about it
+#[derive(Serialize, Deserialize, Clone)]
+pub struct BackupConfig {
+    pub reminder_days: u64,
+    pub state_file: PathBuf,
+}
+
+impl Default for BackupConfig {
+    fn default() -> Self {
+        BackupConfig {
+            reminder_days: 30,
+            state_file: PathBuf::from("wallet_backup_state"),
+        }
+    }
+}
+
+// Config, keys, contacts and history of every profile that exist on disk
+fn wallet_files(
+    config_path: &Path,
+    config: &Config,
+    primary: Option<&PrimaryProfile>,
+) -> Vec<PathBuf> {
+    let mut files = vec![config_path.to_path_buf(), config.history_file.clone()];
+    let mut keys = config.my_keys.clone();
+    let mut contacts = config.contacts.clone();
+    if let Some(primary) = primary {
+        keys.extend(primary.my_keys.iter().cloned());
+        contacts.extend(primary.contacts.iter().cloned());
+        files.push(primary.history_file.clone());
+    }
+    if let Some(decoy) = config
+        .unlock
+        .as_ref()
+        .and_then(|unlock| unlock.decoy.as_ref())
+    {
+        keys.extend(decoy.my_keys.iter().cloned());
+        contacts.extend(decoy.contacts.iter().cloned());
+        files.push(decoy.history_file.clone());
+    }
+    files.extend(keys.into_iter().flat_map(|key| [key.public, key.private]));
+    files.extend(contacts.into_iter().map(|contact| contact.key));
+    files.sort();
+    files.dedup();
+    files.retain(|file| file.exists());
+    files
+}
+
+// A sealed primary profile only tells its files once `prompt` gave its
+// passphrase
+pub fn backup(
+    config_path: &Path,
+    out: &Path,
+    passphrase: &str,
+    prompt: impl FnOnce() -> Result<String>,
+) -> Result<usize> {
+    let config: Config = toml::from_str(&fs::read_to_string(config_path)?)?;
+    let primary = match &config.unlock {
+        Some(unlock) => Some(
+            PrimaryProfile::open(&unlock.primary, &prompt()?)?
+                .ok_or_else(|| anyhow!("Wrong passphrase"))?,
+        ),
+        None => None,
+    };
+    let mut manifest = Vec::new();
+    let mut files = Vec::new();
+    for path in wallet_files(config_path, &config, primary.as_ref()) {
+        let data = fs::read(&path)?;
+        manifest.push(ManifestEntry {
+            path,
+            size: data.len() as u64,
+            hash: Hash::hash_bytes(&data),
+        });
+        files.push(data);
+    }
+    let count = files.len();
+    let contents = Contents {
+        created: unix_now(),
+        manifest,
+        files,
+    };
+    let mut plaintext = Vec::new();
+    ciborium::into_writer(&contents, &mut plaintext)?;
+    let archive = Archive {
+        version: ARCHIVE_VERSION,
+        sealed: Sealed::seal(passphrase, &plaintext),
+    };
+    let mut bytes = Vec::new();
+    ciborium::into_writer(&archive, &mut bytes)?;
+    fs::write(out, bytes)?;
+    fs::write(&config.backup.state_file, contents.created.to_string())?;
+    Ok(count)
+}
+
+// Every file is checked against the manifest before any is written, and
+// existing files are only replaced with `force`
+pub fn restore(input: &Path, passphrase: &str, force: bool) -> Result<Vec<PathBuf>> {
+    let archive: Archive = ciborium::from_reader(fs::read(input)?.as_slice())?;
+    if archive.version != ARCHIVE_VERSION {
+        return Err(anyhow!("Unsupported backup version {}", archive.version));
+    }
+    let plaintext = archive
+        .sealed
+        .open(passphrase)
+        .map_err(|_| anyhow!("Wrong passphrase or corrupted backup"))?;
+    let contents: Contents = ciborium::from_reader(plaintext.as_slice())?;
+    if contents.manifest.len() != contents.files.len() {
+        return Err(anyhow!("Backup manifest does not match its files"));
+    }
+    for (entry, data) in contents.manifest.iter().zip(&contents.files) {
+        if data.len() as u64 != entry.size || Hash::hash_bytes(data) != entry.hash {
+            return Err(anyhow!("{} failed verification", entry.path.display()));
+        }
+        if entry.path.exists() && !force {
+            return Err(anyhow!(
+                "{} already exists, use --force",
+                entry.path.display()
+            ));
+        }
+    }
+    for (entry, data) in contents.manifest.iter().zip(&contents.files) {
+        if let Some(parent) = entry.path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&entry.path, data)?;
+    }
+    Ok(contents
+        .manifest
+        .into_iter()
+        .map(|entry| entry.path)
+        .collect())
+}
+
+// Shown on startup when no backup was made in reminder_days
+pub fn reminder(config: &BackupConfig) -> Option<String> {
+    if config.reminder_days == 0 {
+        return None;
+    }
+    let last = fs::read_to_string(&config.state_file)
+        .ok()
+        .and_then(|stamp| stamp.trim().parse::<u64>().ok());
+    let days = match last {
+        Some(last) => unix_now().saturating_sub(last) / 86400,
+        None => return Some("This wallet was never backed up.".to_string()),
+    };
+    (days >= config.reminder_days).then(|| format!("The last backup is {} days old.", days))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
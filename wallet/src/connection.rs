@@ -0,0 +1,52 @@
+use anyhow::{anyhow, Result};
+use btclib::network::{self, Capabilities, Message};
+use btclib::types::Transaction;
+use tokio::net::TcpStream;
+use tracing::*;
+
+pub async fn connect(address: &str, auth_token: Option<&str>) -> Result<TcpStream> {
+    let mut stream = TcpStream::connect(address).await?;
+    if let Some(token) = auth_token {
+        Message::Authenticate(token.to_string())
+            .send_async(&mut stream)
+            .await?;
+        match Message::receive_async(&mut stream).await? {
+            Message::Authenticated(true) => info!("Authenticated with node {}", address),
+            _ => return Err(anyhow!("Node {} rejected the access token", address)),
+        }
+    }
+    Ok(stream)
+}
+
+// Tip changes are only pushed to peers that negotiated subscriptions
+pub async fn subscribe_tip(stream: &mut TcpStream) -> Result<()> {
+    Message::Hello {
+        version: network::PROTOCOL_VERSION,
+        capabilities: Capabilities::SUBSCRIPTIONS,
+    }
+    .send_async(stream)
+    .await?;
+    match Message::receive_async(stream).await? {
+        Message::HelloAck { capabilities, .. }
+            if capabilities.contains(Capabilities::SUBSCRIPTIONS) => {}
+        Message::HelloAck { .. } => return Err(anyhow!("Node does not offer tip subscriptions")),
+        Message::Incompatible { min_version } => {
+            return Err(anyhow!("Node requires protocol version {}", min_version))
+        }
+        _ => return Err(anyhow!("Unexpected response to hello")),
+    }
+    Message::SubscribeTip.send_async(stream).await?;
+    Ok(())
+}
+
+pub async fn submit_transaction(stream: &mut TcpStream, transaction: Transaction) -> Result<()> {
+    Message::SubmitTransaction(transaction)
+        .send_async(stream)
+        .await?;
+    match Message::receive_async(stream).await? {
+        Message::TransactionAccepted(true) => Ok(()),
+        Message::TransactionAccepted(false) => Err(anyhow!("Transaction rejected")),
+        Message::NotReady { progress } => Err(anyhow!("Node is syncing ({}%)", progress)),
+        _ => Err(anyhow!("Unexpected response from node")),
+    }
+}
@@ -1,11 +1,26 @@
+use crate::backup::BackupConfig;
+use crate::connection;
+use crate::headers::HeaderChain;
+use crate::history::{History, HistoryEntry};
+use crate::payment::PaymentRequest;
+use crate::policy::{ApprovalPolicy, SpendingTracker};
+use crate::profile::{self, PrimaryProfile, UnlockConfig};
+use crate::utils;
 use anyhow::{anyhow, Result};
-use btclib::crypto::{PrivateKey, PublicKey};
+use btclib::address::Address;
+use btclib::amount::Amount;
+use btclib::crypto::{self, Fingerprint, PrivateKey, PublicKey, Signature};
+use btclib::encoding::Encode;
 use btclib::network::Message;
-use btclib::types::{Transaction, TransactionOutput};
+use btclib::params::ChainParams;
+use btclib::script::{Script, Witness};
+use btclib::sha256::Hash;
+use btclib::types::{BlockHeight, ChainStats, Transaction, TransactionOutput};
 use btclib::util::Saveable;
 use crossbeam_skiplist::SkipMap;
 use kanal::Sender;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs;
 use std::path::PathBuf;
 use std::sync::Arc;
@@ -13,10 +28,13 @@ use tokio::net::TcpStream;
 use tokio::sync::Mutex;
 use tracing::*;
 
+// Whether a pending transaction spends it, its outpoint hash and the output
+type Utxo = (bool, Hash, TransactionOutput);
+
 #[derive(Clone)]
 struct UtxoStore {
     pub my_keys: Vec<LoadedKey>,
-    pub utxos: Arc<SkipMap<PublicKey, Vec<(bool, TransactionOutput)>>>,
+    pub utxos: Arc<SkipMap<PublicKey, Vec<Utxo>>>,
 }
 
 impl UtxoStore {
@@ -31,115 +49,647 @@ impl UtxoStore {
     }
 }
 
+const MAX_RECENT_RECIPIENTS: usize = 10;
+// Sats per 1000 bytes paid with FeeType::Estimate until the node has one
+const FALLBACK_FEE_RATE: u64 = 1_000;
+// Size estimated fees are paid for, a few signed inputs and two outputs
+const ESTIMATED_TRANSACTION_SIZE: u64 = 500;
+
 pub struct Core {
     pub config: Config,
+    config_path: PathBuf,
+    // Reseals the primary profile when keys are imported into it
+    passphrase: Option<String>,
     utxos: UtxoStore,
-    pub tx_sender: Sender<Transaction>,
+    pub tx_sender: Sender<Outgoing>,
     pub stream: Mutex<TcpStream>,
+    recent_recipients: std::sync::Mutex<Vec<String>>,
+    node_progress: std::sync::Mutex<Option<u8>>,
+    last_broadcast: std::sync::Mutex<Option<(usize, usize)>>,
+    spending: std::sync::Mutex<SpendingTracker>,
+    history: std::sync::Mutex<History>,
+    fee_rate_estimate: std::sync::Mutex<Option<u64>>,
+    headers: std::sync::Mutex<HeaderChain>,
+    // Outpoints whose inclusion was proven against the headers
+    verified: std::sync::Mutex<HashSet<Hash>>,
+    // Heights of the blocks that confirmed sent transactions
+    confirmed: std::sync::Mutex<HashMap<Hash, BlockHeight>>,
+    chain_stats: std::sync::Mutex<Option<ChainStats>>,
+}
+
+// Work for the transaction task
+pub enum Outgoing {
+    Send {
+        transaction: Transaction,
+        passphrase: Option<String>,
+    },
+    Rebroadcast(Transaction),
 }
 
 impl Core {
-    fn new(config: Config, utxos: UtxoStore, stream: TcpStream) -> Self {
+    fn new(
+        config: Config,
+        config_path: PathBuf,
+        utxos: UtxoStore,
+        stream: TcpStream,
+        history: History,
+        params: ChainParams,
+    ) -> Self {
         let (tx_sender, _) = kanal::bounded(10);
         Core {
             config,
+            config_path,
+            passphrase: None,
             utxos,
             tx_sender,
             stream: Mutex::new(stream),
+            recent_recipients: std::sync::Mutex::new(Vec::new()),
+            node_progress: std::sync::Mutex::new(None),
+            last_broadcast: std::sync::Mutex::new(None),
+            spending: std::sync::Mutex::new(SpendingTracker::default()),
+            history: std::sync::Mutex::new(history),
+            fee_rate_estimate: std::sync::Mutex::new(None),
+            headers: std::sync::Mutex::new(HeaderChain::new(params)),
+            verified: std::sync::Mutex::new(HashSet::new()),
+            confirmed: std::sync::Mutex::new(HashMap::new()),
+            chain_stats: std::sync::Mutex::new(None),
         }
     }
     pub async fn load(config_path: PathBuf) -> Result<Self> {
         let config: Config = toml::from_str(&fs::read_to_string(&config_path)?)?;
+        let mut passphrase = None;
+        let config = profile::unlock(config, || {
+            let entered = utils::prompt_passphrase()?;
+            passphrase = Some(entered.clone());
+            Ok(entered)
+        })?;
         let mut utxos = UtxoStore::new();
-        let stream = TcpStream::connect(&config.default_node).await?;
+        let stream =
+            connection::connect(&config.default_node, config.auth_token.as_deref()).await?;
+        let mut passphrases = Vec::new();
         for key in &config.my_keys {
             let public = PublicKey::load_from_file(&key.public)?;
-            let private = PrivateKey::load_from_file(&key.private)?;
-            utxos.add_key(LoadedKey { public, private });
+            let private = utils::load_private_key(&key.private, &mut passphrases)?;
+            utxos.add_key(LoadedKey {
+                public,
+                private,
+                daily_limit: key.daily_limit,
+            });
         }
-        Ok(Core::new(config, utxos, stream))
+        let history = History::load(&config.history_file)?;
+        let params = config.chain_params()?;
+        let mut core = Core::new(config, config_path, utxos, stream, history, params);
+        core.passphrase = passphrase;
+        Ok(core)
     }
 
     pub async fn fetch_utxos(&self) -> Result<()> {
         for key in &self.utxos.my_keys {
             let message = Message::FetchUTXOs(key.public.clone());
             message.send_async(&mut *self.stream.lock().await).await?;
-            if let Message::UTXOs(utxos) =
-                Message::receive_async(&mut *self.stream.lock().await).await?
-            {
-                self.utxos.utxos.insert(
-                    key.public.clone(),
-                    utxos
-                        .into_iter()
-                        .map(|(output, marked)| (marked, output))
-                        .collect(),
+            match Message::receive_async(&mut *self.stream.lock().await).await? {
+                Message::UTXOs(utxos) => {
+                    self.utxos.utxos.insert(
+                        key.public.clone(),
+                        utxos
+                            .into_iter()
+                            .map(|(hash, output, marked)| (marked, hash, output))
+                            .collect(),
+                    );
+                }
+                Message::NotReady { progress } => {
+                    info!("Node is still syncing: {}%", progress);
+                    *self.node_progress.lock().unwrap() = Some(progress);
+                    return Ok(());
+                }
+                // Retried on the next refresh
+                Message::Busy => {
+                    info!("Node is busy, keeping the previous UTXOs");
+                    return Ok(());
+                }
+                _ => return Err(anyhow!("Unexpected response from node")),
+            }
+        }
+        *self.node_progress.lock().unwrap() = None;
+        if let FeeType::Estimate = self.config.fee_config.fee_type {
+            self.fetch_fee_estimate().await?;
+        }
+        self.fetch_chain_stats().await?;
+        self.verify_utxos().await?;
+        self.fetch_confirmations().await
+    }
+
+    // Asks the node's txindex about sent transactions not yet confirmed
+    async fn fetch_confirmations(&self) -> Result<()> {
+        let pending = {
+            let confirmed = self.confirmed.lock().unwrap();
+            self.history
+                .lock()
+                .unwrap()
+                .entries()
+                .iter()
+                .map(|entry| entry.txid)
+                .filter(|txid| !confirmed.contains_key(txid))
+                .collect::<Vec<_>>()
+        };
+        for txid in pending {
+            Message::FetchTransaction(txid)
+                .send_async(&mut *self.stream.lock().await)
+                .await?;
+            match Message::receive_async(&mut *self.stream.lock().await).await? {
+                Message::TransactionInfo(Some(info)) => {
+                    debug!("{} confirmed at height {}", txid, info.location.height);
+                    self.confirmed
+                        .lock()
+                        .unwrap()
+                        .insert(txid, info.location.height);
+                }
+                Message::TransactionInfo(None) => {}
+                _ => return Err(anyhow!("Unexpected response from node")),
+            }
+        }
+        Ok(())
+    }
+
+    // Blocks on top of and including the one that confirmed the transaction,
+    // None while the node's txindex does not know it
+    pub fn confirmations(&self, txid: &Hash) -> Option<u64> {
+        let height = *self.confirmed.lock().unwrap().get(txid)?;
+        Some(
+            self.headers
+                .lock()
+                .unwrap()
+                .len()
+                .saturating_sub(height.get()),
+        )
+    }
+
+    // Rewinds to where the node's chain and ours part before fetching
+    async fn sync_headers(&self) -> Result<()> {
+        let locator = self.headers.lock().unwrap().locator();
+        if !locator.is_empty() {
+            Message::FetchForkPoint(locator)
+                .send_async(&mut *self.stream.lock().await)
+                .await?;
+            let fork_point = match Message::receive_async(&mut *self.stream.lock().await).await? {
+                Message::ForkPoint(Some(fork_point)) => fork_point,
+                Message::ForkPoint(None) => return Err(anyhow!("Node shares no block with us")),
+                _ => return Err(anyhow!("Unexpected response from node")),
+            };
+            if self.headers.lock().unwrap().truncate_above(fork_point) {
+                warn!(
+                    "Node switched chains below our tip, resyncing from {}",
+                    fork_point
                 );
-            } else {
-                return Err(anyhow!("Unexpected response from node"));
+                self.verified.lock().unwrap().clear();
+                self.confirmed.lock().unwrap().clear();
+            }
+        }
+        loop {
+            let start = BlockHeight::new(self.headers.lock().unwrap().len());
+            let message = Message::FetchHeaders {
+                start,
+                count: btclib::MAX_HEADERS_PER_MESSAGE,
+            };
+            message.send_async(&mut *self.stream.lock().await).await?;
+            let headers = match Message::receive_async(&mut *self.stream.lock().await).await? {
+                Message::Headers(headers) => headers,
+                _ => return Err(anyhow!("Unexpected response from node")),
+            };
+            let done = headers.len() < btclib::MAX_HEADERS_PER_MESSAGE as usize;
+            self.headers.lock().unwrap().extend(headers)?;
+            if done {
+                return Ok(());
+            }
+        }
+    }
+
+    // Checks the UTXOs the node reported against headers validated here, so
+    // the balance does not rest on the node's word. Proofs are fetched in
+    // batches, once per output.
+    async fn verify_utxos(&self) -> Result<()> {
+        self.sync_headers().await?;
+        let unverified = {
+            let verified = self.verified.lock().unwrap();
+            self.utxos
+                .utxos
+                .iter()
+                .flat_map(|entry| {
+                    entry
+                        .value()
+                        .iter()
+                        .map(|(_, outpoint, output)| (*outpoint, output.encoded_hash()))
+                        .collect::<Vec<_>>()
+                })
+                .filter(|(outpoint, _)| !verified.contains(outpoint))
+                .collect::<HashMap<_, _>>()
+        };
+        let outpoints = unverified.keys().copied().collect::<Vec<_>>();
+        for batch in outpoints.chunks(btclib::MAX_UTXO_PROOFS_PER_MESSAGE) {
+            let message = Message::FetchUtxoProofs(batch.to_vec());
+            message.send_async(&mut *self.stream.lock().await).await?;
+            let proofs = match Message::receive_async(&mut *self.stream.lock().await).await? {
+                Message::UtxoProofs(proofs) => proofs,
+                Message::Busy => {
+                    info!("Node is busy, verifying UTXOs later");
+                    return Ok(());
+                }
+                _ => return Err(anyhow!("Unexpected response from node")),
+            };
+            let headers = self.headers.lock().unwrap();
+            let mut verified = self.verified.lock().unwrap();
+            for proof in proofs {
+                let matches_report = proof.output().map(|output| output.encoded_hash())
+                    == unverified.get(&proof.outpoint).copied();
+                let included = headers
+                    .get(proof.height)
+                    .is_some_and(|header| proof.verify(header));
+                if matches_report && included {
+                    verified.insert(proof.outpoint);
+                } else {
+                    warn!("Node sent an invalid proof for {}", proof.outpoint);
+                }
             }
         }
         Ok(())
     }
 
-    pub async fn send_transaction(&self, transaction: Transaction) -> Result<()> {
-        let message = Message::SubmitTransaction(transaction);
+    // Reported UTXOs with an inclusion proof, and all reported UTXOs
+    pub fn verification_progress(&self) -> (usize, usize) {
+        let verified = self.verified.lock().unwrap();
+        self.utxos
+            .utxos
+            .iter()
+            .fold((0, 0), |(proven, total), entry| {
+                let utxos = entry.value();
+                let proven_here = utxos
+                    .iter()
+                    .filter(|(_, outpoint, _)| verified.contains(outpoint))
+                    .count();
+                (proven + proven_here, total + utxos.len())
+            })
+    }
+
+    async fn fetch_fee_estimate(&self) -> Result<()> {
+        let confirmation_target = self.config.fee_config.value.max(1.0) as u64;
+        let message = Message::FetchFeeEstimate {
+            confirmation_target,
+        };
         message.send_async(&mut *self.stream.lock().await).await?;
+        match Message::receive_async(&mut *self.stream.lock().await).await? {
+            Message::FeeEstimate(estimate) => {
+                debug!(
+                    "Fee estimate for {} blocks: {:?}",
+                    confirmation_target, estimate
+                );
+                *self.fee_rate_estimate.lock().unwrap() = estimate;
+                Ok(())
+            }
+            _ => Err(anyhow!("Unexpected response from node")),
+        }
+    }
+
+    async fn fetch_chain_stats(&self) -> Result<()> {
+        Message::FetchStats
+            .send_async(&mut *self.stream.lock().await)
+            .await?;
+        match Message::receive_async(&mut *self.stream.lock().await).await? {
+            Message::Stats(stats) => {
+                *self.chain_stats.lock().unwrap() = Some(stats);
+                Ok(())
+            }
+            // Kept from the last refresh
+            Message::Busy => Ok(()),
+            _ => Err(anyhow!("Unexpected response from node")),
+        }
+    }
+
+    pub fn node_status(&self) -> String {
+        let node = match *self.node_progress.lock().unwrap() {
+            Some(progress) => format!("Node syncing: {}%", progress),
+            None => "Node ready".to_string(),
+        };
+        let node = match &*self.chain_stats.lock().unwrap() {
+            Some(stats) => format!(
+                "{} | Height {} | Difficulty {:.2} | Block time {:.0}s | Mempool {} tx",
+                node,
+                stats.tip.map(|tip| tip.to_string()).unwrap_or_default(),
+                stats.difficulty,
+                stats.recent_block_interval,
+                stats.mempool.transactions
+            ),
+            None => node,
+        };
+        let (proven, total) = self.verification_progress();
+        let node = format!("{} | UTXOs verified {}/{}", node, proven, total);
+        match *self.last_broadcast.lock().unwrap() {
+            Some((accepted, total)) => {
+                format!(
+                    "{} | Last broadcast accepted by {}/{} nodes",
+                    node, accepted, total
+                )
+            }
+            None => node,
+        }
+    }
+
+    pub async fn send_transaction(
+        &self,
+        transaction: Transaction,
+        passphrase: Option<&str>,
+    ) -> Result<()> {
+        let spends = self.check_policy(&transaction, passphrase)?;
+        self.broadcast(&transaction).await?;
+        let mut spending = self.spending.lock().unwrap();
+        for (key, amount) in &spends {
+            spending.record(key, *amount);
+        }
+        drop(spending);
+        let recorded = HistoryEntry::new(&transaction)
+            .and_then(|entry| self.history.lock().unwrap().record(entry));
+        if let Err(e) = recorded {
+            error!("Failed to record transaction in history: {}", e);
+        }
         info!("Transaction sent");
         Ok(())
     }
 
-    pub fn send_transaction_async(&self, recipient: &str, amount: u64) -> Result<()> {
-        info!("Preparing to sent {} statoshis to {}", amount, recipient);
-        let recipient_key = self
+    // Sends the transaction to the default and all broadcast nodes
+    pub async fn broadcast(&self, transaction: &Transaction) -> Result<()> {
+        let default_result = {
+            let mut stream = self.stream.lock().await;
+            connection::submit_transaction(&mut stream, transaction.clone()).await
+        };
+        let mut results = vec![(self.config.default_node.clone(), default_result)];
+        for node in &self.config.broadcast_nodes {
+            let result = match connection::connect(node, self.config.auth_token.as_deref()).await {
+                Ok(mut stream) => {
+                    connection::submit_transaction(&mut stream, transaction.clone()).await
+                }
+                Err(e) => Err(e),
+            };
+            results.push((node.clone(), result));
+        }
+        let mut accepted = 0;
+        for (node, result) in &results {
+            match result {
+                Ok(()) => {
+                    info!("Transaction accepted by {}", node);
+                    accepted += 1;
+                }
+                Err(e) => warn!("Transaction not accepted by {}: {}", node, e),
+            }
+        }
+        *self.last_broadcast.lock().unwrap() = Some((accepted, results.len()));
+        if accepted == 0 {
+            return Err(anyhow!("Transaction was not accepted by any node"));
+        }
+        Ok(())
+    }
+
+    pub fn history(&self) -> Vec<HistoryEntry> {
+        self.history.lock().unwrap().entries().to_vec()
+    }
+
+    pub fn rebroadcast_async(&self, txid: &Hash) -> Result<()> {
+        let transaction = self.history.lock().unwrap().get(txid)?.transaction()?;
+        info!("Rebroadcasting transaction {}", txid);
+        self.tx_sender.send(Outgoing::Rebroadcast(transaction))?;
+        Ok(())
+    }
+
+    pub fn send_transaction_async(
+        &self,
+        recipient: &str,
+        amount: Amount,
+        passphrase: Option<&str>,
+    ) -> Result<Hash> {
+        info!(
+            "Preparing to sent {} to {}",
+            amount.display_sat(),
+            recipient
+        );
+        let recipient_script = self.resolve_recipient(recipient)?;
+        let transaction = self.create_transaction(&recipient_script, amount)?;
+        // Checked here as well so the UI learns about violations right away
+        self.check_policy(&transaction, passphrase)?;
+        debug!("Sending async transcaction");
+        let txid = transaction.hash();
+        self.tx_sender.send(Outgoing::Send {
+            transaction,
+            passphrase: passphrase.map(str::to_string),
+        })?;
+        self.remember_recipient(recipient);
+        Ok(txid)
+    }
+
+    fn remember_recipient(&self, recipient: &str) {
+        let mut recent = self.recent_recipients.lock().unwrap();
+        recent.retain(|r| r != recipient);
+        recent.insert(0, recipient.to_string());
+        recent.truncate(MAX_RECENT_RECIPIENTS);
+    }
+
+    // WIF of the key at `index` in the config, for moving it to another wallet
+    pub fn export_wif(&self, index: usize) -> Result<String> {
+        let key = self
+            .utxos
+            .my_keys
+            .get(index)
+            .ok_or_else(|| anyhow!("No key at position {}", index))?;
+        info!("Exporting key {}", key.public.fingerprint());
+        Ok(key.private.to_wif(&self.config.chain_params()?))
+    }
+
+    // Proves control of the key at `index` in the config. Verifiers need the
+    // public key as well, the address only holds its hash.
+    pub fn sign_message(
+        &self,
+        index: usize,
+        message: &str,
+    ) -> Result<(Address, PublicKey, Signature)> {
+        let key = self
+            .utxos
+            .my_keys
+            .get(index)
+            .ok_or_else(|| anyhow!("No key at position {}", index))?;
+        info!("Signing a message with key {}", key.public.fingerprint());
+        let signature = crypto::sign_message(&key.private, message);
+        Ok((
+            Address::from_public_key(&key.public),
+            key.public.clone(),
+            signature,
+        ))
+    }
+
+    // In the order of the keys in the config
+    pub fn key_fingerprints(&self) -> Vec<Fingerprint> {
+        self.utxos
+            .my_keys
+            .iter()
+            .map(|key| key.public.fingerprint())
+            .collect()
+    }
+
+    pub fn recipient_candidates(&self) -> Vec<String> {
+        let mut candidates = self
             .config
             .contacts
             .iter()
-            .find(|r| r.name == recipient)
-            .ok_or_else(|| anyhow::anyhow!("Recipient not found"))?
-            .load()?
-            .key;
-        let transaction = self.create_transaction(&recipient_key, amount)?;
-        debug!("Sending async transcaction");
-        self.tx_sender.send(transaction)?;
-        Ok(())
+            .map(|contact| contact.name.clone())
+            .collect::<Vec<_>>();
+        for recent in self.recent_recipients.lock().unwrap().iter() {
+            if !candidates.contains(recent) {
+                candidates.push(recent.clone());
+            }
+        }
+        candidates
     }
-    pub fn get_balance(&self) -> u64 {
-        self.utxos
+    // A recipient is a contact name, an address or an encoded payment request
+    pub fn resolve_recipient(&self, recipient: &str) -> Result<Script> {
+        if let Some(contact) = self.config.contacts.iter().find(|r| r.name == recipient) {
+            return Ok(Script::PayToPubkey(contact.load()?.key));
+        }
+        if let Ok(address) = recipient.parse::<Address>() {
+            return Ok(address.script());
+        }
+        match PaymentRequest::decode(recipient) {
+            Ok(request) if request.is_expired() => Err(anyhow!("Payment request has expired")),
+            Ok(request) => Ok(Script::PayToPubkey(request.address)),
+            Err(_) => Err(anyhow!("Recipient not found")),
+        }
+    }
+
+    // Saves the key as <name>.priv.pem and <name>.pub.pem and lists it in the
+    // config of the profile that is open. It is spendable after a restart.
+    pub fn import_wif(&self, wif: &str, name: &str) -> Result<Address> {
+        let private = PrivateKey::from_wif(wif, &self.config.chain_params()?)?;
+        let public = private.public_key();
+        if name.is_empty() {
+            return Err(anyhow!("The key needs a name"));
+        }
+        let key = Key {
+            public: PathBuf::from(format!("{}.pub.pem", name)),
+            private: PathBuf::from(format!("{}.priv.pem", name)),
+            daily_limit: None,
+        };
+        if key.public.exists() || key.private.exists() {
+            return Err(anyhow!("Key files named {} already exist", name));
+        }
+        private.save_to_file(&key.private)?;
+        public.save_to_file(&key.public)?;
+        let mut config: Config = toml::from_str(&fs::read_to_string(&self.config_path)?)?;
+        match config.unlock.as_mut() {
+            Some(unlock) => match unlock.decoy.as_mut() {
+                Some(decoy) if decoy.history_file == self.config.history_file => {
+                    decoy.my_keys.push(key)
+                }
+                _ => {
+                    let passphrase = self
+                        .passphrase
+                        .as_deref()
+                        .ok_or_else(|| anyhow!("The wallet was not unlocked"))?;
+                    let mut primary = PrimaryProfile::open(&unlock.primary, passphrase)?
+                        .ok_or_else(|| anyhow!("Wrong passphrase"))?;
+                    primary.my_keys.push(key);
+                    unlock.primary = primary.seal(passphrase)?;
+                }
+            },
+            None => config.my_keys.push(key),
+        }
+        fs::write(&self.config_path, toml::to_string_pretty(&config)?)?;
+        info!("Imported key {} ({})", name, public.fingerprint());
+        Ok(Address::from_public_key(&public))
+    }
+
+    pub fn address(&self) -> Result<Address> {
+        let key = self
+            .utxos
+            .my_keys
+            .first()
+            .ok_or_else(|| anyhow!("No keys configured"))?;
+        Ok(Address::from_public_key(&key.public))
+    }
+
+    pub fn create_payment_request(
+        &self,
+        amount: Amount,
+        memo: String,
+        valid_for_secs: Option<u64>,
+    ) -> Result<PaymentRequest> {
+        let key = self
             .utxos
+            .my_keys
+            .first()
+            .ok_or_else(|| anyhow!("No keys configured"))?;
+        Ok(PaymentRequest::new(
+            amount,
+            key.public.clone(),
+            memo,
+            valid_for_secs,
+        ))
+    }
+
+    pub fn contact_name_for(&self, key: &PublicKey) -> Option<String> {
+        self.config
+            .contacts
             .iter()
-            .map(|entry| entry.value().iter().map(|utxo| utxo.1.value).sum::<u64>())
-            .sum()
+            .filter_map(|contact| contact.load().ok())
+            .find(|contact| contact.key == *key)
+            .map(|contact| contact.name)
     }
 
-    pub fn create_transaction(&self, recipient: &PublicKey, amount: u64) -> Result<Transaction> {
+    pub fn get_balance(&self) -> Amount {
+        let balance = self
+            .utxos
+            .utxos
+            .iter()
+            .try_fold(Amount::ZERO, |balance, entry| {
+                Amount::checked_sum(entry.value().iter().map(|utxo| utxo.2.value))
+                    .and_then(|value| balance.checked_add(value))
+            });
+        balance.unwrap_or_else(|| {
+            error!("Balance overflows, node reported bogus UTXOs");
+            Amount::ZERO
+        })
+    }
+
+    pub fn create_transaction(&self, recipient: &Script, amount: Amount) -> Result<Transaction> {
         let fee = self.calculate_fee(amount);
-        let total_amount = amount + fee;
+        let total_amount = amount
+            .checked_add(fee)
+            .ok_or_else(|| anyhow!("Amount too large"))?;
         let mut inputs = Vec::new();
-        let mut input_sum = 0;
+        let mut signers = Vec::new();
+        let mut input_sum = Amount::ZERO;
         for entry in self.utxos.utxos.iter() {
             let pubkey = entry.key();
             let utxos = entry.value();
-            for (marked, utxo) in utxos.iter() {
+            for (marked, utxo_hash, utxo) in utxos.iter() {
                 if *marked {
                     continue;
                 }
                 if input_sum >= total_amount {
                     break;
                 }
+                // Only outputs locked to a single key can be spent by this wallet,
+                // the node marks time locked outputs until they unlock
+                let witness = match &utxo.script {
+                    Script::PayToPubkey(_)
+                    | Script::PayToSchnorrPubkey(_)
+                    | Script::TimeLock { .. } => Witness::default(),
+                    Script::PayToPubkeyHash(_) => Witness::default().with_pubkey(pubkey.clone()),
+                    _ => continue,
+                };
                 inputs.push(btclib::types::TransactionInput {
-                    prev_transaction_output_hash: utxo.hash(),
-                    signature: btclib::crypto::Signature::sign_output(
-                        &utxo.hash(),
-                        &self
-                            .utxos
-                            .my_keys
-                            .iter()
-                            .find(|k| k.public == *pubkey)
-                            .unwrap()
-                            .private,
-                    ),
+                    prev_transaction_output_hash: *utxo_hash,
+                    witness,
+                    sequence: 0,
                 });
-                input_sum += utxo.value;
+                signers.push((pubkey.clone(), utxo.script.scheme()));
+                input_sum = input_sum
+                    .checked_add(utxo.value)
+                    .ok_or_else(|| anyhow!("Input values overflow"))?;
             }
             if input_sum >= total_amount {
                 break;
@@ -150,24 +700,156 @@ impl Core {
         }
         let mut outputs = vec![TransactionOutput {
             value: amount,
-            unique_id: uuid::Uuid::new_v4(),
-            pubkey: recipient.clone(),
+            script: recipient.clone(),
         }];
-        if input_sum > total_amount {
+        let change = input_sum
+            .checked_sub(total_amount)
+            .expect("Bug: Impossible");
+        // Change below the dust limit would not be relayed, it goes to the fee instead
+        if change >= Amount::from_sat(btclib::DUST_LIMIT) {
             outputs.push(TransactionOutput {
-                value: input_sum - total_amount,
-                unique_id: uuid::Uuid::new_v4(),
-                pubkey: self.utxos.my_keys[0].public.clone(),
+                value: change,
+                script: Script::PayToPubkey(self.utxos.my_keys[0].public.clone()),
             });
         }
+        // Inputs are signed once the transaction is complete, the digest commits to all of it
+        let mut transaction = Transaction::new(inputs, outputs);
+        for (index, (signer, scheme)) in signers.iter().enumerate() {
+            let private = &self
+                .utxos
+                .my_keys
+                .iter()
+                .find(|k| k.public == *signer)
+                .unwrap()
+                .private;
+            transaction.sign_input(index, private, *scheme)?;
+        }
         info!("Created transaction");
-        Ok(Transaction::new(inputs, outputs))
+        Ok(transaction)
+    }
+
+    // Why a send needs an extra confirmation, if it does
+    pub fn send_confirmation_reason(&self, amount: Amount) -> Option<String> {
+        let safety = &self.config.safety;
+        if !safety.enabled {
+            return None;
+        }
+        if amount > safety.large_send_threshold {
+            return Some(format!(
+                "Sending {} is above the {} confirmation threshold.",
+                amount, safety.large_send_threshold
+            ));
+        }
+        let remaining = amount
+            .checked_add(self.calculate_fee(amount))
+            .and_then(|total| self.get_balance().checked_sub(total))
+            .unwrap_or(Amount::ZERO);
+        if remaining < safety.balance_floor {
+            return Some(format!(
+                "This send leaves {}, below the {} balance floor.",
+                remaining, safety.balance_floor
+            ));
+        }
+        None
+    }
+
+    pub fn requires_approval(&self, amount: Amount) -> bool {
+        self.config
+            .approval
+            .as_ref()
+            .is_some_and(|approval| approval.requires_approval(amount))
+    }
+
+    // Enforces the approval policy and daily key limits, returning what each key spends
+    fn check_policy(
+        &self,
+        transaction: &Transaction,
+        passphrase: Option<&str>,
+    ) -> Result<BTreeMap<PublicKey, Amount>> {
+        let spends = self.spends_by_key(transaction);
+        if let Some(approval) = &self.config.approval {
+            let outgoing = Amount::checked_sum(
+                transaction
+                    .outputs
+                    .iter()
+                    .filter(|output| !self.is_own_output(output))
+                    .map(|output| output.value),
+            )
+            .ok_or_else(|| anyhow!("Output values overflow"))?;
+            approval.check(outgoing, passphrase)?;
+        }
+        let spending = self.spending.lock().unwrap();
+        for key in &self.utxos.my_keys {
+            let (Some(limit), Some(amount)) = (key.daily_limit, spends.get(&key.public)) else {
+                continue;
+            };
+            let spent = spending.spent_today(&key.public);
+            match spent.checked_add(*amount) {
+                Some(total) if total <= limit => {}
+                _ => {
+                    return Err(anyhow!(
+                        "Send exceeds the daily limit of {} for this key, {} already spent today",
+                        limit,
+                        spent
+                    ))
+                }
+            }
+        }
+        Ok(spends)
+    }
+
+    // Value of the inputs owned by each key, minus the change paid back to it
+    fn spends_by_key(&self, transaction: &Transaction) -> BTreeMap<PublicKey, Amount> {
+        let mut spends = BTreeMap::new();
+        for input in &transaction.inputs {
+            for entry in self.utxos.utxos.iter() {
+                let spent = entry
+                    .value()
+                    .iter()
+                    .find(|(_, hash, _)| *hash == input.prev_transaction_output_hash);
+                if let Some((_, _, output)) = spent {
+                    let total: &mut Amount = spends.entry(entry.key().clone()).or_default();
+                    *total = total.checked_add(output.value).unwrap_or(*total);
+                }
+            }
+        }
+        for output in &transaction.outputs {
+            if let Script::PayToPubkey(key) = &output.script {
+                if let Some(total) = spends.get_mut(key) {
+                    *total = total.checked_sub(output.value).unwrap_or(Amount::ZERO);
+                }
+            }
+        }
+        spends
     }
 
-    fn calculate_fee(&self, amount: u64) -> u64 {
+    fn is_own_output(&self, output: &TransactionOutput) -> bool {
+        match &output.script {
+            Script::PayToPubkey(key) | Script::PayToSchnorrPubkey(key) => {
+                self.utxos.my_keys.iter().any(|k| k.public == *key)
+            }
+            Script::PayToPubkeyHash(hash) => self
+                .utxos
+                .my_keys
+                .iter()
+                .any(|k| Script::pubkey_hash(&k.public) == *hash),
+            _ => false,
+        }
+    }
+
+    fn calculate_fee(&self, amount: Amount) -> Amount {
+        let value = self.config.fee_config.value;
         match self.config.fee_config.fee_type {
-            FeeType::Fixed => self.config.fee_config.value as u64,
-            FeeType::Percent => (amount as f64 * self.config.fee_config.value / 100.0) as u64,
+            FeeType::Fixed => Amount::from_sat(value as u64),
+            FeeType::Percent => Amount::from_sat((amount.to_sat() as f64 * value / 100.0) as u64),
+            FeeType::Estimate => {
+                let fee_rate = self
+                    .fee_rate_estimate
+                    .lock()
+                    .unwrap()
+                    .unwrap_or(FALLBACK_FEE_RATE);
+                Amount::from_sat(fee_rate.saturating_mul(ESTIMATED_TRANSACTION_SIZE) / 1000)
+            }
         }
     }
 }
@@ -176,12 +858,15 @@ impl Core {
 pub struct Key {
     pub public: PathBuf,
     pub private: PathBuf,
+    #[serde(default)]
+    pub daily_limit: Option<Amount>,
 }
 
 #[derive(Clone)]
 struct LoadedKey {
     pub public: PublicKey,
     pub private: PrivateKey,
+    pub daily_limit: Option<Amount>,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -210,6 +895,8 @@ impl Recipient {
 pub enum FeeType {
     Fixed,
     Percent,
+    // The node's fee rate estimate, `value` being the confirmation target in blocks
+    Estimate,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -224,4 +911,55 @@ pub struct Config {
     pub contacts: Vec<Recipient>,
     pub default_node: String,
     pub fee_config: FeeConfig,
+    #[serde(default)]
+    pub auth_token: Option<String>,
+    #[serde(default)]
+    pub broadcast_nodes: Vec<String>,
+    #[serde(default)]
+    pub safety: SafetyConfig,
+    #[serde(default)]
+    pub approval: Option<ApprovalPolicy>,
+    #[serde(default = "default_history_file")]
+    pub history_file: PathBuf,
+    #[serde(default)]
+    pub unlock: Option<UnlockConfig>,
+    #[serde(default)]
+    pub backup: BackupConfig,
+    // Chain parameter preset the keys belong to, checked when importing keys
+    #[serde(default = "default_network")]
+    pub network: String,
+}
+
+fn default_history_file() -> PathBuf {
+    PathBuf::from("wallet_history.cbor")
+}
+
+fn default_network() -> String {
+    "mainnet".to_string()
+}
+
+impl Config {
+    pub fn chain_params(&self) -> Result<ChainParams> {
+        ChainParams::preset(&self.network)
+            .ok_or_else(|| anyhow!("Unknown network {}", self.network))
+    }
+}
+
+// Sends above the threshold or leaving less than the floor must be confirmed
+// by retyping the amount, both given in satoshis
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SafetyConfig {
+    pub enabled: bool,
+    pub large_send_threshold: Amount,
+    pub balance_floor: Amount,
+}
+
+impl Default for SafetyConfig {
+    fn default() -> Self {
+        SafetyConfig {
+            enabled: true,
+            large_send_threshold: Amount::from_sat(100_000_000),
+            balance_floor: Amount::ZERO,
+        }
+    }
 }
@@ -0,0 +1,69 @@
+use anyhow::{anyhow, Result};
+use btclib::params::ChainParams;
+use btclib::sha256::Hash;
+use btclib::types::{Block, BlockHeader, BlockHeight};
+
+// Headers of the node's chain from the genesis block of the configured
+// network on, each linked to the previous one and meeting its target.
+// Retargeting is not replayed, a target is only held to the network minimum.
+pub struct HeaderChain {
+    params: ChainParams,
+    headers: Vec<BlockHeader>,
+}
+
+impl HeaderChain {
+    pub fn new(params: ChainParams) -> Self {
+        HeaderChain {
+            params,
+            headers: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.headers.len() as u64
+    }
+
+    pub fn get(&self, height: BlockHeight) -> Option<&BlockHeader> {
+        self.headers.get(height.index())
+    }
+
+    pub fn locator(&self) -> Vec<Hash> {
+        let Some(tip) = BlockHeight::tip_of(self.len()) else {
+            return Vec::new();
+        };
+        tip.locator_heights()
+            .into_iter()
+            .filter_map(|height| self.get(height).map(BlockHeader::hash))
+            .collect()
+    }
+
+    // Drops the headers above `height`, returns whether any were dropped
+    pub fn truncate_above(&mut self, height: BlockHeight) -> bool {
+        let keep = height.next().index();
+        let dropped = self.headers.len() > keep;
+        self.headers.truncate(keep);
+        dropped
+    }
+
+    pub fn extend(&mut self, headers: Vec<BlockHeader>) -> Result<()> {
+        for header in headers {
+            match self.headers.last() {
+                None if header.hash() != Block::genesis(&self.params).header.hash() => {
+                    return Err(anyhow!("Node is not on {}", self.params.name));
+                }
+                None => {}
+                Some(last) if header.prev_block_hash != last.hash() => {
+                    return Err(anyhow!("Header does not extend the previous one"));
+                }
+                Some(_) => {
+                    let target = header.target();
+                    if target > self.params.min_target || !header.hash().matches_target(target) {
+                        return Err(anyhow!("Header lacks proof of work"));
+                    }
+                }
+            }
+            self.headers.push(header);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,88 @@
+use anyhow::{anyhow, Result};
+use btclib::sha256::Hash;
+use btclib::types::Transaction;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HistoryEntry {
+    pub txid: Hash,
+    // CBOR encoded transaction as it was broadcast
+    pub raw: Vec<u8>,
+    pub timestamp: u64,
+}
+
+impl HistoryEntry {
+    pub fn new(transaction: &Transaction) -> Result<Self> {
+        let mut raw = Vec::new();
+        ciborium::into_writer(transaction, &mut raw)?;
+        Ok(HistoryEntry {
+            txid: transaction.hash(),
+            raw,
+            timestamp: unix_now(),
+        })
+    }
+
+    pub fn transaction(&self) -> Result<Transaction> {
+        Ok(ciborium::from_reader(self.raw.as_slice())?)
+    }
+
+    pub fn age(&self) -> String {
+        let secs = unix_now().saturating_sub(self.timestamp);
+        match secs {
+            0..=59 => format!("{}s ago", secs),
+            60..=3599 => format!("{}m ago", secs / 60),
+            3600..=86399 => format!("{}h ago", secs / 3600),
+            _ => format!("{}d ago", secs / 86400),
+        }
+    }
+}
+
+// Broadcast transactions, newest last, saved after every change
+pub struct History {
+    path: PathBuf,
+    entries: Vec<HistoryEntry>,
+}
+
+impl History {
+    pub fn load(path: &Path) -> Result<Self> {
+        let entries = if path.exists() {
+            ciborium::from_reader(fs::read(path)?.as_slice())?
+        } else {
+            Vec::new()
+        };
+        Ok(History {
+            path: path.to_path_buf(),
+            entries,
+        })
+    }
+
+    pub fn entries(&self) -> &[HistoryEntry] {
+        &self.entries
+    }
+
+    pub fn get(&self, txid: &Hash) -> Result<&HistoryEntry> {
+        self.entries
+            .iter()
+            .find(|entry| entry.txid == *txid)
+            .ok_or_else(|| anyhow!("Transaction {} is not in the history", txid))
+    }
+
+    pub fn record(&mut self, entry: HistoryEntry) -> Result<()> {
+        self.entries.retain(|known| known.txid != entry.txid);
+        self.entries.push(entry);
+        let mut bytes = Vec::new();
+        ciborium::into_writer(&self.entries, &mut bytes)?;
+        fs::write(&self.path, bytes)?;
+        Ok(())
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
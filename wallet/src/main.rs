@@ -1,12 +1,23 @@
+mod backup;
+mod connection;
 mod core;
+mod headers;
+mod history;
+mod payment;
+mod policy;
+mod profile;
 mod tasks;
 mod ui;
 mod utils;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use btclib::address::Address;
+use btclib::crypto::{self, KeyFile, PublicKey, Signature};
+use btclib::util::Saveable;
 use clap::{Parser, Subcommand};
 use core::Core;
 use cursive::views::TextContent;
+use std::fs;
 use std::path::PathBuf;
 use std::sync::Arc;
 use tasks::{handle_transactions, ui_task, update_balance, update_utxos};
@@ -33,6 +44,49 @@ enum Commands {
         #[arg(short, long, value_name = "FILE", default_value_os_t = PathBuf::from("wallet_config.toml"))]
         output: PathBuf,
     },
+    HashPassphrase {
+        passphrase: String,
+    },
+    // Moves the keys, contacts and history file of the config into the
+    // primary profile sealed under the wallet passphrase
+    SealProfile,
+    // Encrypted archive of the config, keys, contacts and history
+    Backup {
+        #[arg(short, long, value_name = "FILE")]
+        out: PathBuf,
+    },
+    Restore {
+        #[arg(value_name = "FILE")]
+        input: PathBuf,
+        #[arg(long)]
+        force: bool,
+    },
+    // Prints a private key file in wallet import format for the config's network
+    ExportWif {
+        #[arg(value_name = "FILE")]
+        private_key: PathBuf,
+    },
+    // Signs a message with a private key file to prove control of its address
+    SignMessage {
+        #[arg(value_name = "FILE")]
+        private_key: PathBuf,
+        message: String,
+    },
+    // Checks a signed message against the address it claims
+    VerifyMessage {
+        #[arg(long)]
+        address: Address,
+        #[arg(long)]
+        pubkey: PublicKey,
+        #[arg(long)]
+        signature: Signature,
+        message: String,
+    },
+    // Replaces a plaintext private key file with one sealed by a passphrase
+    EncryptKey {
+        #[arg(value_name = "FILE")]
+        private_key: PathBuf,
+    },
 }
 
 #[tokio::main]
@@ -46,6 +100,88 @@ async fn main() -> Result<()> {
             debug!("Generating dummy config at: {:?}", output);
             return generate_dummy_config(output);
         }
+        Some(Commands::HashPassphrase { passphrase }) => {
+            println!("{}", policy::hash_passphrase(passphrase));
+            return Ok(());
+        }
+        Some(Commands::SealProfile) => {
+            let mut config: core::Config = toml::from_str(&fs::read_to_string(&cli.config)?)?;
+            let passphrase = utils::prompt_passphrase()?;
+            if config.unlock.is_none() && passphrase != utils::prompt_passphrase()? {
+                return Err(anyhow!("Passphrases do not match"));
+            }
+            profile::seal_primary(&mut config, &passphrase)?;
+            fs::write(&cli.config, toml::to_string_pretty(&config)?)?;
+            println!("Sealed the primary profile in {}", cli.config.display());
+            return Ok(());
+        }
+        Some(Commands::Backup { out }) => {
+            let passphrase = utils::prompt_line("Backup passphrase")?;
+            if passphrase != utils::prompt_line("Repeat backup passphrase")? {
+                return Err(anyhow!("Passphrases do not match"));
+            }
+            let count = backup::backup(&cli.config, out, &passphrase, || {
+                utils::prompt_line("Wallet passphrase")
+            })?;
+            info!("Backed up {} files to {:?}", count, out);
+            println!("Backed up {} files to {}", count, out.display());
+            return Ok(());
+        }
+        Some(Commands::Restore { input, force }) => {
+            let passphrase = utils::prompt_line("Backup passphrase")?;
+            let restored = backup::restore(input, &passphrase, *force)?;
+            info!("Restored {} files from {:?}", restored.len(), input);
+            for path in restored {
+                println!("Restored {}", path.display());
+            }
+            return Ok(());
+        }
+        Some(Commands::ExportWif { private_key }) => {
+            let config: core::Config = toml::from_str(&fs::read_to_string(&cli.config)?)?;
+            let key = utils::load_private_key(private_key, &mut Vec::new())?;
+            println!("{}", key.to_wif(&config.chain_params()?));
+            return Ok(());
+        }
+        Some(Commands::SignMessage {
+            private_key,
+            message,
+        }) => {
+            let key = utils::load_private_key(private_key, &mut Vec::new())?;
+            let pubkey = key.public_key();
+            println!("Address: {}", Address::from_public_key(&pubkey));
+            println!("Public key: {}", pubkey);
+            println!("Signature: {}", crypto::sign_message(&key, message));
+            return Ok(());
+        }
+        Some(Commands::VerifyMessage {
+            address,
+            pubkey,
+            signature,
+            message,
+        }) => {
+            if Address::from_public_key(pubkey) != *address {
+                return Err(anyhow!("The public key does not belong to {}", address));
+            }
+            if !crypto::verify_message(pubkey, message, signature) {
+                return Err(anyhow!("The signature does not match the message"));
+            }
+            println!("Valid signature by {}", address);
+            return Ok(());
+        }
+        Some(Commands::EncryptKey { private_key }) => {
+            let key = match KeyFile::load_from_file(private_key)? {
+                KeyFile::Plain(key) => key,
+                KeyFile::Encrypted(_) => return Err(anyhow!("The key is already encrypted")),
+            };
+            let passphrase = utils::prompt_line("Key passphrase")?;
+            if passphrase != utils::prompt_line("Repeat key passphrase")? {
+                return Err(anyhow!("Passphrases do not match"));
+            }
+            KeyFile::Encrypted(key.encrypt(&passphrase)).save_to_file(private_key)?;
+            info!("Encrypted key {:?}", private_key);
+            println!("Encrypted {}", private_key.display());
+            return Ok(());
+        }
         None => (),
     }
     info!("Loading config from: {:?}", cli.config);
@@ -59,11 +195,12 @@ async fn main() -> Result<()> {
     let core = Arc::new(core);
     info!("Starting backgrounf tasks");
     let balance_content = TextContent::new(big_mode_btc(&core));
+    let status_content = TextContent::new(core.node_status());
     tokio::select! {
-        _ = ui_task(core.clone(), balance_content.clone()).await => (),
+        _ = ui_task(core.clone(), balance_content.clone(), status_content.clone()).await => (),
         _ = update_utxos(core.clone()).await => (),
         _ = handle_transactions(tx_receiver. clone_async(), core.clone()).await => (),
-        _ = update_balance(core.clone(), balance_content).await => ()
+        _ = update_balance(core.clone(), balance_content, status_content).await => ()
     }
     info!("Application Shutdown!");
     Ok(())
@@ -0,0 +1,68 @@
+use anyhow::{anyhow, Result};
+use btclib::amount::Amount;
+use btclib::crypto::PublicKey;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const REQUEST_PREFIX: &str = "btcreq:";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PaymentRequest {
+    pub amount: Amount,
+    pub address: PublicKey,
+    pub memo: String,
+    pub expiry: Option<u64>,
+}
+
+impl PaymentRequest {
+    pub fn new(
+        amount: Amount,
+        address: PublicKey,
+        memo: String,
+        valid_for_secs: Option<u64>,
+    ) -> Self {
+        PaymentRequest {
+            amount,
+            address,
+            memo,
+            expiry: valid_for_secs.map(|secs| unix_now() + secs),
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expiry.is_some_and(|expiry| unix_now() > expiry)
+    }
+
+    pub fn encode(&self) -> Result<String> {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(self, &mut bytes)?;
+        Ok(format!("{}{}", REQUEST_PREFIX, hex::encode(bytes)))
+    }
+
+    pub fn decode(s: &str) -> Result<Self> {
+        let payload = s
+            .trim()
+            .strip_prefix(REQUEST_PREFIX)
+            .ok_or_else(|| anyhow!("Not a payment request"))?;
+        let bytes = hex::decode(payload)?;
+        Ok(ciborium::from_reader(bytes.as_slice())?)
+    }
+
+    pub fn save_to_file<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        fs::write(path, self.encode()?)?;
+        Ok(())
+    }
+
+    pub fn load_from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::decode(&fs::read_to_string(path)?)
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
@@ -0,0 +1,109 @@
+use anyhow::{anyhow, Result};
+use btclib::amount::Amount;
+use btclib::crypto::{PublicKey, Sealed};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Spends above the threshold need the passphrase, stored as produced by
+// `wallet hash-passphrase`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ApprovalPolicy {
+    pub threshold: Amount,
+    pub passphrase_hash: String,
+}
+
+impl ApprovalPolicy {
+    pub fn requires_approval(&self, amount: Amount) -> bool {
+        amount > self.threshold
+    }
+
+    pub fn check(&self, amount: Amount, passphrase: Option<&str>) -> Result<()> {
+        if !self.requires_approval(amount) {
+            return Ok(());
+        }
+        match passphrase {
+            Some(passphrase) if verify_passphrase(passphrase, &self.passphrase_hash) => Ok(()),
+            Some(_) => Err(anyhow!("Wrong passphrase")),
+            None => Err(anyhow!(
+                "Sends above {} need the passphrase",
+                self.threshold
+            )),
+        }
+    }
+}
+
+// Nothing sealed under the passphrase, so only that passphrase opens it. The
+// salt and Argon2id costs travel in the hex encoded result.
+pub fn hash_passphrase(passphrase: &str) -> String {
+    let mut bytes = Vec::new();
+    ciborium::into_writer(&Sealed::seal(passphrase, &[]), &mut bytes).expect("Bug: Impossible");
+    hex::encode(bytes)
+}
+
+pub fn verify_passphrase(passphrase: &str, hash: &str) -> bool {
+    hex::decode(hash)
+        .ok()
+        .and_then(|bytes| ciborium::from_reader::<Sealed, _>(bytes.as_slice()).ok())
+        .is_some_and(|sealed| sealed.open(passphrase).is_ok())
+}
+
+// Amounts spent per key on the current UTC day, kept in memory only
+#[derive(Default)]
+pub struct SpendingTracker {
+    spent: BTreeMap<PublicKey, (u64, Amount)>,
+}
+
+impl SpendingTracker {
+    pub fn spent_today(&self, key: &PublicKey) -> Amount {
+        match self.spent.get(key) {
+            Some((day, amount)) if *day == today() => *amount,
+            _ => Amount::ZERO,
+        }
+    }
+
+    pub fn record(&mut self, key: &PublicKey, amount: Amount) {
+        let spent = self
+            .spent_today(key)
+            .checked_add(amount)
+            .unwrap_or(Amount::from_sat(u64::MAX));
+        self.spent.insert(key.clone(), (today(), spent));
+    }
+}
+
+fn today() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() / (24 * 60 * 60))
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passphrase_hash_is_salted_and_verifies() {
+        let hash = hash_passphrase("correct horse");
+        assert_ne!(hash, hash_passphrase("correct horse"));
+        assert!(verify_passphrase("correct horse", &hash));
+        assert!(!verify_passphrase("battery staple", &hash));
+        assert!(!verify_passphrase("correct horse", "not hex"));
+    }
+
+    #[test]
+    fn approval_needs_passphrase_above_threshold() {
+        let policy = ApprovalPolicy {
+            threshold: Amount::from_sat(1_000),
+            passphrase_hash: hash_passphrase("correct horse"),
+        };
+        assert!(policy.check(Amount::from_sat(1_000), None).is_ok());
+        assert!(policy.check(Amount::from_sat(1_001), None).is_err());
+        assert!(policy
+            .check(Amount::from_sat(1_001), Some("battery staple"))
+            .is_err());
+        assert!(policy
+            .check(Amount::from_sat(1_001), Some("correct horse"))
+            .is_ok());
+    }
+}
@@ -0,0 +1,157 @@
+use crate::core::{Config, Key, Recipient};
+use crate::policy::verify_passphrase;
+use anyhow::{anyhow, Result};
+use btclib::crypto::Sealed;
+use btclib::error::BtcError;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+// Opening the wallet needs the passphrase, which the primary profile is
+// sealed under by `wallet seal-profile`, so the config does not tell which
+// keys it holds. A second passphrase opens the decoy instead, hashed with
+// `wallet hash-passphrase`.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct UnlockConfig {
+    // Hex encoded, see PrimaryProfile::seal
+    pub primary: String,
+    #[serde(default)]
+    pub decoy: Option<DecoyProfile>,
+}
+
+// Keys, contacts and history shown in place of the primary ones. Its keys
+// should hold only funds the owner can afford to hand over.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DecoyProfile {
+    pub passphrase_hash: String,
+    pub my_keys: Vec<Key>,
+    #[serde(default)]
+    pub contacts: Vec<Recipient>,
+    pub history_file: PathBuf,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PrimaryProfile {
+    pub my_keys: Vec<Key>,
+    pub contacts: Vec<Recipient>,
+    pub history_file: PathBuf,
+}
+
+impl PrimaryProfile {
+    pub fn seal(&self, passphrase: &str) -> Result<String> {
+        let mut plaintext = Vec::new();
+        ciborium::into_writer(self, &mut plaintext)?;
+        let mut bytes = Vec::new();
+        ciborium::into_writer(&Sealed::seal(passphrase, &plaintext), &mut bytes)?;
+        Ok(hex::encode(bytes))
+    }
+
+    // None if the passphrase is not the one it was sealed under
+    pub fn open(sealed: &str, passphrase: &str) -> Result<Option<Self>> {
+        let sealed: Sealed = ciborium::from_reader(hex::decode(sealed)?.as_slice())?;
+        match sealed.open(passphrase) {
+            Ok(plaintext) => Ok(Some(ciborium::from_reader(plaintext.as_slice())?)),
+            Err(BtcError::WrongPassphrase) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+// Moves the plaintext keys and contacts of a config into the sealed primary
+// profile, creating it with the plaintext history file on first use. The
+// plaintext fields are left empty.
+pub fn seal_primary(config: &mut Config, passphrase: &str) -> Result<()> {
+    let mut primary = match &config.unlock {
+        Some(unlock) => PrimaryProfile::open(&unlock.primary, passphrase)?
+            .ok_or_else(|| anyhow!("Wrong passphrase"))?,
+        None => PrimaryProfile {
+            my_keys: Vec::new(),
+            contacts: Vec::new(),
+            history_file: config.history_file.clone(),
+        },
+    };
+    primary.my_keys.append(&mut config.my_keys);
+    primary.contacts.append(&mut config.contacts);
+    let sealed = primary.seal(passphrase)?;
+    match &mut config.unlock {
+        Some(unlock) => unlock.primary = sealed,
+        None => {
+            config.unlock = Some(UnlockConfig {
+                primary: sealed,
+                decoy: None,
+            })
+        }
+    }
+    config.history_file = PathBuf::new();
+    Ok(())
+}
+
+// Narrows the config to the profile the passphrase opens. The result never
+// mentions the other profile, so its key and history files are not read.
+// Deliberately not logged, the log would tell which profile was opened.
+pub fn unlock(mut config: Config, prompt: impl FnOnce() -> Result<String>) -> Result<Config> {
+    let Some(unlock) = config.unlock.take() else {
+        return Ok(config);
+    };
+    let passphrase = prompt()?;
+    if let Some(primary) = PrimaryProfile::open(&unlock.primary, &passphrase)? {
+        return Ok(Config {
+            my_keys: primary.my_keys,
+            contacts: primary.contacts,
+            history_file: primary.history_file,
+            ..config
+        });
+    }
+    match unlock.decoy {
+        Some(decoy) if verify_passphrase(&passphrase, &decoy.passphrase_hash) => Ok(Config {
+            my_keys: decoy.my_keys,
+            contacts: decoy.contacts,
+            history_file: decoy.history_file,
+            approval: None,
+            ..config
+        }),
+        _ => Err(anyhow!("Wrong passphrase")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy::hash_passphrase;
+
+    fn config() -> Config {
+        toml::from_str(
+            r#"
+            default_node = "127.0.0.1:9000"
+            contacts = []
+            history_file = "primary_history.cbor"
+            fee_config = { fee_type = "Fixed", value = 0.0 }
+            my_keys = [{ public = "primary.pub.pem", private = "primary.priv.pem" }]
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn sealed_primary_opens_only_with_its_passphrase() {
+        let mut config = config();
+        seal_primary(&mut config, "primary").unwrap();
+        assert!(config.my_keys.is_empty());
+        assert_eq!(config.history_file, PathBuf::new());
+        config.unlock.as_mut().unwrap().decoy = Some(DecoyProfile {
+            passphrase_hash: hash_passphrase("decoy"),
+            my_keys: Vec::new(),
+            contacts: Vec::new(),
+            history_file: PathBuf::from("decoy_history.cbor"),
+        });
+
+        let primary = unlock(config.clone(), || Ok("primary".to_string())).unwrap();
+        assert_eq!(primary.my_keys.len(), 1);
+        assert_eq!(primary.history_file, PathBuf::from("primary_history.cbor"));
+
+        let decoy = unlock(config.clone(), || Ok("decoy".to_string())).unwrap();
+        assert!(decoy.my_keys.is_empty());
+        assert_eq!(decoy.history_file, PathBuf::from("decoy_history.cbor"));
+
+        assert!(unlock(config, || Ok("guess".to_string())).is_err());
+    }
+}
@@ -1,7 +1,9 @@
-use crate::core::Core;
+use crate::connection;
+use crate::core::{Core, Outgoing};
 use crate::ui::run_ui;
 use crate::utils::big_mode_btc;
-use btclib::types::Transaction;
+use anyhow::{anyhow, Result};
+use btclib::network::Message;
 use cursive::views::TextContent;
 use std::sync::Arc;
 use tokio::task::JoinHandle;
@@ -10,6 +12,9 @@ use tracing::*;
 
 pub async fn update_utxos(core: Arc<Core>) -> JoinHandle<()> {
     tokio::spawn(async move {
+        if let Err(e) = follow_tip(&core).await {
+            warn!("Tip subscription ended, polling for UTXOs instead: {}", e);
+        }
         let mut interval = time::interval(Duration::from_secs(20));
         loop {
             interval.tick().await;
@@ -20,34 +25,71 @@ pub async fn update_utxos(core: Arc<Core>) -> JoinHandle<()> {
     })
 }
 
+// Refreshes UTXOs whenever the node connects a new block
+async fn follow_tip(core: &Core) -> Result<()> {
+    let mut stream =
+        connection::connect(&core.config.default_node, core.config.auth_token.as_deref()).await?;
+    connection::subscribe_tip(&mut stream).await?;
+    loop {
+        match Message::receive_async(&mut stream).await? {
+            Message::TipChanged { height, .. } => {
+                info!("New tip at height {}, updating UTXOs", height);
+                if let Err(e) = core.fetch_utxos().await {
+                    error!("Failed to update UTXOs: {}", e);
+                }
+            }
+            _ => return Err(anyhow!("Unexpected message on tip subscription")),
+        }
+    }
+}
+
 pub async fn handle_transactions(
-    rx: kanal::AsyncReceiver<Transaction>,
+    rx: kanal::AsyncReceiver<Outgoing>,
     core: Arc<Core>,
 ) -> JoinHandle<()> {
     tokio::spawn(async move {
-        while let Ok(transaction) = rx.recv().await {
-            if let Err(e) = core.send_transaction(transaction).await {
+        while let Ok(outgoing) = rx.recv().await {
+            let result = match outgoing {
+                Outgoing::Send {
+                    transaction,
+                    passphrase,
+                } => {
+                    core.send_transaction(transaction, passphrase.as_deref())
+                        .await
+                }
+                Outgoing::Rebroadcast(transaction) => core.broadcast(&transaction).await,
+            };
+            if let Err(e) = result {
                 error!("Failed to send transaction: {}", e);
             }
         }
     })
 }
 
-pub async fn ui_task(core: Arc<Core>, balance_content: TextContent) -> JoinHandle<()> {
+pub async fn ui_task(
+    core: Arc<Core>,
+    balance_content: TextContent,
+    status_content: TextContent,
+) -> JoinHandle<()> {
     tokio::task::spawn_blocking(move || {
         info!("Running UI");
-        if let Err(e) = run_ui(core, balance_content) {
+        if let Err(e) = run_ui(core, balance_content, status_content) {
             eprintln!("UI ends with error: {e}");
         };
     })
 }
 
-pub async fn update_balance(core: Arc<Core>, balance_content: TextContent) -> JoinHandle<()> {
+pub async fn update_balance(
+    core: Arc<Core>,
+    balance_content: TextContent,
+    status_content: TextContent,
+) -> JoinHandle<()> {
     tokio::spawn(async move {
         loop {
             tokio::time::sleep(Duration::from_millis(500)).await;
             info!("updating balance string");
             balance_content.set_content(big_mode_btc(&core));
+            status_content.set_content(core.node_status());
         }
     })
 }
@@ -1,14 +1,22 @@
+use crate::backup;
 use crate::core::Core;
+use crate::payment::PaymentRequest;
+use crate::utils::fuzzy_score;
 use anyhow::Result;
+use btclib::amount::Amount;
+use btclib::sha256::Hash;
 use cursive::event::{Event, Key};
 use cursive::traits::*;
 use cursive::views::{
-    Button, Dialog, EditView, LinearLayout, Panel, ResizedView, TextContent, TextView,
+    Button, Dialog, EditView, LinearLayout, OnEventView, Panel, ResizedView, SelectView,
+    TextContent, TextView,
 };
 use cursive::Cursive;
 use std::sync::{Arc, Mutex};
 use tracing::*;
 
+const PAYMENT_REQUEST_FILE: &str = "payment_request.txt";
+
 #[derive(Clone, Copy)]
 enum Unit {
     Btc,
@@ -23,16 +31,25 @@ fn convert_amount(amount: f64, from: Unit, to: Unit) -> f64 {
     }
 }
 
-pub fn run_ui(core: Arc<Core>, balance_content: TextContent) -> Result<()> {
+pub fn run_ui(
+    core: Arc<Core>,
+    balance_content: TextContent,
+    status_content: TextContent,
+) -> Result<()> {
     let mut siv = cursive::default();
-    setup_siv(&mut siv, core.clone(), balance_content);
+    setup_siv(&mut siv, core.clone(), balance_content, status_content);
     info!("Starting UI event loop");
     siv.run();
     info!("Ui event loop ended");
     Ok(())
 }
 
-fn setup_siv(siv: &mut Cursive, core: Arc<Core>, balance_content: TextContent) {
+fn setup_siv(
+    siv: &mut Cursive,
+    core: Arc<Core>,
+    balance_content: TextContent,
+    status_content: TextContent,
+) {
     siv.set_autorefresh(true);
     siv.set_window_title("BTC Wallet".to_string());
     siv.add_global_callback('q', |s| {
@@ -40,26 +57,61 @@ fn setup_siv(siv: &mut Cursive, core: Arc<Core>, balance_content: TextContent) {
         s.quit()
     });
     setup_menubar(siv, core.clone());
-    setup_layout(siv, core, balance_content);
+    let reminder = backup::reminder(&core.config.backup);
+    setup_layout(siv, core, balance_content, status_content);
+    if let Some(reminder) = reminder {
+        siv.add_layer(
+            Dialog::text(format!("{} Run `wallet backup --out <file>`.", reminder))
+                .title("Backup")
+                .dismiss_button("OK"),
+        );
+    }
     siv.add_global_callback(Event::Key(Key::Esc), |siv| siv.select_menubar());
     siv.select_menubar()
 }
 
 fn setup_menubar(siv: &mut Cursive, core: Arc<Core>) {
+    let receive_core = core.clone();
+    let history_core = core.clone();
+    let import_core = core.clone();
+    let export_core = core.clone();
+    let sign_core = core.clone();
     siv.menubar()
         .add_leaf("Send", move |s| {
             show_send_transaction(s, core.clone());
         })
+        .add_leaf("Receive", move |s| {
+            show_receive(s, receive_core.clone());
+        })
+        .add_leaf("History", move |s| {
+            show_history(s, history_core.clone());
+        })
+        .add_leaf("Import Key", move |s| {
+            show_import_key(s, import_core.clone());
+        })
+        .add_leaf("Export Key", move |s| {
+            show_export_key(s, export_core.clone());
+        })
+        .add_leaf("Sign Message", move |s| {
+            show_sign_message(s, sign_core.clone());
+        })
         .add_leaf("Quit", |s| s.quit());
     siv.set_autohide_menu(false)
 }
 
-fn setup_layout(siv: &mut Cursive, core: Arc<Core>, balance_content: TextContent) {
+fn setup_layout(
+    siv: &mut Cursive,
+    core: Arc<Core>,
+    balance_content: TextContent,
+    status_content: TextContent,
+) {
     let instruction = TextView::new("Press escape to select the top menu");
+    let status = TextView::new_with_content(status_content);
     let balance_panel = Panel::new(TextView::new_with_content(balance_content)).title("Balance");
     let info_layout = create_info_layout(&core);
     let layout = LinearLayout::vertical()
         .child(instruction)
+        .child(status)
         .child(balance_panel)
         .child(info_layout);
     siv.add_layer(layout);
@@ -71,7 +123,8 @@ fn create_info_layout(core: &Arc<Core>) -> LinearLayout {
         .config
         .my_keys
         .iter()
-        .map(|key| format!("{}", key.private.display()))
+        .zip(core.key_fingerprints())
+        .map(|(key, fingerprint)| format!("{}  {}", fingerprint, key.private.display()))
         .collect::<Vec<String>>()
         .join("\n");
     info_layout.add_child(ResizedView::with_full_width(
@@ -81,7 +134,10 @@ fn create_info_layout(core: &Arc<Core>) -> LinearLayout {
         .config
         .contacts
         .iter()
-        .map(|contact| contact.name.clone())
+        .map(|contact| match contact.load() {
+            Ok(loaded) => format!("{}  {}", loaded.key.fingerprint(), contact.name),
+            Err(_) => format!("????????  {}", contact.name),
+        })
         .collect::<Vec<String>>()
         .join("\n");
     info_layout.add_child(ResizedView::with_full_width(
@@ -94,7 +150,7 @@ fn show_send_transaction(s: &mut Cursive, core: Arc<Core>) {
     info!("Showing send transaction dialog");
     let unit = Arc::new(Mutex::new(Unit::Btc));
     s.add_layer(
-        Dialog::around(create_transaction_layout(unit.clone()))
+        Dialog::around(create_transaction_layout(core.clone(), unit.clone()))
             .title("Send Transactiomn")
             .button("Send", move |siv| {
                 send_transaction(siv, core.clone(), *unit.lock().unwrap())
@@ -106,10 +162,33 @@ fn show_send_transaction(s: &mut Cursive, core: Arc<Core>) {
     );
 }
 
-fn create_transaction_layout(unit: Arc<Mutex<Unit>>) -> LinearLayout {
+fn create_transaction_layout(core: Arc<Core>, unit: Arc<Mutex<Unit>>) -> LinearLayout {
+    let request_unit = unit.clone();
+    let suggestion_core = core.clone();
     LinearLayout::vertical()
-        .child(TextView::new("Recipient:"))
-        .child(EditView::new().with_name("recipient"))
+        .child(TextView::new("Payment request (string or file):"))
+        .child(
+            LinearLayout::horizontal()
+                .child(EditView::new().with_name("payment_request").full_width())
+                .child(Button::new("Load", move |s| {
+                    load_payment_request(s, core.clone(), *request_unit.lock().unwrap());
+                })),
+        )
+        .child(TextView::new("Recipient or address (Tab to complete):"))
+        .child(
+            OnEventView::new(
+                EditView::new()
+                    .on_edit(move |s, text, _| update_suggestions(s, &suggestion_core, text))
+                    .with_name("recipient"),
+            )
+            .on_pre_event(Key::Tab, complete_recipient),
+        )
+        .child(
+            SelectView::<String>::new()
+                .on_submit(|s, name: &String| set_recipient(s, name))
+                .with_name("recipient_suggestions")
+                .fixed_height(3),
+        )
         .child(TextView::new("Amount:"))
         .child(EditView::new().with_name("amount"))
         .child(create_unit_layout(unit))
@@ -124,6 +203,272 @@ fn create_unit_layout(unit: Arc<Mutex<Unit>>) -> LinearLayout {
         }))
 }
 
+fn update_suggestions(s: &mut Cursive, core: &Core, text: &str) {
+    let mut matches = core
+        .recipient_candidates()
+        .into_iter()
+        .filter_map(|name| fuzzy_score(text, &name).map(|score| (score, name)))
+        .collect::<Vec<_>>();
+    matches.sort();
+    s.call_on_name("recipient_suggestions", |view: &mut SelectView<String>| {
+        view.clear();
+        if !text.is_empty() {
+            view.add_all_str(matches.into_iter().map(|(_, name)| name));
+        }
+    });
+}
+
+fn complete_recipient(s: &mut Cursive) {
+    let first = s
+        .call_on_name("recipient_suggestions", |view: &mut SelectView<String>| {
+            view.get_item(0).map(|(_, name)| name.clone())
+        })
+        .flatten();
+    if let Some(name) = first {
+        set_recipient(s, &name);
+    }
+}
+
+fn set_recipient(s: &mut Cursive, name: &str) {
+    s.call_on_name("recipient", |view: &mut EditView| {
+        view.set_content(name);
+    });
+    s.call_on_name("recipient_suggestions", |view: &mut SelectView<String>| {
+        view.clear();
+    });
+}
+
+fn load_payment_request(s: &mut Cursive, core: Arc<Core>, unit: Unit) {
+    let input = s
+        .call_on_name("payment_request", |view: &mut EditView| view.get_content())
+        .unwrap();
+    let request =
+        PaymentRequest::decode(&input).or_else(|_| PaymentRequest::load_from_file(input.trim()));
+    let request = match request {
+        Ok(request) if request.is_expired() => {
+            s.add_layer(Dialog::info("Payment request has expired").title("Error"));
+            return;
+        }
+        Ok(request) => request,
+        Err(e) => {
+            error!("Failed to load payment request: {}", e);
+            s.add_layer(Dialog::info(format!("Invalid payment request: {}", e)).title("Error"));
+            return;
+        }
+    };
+    info!(
+        "Loaded payment request for {}",
+        request.amount.display_sat()
+    );
+    let recipient = core
+        .contact_name_for(&request.address)
+        .unwrap_or_else(|| input.trim().to_string());
+    let amount = convert_amount(request.amount.to_sat() as f64, Unit::Sats, unit);
+    s.call_on_name("recipient", |view: &mut EditView| {
+        view.set_content(recipient);
+    });
+    s.call_on_name("amount", |view: &mut EditView| {
+        view.set_content(amount.to_string());
+    });
+    if !request.memo.is_empty() {
+        s.add_layer(Dialog::info(format!("Memo: {}", request.memo)).title("Payment request"));
+    }
+}
+
+fn show_receive(s: &mut Cursive, core: Arc<Core>) {
+    info!("Showing receive dialog");
+    let address = match core.address() {
+        Ok(address) => address.to_string(),
+        Err(e) => e.to_string(),
+    };
+    s.add_layer(
+        Dialog::around(
+            LinearLayout::vertical()
+                .child(TextView::new(format!("Your address: {}", address)))
+                .child(TextView::new("Amount (BTC):"))
+                .child(EditView::new().with_name("request_amount"))
+                .child(TextView::new("Memo:"))
+                .child(EditView::new().with_name("request_memo"))
+                .child(TextView::new("Expires in minutes (empty for never):"))
+                .child(EditView::new().with_name("request_expiry")),
+        )
+        .title("Request Payment")
+        .button("Generate", move |siv| {
+            generate_payment_request(siv, core.clone())
+        })
+        .button("Cancel", |siv| {
+            siv.pop_layer();
+        }),
+    );
+}
+
+fn show_import_key(s: &mut Cursive, core: Arc<Core>) {
+    info!("Showing import key dialog");
+    s.add_layer(
+        Dialog::around(
+            LinearLayout::vertical()
+                .child(TextView::new(format!(
+                    "Private key (WIF, {}):",
+                    core.config.network
+                )))
+                .child(EditView::new().secret().with_name("import_wif"))
+                .child(TextView::new("Name for the key files:"))
+                .child(EditView::new().with_name("import_name")),
+        )
+        .title("Import Key")
+        .button("Import", move |siv| {
+            let wif = siv
+                .call_on_name("import_wif", |view: &mut EditView| view.get_content())
+                .unwrap();
+            let name = siv
+                .call_on_name("import_name", |view: &mut EditView| view.get_content())
+                .unwrap();
+            let message = match core.import_wif(&wif, name.trim()) {
+                Ok(address) => {
+                    format!("Imported {}, restart the wallet to spend from it", address)
+                }
+                Err(e) => {
+                    error!("Failed to import key: {}", e);
+                    format!("Import failed: {}", e)
+                }
+            };
+            siv.pop_layer();
+            siv.add_layer(Dialog::info(message).title("Import Key"));
+        })
+        .button("Cancel", |siv| {
+            siv.pop_layer();
+        }),
+    );
+}
+
+fn show_export_key(s: &mut Cursive, core: Arc<Core>) {
+    info!("Showing export key dialog");
+    let mut keys = SelectView::<usize>::new();
+    for (index, (key, fingerprint)) in core
+        .config
+        .my_keys
+        .iter()
+        .zip(core.key_fingerprints())
+        .enumerate()
+    {
+        keys.add_item(format!("{}  {}", fingerprint, key.private.display()), index);
+    }
+    keys.set_on_submit(move |siv, index: &usize| {
+        let message = match core.export_wif(*index) {
+            Ok(wif) => format!("Anyone who sees this key can spend its coins:\n\n{}", wif),
+            Err(e) => {
+                error!("Failed to export key: {}", e);
+                format!("Export failed: {}", e)
+            }
+        };
+        siv.pop_layer();
+        siv.add_layer(Dialog::info(message).title("Export Key"));
+    });
+    s.add_layer(
+        Dialog::around(keys)
+            .title("Export Key")
+            .button("Cancel", |siv| {
+                siv.pop_layer();
+            }),
+    );
+}
+
+fn show_sign_message(s: &mut Cursive, core: Arc<Core>) {
+    info!("Showing sign message dialog");
+    let mut keys = SelectView::<usize>::new().popup();
+    for (index, (key, fingerprint)) in core
+        .config
+        .my_keys
+        .iter()
+        .zip(core.key_fingerprints())
+        .enumerate()
+    {
+        keys.add_item(format!("{}  {}", fingerprint, key.private.display()), index);
+    }
+    s.add_layer(
+        Dialog::around(
+            LinearLayout::vertical()
+                .child(TextView::new("Key:"))
+                .child(keys.with_name("sign_key"))
+                .child(TextView::new("Message:"))
+                .child(EditView::new().with_name("sign_message")),
+        )
+        .title("Sign Message")
+        .button("Sign", move |siv| {
+            let index = siv
+                .call_on_name("sign_key", |view: &mut SelectView<usize>| view.selection())
+                .unwrap();
+            let message = siv
+                .call_on_name("sign_message", |view: &mut EditView| view.get_content())
+                .unwrap();
+            let Some(index) = index else {
+                siv.add_layer(Dialog::info("The wallet has no keys").title("Sign Message"));
+                return;
+            };
+            let text = match core.sign_message(*index, &message) {
+                Ok((address, pubkey, signature)) => format!(
+                    "Address: {}\nPublic key: {}\nSignature: {}",
+                    address, pubkey, signature
+                ),
+                Err(e) => {
+                    error!("Failed to sign message: {}", e);
+                    format!("Signing failed: {}", e)
+                }
+            };
+            siv.pop_layer();
+            siv.add_layer(Dialog::info(text).title("Signed Message"));
+        })
+        .button("Cancel", |siv| {
+            siv.pop_layer();
+        }),
+    );
+}
+
+fn generate_payment_request(s: &mut Cursive, core: Arc<Core>) {
+    let amount: f64 = s
+        .call_on_name("request_amount", |view: &mut EditView| view.get_content())
+        .unwrap()
+        .parse()
+        .unwrap_or(0.0);
+    let memo = s
+        .call_on_name("request_memo", |view: &mut EditView| view.get_content())
+        .unwrap()
+        .to_string();
+    let valid_for_secs = s
+        .call_on_name("request_expiry", |view: &mut EditView| view.get_content())
+        .unwrap()
+        .parse::<u64>()
+        .ok()
+        .map(|minutes| minutes * 60);
+    let amount = Amount::from_sat(convert_amount(amount, Unit::Btc, Unit::Sats) as u64);
+    let encoded = core
+        .create_payment_request(amount, memo, valid_for_secs)
+        .and_then(|request| {
+            request.save_to_file(PAYMENT_REQUEST_FILE)?;
+            request.encode()
+        });
+    match encoded {
+        Ok(encoded) => {
+            info!("Generated payment request for {}", amount.display_sat());
+            s.pop_layer();
+            s.add_layer(
+                Dialog::around(TextView::new(format!(
+                    "{}\n\nSaved to {}",
+                    encoded, PAYMENT_REQUEST_FILE
+                )))
+                .title("Payment request")
+                .button("Ok", |s| {
+                    s.pop_layer();
+                }),
+            );
+        }
+        Err(e) => {
+            error!("Failed to generate payment request: {}", e);
+            s.add_layer(Dialog::info(format!("Failed to generate request: {}", e)).title("Error"));
+        }
+    }
+}
+
 fn switch_unit(s: &mut Cursive, unit: Arc<Mutex<Unit>>) {
     let mut unit = unit.lock().unwrap();
     *unit = match *unit {
@@ -149,21 +494,100 @@ fn send_transaction(s: &mut Cursive, core: Arc<Core>, unit: Unit) {
         .unwrap()
         .parse()
         .unwrap_or(0.0);
-    let amount_sats = convert_amount(amount, unit, Unit::Sats) as u64;
+    let amount = Amount::from_sat(convert_amount(amount, unit, Unit::Sats) as u64);
+    match core.send_confirmation_reason(amount) {
+        Some(reason) => confirm_send(s, core, recipient.to_string(), amount, unit, reason),
+        None => submit_send(s, &core, &recipient, amount),
+    }
+}
+
+// Guards against fat-finger sends by asking to retype the amount
+fn confirm_send(
+    s: &mut Cursive,
+    core: Arc<Core>,
+    recipient: String,
+    amount: Amount,
+    unit: Unit,
+    reason: String,
+) {
+    info!("Send of {} needs confirmation", amount.display_sat());
+    s.add_layer(
+        Dialog::around(
+            LinearLayout::vertical()
+                .child(TextView::new(reason))
+                .child(TextView::new("Retype the amount to confirm:"))
+                .child(EditView::new().with_name("confirm_amount")),
+        )
+        .title("Confirm send")
+        .button("Confirm", move |s| {
+            let retyped = s
+                .call_on_name("confirm_amount", |view: &mut EditView| view.get_content())
+                .unwrap()
+                .parse::<f64>()
+                .map(|retyped| Amount::from_sat(convert_amount(retyped, unit, Unit::Sats) as u64));
+            s.pop_layer();
+            if retyped == Ok(amount) {
+                submit_send(s, &core, &recipient, amount);
+            } else {
+                show_error_dialog(s, "the retyped amount does not match");
+            }
+        })
+        .button("Cancel", |s| {
+            s.pop_layer();
+        }),
+    );
+}
+
+fn submit_send(s: &mut Cursive, core: &Arc<Core>, recipient: &str, amount: Amount) {
     info!(
-        "Attempting to send transaction to {} for {} satoshis",
-        recipient, amount_sats
+        "Attempting to send transaction to {} for {}",
+        recipient,
+        amount.display_sat()
     );
-    match core.send_transaction_async(recipient.as_str(), amount_sats) {
-        Ok(_) => show_success_dialog(s),
+    if core.requires_approval(amount) {
+        approve_send(s, core, recipient, amount);
+        return;
+    }
+    match core.send_transaction_async(recipient, amount, None) {
+        Ok(txid) => show_success_dialog(s, txid),
         Err(e) => show_error_dialog(s, e),
     }
 }
 
-fn show_success_dialog(s: &mut Cursive) {
-    info!("Transaction sent successfully");
+// Sends over the approval threshold go through only with the passphrase
+fn approve_send(s: &mut Cursive, core: &Arc<Core>, recipient: &str, amount: Amount) {
+    let core = core.clone();
+    let recipient = recipient.to_string();
     s.add_layer(
-        Dialog::text("Transaction sent successfull")
+        Dialog::around(
+            LinearLayout::vertical()
+                .child(TextView::new(format!(
+                    "Enter the passphrase to send {}:",
+                    amount
+                )))
+                .child(EditView::new().secret().with_name("passphrase")),
+        )
+        .title("Approve send")
+        .button("Send", move |s| {
+            let passphrase = s
+                .call_on_name("passphrase", |view: &mut EditView| view.get_content())
+                .unwrap();
+            s.pop_layer();
+            match core.send_transaction_async(&recipient, amount, Some(&passphrase)) {
+                Ok(txid) => show_success_dialog(s, txid),
+                Err(e) => show_error_dialog(s, e),
+            }
+        })
+        .button("Cancel", |s| {
+            s.pop_layer();
+        }),
+    );
+}
+
+fn show_success_dialog(s: &mut Cursive, txid: Hash) {
+    info!("Transaction {} sent successfully", txid);
+    s.add_layer(
+        Dialog::text(format!("Transaction sent successfull\n\ntxid: {}", txid))
             .title("Success")
             .button("Ok", |s| {
                 debug!("Closing success dialog");
@@ -173,6 +597,41 @@ fn show_success_dialog(s: &mut Cursive) {
     );
 }
 
+// Sent transactions, newest first, selecting one broadcasts it again
+fn show_history(s: &mut Cursive, core: Arc<Core>) {
+    info!("Showing transaction history");
+    let history = core.history();
+    if history.is_empty() {
+        s.add_layer(Dialog::info("No transactions sent yet").title("History"));
+        return;
+    }
+    let mut select = SelectView::<Hash>::new();
+    for entry in history.iter().rev() {
+        let status = match core.confirmations(&entry.txid) {
+            Some(confirmations) => format!("{} confirmations", confirmations),
+            None => "unconfirmed".to_string(),
+        };
+        select.add_item(
+            format!("{}  {}  {}", entry.age(), status, entry.txid),
+            entry.txid,
+        );
+    }
+    select.set_on_submit(move |s, txid: &Hash| {
+        let message = match core.rebroadcast_async(txid) {
+            Ok(()) => format!("Rebroadcasting {}", txid),
+            Err(e) => format!("Failed to rebroadcast: {}", e),
+        };
+        s.add_layer(Dialog::info(message).title("Rebroadcast"));
+    });
+    s.add_layer(
+        Dialog::around(select.scrollable().max_height(15))
+            .title("History - select to rebroadcast")
+            .button("Close", |s| {
+                s.pop_layer();
+            }),
+    );
+}
+
 fn show_error_dialog(s: &mut Cursive, error: impl std::fmt::Display) {
     error!("Failed to send transaction {}", error);
 
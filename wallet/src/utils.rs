@@ -1,8 +1,13 @@
-use crate::core::{Config, Core, FeeConfig, FeeType, Recipient};
+use crate::backup::BackupConfig;
+use crate::core::{Config, Core, FeeConfig, FeeType, Recipient, SafetyConfig};
 use anyhow::Result;
+use btclib::amount::Amount;
+use btclib::crypto::{KeyFile, PrivateKey};
+use btclib::util::Saveable;
 use std::fs;
+use std::io::{self, Write};
 use std::panic;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use tracing::*;
 use tracing_appender::rolling::{RollingFileAppender, Rotation};
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
@@ -37,6 +42,14 @@ pub fn generate_dummy_config(path: &PathBuf) -> Result<()> {
             fee_type: FeeType::Percent,
             value: 0.1,
         },
+        auth_token: None,
+        broadcast_nodes: vec![],
+        safety: SafetyConfig::default(),
+        approval: None,
+        history_file: PathBuf::from("wallet_history.cbor"),
+        unlock: None,
+        backup: BackupConfig::default(),
+        network: "mainnet".to_string(),
     };
     let config_str = toml::to_string_pretty(&dummy_config)?;
     fs::write(path, config_str)?;
@@ -44,11 +57,56 @@ pub fn generate_dummy_config(path: &PathBuf) -> Result<()> {
     Ok(())
 }
 
-pub fn sats_to_btc(sats: u64) -> String {
-    let btc = sats as f64 / 100_000_000.0;
-    format!("{} BTC", btc)
+// Asked on the terminal before the UI takes it over
+pub fn prompt_passphrase() -> Result<String> {
+    prompt_line("Passphrase")
+}
+
+// Encrypted key files are tried with the passphrases that unlocked earlier
+// ones before asking, so keys sharing a passphrase ask only once
+pub fn load_private_key(path: &Path, passphrases: &mut Vec<String>) -> Result<PrivateKey> {
+    let sealed = match KeyFile::load_from_file(path)? {
+        KeyFile::Plain(key) => return Ok(key),
+        KeyFile::Encrypted(sealed) => sealed,
+    };
+    if let Some(key) = passphrases.iter().find_map(|p| sealed.decrypt(p).ok()) {
+        return Ok(key);
+    }
+    let passphrase = prompt_line(&format!("Passphrase for {}", path.display()))?;
+    let key = sealed.decrypt(&passphrase)?;
+    passphrases.push(passphrase);
+    Ok(key)
+}
+
+pub fn prompt_line(label: &str) -> Result<String> {
+    print!("{}: ", label);
+    io::stdout().flush()?;
+    let mut passphrase = String::new();
+    io::stdin().read_line(&mut passphrase)?;
+    Ok(passphrase.trim_end_matches(['\r', '\n']).to_string())
+}
+
+pub fn sats_to_btc(amount: Amount) -> String {
+    format!("{} BTC", amount.to_btc())
 }
 
 pub fn big_mode_btc(core: &Core) -> String {
     text_to_ascii_art::convert(sats_to_btc(core.get_balance())).unwrap()
 }
+
+// Lower scores are better matches, None if the query is not a subsequence
+pub fn fuzzy_score(query: &str, candidate: &str) -> Option<usize> {
+    let candidate = candidate.to_lowercase();
+    let mut chars = candidate.chars().enumerate();
+    let mut score = 0;
+    let mut last_match: Option<usize> = None;
+    for q in query.to_lowercase().chars() {
+        let (idx, _) = chars.find(|(_, c)| *c == q)?;
+        score += match last_match {
+            Some(last) => idx - last - 1,
+            None => idx,
+        };
+        last_match = Some(idx);
+    }
+    Some(score)
+}